@@ -1,5 +1,7 @@
+#[cfg(windows)]
 use ferrisetw::query::*;
 
+#[cfg(windows)]
 fn main() {
     println!("Max PMC: {}", SessionlessInfo::max_pmc().unwrap());
     println!(
@@ -7,3 +9,8 @@ fn main() {
         SessionlessInfo::sample_interval(ProfileSource::ProfileTime).unwrap()
     );
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("This example only works on Windows");
+}
@@ -0,0 +1,392 @@
+//! Record events into a compact binary capture, and replay them later
+//!
+//! ETL files are the native way to save ETW events, but they are not easy to post-process outside of Windows.<br/>
+//! This module provides a simple, documented and versioned container for [`OwnedEventRecord`]s:
+//! * a [`CaptureWriter`] can be plugged into any [`Provider`](crate::provider::Provider) (see [`CaptureWriter::callback`]),
+//! * a [`CaptureReader`] replays the events into the usual callback signature (`FnMut(&EventRecord, &SchemaLocator)`).
+//!
+//! Each record carries its raw header fields, its user data, its extended data and (optionally) the schema that was resolved for it when it was captured.
+//! Thanks to the embedded schemas, captured events can be parsed even on a machine that does not know their provider.
+//!
+//! # Example
+//! ```no_run
+//! use ferrisetw::capture::{CaptureReader, CaptureWriter, CaptureOptions};
+//! use ferrisetw::provider::Provider;
+//! use ferrisetw::EventRecord;
+//! use ferrisetw::schema_locator::SchemaLocator;
+//!
+//! let file = std::fs::File::create("capture.fcap").unwrap();
+//! let writer = CaptureWriter::new(file, CaptureOptions::default()).unwrap();
+//! let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716") // Microsoft-Windows-Kernel-Process
+//!     .add_callback(writer.callback())
+//!     .build();
+//! // ...start a trace with this provider, then, once the trace is stopped:
+//! writer.finish().unwrap();
+//!
+//! let mut reader = CaptureReader::open("capture.fcap").unwrap();
+//! reader.replay(|record: &EventRecord, schema_locator: &SchemaLocator| {
+//!     println!("Event {} from {:?}", record.event_id(), record.provider_id());
+//! }).unwrap();
+//! ```
+//!
+//! # File format
+//! All integers are little-endian. GUIDs are stored as `Data1` (u32), `Data2` (u16), `Data3` (u16), `Data4` (8 bytes).
+//!
+//! ```text
+//! file        := file_header block* [index trailer]
+//! file_header := magic "FETWCAP\0" | version: u16 | flags: u16 | reserved: u32
+//! block       := magic "FCBK" | compression: u8 | reserved: [u8; 3] | record_count: u32
+//!                | uncompressed_size: u32 | stored_size: u32 | first_timestamp: i64 | last_timestamp: i64
+//!                | payload: [u8; stored_size]
+//! index       := magic "FCIX" | entry_count: u32 | (block_offset: u64 | record_count: u32 | first_timestamp: i64 | last_timestamp: i64)*
+//! trailer     := index_offset: u64 | magic "FCEND\0\0\0"
+//! ```
+//!
+//! Once decompressed, the payload of a block is a sequence of records:
+//!
+//! ```text
+//! record      := record_size: u32 (the size of what follows)
+//!                | header_size: u16 | header_type: u16 | flags: u16 | event_property: u16
+//!                | thread_id: u32 | process_id: u32 | timestamp: i64 | provider_id: GUID
+//!                | event_id: u16 | version: u8 | channel: u8 | level: u8 | opcode: u8 | task: u16 | keyword: u64
+//!                | processor_time: u64 | activity_id: GUID
+//!                | processor_index: u16 | logger_id: u16
+//!                | user_data_size: u32 | user_data
+//!                | extended_data_count: u16 | (ext_type: u16 | ext_flags: u16 | data_size: u16 | data)*
+//!                | schema_size: u32 | schema (a raw TRACE_EVENT_INFO, absent if schema_size is 0)
+//! ```
+//!
+//! To keep captures small, a given schema is only written once per block.<br/>
+//! The index (and the trailer) are written when the capture is finished. Files without an index (e.g. because the writer did not finish properly) can still be read sequentially.
+use crate::native::TdhNativeError;
+
+mod format;
+mod reader;
+mod writer;
+
+pub use crate::native::etw_types::event_record::OwnedEventRecord;
+pub use reader::CaptureReader;
+pub use writer::CaptureWriter;
+
+/// Capture module errors
+#[derive(Debug)]
+pub enum CaptureError {
+    /// Represents an standard IO Error
+    IoError(std::io::Error),
+    /// The data is not a valid capture (or is corrupted)
+    InvalidFormat(&'static str),
+    /// The capture has been written with an unsupported version of the format
+    UnsupportedVersion(u16),
+    /// The capture uses a compression algorithm that is unknown, or that has not been enabled as a Cargo feature of this crate
+    UnsupportedCompression(u8),
+    /// A schema embedded in the capture is invalid
+    InvalidSchema(TdhNativeError),
+    /// The capture has already been finished, and no more event can be written to it
+    AlreadyFinished,
+    /// A thread panicked while writing to the capture, that may be inconsistent
+    Poisoned,
+}
+
+impl From<std::io::Error> for CaptureError {
+    fn from(err: std::io::Error) -> Self {
+        CaptureError::IoError(err)
+    }
+}
+
+impl From<TdhNativeError> for CaptureError {
+    fn from(err: TdhNativeError) -> Self {
+        CaptureError::InvalidSchema(err)
+    }
+}
+
+impl std::fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureError::IoError(err) => write!(f, "capture I/O error: {}", err),
+            CaptureError::InvalidFormat(reason) => write!(f, "invalid capture: {}", reason),
+            CaptureError::UnsupportedVersion(version) => write!(f, "unsupported capture format version {}", version),
+            CaptureError::UnsupportedCompression(id) => write!(f, "unsupported capture compression {} (is the matching Cargo feature enabled?)", id),
+            CaptureError::InvalidSchema(err) => write!(f, "invalid schema in capture: {}", err),
+            CaptureError::AlreadyFinished => write!(f, "the capture has already been finished"),
+            CaptureError::Poisoned => write!(f, "a thread panicked while writing to the capture"),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CaptureError::IoError(err) => Some(err),
+            CaptureError::InvalidSchema(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) type CaptureResult<T> = Result<T, CaptureError>;
+
+/// How blocks of a capture are compressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Blocks are stored as is
+    #[default]
+    None,
+    /// Blocks are compressed with [zstd](https://facebook.github.io/zstd/), at the given compression level.
+    ///
+    /// This requires the `capture_zstd` Cargo feature.
+    Zstd(i32),
+    /// Blocks are compressed with [LZ4](https://lz4.github.io/lz4/) (block format).
+    ///
+    /// This requires the `capture_lz4` Cargo feature.
+    Lz4,
+}
+
+/// Options for a [`CaptureWriter`]
+#[derive(Debug, Clone, Copy)]
+pub struct CaptureOptions {
+    /// How blocks are compressed
+    pub compression: Compression,
+    /// Size (in bytes) of the uncompressed records after which a block is written.
+    ///
+    /// Smaller blocks make seeking more precise, larger blocks compress better.
+    /// Blocks are written before they exceed 64 MiB anyway, since readers reject larger blocks.
+    pub block_size: usize,
+    /// Whether to embed the schema of the events into the capture.
+    ///
+    /// This makes captures bigger, but enables to parse their events on machines that do not know their provider.
+    pub include_schemas: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            compression: Compression::None,
+            block_size: 1024 * 1024,
+            include_schemas: true,
+        }
+    }
+}
+
+/// An entry of the index of a capture. There is one entry per block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Offset of the block from the start of the capture
+    pub offset: u64,
+    /// Number of records in this block
+    pub record_count: u32,
+    /// Smallest raw timestamp of the records in this block
+    pub first_timestamp: i64,
+    /// Largest raw timestamp of the records in this block
+    pub last_timestamp: i64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use windows::core::GUID;
+    use windows::Win32::System::Diagnostics::Etw::{EVENT_HEADER, ETW_BUFFER_CONTEXT, EVENT_HEADER_EXTENDED_DATA_ITEM};
+
+    const PROVIDER: GUID = GUID::from_u128(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716);
+
+    fn sample_record(i: u16) -> OwnedEventRecord {
+        let mut header = EVENT_HEADER {
+            ProviderId: PROVIDER,
+            ProcessId: 1000 + i as u32,
+            TimeStamp: 10 * i as i64,
+            ..Default::default()
+        };
+        header.EventDescriptor.Id = i;
+
+        let ext_item = EVENT_HEADER_EXTENDED_DATA_ITEM {
+            ExtType: 8, // EVENT_HEADER_EXT_TYPE_TS_ID
+            ..Default::default()
+        };
+        OwnedEventRecord::from_parts(
+            header,
+            ETW_BUFFER_CONTEXT::default(),
+            vec![i as u8; i as usize],
+            vec![(ext_item, vec![1, 0, 0, 0])],
+        )
+    }
+
+    /// A Write implementation that can be inspected after the writer is dropped
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn write_capture(options: CaptureOptions, count: u16) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let writer = CaptureWriter::new(buffer.clone(), options).unwrap();
+        for i in 0..count {
+            writer.write_event(&sample_record(i), None).unwrap();
+        }
+        writer.finish().unwrap();
+        let data = buffer.0.lock().unwrap().clone();
+        data
+    }
+
+    fn check_round_trip(options: CaptureOptions) {
+        let data = write_capture(options, 50);
+        let reader = CaptureReader::new(Cursor::new(data)).unwrap();
+        let records: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(records.len(), 50);
+        for (i, record) in records.iter().enumerate() {
+            let expected = sample_record(i as u16);
+            assert_eq!(record.provider_id(), PROVIDER);
+            assert_eq!(record.event_id(), i as u16);
+            assert_eq!(record.process_id(), expected.process_id());
+            assert_eq!(record.raw_timestamp(), expected.raw_timestamp());
+            assert_eq!(record.user_data(), expected.user_data());
+            assert_eq!(record.extended_data().len(), 1);
+            assert_eq!(record.extended_data()[0].data_type(), 8);
+            assert_eq!(record.extended_data()[0].data(), &[1, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        check_round_trip(CaptureOptions::default());
+        check_round_trip(CaptureOptions {
+            block_size: 256,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "capture_zstd")]
+    fn round_trip_zstd() {
+        check_round_trip(CaptureOptions {
+            compression: Compression::Zstd(3),
+            block_size: 256,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "capture_lz4")]
+    fn round_trip_lz4() {
+        check_round_trip(CaptureOptions {
+            compression: Compression::Lz4,
+            block_size: 256,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn index_and_seek() {
+        let options = CaptureOptions {
+            block_size: 256,
+            ..Default::default()
+        };
+        let data = write_capture(options, 50);
+        let mut reader = CaptureReader::new(Cursor::new(data)).unwrap();
+
+        let index = reader.index().unwrap().to_vec();
+        assert!(index.len() > 1);
+        assert_eq!(index.iter().map(|e| e.record_count as usize).sum::<usize>(), 50);
+
+        // Reading the index does not move the reading position
+        assert_eq!(reader.next_event().unwrap().unwrap().event_id(), 0);
+
+        reader.seek_to_block(2).unwrap();
+        let first_of_block_2 = reader.next_event().unwrap().unwrap();
+        assert_eq!(first_of_block_2.raw_timestamp(), index[2].first_timestamp);
+
+        // Records have increasing timestamps (10 * their ID), so this replays from the start of the block that contains ID 30
+        let block_of_30 = index.iter().position(|e| e.first_timestamp <= 300 && 300 <= e.last_timestamp).unwrap();
+        let first_id = (index[block_of_30].first_timestamp / 10) as u16;
+        assert!(first_id > 0 && first_id <= 30);
+        assert!(reader.seek_to_timestamp(300).unwrap());
+        let mut replayed_ids = Vec::new();
+        let replayed = reader.replay(|record, _| replayed_ids.push(record.event_id())).unwrap();
+        assert_eq!(replayed_ids, (first_id..50).collect::<Vec<_>>());
+        assert_eq!(replayed, replayed_ids.len());
+
+        assert!(!reader.seek_to_timestamp(i64::MAX).unwrap());
+    }
+
+    #[test]
+    fn unfinished_capture() {
+        let data = write_capture(CaptureOptions::default(), 5);
+
+        // Strip the index and the trailer: the events are still readable
+        let index_start = data.windows(4).rposition(|w| w == b"FCIX").unwrap();
+        let mut reader = CaptureReader::new(Cursor::new(data[..index_start].to_vec())).unwrap();
+        assert_eq!(reader.replay(|_, _| {}).unwrap(), 5);
+        assert!(reader.index().is_err());
+    }
+
+    #[test]
+    fn invalid_captures() {
+        assert!(matches!(
+            CaptureReader::new(Cursor::new(b"NOTACAPTUREFILE!".to_vec())),
+            Err(CaptureError::InvalidFormat(_))
+        ));
+
+        let data = write_capture(CaptureOptions::default(), 5);
+        let truncated = data[..data.len() / 2].to_vec();
+        let mut reader = CaptureReader::new(Cursor::new(truncated)).unwrap();
+        assert!(reader.next_event().is_err());
+
+        let mut unsupported_version = data.clone();
+        unsupported_version[8] = 0xff;
+        assert!(matches!(
+            CaptureReader::new(Cursor::new(unsupported_version)),
+            Err(CaptureError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn corrupted_record() {
+        let mut data = write_capture(CaptureOptions::default(), 5);
+        // The record count of the first block, right after the file header, the block magic and the compression: the 6th record cannot be decoded
+        let offset = format::FILE_HEADER_SIZE + 8;
+        data[offset..offset + 4].copy_from_slice(&6u32.to_le_bytes());
+        let reader = CaptureReader::new(Cursor::new(data)).unwrap();
+
+        // Errors are not returned over and over again, so that skipping them does not loop forever
+        let results: Vec<_> = reader.collect();
+        assert_eq!(results.len(), 6);
+        assert!(results[..5].iter().all(|result| result.is_ok()));
+        assert!(matches!(results[5], Err(CaptureError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn corrupted_index() {
+        let mut data = write_capture(CaptureOptions::default(), 5);
+        let count_offset = data.windows(4).rposition(|w| w == b"FCIX").unwrap() + 4;
+        for count in [u32::MAX, 2] {
+            data[count_offset..count_offset + 4].copy_from_slice(&count.to_le_bytes());
+            let mut reader = CaptureReader::new(Cursor::new(data.clone())).unwrap();
+            assert!(matches!(reader.index(), Err(CaptureError::InvalidFormat("invalid index"))));
+        }
+    }
+
+    #[test]
+    fn oversized_block() {
+        let mut data = write_capture(CaptureOptions::default(), 5);
+        // The uncompressed size of the first block, right after the file header, the block magic, the compression and the record count
+        let offset = format::FILE_HEADER_SIZE + 12;
+        data[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = CaptureReader::new(Cursor::new(data)).unwrap();
+        assert!(matches!(reader.next_event(), Err(CaptureError::InvalidFormat("block is too large"))));
+    }
+
+    #[test]
+    fn finished_writer() {
+        let writer = CaptureWriter::new(Vec::new(), CaptureOptions::default()).unwrap();
+        writer.finish().unwrap();
+        assert!(matches!(writer.write_event(&sample_record(0), None), Err(CaptureError::AlreadyFinished)));
+    }
+}
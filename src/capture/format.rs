@@ -0,0 +1,388 @@
+//! Low-level encoding of the capture format
+//!
+//! See the documentation of the [`crate::capture`] module for a description of the format.
+use std::convert::TryInto;
+
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::{
+    ETW_BUFFER_CONTEXT, EVENT_DESCRIPTOR, EVENT_HEADER, EVENT_HEADER_EXTENDED_DATA_ITEM,
+};
+
+use super::{CaptureError, CaptureResult, Compression, IndexEntry};
+use crate::native::etw_types::event_record::{EventRecord, OwnedEventRecord};
+
+pub(crate) const FILE_MAGIC: [u8; 8] = *b"FETWCAP\0";
+pub(crate) const FORMAT_VERSION: u16 = 1;
+pub(crate) const FILE_HEADER_SIZE: usize = 16;
+/// Set in the file header flags in case the writer was configured to embed schemas
+pub(crate) const FLAG_SCHEMAS: u16 = 0x1;
+
+pub(crate) const BLOCK_MAGIC: [u8; 4] = *b"FCBK";
+pub(crate) const BLOCK_HEADER_SIZE: usize = 36;
+/// Largest uncompressed block. This is enforced by readers, so that a corrupted capture cannot make them allocate huge buffers
+pub(crate) const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+
+pub(crate) const INDEX_MAGIC: [u8; 4] = *b"FCIX";
+pub(crate) const INDEX_ENTRY_SIZE: usize = 28;
+
+pub(crate) const TRAILER_MAGIC: [u8; 8] = *b"FCEND\0\0\0";
+pub(crate) const TRAILER_SIZE: usize = 16;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_ZSTD: u8 = 1;
+const COMPRESSION_LZ4: u8 = 2;
+
+/// Append little-endian values to a buffer
+pub(crate) struct ByteWriter<'a>(pub &'a mut Vec<u8>);
+
+impl ByteWriter<'_> {
+    pub fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+    pub fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    pub fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    pub fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    pub fn i64(&mut self, v: i64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.0.extend_from_slice(v);
+    }
+    pub fn guid(&mut self, g: &GUID) {
+        self.u32(g.data1);
+        self.u16(g.data2);
+        self.u16(g.data3);
+        self.bytes(&g.data4);
+    }
+}
+
+/// Read little-endian values from a buffer, failing (rather than panicking) on truncated data
+pub(crate) struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// How many bytes have been read so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize) -> CaptureResult<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or(CaptureError::InvalidFormat("truncated data"))?;
+        let s = self.data.get(self.pos..end).ok_or(CaptureError::InvalidFormat("truncated data"))?;
+        self.pos = end;
+        Ok(s)
+    }
+    pub fn u8(&mut self) -> CaptureResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub fn u16(&mut self) -> CaptureResult<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+    pub fn u32(&mut self) -> CaptureResult<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> CaptureResult<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    pub fn i64(&mut self) -> CaptureResult<i64> {
+        Ok(i64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    pub fn guid(&mut self) -> CaptureResult<GUID> {
+        let data1 = self.u32()?;
+        let data2 = self.u16()?;
+        let data3 = self.u16()?;
+        let data4 = self.bytes(8)?.try_into().unwrap();
+        Ok(GUID { data1, data2, data3, data4 })
+    }
+}
+
+pub(crate) fn encode_file_header(buf: &mut Vec<u8>, flags: u16) {
+    let mut w = ByteWriter(buf);
+    w.bytes(&FILE_MAGIC);
+    w.u16(FORMAT_VERSION);
+    w.u16(flags);
+    w.u32(0);
+}
+
+/// Decode a file header, and returns its flags
+pub(crate) fn decode_file_header(data: &[u8]) -> CaptureResult<u16> {
+    let mut r = ByteReader::new(data);
+    if r.bytes(FILE_MAGIC.len())? != FILE_MAGIC {
+        return Err(CaptureError::InvalidFormat("not a capture file"));
+    }
+    let version = r.u16()?;
+    if version != FORMAT_VERSION {
+        return Err(CaptureError::UnsupportedVersion(version));
+    }
+    r.u16()
+}
+
+/// The header of a block, that precedes its payload
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BlockHeader {
+    pub compression: u8,
+    pub record_count: u32,
+    pub uncompressed_size: u32,
+    pub stored_size: u32,
+    pub first_timestamp: i64,
+    pub last_timestamp: i64,
+}
+
+impl BlockHeader {
+    /// Encode the header (including the block magic)
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut w = ByteWriter(buf);
+        w.bytes(&BLOCK_MAGIC);
+        w.u8(self.compression);
+        w.bytes(&[0; 3]);
+        w.u32(self.record_count);
+        w.u32(self.uncompressed_size);
+        w.u32(self.stored_size);
+        w.i64(self.first_timestamp);
+        w.i64(self.last_timestamp);
+    }
+
+    /// Decode a header (whose block magic has already been read)
+    pub fn decode(data: &[u8]) -> CaptureResult<Self> {
+        let mut r = ByteReader::new(data);
+        let compression = r.u8()?;
+        r.bytes(3)?;
+        Ok(Self {
+            compression,
+            record_count: r.u32()?,
+            uncompressed_size: r.u32()?,
+            stored_size: r.u32()?,
+            first_timestamp: r.i64()?,
+            last_timestamp: r.i64()?,
+        })
+    }
+}
+
+pub(crate) fn encode_index(buf: &mut Vec<u8>, entries: &[IndexEntry]) {
+    let mut w = ByteWriter(buf);
+    w.bytes(&INDEX_MAGIC);
+    w.u32(entries.len() as u32);
+    for entry in entries {
+        w.u64(entry.offset);
+        w.u32(entry.record_count);
+        w.i64(entry.first_timestamp);
+        w.i64(entry.last_timestamp);
+    }
+}
+
+/// Decode the index entries (whose index magic and entry count have already been read)
+pub(crate) fn decode_index_entries(data: &[u8], count: usize) -> CaptureResult<Vec<IndexEntry>> {
+    let mut r = ByteReader::new(data);
+    let mut entries = Vec::with_capacity(count.min(data.len() / INDEX_ENTRY_SIZE));
+    for _ in 0..count {
+        entries.push(IndexEntry {
+            offset: r.u64()?,
+            record_count: r.u32()?,
+            first_timestamp: r.i64()?,
+            last_timestamp: r.i64()?,
+        });
+    }
+    Ok(entries)
+}
+
+pub(crate) fn encode_trailer(buf: &mut Vec<u8>, index_offset: u64) {
+    let mut w = ByteWriter(buf);
+    w.u64(index_offset);
+    w.bytes(&TRAILER_MAGIC);
+}
+
+/// Decode a trailer, and return the offset of the index
+pub(crate) fn decode_trailer(data: &[u8]) -> CaptureResult<u64> {
+    let mut r = ByteReader::new(data);
+    let index_offset = r.u64()?;
+    if r.bytes(TRAILER_MAGIC.len())? != TRAILER_MAGIC {
+        return Err(CaptureError::InvalidFormat("missing trailer"));
+    }
+    Ok(index_offset)
+}
+
+/// Append a record to a block payload
+pub(crate) fn encode_record(buf: &mut Vec<u8>, record: &EventRecord, schema: Option<&[u8]>) {
+    let raw = record.as_raw();
+    let header = &raw.EventHeader;
+
+    let size_offset = buf.len();
+    let mut w = ByteWriter(buf);
+    w.u32(0); // Placeholder, this will be filled once the record is written
+
+    w.u16(header.Size);
+    w.u16(header.HeaderType);
+    w.u16(header.Flags);
+    w.u16(header.EventProperty);
+    w.u32(header.ThreadId);
+    w.u32(header.ProcessId);
+    w.i64(header.TimeStamp);
+    w.guid(&header.ProviderId);
+    w.u16(header.EventDescriptor.Id);
+    w.u8(header.EventDescriptor.Version);
+    w.u8(header.EventDescriptor.Channel);
+    w.u8(header.EventDescriptor.Level);
+    w.u8(header.EventDescriptor.Opcode);
+    w.u16(header.EventDescriptor.Task);
+    w.u64(header.EventDescriptor.Keyword);
+    w.u64(unsafe {
+        // Safety: both members of this union are plain integers that span the whole union
+        header.Anonymous.ProcessorTime
+    });
+    w.guid(&header.ActivityId);
+
+    w.u16(unsafe {
+        // Safety: both members of this union are plain integers that span the whole union
+        raw.BufferContext.Anonymous.ProcessorIndex
+    });
+    w.u16(raw.BufferContext.LoggerId);
+
+    let user_data = record.user_buffer();
+    w.u32(user_data.len() as u32);
+    w.bytes(user_data);
+
+    let extended_data = record.extended_data();
+    w.u16(extended_data.len() as u16);
+    for item in extended_data {
+        let raw_item = item.as_raw();
+        let data = item.data();
+        w.u16(raw_item.ExtType);
+        w.u16(raw_item.Anonymous._bitfield);
+        w.u16(data.len() as u16);
+        w.bytes(data);
+    }
+
+    let schema = schema.unwrap_or(&[]);
+    w.u32(schema.len() as u32);
+    w.bytes(schema);
+
+    let record_size = (buf.len() - size_offset - 4) as u32;
+    buf[size_offset..size_offset + 4].copy_from_slice(&record_size.to_le_bytes());
+}
+
+/// Decode the next record of a block payload, along with its schema (if any)
+pub(crate) fn decode_record(r: &mut ByteReader) -> CaptureResult<(OwnedEventRecord, Option<Vec<u8>>)> {
+    let record_size = r.u32()? as usize;
+    let mut r = ByteReader::new(r.bytes(record_size)?);
+
+    let mut header = EVENT_HEADER {
+        Size: r.u16()?,
+        HeaderType: r.u16()?,
+        Flags: r.u16()?,
+        EventProperty: r.u16()?,
+        ThreadId: r.u32()?,
+        ProcessId: r.u32()?,
+        TimeStamp: r.i64()?,
+        ProviderId: r.guid()?,
+        EventDescriptor: EVENT_DESCRIPTOR {
+            Id: r.u16()?,
+            Version: r.u8()?,
+            Channel: r.u8()?,
+            Level: r.u8()?,
+            Opcode: r.u8()?,
+            Task: r.u16()?,
+            Keyword: r.u64()?,
+        },
+        ..Default::default()
+    };
+    header.Anonymous.ProcessorTime = r.u64()?;
+    header.ActivityId = r.guid()?;
+
+    let mut buffer_context = ETW_BUFFER_CONTEXT::default();
+    buffer_context.Anonymous.ProcessorIndex = r.u16()?;
+    buffer_context.LoggerId = r.u16()?;
+
+    let user_data_size = r.u32()? as usize;
+    if user_data_size > u16::MAX as usize {
+        return Err(CaptureError::InvalidFormat("user data is too large"));
+    }
+    let user_data = r.bytes(user_data_size)?.to_vec();
+
+    let extended_data_count = r.u16()?;
+    let mut extended_data = Vec::with_capacity(extended_data_count as usize);
+    for _ in 0..extended_data_count {
+        let mut item = EVENT_HEADER_EXTENDED_DATA_ITEM {
+            ExtType: r.u16()?,
+            ..Default::default()
+        };
+        item.Anonymous._bitfield = r.u16()?;
+        let data_size = r.u16()? as usize;
+        extended_data.push((item, r.bytes(data_size)?.to_vec()));
+    }
+
+    let schema_size = r.u32()? as usize;
+    let schema = match schema_size {
+        0 => None,
+        size => Some(r.bytes(size)?.to_vec()),
+    };
+
+    let record = OwnedEventRecord::from_parts(header, buffer_context, user_data, extended_data);
+    Ok((record, schema))
+}
+
+/// The on-disk identifier of a compression algorithm
+pub(crate) fn compression_id(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => COMPRESSION_NONE,
+        Compression::Zstd(_) => COMPRESSION_ZSTD,
+        Compression::Lz4 => COMPRESSION_LZ4,
+    }
+}
+
+/// Check a compression algorithm is available in this build
+pub(crate) fn check_compression_support(compression: Compression) -> CaptureResult<()> {
+    match compression {
+        Compression::None => Ok(()),
+        #[cfg(feature = "capture_zstd")]
+        Compression::Zstd(_) => Ok(()),
+        #[cfg(feature = "capture_lz4")]
+        Compression::Lz4 => Ok(()),
+        #[allow(unreachable_patterns)]
+        other => Err(CaptureError::UnsupportedCompression(compression_id(other))),
+    }
+}
+
+pub(crate) fn compress(compression: Compression, data: &[u8]) -> CaptureResult<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        #[cfg(feature = "capture_zstd")]
+        Compression::Zstd(level) => Ok(zstd::bulk::compress(data, level)?),
+        #[cfg(feature = "capture_lz4")]
+        Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+        #[allow(unreachable_patterns)]
+        other => Err(CaptureError::UnsupportedCompression(compression_id(other))),
+    }
+}
+
+pub(crate) fn decompress(compression_id: u8, data: Vec<u8>, uncompressed_size: usize) -> CaptureResult<Vec<u8>> {
+    if uncompressed_size > MAX_BLOCK_SIZE {
+        return Err(CaptureError::InvalidFormat("block is too large"));
+    }
+
+    let decompressed = match compression_id {
+        COMPRESSION_NONE => data,
+        #[cfg(feature = "capture_zstd")]
+        COMPRESSION_ZSTD => zstd::bulk::decompress(&data, uncompressed_size)?,
+        #[cfg(feature = "capture_lz4")]
+        COMPRESSION_LZ4 => lz4_flex::block::decompress(&data, uncompressed_size)
+            .map_err(|_| CaptureError::InvalidFormat("invalid LZ4 block"))?,
+        other => return Err(CaptureError::UnsupportedCompression(other)),
+    };
+
+    if decompressed.len() != uncompressed_size {
+        return Err(CaptureError::InvalidFormat("unexpected block size"));
+    }
+    Ok(decompressed)
+}
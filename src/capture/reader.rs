@@ -0,0 +1,280 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::format::{self, BlockHeader, ByteReader};
+use super::{CaptureError, CaptureResult, IndexEntry};
+use crate::native::etw_types::event_record::{EventRecord, OwnedEventRecord};
+use crate::native::tdh::TraceEventInfo;
+use crate::schema::Schema;
+use crate::schema_locator::SchemaLocator;
+
+/// Reads events from a capture
+///
+/// Events can be read one by one (this type is an `Iterator`), or replayed into a callback (see [`Self::replay`]).<br/>
+/// Schemas embedded into the capture are made available through [`Self::schema_locator`].
+///
+/// See the [module-level documentation](crate::capture) for more info.
+pub struct CaptureReader<R: Read> {
+    source: R,
+    /// Decompressed payload of the current block
+    block: Vec<u8>,
+    block_pos: usize,
+    block_remaining_records: u32,
+    /// Set once the index (or the end of the data) has been reached
+    reached_end: bool,
+    schema_locator: SchemaLocator,
+    index: Option<Vec<IndexEntry>>,
+}
+
+impl<R: Read> std::fmt::Debug for CaptureReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureReader")
+            .field("block_remaining_records", &self.block_remaining_records)
+            .field("reached_end", &self.reached_end)
+            .field("schema_locator", &self.schema_locator)
+            .finish()
+    }
+}
+
+impl CaptureReader<std::io::BufReader<std::fs::File>> {
+    /// Convenience function that opens a capture file
+    pub fn open<P: AsRef<Path>>(path: P) -> CaptureResult<Self> {
+        let file = std::fs::File::open(path)?;
+        Self::new(std::io::BufReader::new(file))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Start reading a capture
+    ///
+    /// `source` does not need to be seekable, unless you want to use the index of the capture.
+    pub fn new(mut source: R) -> CaptureResult<Self> {
+        let mut header = [0u8; format::FILE_HEADER_SIZE];
+        source.read_exact(&mut header)?;
+        let _flags = format::decode_file_header(&header)?;
+
+        Ok(Self {
+            source,
+            block: Vec::new(),
+            block_pos: 0,
+            block_remaining_records: 0,
+            reached_end: false,
+            schema_locator: SchemaLocator::new(),
+            index: None,
+        })
+    }
+
+    /// The schemas that have been read from the capture so far
+    ///
+    /// Schemas are (optionally) embedded into the capture, before the first event that needs them in every block.
+    pub fn schema_locator(&self) -> &SchemaLocator {
+        &self.schema_locator
+    }
+
+    /// Read the next event, or `Ok(None)` at the end of the capture
+    ///
+    /// Reading stops at the first malformed block or record: once this returned an `Err`, it returns `Ok(None)` (unless [`Self::seek_to_block`] is used to skip it).
+    pub fn next_event(&mut self) -> CaptureResult<Option<OwnedEventRecord>> {
+        self.next_event_with_schema()
+            .map(|opt| opt.map(|(record, _schema)| record))
+    }
+
+    /// Read the next event, along with its schema in case it was embedded right before this event
+    pub(crate) fn next_event_with_schema(&mut self) -> CaptureResult<Option<(OwnedEventRecord, Option<Arc<Schema>>)>> {
+        while self.block_remaining_records == 0 {
+            if self.reached_end {
+                return Ok(None);
+            }
+            match self.load_next_block() {
+                Ok(true) => (),
+                Ok(false) => return Ok(None),
+                Err(err) => {
+                    self.stop_reading();
+                    return Err(err);
+                },
+            }
+        }
+
+        let mut r = ByteReader::new(self.block.get(self.block_pos..).unwrap_or(&[]));
+        let (record, schema) = match format::decode_record(&mut r) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                // The next records cannot be located anymore
+                self.stop_reading();
+                return Err(err);
+            },
+        };
+        self.block_pos += r.position();
+        self.block_remaining_records -= 1;
+
+        let schema = match schema {
+            None => None,
+            Some(te_info_bytes) => {
+                let te_info = TraceEventInfo::from_bytes(&te_info_bytes)?;
+                let schema = Arc::new(Schema::new(te_info));
+                self.schema_locator.insert_schema(&record, Arc::clone(&schema));
+                Some(schema)
+            }
+        };
+
+        Ok(Some((record, schema)))
+    }
+
+    /// Feed every remaining event of the capture to a callback
+    ///
+    /// Returns the number of replayed events
+    pub fn replay<F>(&mut self, mut callback: F) -> CaptureResult<usize>
+    where
+        F: FnMut(&EventRecord, &SchemaLocator),
+    {
+        let mut count = 0;
+        while let Some(record) = self.next_event()? {
+            callback(record.as_event_record(), &self.schema_locator);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Called after a malformed block or record, so that the next calls do not fail over and over again
+    fn stop_reading(&mut self) {
+        self.reached_end = true;
+        self.block.clear();
+        self.block_pos = 0;
+        self.block_remaining_records = 0;
+    }
+
+    /// Read the next block header and payload. Returns `Ok(false)` at the end of the blocks.
+    fn load_next_block(&mut self) -> CaptureResult<bool> {
+        let mut magic = [0u8; 4];
+        if !read_exact_or_eof(&mut self.source, &mut magic)? {
+            // This capture has not been properly finished. That's fine, we can still use it.
+            self.reached_end = true;
+            return Ok(false);
+        }
+
+        if magic == format::INDEX_MAGIC {
+            self.reached_end = true;
+            return Ok(false);
+        }
+        if magic != format::BLOCK_MAGIC {
+            return Err(CaptureError::InvalidFormat("invalid block header"));
+        }
+
+        let mut header = [0u8; format::BLOCK_HEADER_SIZE - 4];
+        self.source.read_exact(&mut header)?;
+        let header = BlockHeader::decode(&header)?;
+
+        let mut payload = Vec::new();
+        (&mut self.source).take(header.stored_size as u64).read_to_end(&mut payload)?;
+        if payload.len() != header.stored_size as usize {
+            return Err(CaptureError::InvalidFormat("truncated block"));
+        }
+
+        self.block = format::decompress(header.compression, payload, header.uncompressed_size as usize)?;
+        self.block_pos = 0;
+        self.block_remaining_records = header.record_count;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> CaptureReader<R> {
+    /// Read the index of the capture.
+    ///
+    /// This is read from the end of the capture, and the current reading position is preserved.<br/>
+    /// This fails in case the capture has no index (e.g. because its writer has not been properly finished).
+    pub fn index(&mut self) -> CaptureResult<&[IndexEntry]> {
+        if self.index.is_none() {
+            let current_position = self.source.stream_position()?;
+            let index = self.read_index();
+            self.source.seek(SeekFrom::Start(current_position))?;
+            self.index = Some(index?);
+        }
+
+        Ok(self.index.as_deref().unwrap_or(&[]))
+    }
+
+    fn read_index(&mut self) -> CaptureResult<Vec<IndexEntry>> {
+        let trailer_offset = self.source.seek(SeekFrom::End(-(format::TRAILER_SIZE as i64)))?;
+        let mut trailer = [0u8; format::TRAILER_SIZE];
+        self.source.read_exact(&mut trailer)?;
+        let index_offset = format::decode_trailer(&trailer)?;
+
+        self.source.seek(SeekFrom::Start(index_offset))?;
+        let mut index_header = [0u8; 8];
+        self.source.read_exact(&mut index_header)?;
+        if index_header[..4] != format::INDEX_MAGIC {
+            return Err(CaptureError::InvalidFormat("invalid index"));
+        }
+        let mut r = ByteReader::new(&index_header[4..]);
+        let count = r.u32()? as usize;
+
+        // The count is read from the file: it must not make us read past the trailer
+        let entries_size = count
+            .checked_mul(format::INDEX_ENTRY_SIZE)
+            .ok_or(CaptureError::InvalidFormat("invalid index"))?;
+        let available_size = trailer_offset.saturating_sub(index_offset.saturating_add(index_header.len() as u64));
+        if entries_size as u64 > available_size {
+            return Err(CaptureError::InvalidFormat("invalid index"));
+        }
+
+        let mut entries = Vec::new();
+        (&mut self.source).take(entries_size as u64).read_to_end(&mut entries)?;
+        format::decode_index_entries(&entries, count)
+    }
+
+    /// Move the reading position to the start of the `n`-th block of the capture
+    pub fn seek_to_block(&mut self, n: usize) -> CaptureResult<()> {
+        let offset = self
+            .index()?
+            .get(n)
+            .map(|entry| entry.offset)
+            .ok_or(CaptureError::InvalidFormat("no such block"))?;
+
+        self.source.seek(SeekFrom::Start(offset))?;
+        self.block.clear();
+        self.block_pos = 0;
+        self.block_remaining_records = 0;
+        self.reached_end = false;
+        Ok(())
+    }
+
+    /// Move the reading position to the first block that may contain events with a raw timestamp greater than or equal to `timestamp`
+    ///
+    /// Note that records are stored in the order they have been received, which is not strictly the order of their timestamps.
+    /// This returns `Ok(false)` (and does not move) in case no such block exists.
+    pub fn seek_to_timestamp(&mut self, timestamp: i64) -> CaptureResult<bool> {
+        let block = self
+            .index()?
+            .iter()
+            .position(|entry| entry.last_timestamp >= timestamp);
+
+        match block {
+            None => Ok(false),
+            Some(n) => self.seek_to_block(n).map(|_| true),
+        }
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = CaptureResult<OwnedEventRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+/// Similar to `read_exact`, but returns `Ok(false)` in case the source is exhausted before any byte is read
+fn read_exact_or_eof<R: Read>(source: &mut R, buf: &mut [u8]) -> CaptureResult<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match source.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(CaptureError::InvalidFormat("truncated data")),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,268 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::format::{self, BlockHeader};
+use super::{CaptureError, CaptureOptions, CaptureResult, IndexEntry};
+use crate::native::etw_types::event_record::EventRecord;
+use crate::schema::Schema;
+use crate::schema_locator::{SchemaKey, SchemaLocator};
+
+/// Writes events into a capture
+///
+/// This can be cheaply cloned, and all clones write to the same capture.<br/>
+/// The capture is finished (i.e. its last block and its index are written) when [`Self::finish`] is called, or when the last clone is dropped.
+///
+/// See the [module-level documentation](crate::capture) for more info.
+#[derive(Clone)]
+pub struct CaptureWriter {
+    state: Arc<Mutex<WriterState>>,
+}
+
+struct WriterState {
+    /// `None` once the capture is finished
+    sink: Option<Box<dyn Write + Send>>,
+    options: CaptureOptions,
+    /// How many bytes have been written to the sink so far
+    position: u64,
+    /// Uncompressed records of the current block
+    block: Vec<u8>,
+    block_record_count: u32,
+    block_first_timestamp: i64,
+    block_last_timestamp: i64,
+    /// Schemas that have already been written in the current block
+    block_schemas: HashSet<SchemaKey>,
+    index: Vec<IndexEntry>,
+    /// Errors that happened in callbacks (that have no way to report them) are stored here, and returned by `finish`
+    deferred_error: Option<CaptureError>,
+    /// Whether a callback has already warned that it received events after the capture was finished
+    warned_finished: bool,
+}
+
+impl std::fmt::Debug for CaptureWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("CaptureWriter");
+        if let Ok(state) = self.state.try_lock() {
+            s.field("options", &state.options)
+                .field("position", &state.position)
+                .field("blocks", &state.index.len())
+                .field("finished", &state.sink.is_none());
+        }
+        s.finish()
+    }
+}
+
+impl CaptureWriter {
+    /// Start a new capture that will be written to `sink`
+    ///
+    /// `sink` does not need to be seekable, this makes it possible to stream a capture (e.g. over the network).<br/>
+    /// This fails in case the requested compression is not supported by this build, or if the file header cannot be written.
+    pub fn new<W>(sink: W, options: CaptureOptions) -> CaptureResult<Self>
+    where
+        W: Write + Send + 'static,
+    {
+        format::check_compression_support(options.compression)?;
+
+        let mut sink: Box<dyn Write + Send> = Box::new(sink);
+        let mut header = Vec::with_capacity(format::FILE_HEADER_SIZE);
+        let flags = if options.include_schemas { format::FLAG_SCHEMAS } else { 0 };
+        format::encode_file_header(&mut header, flags);
+        sink.write_all(&header)?;
+
+        let state = WriterState {
+            sink: Some(sink),
+            options,
+            position: header.len() as u64,
+            block: Vec::new(),
+            block_record_count: 0,
+            block_first_timestamp: i64::MAX,
+            block_last_timestamp: i64::MIN,
+            block_schemas: HashSet::new(),
+            index: Vec::new(),
+            deferred_error: None,
+            warned_finished: false,
+        };
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Convenience function that creates a file and starts a new capture in it
+    pub fn create<P: AsRef<Path>>(path: P, options: CaptureOptions) -> CaptureResult<Self> {
+        let file = std::fs::File::create(path)?;
+        Self::new(std::io::BufWriter::new(file), options)
+    }
+
+    /// Returns a callback that writes every event it receives into this capture
+    ///
+    /// This is meant to be given to [`ProviderBuilder::add_callback`](crate::provider::ProviderBuilder::add_callback).<br/>
+    /// Because callbacks cannot return errors, write errors are logged, and returned by the next call to [`Self::finish`].<br/>
+    /// Events received after the capture is finished are ignored (with a single warning).
+    pub fn callback(&self) -> impl FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static {
+        let writer = self.clone();
+        move |record: &EventRecord, schema_locator: &SchemaLocator| {
+            let include_schemas = writer.state.lock().map(|s| s.options.include_schemas).unwrap_or(false);
+            let schema = if include_schemas {
+                schema_locator.event_schema(record).ok()
+            } else {
+                None
+            };
+
+            match writer.write_event(record, schema.as_deref()) {
+                Ok(()) => (),
+                Err(CaptureError::AlreadyFinished) => {
+                    if let Ok(mut state) = writer.state.lock() {
+                        if !state.warned_finished {
+                            log::warn!("The capture has already been finished, further events are ignored");
+                            state.warned_finished = true;
+                        }
+                    }
+                },
+                Err(err) => {
+                    log::error!("Unable to write event to capture: {:?}", err);
+                    if let Ok(mut state) = writer.state.lock() {
+                        state.deferred_error.get_or_insert(err);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Write an event, along with its schema (if known and if the writer is configured to include schemas)
+    pub fn write_event(&self, record: &EventRecord, schema: Option<&Schema>) -> CaptureResult<()> {
+        let mut state = self.state.lock().map_err(|_| CaptureError::Poisoned)?;
+        state.write_event(record, schema)
+    }
+
+    /// Write the pending block and the index, and flush the underlying writer
+    ///
+    /// No more event can be written afterwards.
+    /// In case an error happened while writing events from a callback, it is returned here.
+    pub fn finish(&self) -> CaptureResult<()> {
+        let mut state = self.state.lock().map_err(|_| CaptureError::Poisoned)?;
+        state.finish()?;
+        match state.deferred_error.take() {
+            None => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
+}
+
+impl WriterState {
+    fn write_event(&mut self, record: &EventRecord, schema: Option<&Schema>) -> CaptureResult<()> {
+        if self.sink.is_none() {
+            return Err(CaptureError::AlreadyFinished);
+        }
+
+        let schema_bytes = match schema {
+            Some(schema) if self.options.include_schemas => {
+                if self.block_schemas.insert(SchemaKey::new(record)) {
+                    Some(schema.te_info().as_bytes())
+                } else {
+                    // Already written in this block
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let record_start = self.block.len();
+        format::encode_record(&mut self.block, record, schema_bytes);
+        if self.block.len() > format::MAX_BLOCK_SIZE {
+            self.block.truncate(record_start);
+            if schema_bytes.is_some() {
+                self.block_schemas.remove(&SchemaKey::new(record));
+            }
+            if record_start == 0 {
+                return Err(CaptureError::InvalidFormat("event is larger than the maximum block size"));
+            }
+            // Write this event into a new block
+            self.flush_block()?;
+            return self.write_event(record, schema);
+        }
+
+        let timestamp = record.raw_timestamp();
+        self.block_record_count += 1;
+        self.block_first_timestamp = self.block_first_timestamp.min(timestamp);
+        self.block_last_timestamp = self.block_last_timestamp.max(timestamp);
+
+        if self.block.len() >= self.options.block_size {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> CaptureResult<()> {
+        let sink = self.sink.as_mut().ok_or(CaptureError::AlreadyFinished)?;
+        sink.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> CaptureResult<()> {
+        if self.block_record_count == 0 {
+            return Ok(());
+        }
+
+        let payload = format::compress(self.options.compression, &self.block)?;
+        let header = BlockHeader {
+            compression: format::compression_id(self.options.compression),
+            record_count: self.block_record_count,
+            uncompressed_size: self.block.len() as u32,
+            stored_size: payload.len() as u32,
+            first_timestamp: self.block_first_timestamp,
+            last_timestamp: self.block_last_timestamp,
+        };
+
+        let entry = IndexEntry {
+            offset: self.position,
+            record_count: header.record_count,
+            first_timestamp: header.first_timestamp,
+            last_timestamp: header.last_timestamp,
+        };
+
+        let mut encoded_header = Vec::with_capacity(format::BLOCK_HEADER_SIZE);
+        header.encode(&mut encoded_header);
+        self.write_all(&encoded_header)?;
+        self.write_all(&payload)?;
+        self.index.push(entry);
+
+        self.block.clear();
+        self.block_record_count = 0;
+        self.block_first_timestamp = i64::MAX;
+        self.block_last_timestamp = i64::MIN;
+        self.block_schemas.clear();
+        Ok(())
+    }
+
+    fn finish(&mut self) -> CaptureResult<()> {
+        if self.sink.is_none() {
+            return Err(CaptureError::AlreadyFinished);
+        }
+
+        self.flush_block()?;
+
+        let index_offset = self.position;
+        let mut end = Vec::with_capacity(8 + self.index.len() * format::INDEX_ENTRY_SIZE + format::TRAILER_SIZE);
+        format::encode_index(&mut end, &self.index);
+        format::encode_trailer(&mut end, index_offset);
+        self.write_all(&end)?;
+
+        if let Some(mut sink) = self.sink.take() {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WriterState {
+    fn drop(&mut self) {
+        if self.sink.is_some() {
+            if let Err(err) = self.finish() {
+                log::error!("Unable to finish capture: {:?}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! A single error type for the whole crate
+//!
+//! Every module has its own error type (e.g. [`TraceError`], [`ParserError`]). They all implement [`std::error::Error`],
+//! and can be converted into an [`Error`], so that functions that use several modules can return a single error type.
+//!
+//! ```
+//! # use ferrisetw::{EventRecord, SchemaLocator};
+//! # use ferrisetw::parser::Parser;
+//! fn process_id(record: &EventRecord, schema_locator: &SchemaLocator) -> Result<u32, ferrisetw::Error> {
+//!     let schema = schema_locator.event_schema(record)?;
+//!     let parser = Parser::create(record, &schema);
+//!     Ok(parser.try_parse("ProcessID")?)
+//! }
+//! ```
+use crate::capture::CaptureError;
+use crate::native::{EvntraceNativeError, TdhNativeError};
+use crate::parser::ParserError;
+use crate::provider::EventFilterError;
+use crate::provider::payload_filter::PayloadFilterError;
+#[cfg(windows)]
+use crate::provider::ProviderError;
+use crate::record_builder::RecordBuilderError;
+use crate::schema_locator::SchemaError;
+use crate::trace::TraceError;
+
+/// Any error returned by this crate
+#[derive(Debug)]
+pub enum Error {
+    Trace(TraceError),
+    Schema(SchemaError),
+    Parser(ParserError),
+    #[cfg(windows)]
+    Provider(ProviderError),
+    EventFilter(EventFilterError),
+    PayloadFilter(PayloadFilterError),
+    Capture(CaptureError),
+    RecordBuilder(RecordBuilderError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Trace(err) => err.fmt(f),
+            Error::Schema(err) => err.fmt(f),
+            Error::Parser(err) => err.fmt(f),
+            #[cfg(windows)]
+            Error::Provider(err) => err.fmt(f),
+            Error::EventFilter(err) => err.fmt(f),
+            Error::PayloadFilter(err) => err.fmt(f),
+            Error::Capture(err) => err.fmt(f),
+            Error::RecordBuilder(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    // The wrapped errors are transparent: their message is already ours, so we forward to their own sources
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Trace(err) => err.source(),
+            Error::Schema(err) => err.source(),
+            Error::Parser(err) => err.source(),
+            #[cfg(windows)]
+            Error::Provider(err) => err.source(),
+            Error::EventFilter(err) => err.source(),
+            Error::PayloadFilter(err) => err.source(),
+            Error::Capture(err) => err.source(),
+            Error::RecordBuilder(err) => err.source(),
+        }
+    }
+}
+
+impl From<TraceError> for Error {
+    fn from(err: TraceError) -> Self {
+        Error::Trace(err)
+    }
+}
+
+impl From<EvntraceNativeError> for Error {
+    fn from(err: EvntraceNativeError) -> Self {
+        Error::Trace(TraceError::from(err))
+    }
+}
+
+impl From<SchemaError> for Error {
+    fn from(err: SchemaError) -> Self {
+        Error::Schema(err)
+    }
+}
+
+impl From<TdhNativeError> for Error {
+    fn from(err: TdhNativeError) -> Self {
+        Error::Schema(SchemaError::from(err))
+    }
+}
+
+impl From<ParserError> for Error {
+    fn from(err: ParserError) -> Self {
+        Error::Parser(err)
+    }
+}
+
+#[cfg(windows)]
+impl From<ProviderError> for Error {
+    fn from(err: ProviderError) -> Self {
+        Error::Provider(err)
+    }
+}
+
+#[cfg(windows)]
+impl From<crate::native::PlaError> for Error {
+    fn from(err: crate::native::PlaError) -> Self {
+        Error::Provider(ProviderError::from(err))
+    }
+}
+
+impl From<EventFilterError> for Error {
+    fn from(err: EventFilterError) -> Self {
+        Error::EventFilter(err)
+    }
+}
+
+impl From<PayloadFilterError> for Error {
+    fn from(err: PayloadFilterError) -> Self {
+        Error::PayloadFilter(err)
+    }
+}
+
+impl From<CaptureError> for Error {
+    fn from(err: CaptureError) -> Self {
+        Error::Capture(err)
+    }
+}
+
+impl From<RecordBuilderError> for Error {
+    fn from(err: RecordBuilderError) -> Self {
+        Error::RecordBuilder(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_source_chain() {
+        let io_error = std::io::Error::other("disk full");
+        let err = Error::from(EvntraceNativeError::IoError(io_error));
+        assert_eq!(err.to_string(), "ETW error: disk full");
+        assert_eq!(err.source().unwrap().to_string(), "disk full");
+
+        let err = Error::from(TraceError::from(EvntraceNativeError::AccessDenied));
+        assert!(err.to_string().starts_with("access denied"));
+        assert!(err.source().is_none());
+
+        let invalid_utf8 = vec![0xff];
+        let err = Error::from(ParserError::from(std::str::from_utf8(&invalid_utf8).unwrap_err()));
+        assert!(err.source().unwrap().is::<std::str::Utf8Error>());
+    }
+}
@@ -67,6 +67,8 @@
 //!     }
 //! }
 //!
+//! # #[cfg(not(windows))] fn main() {} // ETW sessions can only be started on Windows
+//! # #[cfg(windows)]
 //! fn main() {
 //!     // First we build a Provider
 //!     let process_provider = Provider
@@ -102,6 +104,11 @@
 //! ferrisetw may (very) occasionally write error log messages using the [`log`](https://docs.rs/log/latest/log/) crate.<br/>
 //! In case you want them to be printed to the console, your binary should use one of the various logger implementations. [`env_logger`](https://docs.rs/env_logger/latest/env_logger/) is one of them.<br/>
 //! You can have a look at how to use it in the `examples/` folder in the GitHub repository.
+//!
+//! # Platform support
+//! ETW sessions only exist on Windows. But ferrisetw can also be built for other targets, where it is able to decode events
+//! (e.g. from a [capture], or [synthesized](crate::record_builder) ones) and to [replay](crate::trace::replay) them into traces.<br/>
+//! On such targets, starting an actual session fails at runtime, and a few Windows-only APIs (e.g. `Provider::by_name`) are not built at all.
 
 #[macro_use]
 extern crate memoffset;
@@ -113,25 +120,34 @@ extern crate bitflags;
 extern crate num_derive;
 extern crate num_traits;
 
+pub mod capture;
+pub mod error;
 pub mod native;
 pub mod parser;
 mod property;
 pub mod provider;
 pub mod query;
+pub mod record_builder;
 pub mod schema;
 pub mod schema_locator;
+pub mod timestamp;
 pub mod trace;
+#[cfg(windows)]
 mod traits;
 mod utils;
 
 pub(crate) type EtwCallback = Box<dyn FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static>;
+pub(crate) type EtwContextCallback = Box<dyn FnMut(&EventRecord, &SchemaLocator, &trace::event_context::EventContext) -> std::ops::ControlFlow<()> + Send + Sync + 'static>;
+pub(crate) type EtwSharedCallback = Box<dyn Fn(&EventRecord, &SchemaLocator, &trace::event_context::EventContext) -> std::ops::ControlFlow<()> + Send + Sync + 'static>;
 
 // Convenience re-exports.
 pub use crate::trace::UserTrace;
 pub use crate::trace::KernelTrace;
 pub use crate::trace::FileTrace;
 pub use crate::native::etw_types::event_record::EventRecord;
+pub use crate::native::etw_types::event_record::OwnedEventRecord;
 pub use crate::schema_locator::SchemaLocator;
+pub use crate::error::Error;
 
 // These types are returned by some public APIs of this crate.
 // They must be re-exported, so that users of the crate have a way to avoid version conflicts
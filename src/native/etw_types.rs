@@ -14,6 +14,7 @@ use std::ffi::{c_void, OsString};
 use std::fmt::Formatter;
 use std::marker::PhantomData;
 use std::sync::Arc;
+use std::time::Duration;
 
 use windows::core::GUID;
 use windows::core::PWSTR;
@@ -226,11 +227,11 @@ impl EventTraceProperties {
         etw_trace_properties.Wnode.BufferSize = std::mem::size_of::<EventTraceProperties>() as u32;
         etw_trace_properties.Wnode.Guid = T::trace_guid();
         etw_trace_properties.Wnode.Flags = Etw::WNODE_FLAG_TRACED_GUID;
-        etw_trace_properties.Wnode.ClientContext = 1; // QPC clock resolution
+        etw_trace_properties.Wnode.ClientContext = trace_properties.clock_type as u32;
         etw_trace_properties.BufferSize = trace_properties.buffer_size;
         etw_trace_properties.MinimumBuffers = trace_properties.min_buffer;
         etw_trace_properties.MaximumBuffers = trace_properties.max_buffer;
-        etw_trace_properties.FlushTimer = trace_properties.flush_timer.as_secs().clamp(1, u32::MAX as u64) as u32; // See https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties
+        etw_trace_properties.FlushTimer = flush_timer_secs(trace_properties.flush_timer);
 
         if trace_properties.log_file_mode.is_empty() == false {
             etw_trace_properties.LogFileMode = trace_properties.log_file_mode.bits();
@@ -280,6 +281,45 @@ impl EventTraceProperties {
         s
     }
 
+    /// A copy of these properties, ready to be given to `ControlTraceW(EVENT_TRACE_CONTROL_UPDATE)`
+    ///
+    /// Settings that are `None` are left unchanged.
+    pub(crate) fn for_update(
+        &self,
+        flush_timer: Option<Duration>,
+        max_buffer: Option<u32>,
+        etl_dump_file: Option<&U16CStr>,
+        enable_flags: Option<u32>,
+    ) -> Self {
+        let mut s = *self;
+
+        if let Some(flush_timer) = flush_timer {
+            s.etw_trace_properties.FlushTimer = flush_timer_secs(flush_timer);
+        }
+        if let Some(max_buffer) = max_buffer {
+            s.etw_trace_properties.MaximumBuffers = max_buffer;
+        }
+        if let Some(enable_flags) = enable_flags {
+            s.etw_trace_properties.EnableFlags = Etw::EVENT_TRACE_FLAG(enable_flags);
+        }
+
+        // > If you are not changing the log file name, set LogFileNameOffset to 0
+        // (https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-controltracew#remarks)
+        match etl_dump_file {
+            None => {
+                s.etw_trace_properties.LogFileNameOffset = 0;
+            },
+            Some(path) => {
+                let path_len = path.len().min(TRACE_NAME_MAX_CHARS);
+                s.wide_etl_dump_file_path = [0u16; TRACE_NAME_MAX_CHARS+1];
+                s.wide_etl_dump_file_path[..path_len].copy_from_slice(&path.as_slice()[..path_len]);
+                s.etw_trace_properties.LogFileNameOffset = offset_of!(EventTraceProperties, wide_etl_dump_file_path) as u32;
+            },
+        }
+
+        s
+    }
+
     /// Gets a pointer to the wrapped [Etw::EVENT_TRACE_PROPERTIES]
     ///
     /// # Safety
@@ -292,6 +332,11 @@ impl EventTraceProperties {
         &mut self.etw_trace_properties as *mut Etw::EVENT_TRACE_PROPERTIES
     }
 
+    /// The wrapped [Etw::EVENT_TRACE_PROPERTIES]
+    pub(crate) fn as_raw(&self) -> &Etw::EVENT_TRACE_PROPERTIES {
+        &self.etw_trace_properties
+    }
+
     pub fn trace_name_array(&self) -> &[u16] {
         &self.wide_trace_name
     }
@@ -300,6 +345,91 @@ impl EventTraceProperties {
             .map(|ws| ws.to_os_string())
             .unwrap_or_else(|_| OsString::from("<invalid name>"))
     }
+
+    /// The path of the ETL dump file, if these properties set one
+    pub fn etl_dump_file(&self) -> Option<OsString> {
+        if self.etw_trace_properties.LogFileNameOffset == 0 {
+            return None;
+        }
+        widestring::U16CStr::from_slice_truncate(&self.wide_etl_dump_file_path)
+            .map(|ws| ws.to_os_string())
+            .ok()
+    }
+}
+
+/// See <https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties>: the flush timer is expressed in seconds, and 0 has a special meaning we do not want
+fn flush_timer_secs(flush_timer: Duration) -> u32 {
+    flush_timer.as_secs().clamp(1, u32::MAX as u64) as u32
+}
+
+/// Session names and log file paths are at most 1024 characters long
+/// (see <https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-queryalltracesw#remarks>)
+const QUERIED_NAME_MAX_CHARS: usize = 1024;
+
+/// Wrapper over an [EVENT_TRACE_PROPERTIES](https://docs.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties), used to query sessions the program did not start
+///
+/// Unlike [`EventTraceProperties`], its buffers are large enough for any session name and log file path.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct QueriedTraceProperties {
+    etw_trace_properties: Etw::EVENT_TRACE_PROPERTIES,
+    wide_trace_name: [u16; QUERIED_NAME_MAX_CHARS],
+    wide_log_file_path: [u16; QUERIED_NAME_MAX_CHARS],
+}
+
+impl QueriedTraceProperties {
+    /// Create an empty instance, ready to be filled by `QueryAllTracesW`
+    pub(crate) fn new() -> Self {
+        let mut etw_trace_properties = Etw::EVENT_TRACE_PROPERTIES::default();
+        etw_trace_properties.Wnode.BufferSize = std::mem::size_of::<QueriedTraceProperties>() as u32;
+        etw_trace_properties.LoggerNameOffset = offset_of!(QueriedTraceProperties, wide_trace_name) as u32;
+        etw_trace_properties.LogFileNameOffset = offset_of!(QueriedTraceProperties, wide_log_file_path) as u32;
+
+        Self {
+            etw_trace_properties,
+            wide_trace_name: [0u16; QUERIED_NAME_MAX_CHARS],
+            wide_log_file_path: [0u16; QUERIED_NAME_MAX_CHARS],
+        }
+    }
+
+    /// Gets a pointer to the wrapped [Etw::EVENT_TRACE_PROPERTIES]
+    ///
+    /// # Safety
+    ///
+    /// The name buffers that follow the `EVENT_TRACE_PROPERTIES` must not be written past their end.
+    pub unsafe fn as_mut_ptr(&mut self) -> *mut Etw::EVENT_TRACE_PROPERTIES {
+        &mut self.etw_trace_properties as *mut Etw::EVENT_TRACE_PROPERTIES
+    }
+
+    /// The wrapped [Etw::EVENT_TRACE_PROPERTIES]
+    pub(crate) fn as_raw(&self) -> &Etw::EVENT_TRACE_PROPERTIES {
+        &self.etw_trace_properties
+    }
+
+    /// Write the names, like `QueryAllTracesW` would
+    #[cfg(test)]
+    pub(crate) fn set_names(&mut self, name: &U16CStr, log_file: Option<&U16CStr>) {
+        let name_len = name.len().min(QUERIED_NAME_MAX_CHARS - 1);
+        self.wide_trace_name[..name_len].copy_from_slice(&name.as_slice()[..name_len]);
+        if let Some(log_file) = log_file {
+            let path_len = log_file.len().min(QUERIED_NAME_MAX_CHARS - 1);
+            self.wide_log_file_path[..path_len].copy_from_slice(&log_file.as_slice()[..path_len]);
+        }
+    }
+
+    pub fn name(&self) -> OsString {
+        widestring::U16CStr::from_slice_truncate(&self.wide_trace_name)
+            .map(|ws| ws.to_os_string())
+            .unwrap_or_else(|_| OsString::from("<invalid name>"))
+    }
+
+    /// The path of the log file of this session, if any
+    pub fn log_file(&self) -> Option<OsString> {
+        widestring::U16CStr::from_slice_truncate(&self.wide_log_file_path)
+            .ok()
+            .filter(|path| !path.is_empty())
+            .map(|path| path.to_os_string())
+    }
 }
 
 /// Newtype wrapper over an [EVENT_TRACE_LOGFILEW]
@@ -318,13 +448,14 @@ pub struct EventTraceLogfile<'callbackdata> {
 impl<'callbackdata> EventTraceLogfile<'callbackdata> {
     /// Create a new instance
     #[allow(clippy::borrowed_box)] // Being Boxed is really important, let's keep the Box<...> in the function signature to make the intent clearer (see https://github.com/n4r1b/ferrisetw/issues/72)
-    pub fn create(callback_data: &'callbackdata Box<Arc<CallbackData>>, subscription_source: SubscriptionSource, callback: unsafe extern "system" fn(*mut Etw::EVENT_RECORD)) -> Self {
+    pub fn create(callback_data: &'callbackdata Box<Arc<CallbackData>>, subscription_source: SubscriptionSource, raw_timestamps: bool, callback: unsafe extern "system" fn(*mut Etw::EVENT_RECORD), buffer_callback: unsafe extern "system" fn(*mut Etw::EVENT_TRACE_LOGFILEW) -> u32) -> Self {
         let not_really_mut_ptr = callback_data.as_ref() as *const Arc<CallbackData> as *const c_void as *mut c_void; // That's kind-of fine because the user context is _not supposed_ to be changed by Windows APIs
 
         let native = Etw::EVENT_TRACE_LOGFILEW {
             Anonymous2: Etw::EVENT_TRACE_LOGFILEW_1 {
                 EventRecordCallback: Some(callback)
             },
+            BufferCallback: Some(buffer_callback),
             Context: not_really_mut_ptr,
             ..Default::default()
         };
@@ -342,7 +473,6 @@ impl<'callbackdata> EventTraceLogfile<'callbackdata> {
 
                 log_file.native.Anonymous1 = Etw::EVENT_TRACE_LOGFILEW_0 {
                     ProcessTraceMode: Etw::PROCESS_TRACE_MODE_REAL_TIME | Etw::PROCESS_TRACE_MODE_EVENT_RECORD
-                    // In case you really want to use PROCESS_TRACE_MODE_RAW_TIMESTAMP, please review EventRecord::timestamp(), which could not be valid anymore
                 };
             },
             SubscriptionSource::FromFile(wide_file_name) => {
@@ -350,11 +480,18 @@ impl<'callbackdata> EventTraceLogfile<'callbackdata> {
 
                 log_file.native.Anonymous1 = Etw::EVENT_TRACE_LOGFILEW_0 {
                     ProcessTraceMode: Etw::PROCESS_TRACE_MODE_EVENT_RECORD
-                    // In case you really want to use PROCESS_TRACE_MODE_RAW_TIMESTAMP, please review EventRecord::timestamp(), which could not be valid anymore
                 };
             }
         }
 
+        if raw_timestamps {
+            // EventRecord::timestamp() is not valid anymore in this case, see crate::timestamp::TimestampConverter instead
+            unsafe {
+                // Safety: both branches above populate the `ProcessTraceMode` member of this union
+                log_file.native.Anonymous1.ProcessTraceMode |= Etw::PROCESS_TRACE_MODE_RAW_TIMESTAMP;
+            }
+        }
+
         log_file
     }
 
@@ -364,6 +501,7 @@ impl<'callbackdata> EventTraceLogfile<'callbackdata> {
     ///
     /// This pointer is valid as long as [`Self`] is alive (and not modified elsewhere)<br/>
     /// Note that `OpenTraceW` **will** modify its content on output, and thus you should make sure to be the only user of this instance.
+    #[cfg_attr(not(windows), allow(dead_code))]
     pub(crate) unsafe fn as_mut_ptr(&mut self) -> *mut Etw::EVENT_TRACE_LOGFILEW {
         &mut self.native as *mut Etw::EVENT_TRACE_LOGFILEW
     }
@@ -372,6 +510,45 @@ impl<'callbackdata> EventTraceLogfile<'callbackdata> {
     pub fn context_ptr(&self) -> *const std::ffi::c_void {
         self.native.Context
     }
+
+    /// The header of the trace, that `OpenTraceW` populates
+    pub(crate) fn logfile_header(&self) -> &Etw::TRACE_LOGFILE_HEADER {
+        &self.native.LogfileHeader
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_logfile_header(&mut self, header: Etw::TRACE_LOGFILE_HEADER) {
+        self.native.LogfileHeader = header;
+    }
+
+    /// The `ProcessTraceMode` flags this log file is opened with
+    #[cfg(test)]
+    pub(crate) fn process_trace_mode(&self) -> u32 {
+        unsafe {
+            // Safety: `create` always populates the `ProcessTraceMode` member of this union
+            self.native.Anonymous1.ProcessTraceMode
+        }
+    }
+
+    /// What this log file subscribes to
+    #[cfg(test)]
+    pub(crate) fn subscription_source(&self) -> &SubscriptionSource {
+        &self.owned_subscription_source
+    }
+
+    /// The function that should be called for every event
+    pub(crate) fn event_record_callback(&self) -> Option<unsafe extern "system" fn(*mut Etw::EVENT_RECORD)> {
+        unsafe {
+            // Safety: `create` always populates the `EventRecordCallback` member of this union
+            self.native.Anonymous2.EventRecordCallback
+        }
+    }
+
+    /// The function that should be called after every buffer
+    #[cfg(test)]
+    pub(crate) fn buffer_callback(&self) -> Option<unsafe extern "system" fn(*mut Etw::EVENT_TRACE_LOGFILEW) -> u32> {
+        self.native.BufferCallback
+    }
 }
 
 /// Newtype wrapper over an [ENABLE_TRACE_PARAMETERS]
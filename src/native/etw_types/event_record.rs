@@ -1,10 +1,15 @@
 //! Safe wrappers over the EVENT_RECORD type
 
-use windows::Win32::System::Diagnostics::Etw::EVENT_RECORD;
+use windows::Win32::System::Diagnostics::Etw::{EVENT_RECORD, EVENT_HEADER, ETW_BUFFER_CONTEXT, EVENT_HEADER_EXTENDED_DATA_ITEM};
 use windows::core::GUID;
 
 use crate::native::etw_types::extended_data::EventHeaderExtendedDataItem;
 
+/// Opcode of rundown events that describe the state at the start of a data collection (i.e. when the capture state was requested)
+const EVENT_TRACE_TYPE_DC_START: u8 = 3;
+/// Opcode of rundown events that describe the state at the end of a data collection
+const EVENT_TRACE_TYPE_DC_END: u8 = 4;
+
 /// A read-only wrapper over an [EVENT_RECORD](https://docs.microsoft.com/en-us/windows/win32/api/evntcons/ns-evntcons-event_record)
 #[repr(transparent)]
 pub struct EventRecord(EVENT_RECORD);
@@ -27,10 +32,16 @@ impl EventRecord {
     /// # Safety
     ///
     /// Obviously, the returned pointer is only valid as long `self` is valid and not modified.
+    #[cfg_attr(not(windows), allow(dead_code))]
     pub(crate) fn as_raw_ptr(&self) -> *const EVENT_RECORD {
         &self.0 as *const EVENT_RECORD
     }
 
+    /// Get a reference to the wrapped `EVENT_RECORD`
+    pub(crate) fn as_raw(&self) -> &EVENT_RECORD {
+        &self.0
+    }
+
     /// The `UserContext` field from the wrapped `EVENT_RECORD`
     ///
     /// In this crate, it is always populated to point to a valid [`CallbackData`](crate::trace::CallbackData)
@@ -53,6 +64,16 @@ impl EventRecord {
         self.0.EventHeader.EventDescriptor.Opcode
     }
 
+    /// Whether this event uses the `DC_START` (3) or `DC_END` (4) opcode, i.e. the opcodes of classic kernel "rundown" events
+    ///
+    /// Rundown events describe a state that existed before the trace started (e.g. running processes), rather than something that just happened.
+    /// This opcode check only works for classic (MOF) events, e.g. the process, thread and image events of kernel traces.<br/>
+    /// Manifest-based providers emit dedicated events instead, that callbacks must recognize by their provider, event ID or opcode
+    /// (e.g. the `ProcessRundown` event of Kernel-Process, or the events of the `Microsoft-Windows-DotNETRuntimeRundown` provider for the CLR).
+    pub fn has_rundown_opcode(&self) -> bool {
+        matches!(self.opcode(), EVENT_TRACE_TYPE_DC_START | EVENT_TRACE_TYPE_DC_END)
+    }
+
     /// The `Version` field from the wrapped `EVENT_RECORD`
     pub fn version(&self) -> u8 {
         self.0.EventHeader.EventDescriptor.Version
@@ -63,6 +84,11 @@ impl EventRecord {
         self.0.EventHeader.EventDescriptor.Level
     }
 
+    /// The `Task` field from the wrapped `EVENT_RECORD`
+    pub fn task(&self) -> u16 {
+        self.0.EventHeader.EventDescriptor.Task
+    }
+
     /// The `Flags` field from the wrapped `EVENT_RECORD`
     pub fn event_flags(&self) -> u16 {
         self.0.EventHeader.Flags
@@ -98,18 +124,13 @@ impl EventRecord {
     }
 
     /// The `TimeStamp` field from the wrapped `EVENT_RECORD`, as a strongly-typed `time::OffsetDateTime`
+    ///
+    /// This assumes the timestamp is a "system time", which is not the case if the trace is processed with raw timestamps
+    /// (see [`TraceBuilder::raw_timestamps`](crate::trace::TraceBuilder::raw_timestamps)). Use a [`TimestampConverter`](crate::timestamp::TimestampConverter) in this case.
     #[cfg(feature = "time_rs")]
     pub fn timestamp(&self) -> time::OffsetDateTime {
         // "system time" means the count of hundreds of nanoseconds since midnight, January 1, 1601
-        let system_time = self.0.EventHeader.TimeStamp;
-
-        const SECONDS_BETWEEN_1601_AND_1970: i128 = 11_644_473_600;
-        const HUNDREDS_OF_NANOS_IN_SECOND: i128 = 10_000_000;
-        const HUNDREDS_OF_NANOSECONDS_BETWEEN_1601_AND_1970: i128 =
-            SECONDS_BETWEEN_1601_AND_1970 * HUNDREDS_OF_NANOS_IN_SECOND;
-
-        let unix_as_hundreds_of_nano_seconds = (system_time as i128) - HUNDREDS_OF_NANOSECONDS_BETWEEN_1601_AND_1970;
-        let unix_as_nano_seconds = unix_as_hundreds_of_nano_seconds * 100;
+        let unix_as_nano_seconds = crate::timestamp::filetime_to_unix_nanos(self.0.EventHeader.TimeStamp);
 
         // Can't panic.
         // A filetime can go from 1601 to 30828.
@@ -161,3 +182,135 @@ impl EventRecord {
         }
     }
 }
+
+
+/// An owned copy of an [`EventRecord`]
+///
+/// `EventRecord`s are only valid during the callback they are given to.
+/// This type copies the event header, its user data and its extended data, so that the event can be kept around (e.g. sent to another thread, or written to a file).
+///
+/// It dereferences to an [`EventRecord`], so that every usual getter (and the [`Parser`](crate::parser::Parser)) can be used on it.
+pub struct OwnedEventRecord {
+    /// The wrapped record. Its `UserData` and `ExtendedData` pointers point into the buffers below
+    record: EventRecord,
+    user_data: Vec<u8>,
+    extended_data: Vec<EVENT_HEADER_EXTENDED_DATA_ITEM>,
+    /// Backing storage for the extended data items.
+    /// These are stored as `u64`s, so that they are suitably aligned for any type an extended data item may contain
+    #[allow(dead_code)] // This is only read through the pointers in `extended_data`
+    extended_data_buffers: Vec<Vec<u64>>,
+}
+
+// Safety: the only pointers in `OwnedEventRecord` point to data it owns, and that is never mutated
+unsafe impl Send for OwnedEventRecord {}
+// Safety: see above
+unsafe impl Sync for OwnedEventRecord {}
+
+impl OwnedEventRecord {
+    /// Copy an `EventRecord`
+    pub fn from_event_record(record: &EventRecord) -> Self {
+        let extended_data = record
+            .extended_data()
+            .iter()
+            .map(|item| (*item.as_raw(), item.data().to_vec()))
+            .collect();
+
+        Self::from_parts(
+            record.0.EventHeader,
+            record.0.BufferContext,
+            record.user_buffer().to_vec(),
+            extended_data,
+        )
+    }
+
+    /// Build an instance from its various components
+    ///
+    /// The `DataPtr` and `DataSize` fields of the extended data items will be overwritten to point to their data.<br/>
+    /// User data longer than 64 KiB (which ETW does not support anyway) is truncated, and so is the list of extended data items.
+    pub(crate) fn from_parts(
+        header: EVENT_HEADER,
+        buffer_context: ETW_BUFFER_CONTEXT,
+        mut user_data: Vec<u8>,
+        mut extended_data: Vec<(EVENT_HEADER_EXTENDED_DATA_ITEM, Vec<u8>)>,
+    ) -> Self {
+        user_data.truncate(u16::MAX as usize);
+        extended_data.truncate(u16::MAX as usize);
+
+        let mut items = Vec::with_capacity(extended_data.len());
+        let mut buffers = Vec::with_capacity(extended_data.len());
+        for (mut item, mut data) in extended_data {
+            data.truncate(u16::MAX as usize);
+            let mut buffer = vec![0u64; data.len().div_ceil(8)];
+            // Safety: `buffer` is at least `data.len()` bytes long, and both buffers do not overlap
+            unsafe {
+                std::ptr::copy_nonoverlapping(data.as_ptr(), buffer.as_mut_ptr() as *mut u8, data.len());
+            }
+            // The heap allocation of `buffer` will not move when `buffer` is moved into `buffers`
+            item.DataPtr = buffer.as_ptr() as u64;
+            item.DataSize = data.len() as u16;
+            items.push(item);
+            buffers.push(buffer);
+        }
+
+        let raw = EVENT_RECORD {
+            EventHeader: header,
+            BufferContext: buffer_context,
+            ExtendedDataCount: items.len() as u16,
+            UserDataLength: user_data.len() as u16,
+            ExtendedData: items.as_ptr() as *mut EVENT_HEADER_EXTENDED_DATA_ITEM,
+            UserData: user_data.as_ptr() as *mut std::ffi::c_void,
+            UserContext: std::ptr::null_mut(),
+        };
+
+        Self {
+            record: EventRecord(raw),
+            user_data,
+            extended_data: items,
+            extended_data_buffers: buffers,
+        }
+    }
+
+    /// Get a reference to the copied `EventRecord`
+    pub fn as_event_record(&self) -> &EventRecord {
+        &self.record
+    }
+
+    /// The copied user data (i.e. the payload of the event)
+    pub fn user_data(&self) -> &[u8] {
+        &self.user_data
+    }
+}
+
+impl std::ops::Deref for OwnedEventRecord {
+    type Target = EventRecord;
+
+    fn deref(&self) -> &Self::Target {
+        self.as_event_record()
+    }
+}
+
+impl From<&EventRecord> for OwnedEventRecord {
+    fn from(record: &EventRecord) -> Self {
+        Self::from_event_record(record)
+    }
+}
+
+impl Clone for OwnedEventRecord {
+    fn clone(&self) -> Self {
+        // Pointers must be re-built, so that they point to the buffers of the new instance
+        Self::from_event_record(self.as_event_record())
+    }
+}
+
+impl std::fmt::Debug for OwnedEventRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnedEventRecord")
+            .field("provider_id", &self.provider_id())
+            .field("event_id", &self.event_id())
+            .field("opcode", &self.opcode())
+            .field("timestamp", &self.raw_timestamp())
+            .field("user_data_len", &self.user_data.len())
+            .field("extended_data_count", &self.extended_data.len())
+            .finish()
+    }
+}
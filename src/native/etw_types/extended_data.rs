@@ -11,6 +11,7 @@ use windows::Win32::System::Diagnostics::Etw::{
     EVENT_HEADER_EXT_TYPE_STACK_TRACE64,
     EVENT_HEADER_EXT_TYPE_EVENT_KEY,
     EVENT_HEADER_EXT_TYPE_PROCESS_START_KEY,
+    EVENT_HEADER_EXT_TYPE_PMC_COUNTERS,
 };
 use windows::Win32::System::Diagnostics::Etw::{
     EVENT_EXTENDED_ITEM_RELATED_ACTIVITYID,
@@ -57,6 +58,8 @@ pub enum ExtendedDataItem {
     EventKey(u64),
     /// Unique process identifier (unique across the boot session)
     ProcessStartKey(u64),
+    /// Values of the hardware counters that were configured on the session (see [`TraceBuilder::set_pmc_sources`](crate::trace::TraceBuilder::set_pmc_sources)), in the same order
+    PmcCounters(Vec<u64>),
 }
 
 impl EventHeaderExtendedDataItem {
@@ -67,6 +70,25 @@ impl EventHeaderExtendedDataItem {
         self.0.ExtType
     }
 
+    /// Get a reference to the wrapped `EVENT_HEADER_EXTENDED_DATA_ITEM`
+    pub(crate) fn as_raw(&self) -> &EVENT_HEADER_EXTENDED_DATA_ITEM {
+        &self.0
+    }
+
+    /// The raw bytes this extended data item points to
+    pub(crate) fn data(&self) -> &[u8] {
+        let data_ptr = self.0.DataPtr as *const u8;
+        if data_ptr.is_null() || self.0.DataSize == 0 {
+            return &[];
+        }
+
+        // Safety: * `DataPtr` and `DataSize` are given by Windows (or by an `OwnedEventRecord`) and describe a valid buffer
+        //         * the pointed data is not supposed to be mutated during the lifetime of `Self`
+        unsafe {
+            std::slice::from_raw_parts(data_ptr, self.0.DataSize as usize)
+        }
+    }
+
     /// Returns this extended data as a variant of a Rust enum.
     pub fn to_extended_data_item(&self) -> ExtendedDataItem {
         let data_ptr = self.0.DataPtr as *const std::ffi::c_void;
@@ -115,6 +137,14 @@ impl EventHeaderExtendedDataItem {
                 ExtendedDataItem::EventKey( unsafe{ *data_ptr } )
             }
 
+            EVENT_HEADER_EXT_TYPE_PMC_COUNTERS => {
+                let counters = self.data()
+                    .chunks_exact(8)
+                    .map(|c| u64::from_ne_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]))
+                    .collect();
+                ExtendedDataItem::PmcCounters(counters)
+            }
+
             _ => ExtendedDataItem::Unsupported,
         }
     }
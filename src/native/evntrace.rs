@@ -2,33 +2,42 @@
 //!
 //! This module makes sure the calls are safe memory-wise, but does not attempt to ensure they are called in the right order.<br/>
 //! Thus, you should prefer using `UserTrace`s, `KernelTrace`s and `TraceBuilder`s, that will ensure these API are correctly used.
-use std::collections::HashSet;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::panic::AssertUnwindSafe;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::sync::Mutex;
 use std::ffi::c_void;
 
 use once_cell::sync::Lazy;
 
 use widestring::U16CStr;
-use windows::Win32::System::Diagnostics::Etw::EVENT_CONTROL_CODE_ENABLE_PROVIDER;
 use windows::core::GUID;
-use windows::core::PCWSTR;
-use windows::Win32::Foundation::FILETIME;
+use windows::Win32::System::Diagnostics::Etw::{EVENT_CONTROL_CODE_CAPTURE_STATE, EVENT_CONTROL_CODE_DISABLE_PROVIDER, EVENT_CONTROL_CODE_ENABLE_PROVIDER};
 use windows::Win32::System::Diagnostics::Etw;
-use windows::Win32::System::Diagnostics::Etw::TRACE_QUERY_INFO_CLASS;
 use windows::Win32::Foundation::ERROR_SUCCESS;
+use windows::Win32::Foundation::ERROR_CANCELLED;
 use windows::Win32::Foundation::ERROR_ALREADY_EXISTS;
 use windows::Win32::Foundation::ERROR_CTX_CLOSE_PENDING;
+use windows::Win32::Foundation::ERROR_MORE_DATA;
+use windows::Win32::Foundation::{ERROR_BAD_LENGTH, ERROR_INSUFFICIENT_BUFFER};
+use windows::Win32::Foundation::{ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, ERROR_NOT_FOUND, ERROR_WMI_INSTANCE_NOT_FOUND};
+use windows::Win32::Foundation::{ERROR_NO_SYSTEM_RESOURCES, ERROR_NOT_ENOUGH_MEMORY, ERROR_OUTOFMEMORY};
+use windows::Win32::Foundation::WIN32_ERROR;
 
 
 use super::etw_types::*;
+use super::win32_io_error;
 use crate::provider::Provider;
-use crate::provider::event_filter::EventFilterDescriptor;
+use crate::provider::event_filter::{EventFilter, EventFilterDescriptor, EventFilterError};
 use crate::native::etw_types::event_record::EventRecord;
 use crate::trace::{TraceProperties, RealTimeTraceTrait};
 use crate::trace::callback_data::CallbackData;
+use crate::trace::panic_policy::panic_message;
+use crate::timestamp::TimestampConverter;
 
+pub(crate) mod backend;
+use backend::EvntraceBackend;
 
 pub type TraceHandle = Etw::PROCESSTRACE_HANDLE;
 pub type ControlHandle = Etw::CONTROLTRACE_HANDLE;
@@ -40,8 +49,53 @@ pub enum EvntraceNativeError {
     InvalidHandle,
     /// Represents an ERROR_ALREADY_EXISTS
     AlreadyExist,
+    /// Represents an ERROR_ACCESS_DENIED
+    AccessDenied,
+    /// Represents an ERROR_WMI_INSTANCE_NOT_FOUND (or any other "not found" error)
+    NotFound,
+    /// Represents an ERROR_NO_SYSTEM_RESOURCES (or any other "out of memory" error)
+    OutOfResources,
     /// Represents an standard IO Error
     IoError(std::io::Error),
+    /// A filter of the provider cannot be turned into an `EVENT_FILTER_DESCRIPTOR`
+    InvalidFilter(EventFilterError),
+}
+
+impl EvntraceNativeError {
+    /// Map a Win32 error code returned by ETW to an error
+    pub(crate) fn from_win32(code: WIN32_ERROR) -> Self {
+        match code {
+            ERROR_ALREADY_EXISTS => EvntraceNativeError::AlreadyExist,
+            ERROR_ACCESS_DENIED => EvntraceNativeError::AccessDenied,
+            ERROR_WMI_INSTANCE_NOT_FOUND | ERROR_FILE_NOT_FOUND | ERROR_NOT_FOUND => EvntraceNativeError::NotFound,
+            ERROR_NO_SYSTEM_RESOURCES | ERROR_NOT_ENOUGH_MEMORY | ERROR_OUTOFMEMORY => EvntraceNativeError::OutOfResources,
+            code => EvntraceNativeError::IoError(win32_io_error(code.0)),
+        }
+    }
+}
+
+impl std::fmt::Display for EvntraceNativeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvntraceNativeError::InvalidHandle => write!(f, "invalid trace handle (the trace may have been closed already)"),
+            EvntraceNativeError::AlreadyExist => write!(f, "a trace with this name already exists (stop it, or use another name)"),
+            EvntraceNativeError::AccessDenied => write!(f, "access denied (controlling ETW sessions requires administrator privileges, or membership in the Performance Log Users group)"),
+            EvntraceNativeError::NotFound => write!(f, "no such trace or file"),
+            EvntraceNativeError::OutOfResources => write!(f, "not enough system resources (too many sessions may be running, or the buffers are too large)"),
+            EvntraceNativeError::IoError(err) => write!(f, "ETW error: {}", err),
+            EvntraceNativeError::InvalidFilter(err) => write!(f, "invalid event filter: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for EvntraceNativeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EvntraceNativeError::IoError(err) => Some(err),
+            EvntraceNativeError::InvalidFilter(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 pub(crate) type EvntraceNativeResult<T> = Result<T, EvntraceNativeError>;
@@ -89,6 +143,16 @@ impl UniqueValidContexts {
 }
 
 
+/// The backend each currently opened trace has been opened with, and its callback data.
+///
+/// This is needed because [`process_trace`] is only given a trace handle (see `TraceTrait::process_from_handle`).
+static TRACE_BACKENDS: Lazy<Mutex<HashMap<u64, OpenedTrace>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct OpenedTrace {
+    backend: Arc<dyn EvntraceBackend>,
+    callback_data: Weak<CallbackData>,
+}
+
 /// This will be called by the ETW framework whenever an ETW event is available
 extern "system" fn trace_callback_thunk(p_record: *mut Etw::EVENT_RECORD) {
     match std::panic::catch_unwind(AssertUnwindSafe(|| {
@@ -109,7 +173,7 @@ extern "system" fn trace_callback_thunk(p_record: *mut Etw::EVENT_RECORD) {
                 //  * we've just checked using UNIQUE_VALID_CONTEXTS that this `CallbackData` has not been dropped
                 //  * the API of this crate guarantees this `CallbackData` is not mutated from another thread during the trace:
                 //      * we're the only one to change CallbackData::events_handled (and that's an atomic, so it's fine)
-                //      * the list of Providers is a constant (may change in the future with #54)
+                //      * the list of Providers is behind a RwLock
                 //      * the schema_locator only has interior mutability
                 p_callback_data.as_ref()
             };
@@ -117,18 +181,58 @@ extern "system" fn trace_callback_thunk(p_record: *mut Etw::EVENT_RECORD) {
                 // The UserContext is owned by the `Trace` object. When it is dropped, so will the UserContext.
                 // We clone it now, so that the original Arc can be safely dropped at all times, but the callback data (including the closure captured context) will still be alive until the callback ends.
                 let cloned_arc = Arc::clone(callback_data);
-                cloned_arc.on_event(event_record);
+                cloned_arc.catch_panics(|| cloned_arc.on_event(event_record));
             }
         }
     })) {
         Ok(_) => {}
-        Err(e) => {
-            log::error!("UNIMPLEMENTED PANIC: {e:?}");
-            std::process::exit(1);
+        Err(payload) => abort_on_unhandled_panic(payload.as_ref()),
+    }
+}
+
+/// This will be called by the ETW framework once all the events of a buffer have been delivered
+///
+/// Returning `FALSE` (0) makes `ProcessTrace` stop and return `ERROR_CANCELLED`
+extern "system" fn buffer_callback_thunk(p_log_file: *mut Etw::EVENT_TRACE_LOGFILEW) -> u32 {
+    match std::panic::catch_unwind(AssertUnwindSafe(|| {
+        let log_file = match unsafe {
+            // Safety: ETW gives us a pointer to the log file the trace has been opened with, that is valid until the end of the callback
+            p_log_file.as_ref()
+        } {
+            None => return true,
+            Some(log_file) => log_file,
+        };
+
+        let p_user_context = log_file.Context as *const c_void;
+        if !UNIQUE_VALID_CONTEXTS.is_valid(p_user_context) {
+            return true;
+        }
+        let callback_data = unsafe {
+            // Safety: see `trace_callback_thunk`
+            p_user_context.cast::<Arc<CallbackData>>().as_ref()
+        };
+        match callback_data {
+            None => true,
+            Some(callback_data) => {
+                let cloned_arc = Arc::clone(callback_data);
+                cloned_arc
+                    .catch_panics(|| cloned_arc.on_buffer(log_file))
+                    .unwrap_or_else(|| !cloned_arc.stop_requested())
+            },
         }
+    })) {
+        Ok(true) => 1,
+        Ok(false) => 0,
+        Err(payload) => abort_on_unhandled_panic(payload.as_ref()),
     }
 }
 
+/// Panics are handled according to the panic policy of their trace. This is for panics that happen before the trace is known
+fn abort_on_unhandled_panic(payload: &(dyn Any + Send)) -> ! {
+    log::error!("Panic outside of the callbacks of a trace: {}", panic_message(payload));
+    std::process::abort();
+}
+
 fn filter_invalid_trace_handles(h: TraceHandle) -> Option<TraceHandle> {
     // See https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-opentracew#return-value
     // We're conservative and we always filter out u32::MAX, although it could be valid on 64-bit setups.
@@ -154,6 +258,7 @@ fn filter_invalid_control_handle(h: ControlHandle) -> Option<ControlHandle> {
 ///
 /// This builds an `EventTraceProperties`, calls `StartTraceW` and returns the built `EventTraceProperties` as well as the trace ControlHandle
 pub(crate) fn start_trace<T>(
+    backend: &dyn EvntraceBackend,
     trace_name: &U16CStr,
     etl_dump_file: Option<(&U16CStr, DumpFileLoggingMode, Option<u32>)>,
     trace_properties: &TraceProperties,
@@ -165,25 +270,10 @@ where
     let mut properties = EventTraceProperties::new::<T>(trace_name, etl_dump_file, trace_properties, enable_flags);
 
     let mut control_handle = ControlHandle::default();
-    let status = unsafe {
-        // Safety:
-        //  * first argument points to a valid and allocated address (this is an output and will be modified)
-        //  * second argument is a valid, null terminated widestring (note that it will be copied to the EventTraceProperties...from where it already comes. This will probably be overwritten by Windows, but heck.)
-        //  * third argument is a valid, allocated EVENT_TRACE_PROPERTIES (and will be mutated)
-        //  * Note: the string (that will be overwritten to itself) ends with a null widechar before the end of its buffer (see EventTraceProperties::new())
-        Etw::StartTraceW(
-            &mut control_handle,
-            PCWSTR::from_raw(properties.trace_name_array().as_ptr()),
-            properties.as_mut_ptr(),
-        )
-    };
+    let status = backend.start_trace(&mut control_handle, &mut properties);
 
-    if status == ERROR_ALREADY_EXISTS {
-        return Err(EvntraceNativeError::AlreadyExist);
-    } else if status != ERROR_SUCCESS {
-        return Err(EvntraceNativeError::IoError(
-            std::io::Error::from_raw_os_error(status.0 as i32),
-        ));
+    if status != ERROR_SUCCESS {
+        return Err(EvntraceNativeError::from_win32(status));
     }
 
     match filter_invalid_control_handle(control_handle) {
@@ -197,8 +287,9 @@ where
 ///
 /// Microsoft calls this "opening" the trace (and this calls `OpenTraceW`)
 #[allow(clippy::borrowed_box)] // Being Boxed is really important, let's keep the Box<...> in the function signature to make the intent clearer
-pub(crate) fn open_trace(subscription_source: SubscriptionSource, callback_data: &Box<Arc<CallbackData>>) -> EvntraceNativeResult<TraceHandle> {
-    let mut log_file = EventTraceLogfile::create(callback_data, subscription_source, trace_callback_thunk);
+pub(crate) fn open_trace(backend: &Arc<dyn EvntraceBackend>, subscription_source: SubscriptionSource, raw_timestamps: bool, callback_data: &Box<Arc<CallbackData>>) -> EvntraceNativeResult<TraceHandle> {
+    let is_real_time = matches!(subscription_source, SubscriptionSource::RealTimeSession(_));
+    let mut log_file = EventTraceLogfile::create(callback_data, subscription_source, raw_timestamps, trace_callback_thunk, buffer_callback_thunk);
 
     if let Err(ContextError::AlreadyExist) = UNIQUE_VALID_CONTEXTS.insert(log_file.context_ptr()) {
         // That's probably possible to get multiple handles to the same trace, by opening them multiple times.
@@ -206,56 +297,101 @@ pub(crate) fn open_trace(subscription_source: SubscriptionSource, callback_data:
         return Err(EvntraceNativeError::AlreadyExist);
     }
 
-    let trace_handle = unsafe {
-        // This function modifies the data pointed to by log_file.
-        // This is fine because there is currently no other ref `self` (the current function takes a `&mut self`, and `self` is not used anywhere else in the current function)
-        //
-        // > On success, OpenTrace will update the structure with information from the opened file or session.
-        // https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-opentracea
-        Etw::OpenTraceW(log_file.as_mut_ptr())
+    let result = match backend.open_trace(&mut log_file) {
+        Err(status) => Err(EvntraceNativeError::from_win32(status)),
+        Ok(trace_handle) => match filter_invalid_trace_handles(trace_handle) {
+            None => Err(EvntraceNativeError::InvalidHandle),
+            Some(handle) => Ok(handle),
+        },
     };
 
-    if filter_invalid_trace_handles(trace_handle).is_none() {
-        Err(EvntraceNativeError::IoError(std::io::Error::last_os_error()))
-    } else {
-        Ok(trace_handle)
+    match result {
+        Ok(handle) => {
+            TRACE_BACKENDS.lock().unwrap().insert(handle.0, OpenedTrace {
+                backend: Arc::clone(backend),
+                callback_data: Arc::downgrade(callback_data.as_ref()),
+            });
+            // The clock of a real-time session can be read right now, in case the header does not tell when the system booted
+            let current_qpc = if is_real_time { crate::timestamp::current_qpc() } else { None };
+            if let Some(converter) = TimestampConverter::from_logfile_header(log_file.logfile_header(), raw_timestamps, current_qpc) {
+                callback_data.set_timestamp_converter(converter);
+            }
+        },
+        Err(_) => {
+            // No callback will ever be invoked with this context. And this context will be freed once the caller drops its `CallbackData`.
+            // Let's forget about it, otherwise a future `CallbackData` allocated at the same address could not be opened anymore.
+            UNIQUE_VALID_CONTEXTS.remove(log_file.context_ptr());
+        },
     }
+    result
 }
 
 /// Attach a provider to a trace
-pub(crate) fn enable_provider(control_handle: ControlHandle, provider: &Provider) -> EvntraceNativeResult<()> {
+pub(crate) fn enable_provider(backend: &dyn EvntraceBackend, control_handle: ControlHandle, provider: &Provider) -> EvntraceNativeResult<()> {
+    enable_trace(backend, control_handle, provider, EVENT_CONTROL_CODE_ENABLE_PROVIDER.0)
+}
+
+/// Request a provider that is enabled on a trace to log its current state (i.e. to emit rundown events)
+pub(crate) fn capture_state(backend: &dyn EvntraceBackend, control_handle: ControlHandle, provider: &Provider) -> EvntraceNativeResult<()> {
+    enable_trace(backend, control_handle, provider, EVENT_CONTROL_CODE_CAPTURE_STATE.0)
+}
+
+/// Call `EnableTraceEx2` with the settings of `provider`
+fn enable_trace(backend: &dyn EvntraceBackend, control_handle: ControlHandle, provider: &Provider, control_code: u32) -> EvntraceNativeResult<()> {
     match filter_invalid_control_handle(control_handle) {
         None => Err(EvntraceNativeError::InvalidHandle),
         Some(handle) => {
-            let owned_event_filter_descriptors: Vec<EventFilterDescriptor> = provider.filters()
-                .iter()
-                .filter_map(|filter| filter.to_event_filter_descriptor().ok()) // Silently ignoring invalid filters (basically, empty ones)
-                .collect();
+            let mut owned_event_filter_descriptors: Vec<EventFilterDescriptor> = Vec::new();
+            for descriptor in EventFilter::to_event_filter_descriptors(provider.filters()) {
+                match descriptor {
+                    Ok(descriptor) => owned_event_filter_descriptors.push(descriptor),
+                    // An empty filter does not restrict anything
+                    Err(EventFilterError::Empty) => (),
+                    Err(err) => return Err(EvntraceNativeError::InvalidFilter(err)),
+                }
+            }
 
             let parameters =
                 EnableTraceParameters::create(provider.guid(), provider.trace_flags(), &owned_event_filter_descriptors);
 
-            let res = unsafe {
-                Etw::EnableTraceEx2(
-                    handle,
-                    &provider.guid() as *const GUID,
-                    EVENT_CONTROL_CODE_ENABLE_PROVIDER.0,
-                    provider.level(),
-                    provider.any(),
-                    provider.all(),
-                    0,
-                    Some(parameters.as_ptr()),
-                )
-            };
+            let res = backend.enable_trace(
+                handle,
+                &provider.guid(),
+                control_code,
+                provider.level(),
+                provider.any(),
+                provider.all(),
+                Some(&parameters),
+            );
 
             if res == ERROR_SUCCESS {
                 Ok(())
             } else {
-                Err(
-                    EvntraceNativeError::IoError(
-                        std::io::Error::from_raw_os_error(res.0 as i32)
-                    )
-                )
+                Err(EvntraceNativeError::from_win32(res))
+            }
+        }
+    }
+}
+
+/// Detach a provider from a trace
+pub(crate) fn disable_provider(backend: &dyn EvntraceBackend, control_handle: ControlHandle, provider_guid: &GUID) -> EvntraceNativeResult<()> {
+    match filter_invalid_control_handle(control_handle) {
+        None => Err(EvntraceNativeError::InvalidHandle),
+        Some(handle) => {
+            let res = backend.enable_trace(
+                handle,
+                provider_guid,
+                EVENT_CONTROL_CODE_DISABLE_PROVIDER.0,
+                0,
+                0,
+                0,
+                None,
+            );
+
+            if res == ERROR_SUCCESS {
+                Ok(())
+            } else {
+                Err(EvntraceNativeError::from_win32(res))
             }
         }
     }
@@ -264,26 +400,34 @@ pub(crate) fn enable_provider(control_handle: ControlHandle, provider: &Provider
 /// Start processing a trace (this call is blocking until the trace is stopped)
 ///
 /// You probably want to spawn a thread that will block on this call.
+///
+/// This uses the backend the trace has been opened with.
 pub(crate) fn process_trace(trace_handle: TraceHandle) -> EvntraceNativeResult<()> {
-    if filter_invalid_trace_handles(trace_handle).is_none() {
-        Err(EvntraceNativeError::InvalidHandle)
-    } else {
-        let result = unsafe {
-            // We want to start processing events as soon as January 1601.
-            // * for ETL file traces, this is fine, this means "process everything from the file"
-            // * for real-time traces, this means we might process a few events already waiting in the buffers when the processing is starting. This is fine, I suppose.
-            let mut start = FILETIME::default();
-            Etw::ProcessTrace(&[trace_handle], Some(&mut start as *mut FILETIME), None)
-        };
+    let backend = filter_invalid_trace_handles(trace_handle)
+        .and_then(|handle| TRACE_BACKENDS.lock().unwrap().get(&handle.0).map(|opened| Arc::clone(&opened.backend)));
 
-        if result == ERROR_SUCCESS {
-            Ok(())
-        } else {
-            Err(EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(result.0 as i32)))
+    match backend {
+        // This trace has never been opened, or has already been closed
+        None => Err(EvntraceNativeError::InvalidHandle),
+        Some(backend) => {
+            let result = backend.process_trace(trace_handle);
+
+            // ERROR_CANCELLED means a buffer callback asked to stop processing the trace
+            if result == ERROR_SUCCESS || result == ERROR_CANCELLED {
+                Ok(())
+            } else {
+                Err(EvntraceNativeError::from_win32(result))
+            }
         }
     }
 }
 
+/// The callback data of a trace that is currently opened
+pub(crate) fn opened_callback_data(trace_handle: TraceHandle) -> Option<Arc<CallbackData>> {
+    filter_invalid_trace_handles(trace_handle)
+        .and_then(|handle| TRACE_BACKENDS.lock().unwrap().get(&handle.0).and_then(|opened| opened.callback_data.upgrade()))
+}
+
 /// Call `ControlTraceW` on the trace
 ///
 /// # Notes
@@ -292,6 +436,7 @@ pub(crate) fn process_trace(trace_handle: TraceHandle) -> EvntraceNativeResult<(
 /// because stop the trace makes the trace handle invalid.
 /// A stopped trace could theoretically(?) be re-used, but the trace handle should be re-created, so `open` should be called again.
 pub(crate) fn control_trace(
+    backend: &dyn EvntraceBackend,
     properties: &mut EventTraceProperties,
     control_handle: ControlHandle,
     control_code: Etw::EVENT_TRACE_CONTROL,
@@ -299,22 +444,10 @@ pub(crate) fn control_trace(
     match filter_invalid_control_handle(control_handle) {
         None => Err(EvntraceNativeError::InvalidHandle),
         Some(handle) => {
-            let status = unsafe {
-                // Safety:
-                //  * the trace handle is valid (by construction)
-                //  * depending on the control code, the `Properties` can be mutated. This is fine because properties is declared as `&mut` in this function, which means no other Rust function has a reference to it, and the mutation can only happen in the call to `ControlTraceW`, which returns immediately.
-                Etw::ControlTraceW(
-                    handle,
-                    PCWSTR::null(),
-                    properties.as_mut_ptr(),
-                    control_code,
-                )
-            };
+            let status = backend.control_trace(handle, None, properties, control_code);
 
             if status != ERROR_SUCCESS {
-                return Err(EvntraceNativeError::IoError(
-                    std::io::Error::from_raw_os_error(status.0 as i32),
-                ));
+                return Err(EvntraceNativeError::from_win32(status));
             }
 
             Ok(())
@@ -324,25 +457,15 @@ pub(crate) fn control_trace(
 
 /// Similar to [`control_trace`], but using a trace name instead of a handle
 pub(crate) fn control_trace_by_name(
+    backend: &dyn EvntraceBackend,
     properties: &mut EventTraceProperties,
     trace_name: &U16CStr,
     control_code: Etw::EVENT_TRACE_CONTROL,
 ) -> EvntraceNativeResult<()> {
-    let status = unsafe {
-        // Safety:
-        //  * depending on the control code, the `Properties` can be mutated. This is fine because properties is declared as `&mut` in this function, which means no other Rust function has a reference to it, and the mutation can only happen in the call to `ControlTraceW`, which returns immediately.
-        Etw::ControlTraceW(
-            Etw::CONTROLTRACE_HANDLE(0),
-            PCWSTR::from_raw(trace_name.as_ptr()),
-            properties.as_mut_ptr(),
-            control_code,
-        )
-    };
+    let status = backend.control_trace(Etw::CONTROLTRACE_HANDLE(0), Some(trace_name), properties, control_code);
 
     if status != ERROR_SUCCESS {
-        return Err(EvntraceNativeError::IoError(
-            std::io::Error::from_raw_os_error(status.0 as i32),
-        ));
+        return Err(EvntraceNativeError::from_win32(status));
     }
 
     Ok(())
@@ -356,42 +479,246 @@ pub(crate) fn control_trace_by_name(
 /// If no further event callback will be invoked, this returns Ok(false)<br/>
 /// On error, this returns an `Err`
 #[allow(clippy::borrowed_box)] // Being Boxed is really important, let's keep the Box<...> in the function signature to make the intent clearer
-pub(crate) fn close_trace(trace_handle: TraceHandle, callback_data: &Box<Arc<CallbackData>>) -> EvntraceNativeResult<bool> {
+pub(crate) fn close_trace(backend: &dyn EvntraceBackend, trace_handle: TraceHandle, callback_data: &Box<Arc<CallbackData>>) -> EvntraceNativeResult<bool> {
     match filter_invalid_trace_handles(trace_handle) {
         None => Err(EvntraceNativeError::InvalidHandle),
         Some(handle) => {
             // By contruction, only one Provider used this context in its callback. It is safe to remove it, it won't be used by anyone else.
             UNIQUE_VALID_CONTEXTS.remove(callback_data.as_ref() as *const Arc<CallbackData> as *const c_void);
+            TRACE_BACKENDS.lock().unwrap().remove(&handle.0);
 
-            let status = unsafe {
-                Etw::CloseTrace(handle)
-            };
+            let status = backend.close_trace(handle);
 
             match status {
                 ERROR_SUCCESS => Ok(false),
                 ERROR_CTX_CLOSE_PENDING => Ok(true),
-                status => Err(EvntraceNativeError::IoError(
-                    std::io::Error::from_raw_os_error(status.0 as i32),
-                ))
+                status => Err(EvntraceNativeError::from_win32(status))
             }
         },
     }
 }
 
-/// Queries the system for system-wide ETW information (that does not require an active session).
-pub(crate) fn query_info(class: TraceInformation, buf: &mut [u8]) -> EvntraceNativeResult<()> {
-    match unsafe {
-        Etw::TraceQueryInformation(
-            Etw::CONTROLTRACE_HANDLE(0),
-            TRACE_QUERY_INFO_CLASS(class as i32),
-            buf.as_mut_ptr() as *mut c_void,
-            buf.len() as u32,
-            None,
-        )
-    } {
+/// List the running sessions (including the ones this program did not start)
+pub(crate) fn query_all_traces(backend: &dyn EvntraceBackend) -> EvntraceNativeResult<Vec<QueriedTraceProperties>> {
+    // Windows supports 64 sessions by default. More can be configured, in which case we'll retry with a larger array
+    let mut capacity = 64;
+    loop {
+        let mut properties = vec![QueriedTraceProperties::new(); capacity];
+        let mut logger_count = 0;
+        let status = backend.query_all_traces(&mut properties, &mut logger_count);
+
+        match status {
+            ERROR_SUCCESS => {
+                properties.truncate(logger_count as usize);
+                return Ok(properties);
+            },
+            ERROR_MORE_DATA if logger_count as usize > capacity => {
+                capacity = logger_count as usize;
+            },
+            status => return Err(EvntraceNativeError::from_win32(status)),
+        }
+    }
+}
+
+/// Set some information on a running session (e.g. which kernel events should have stack walks)
+pub(crate) fn set_information(backend: &dyn EvntraceBackend, control_handle: ControlHandle, class: TraceInformation, data: &[u8]) -> EvntraceNativeResult<()> {
+    match filter_invalid_control_handle(control_handle) {
+        None => Err(EvntraceNativeError::InvalidHandle),
+        Some(handle) => match backend.set_information(handle, class, data) {
+            ERROR_SUCCESS => Ok(()),
+            e => Err(EvntraceNativeError::from_win32(e)),
+        },
+    }
+}
+
+/// Set some system-wide ETW information (that does not require an active session, e.g. the sampling interval of a profile source)
+pub(crate) fn set_sessionless_info(backend: &dyn EvntraceBackend, class: TraceInformation, data: &[u8]) -> EvntraceNativeResult<()> {
+    match backend.set_information(Etw::CONTROLTRACE_HANDLE(0), class, data) {
         ERROR_SUCCESS => Ok(()),
-        e => Err(EvntraceNativeError::IoError(
-            std::io::Error::from_raw_os_error(e.0 as i32),
-        )),
+        e => Err(EvntraceNativeError::from_win32(e)),
+    }
+}
+
+/// Queries the system for system-wide ETW information (that does not require an active session).
+///
+/// `buf` may contain some input (depending on the information class), and receives the output. This returns the size of the output.
+pub(crate) fn query_info(backend: &dyn EvntraceBackend, class: TraceInformation, buf: &mut [u8]) -> EvntraceNativeResult<usize> {
+    let mut return_length = 0;
+    match backend.query_information(Etw::CONTROLTRACE_HANDLE(0), class, buf, &mut return_length) {
+        ERROR_SUCCESS => Ok(return_length as usize),
+        e => Err(EvntraceNativeError::from_win32(e)),
+    }
+}
+
+/// Same as [`query_info`], for information classes whose output size is not known in advance
+pub(crate) fn query_info_to_vec(backend: &dyn EvntraceBackend, class: TraceInformation) -> EvntraceNativeResult<Vec<u8>> {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let mut return_length = 0;
+        match backend.query_information(Etw::CONTROLTRACE_HANDLE(0), class, &mut buf, &mut return_length) {
+            ERROR_SUCCESS => {
+                buf.truncate(return_length as usize);
+                return Ok(buf);
+            },
+            ERROR_BAD_LENGTH | ERROR_INSUFFICIENT_BUFFER | ERROR_MORE_DATA if return_length as usize > buf.len() => {
+                buf.resize(return_length as usize, 0);
+            },
+            e => return Err(EvntraceNativeError::from_win32(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::backend::fake::FakeBackend;
+    use crate::record_builder::EventRecordBuilder;
+    use crate::trace::UserTrace;
+    use crate::trace::callback_data::RealTimeCallbackData;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use widestring::U16CString;
+
+    const PROV: &str = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716";
+
+    #[allow(clippy::borrowed_box)]
+    fn context_ptr(callback_data: &Box<Arc<CallbackData>>) -> *const c_void {
+        callback_data.as_ref() as *const Arc<CallbackData> as *const c_void
+    }
+
+    fn start(backend: &dyn EvntraceBackend, name: &str) -> EvntraceNativeResult<(EventTraceProperties, ControlHandle)> {
+        let name = U16CString::from_str(name).unwrap();
+        start_trace::<UserTrace>(backend, &name, None, &TraceProperties::default(), Etw::EVENT_TRACE_FLAG::default())
+    }
+
+    fn subscribe(name: &str) -> SubscriptionSource {
+        SubscriptionSource::RealTimeSession(U16CString::from_str(name).unwrap())
+    }
+
+    /// A `CallbackData` with a single provider, whose callback is `f`
+    #[allow(clippy::redundant_allocation)]
+    fn callback_data<F>(f: F) -> Box<Arc<CallbackData>>
+    where
+        F: FnMut(&EventRecord, &crate::SchemaLocator) + Send + Sync + 'static
+    {
+        let rt_callback_data = RealTimeCallbackData::new();
+        rt_callback_data.add_provider(Provider::by_guid(PROV).add_callback(f).build());
+        Box::new(Arc::new(CallbackData::RealTime(rt_callback_data)))
+    }
+
+    fn event(id: u16) -> crate::OwnedEventRecord {
+        EventRecordBuilder::new(windows::core::GUID::from(PROV)).event_id(id).build()
+    }
+
+    #[test]
+    fn test_session_names() {
+        let fake = FakeBackend::new();
+        let (mut properties, control_handle) = start(&fake, "session-names").unwrap();
+        assert!(matches!(start(&fake, "session-names"), Err(EvntraceNativeError::AlreadyExist)));
+        assert!(start(&fake, "another-session").is_ok());
+
+        control_trace(&fake, &mut properties, control_handle, Etw::EVENT_TRACE_CONTROL_STOP).unwrap();
+        assert_eq!(fake.session_names(), vec!["another-session".to_string()]);
+        // The handle is not valid anymore, but the name can be re-used
+        assert!(control_trace(&fake, &mut properties, control_handle, Etw::EVENT_TRACE_CONTROL_STOP).is_err());
+        assert!(start(&fake, "session-names").is_ok());
+
+        let name = U16CString::from_str("another-session").unwrap();
+        control_trace_by_name(&fake, &mut properties, &name, Etw::EVENT_TRACE_CONTROL_STOP).unwrap();
+        assert!(control_trace_by_name(&fake, &mut properties, &name, Etw::EVENT_TRACE_CONTROL_STOP).is_err());
+    }
+
+    #[test]
+    fn test_contexts_lifetime() {
+        let fake: Arc<dyn EvntraceBackend> = Arc::new(FakeBackend::new());
+        let callback_data = callback_data(|_, _| {});
+
+        // Failing to open a trace should not keep its context
+        assert!(open_trace(&fake, subscribe("not-started"), false, &callback_data).is_err());
+        assert!(!UNIQUE_VALID_CONTEXTS.is_valid(context_ptr(&callback_data)));
+
+        let (_properties, control_handle) = start(fake.as_ref(), "contexts-lifetime").unwrap();
+        enable_provider(fake.as_ref(), control_handle, &Provider::by_guid(PROV).build()).unwrap();
+        let trace_handle = open_trace(&fake, subscribe("contexts-lifetime"), false, &callback_data).unwrap();
+        assert!(UNIQUE_VALID_CONTEXTS.is_valid(context_ptr(&callback_data)));
+        // The same context cannot be opened twice
+        assert!(matches!(open_trace(&fake, subscribe("contexts-lifetime"), false, &callback_data), Err(EvntraceNativeError::AlreadyExist)));
+
+        assert!(!close_trace(fake.as_ref(), trace_handle, &callback_data).unwrap());
+        assert!(!UNIQUE_VALID_CONTEXTS.is_valid(context_ptr(&callback_data)));
+        // The handle is not valid anymore
+        assert!(close_trace(fake.as_ref(), trace_handle, &callback_data).is_err());
+        assert!(process_trace(trace_handle).is_err());
+    }
+
+    #[test]
+    fn test_close_pending() {
+        let backend = Arc::new(FakeBackend::new());
+        let fake = backend.shared();
+
+        let invocations = Arc::new(AtomicUsize::new(0));
+        let invocations_clone = Arc::clone(&invocations);
+        let (started_sender, started_receiver) = std::sync::mpsc::channel();
+        let (resume_sender, resume_receiver) = std::sync::mpsc::channel::<()>();
+        let resume_receiver = Mutex::new(resume_receiver);
+        let callback_data = callback_data(move |_, _| {
+            invocations_clone.fetch_add(1, Ordering::SeqCst);
+            started_sender.send(()).unwrap();
+            // Block the first callback until the trace is closed
+            let _ = resume_receiver.lock().unwrap().recv();
+        });
+
+        let (mut properties, control_handle) = start(fake.as_ref(), "close-pending").unwrap();
+        let trace_handle = open_trace(&fake, subscribe("close-pending"), false, &callback_data).unwrap();
+        let processing_thread = std::thread::spawn(move || process_trace(trace_handle));
+
+        assert_eq!(backend.emit("close-pending", &event(1)), 1);
+        started_receiver.recv().unwrap();
+        backend.emit("close-pending", &event(2));
+        backend.emit("close-pending", &event(3));
+
+        // Events are still queued: they will be delivered after the trace is closed...
+        assert!(close_trace(fake.as_ref(), trace_handle, &callback_data).unwrap());
+        control_trace(fake.as_ref(), &mut properties, control_handle, Etw::EVENT_TRACE_CONTROL_STOP).unwrap();
+        drop(resume_sender);
+        processing_thread.join().unwrap().unwrap();
+
+        // ...but they must not reach the callbacks
+        assert_eq!(invocations.load(Ordering::SeqCst), 1);
+        assert_eq!(callback_data.events_handled(), 1);
+        assert_eq!(backend.consumer_count(), 0);
+    }
+
+    #[test]
+    fn test_stop_ends_processing() {
+        let backend = Arc::new(FakeBackend::new());
+        let fake = backend.shared();
+        let callback_data = callback_data(|_, _| {});
+
+        let (mut properties, control_handle) = start(fake.as_ref(), "stop-ends-processing").unwrap();
+        let trace_handle = open_trace(&fake, subscribe("stop-ends-processing"), false, &callback_data).unwrap();
+        backend.emit("stop-ends-processing", &event(1));
+        backend.emit("stop-ends-processing", &event(2));
+        control_trace(fake.as_ref(), &mut properties, control_handle, Etw::EVENT_TRACE_CONTROL_STOP).unwrap();
+
+        // Events that were queued before the session was stopped are still processed
+        process_trace(trace_handle).unwrap();
+        assert_eq!(callback_data.events_handled(), 2);
+        assert_eq!(backend.emit("stop-ends-processing", &event(3)), 0);
+
+        assert!(!close_trace(fake.as_ref(), trace_handle, &callback_data).unwrap());
+    }
+
+    #[test]
+    fn test_panics_outside_callbacks() {
+        let mut rt_callback_data = RealTimeCallbackData::new();
+        rt_callback_data.set_panic_policy(crate::trace::panic_policy::PanicPolicy::StopTrace);
+        let callback_data = CallbackData::RealTime(rt_callback_data);
+
+        // Panics of the code around the callbacks follow the panic policy of the trace as well
+        assert!(callback_data.catch_panics(|| panic!("not a callback")).is_none());
+        assert!(callback_data.stop_requested());
+        assert_eq!(callback_data.caught_panics(), 1);
+        let payload = callback_data.take_panic_payload().unwrap();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"not a callback"));
     }
 }
@@ -0,0 +1,289 @@
+//! Backends for the [`evntrace`](super) layer
+//!
+//! The `evntrace` module makes sure the ETW API is used safely (handle validity, lifetime of the callback contexts, error mapping, etc.),
+//! but the actual calls to `StartTraceW`, `OpenTraceW`, `EnableTraceEx2`, `ProcessTrace`, `ControlTraceW`, `CloseTrace`, `QueryAllTracesW`, `TraceSetInformation` and `TraceQueryInformation` are delegated to an [`EvntraceBackend`].
+//!
+//! On Windows, the default backend is `WindowsBackend`, that calls the Windows API. On other targets, the default `UnsupportedBackend` fails with `ERROR_NOT_SUPPORTED`.<br/>
+//! Tests can use the in-process [`FakeBackend`](fake::FakeBackend) instead, which simulates ETW sessions. This makes it possible to test the session state machine without Windows (and without administrator privileges).
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use widestring::U16CStr;
+use windows::core::GUID;
+#[cfg(windows)]
+use windows::core::PCWSTR;
+#[cfg(windows)]
+use windows::Win32::Foundation::{GetLastError, FILETIME};
+#[cfg(not(windows))]
+use windows::Win32::Foundation::ERROR_NOT_SUPPORTED;
+use windows::Win32::Foundation::WIN32_ERROR;
+use windows::Win32::System::Diagnostics::Etw;
+
+use super::{ControlHandle, TraceHandle};
+use crate::native::etw_types::{EnableTraceParameters, EventTraceLogfile, EventTraceProperties, QueriedTraceProperties, TraceInformation};
+
+#[cfg(test)]
+pub(crate) mod fake;
+
+/// The native calls the `evntrace` layer relies on
+///
+/// Every function mirrors its Windows API counterpart, and returns the same status codes.<br/>
+/// Implementations do not have to check handles are valid, or to keep track of the callback contexts: this is the job of the `evntrace` layer.
+pub trait EvntraceBackend: Send + Sync + std::fmt::Debug {
+    /// `StartTraceW`. The session name is taken from `properties`.
+    fn start_trace(&self, control_handle: &mut ControlHandle, properties: &mut EventTraceProperties) -> WIN32_ERROR;
+
+    /// `OpenTraceW`. On failure, this returns what `GetLastError` would return.
+    fn open_trace(&self, log_file: &mut EventTraceLogfile) -> Result<TraceHandle, WIN32_ERROR>;
+
+    /// `EnableTraceEx2`
+    #[allow(clippy::too_many_arguments)] // Let's stick to the signature of the Windows API
+    fn enable_trace(
+        &self,
+        control_handle: ControlHandle,
+        provider_id: &GUID,
+        control_code: u32,
+        level: u8,
+        match_any_keyword: u64,
+        match_all_keyword: u64,
+        parameters: Option<&EnableTraceParameters>,
+    ) -> WIN32_ERROR;
+
+    /// `ProcessTrace`, for a single trace, and starting from the oldest available event.
+    ///
+    /// This blocks until the trace is over (or closed).
+    fn process_trace(&self, trace_handle: TraceHandle) -> WIN32_ERROR;
+
+    /// `ControlTraceW`. The session is designated by `session_name` if it is not `None`, or by `control_handle` otherwise.
+    fn control_trace(
+        &self,
+        control_handle: ControlHandle,
+        session_name: Option<&U16CStr>,
+        properties: &mut EventTraceProperties,
+        control_code: Etw::EVENT_TRACE_CONTROL,
+    ) -> WIN32_ERROR;
+
+    /// `CloseTrace`
+    fn close_trace(&self, trace_handle: TraceHandle) -> WIN32_ERROR;
+
+    /// `QueryAllTracesW`. `logger_count` receives the number of running sessions, even if there are more than `properties.len()`
+    fn query_all_traces(&self, properties: &mut [QueriedTraceProperties], logger_count: &mut u32) -> WIN32_ERROR;
+
+    /// `TraceSetInformation`, on a running session (or system-wide, if `control_handle` is 0)
+    fn set_information(&self, control_handle: ControlHandle, information_class: TraceInformation, data: &[u8]) -> WIN32_ERROR;
+
+    /// `TraceQueryInformation`, on a running session (or system-wide, if `control_handle` is 0). `return_length` receives the size of the output, or the required size if `buffer` is too small
+    fn query_information(&self, control_handle: ControlHandle, information_class: TraceInformation, buffer: &mut [u8], return_length: &mut u32) -> WIN32_ERROR;
+}
+
+#[cfg(windows)]
+static DEFAULT_BACKEND: Lazy<Arc<dyn EvntraceBackend>> = Lazy::new(|| Arc::new(WindowsBackend));
+#[cfg(not(windows))]
+static DEFAULT_BACKEND: Lazy<Arc<dyn EvntraceBackend>> = Lazy::new(|| Arc::new(UnsupportedBackend));
+
+/// The backend traces use, unless told otherwise
+pub(crate) fn default_backend() -> Arc<dyn EvntraceBackend> {
+    Arc::clone(&DEFAULT_BACKEND)
+}
+
+/// The backend that calls the actual Windows API
+#[cfg(windows)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct WindowsBackend;
+
+#[cfg(windows)]
+impl EvntraceBackend for WindowsBackend {
+    fn start_trace(&self, control_handle: &mut ControlHandle, properties: &mut EventTraceProperties) -> WIN32_ERROR {
+        unsafe {
+            // Safety:
+            //  * first argument points to a valid and allocated address (this is an output and will be modified)
+            //  * second argument is a valid, null terminated widestring (note that it will be copied to the EventTraceProperties...from where it already comes. This will probably be overwritten by Windows, but heck.)
+            //  * third argument is a valid, allocated EVENT_TRACE_PROPERTIES (and will be mutated)
+            //  * Note: the string (that will be overwritten to itself) ends with a null widechar before the end of its buffer (see EventTraceProperties::new())
+            Etw::StartTraceW(
+                control_handle,
+                PCWSTR::from_raw(properties.trace_name_array().as_ptr()),
+                properties.as_mut_ptr(),
+            )
+        }
+    }
+
+    fn open_trace(&self, log_file: &mut EventTraceLogfile) -> Result<TraceHandle, WIN32_ERROR> {
+        let trace_handle = unsafe {
+            // This function modifies the data pointed to by log_file.
+            // This is fine because there is currently no other ref to it (we have a `&mut`)
+            //
+            // > On success, OpenTrace will update the structure with information from the opened file or session.
+            // https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-opentracea
+            Etw::OpenTraceW(log_file.as_mut_ptr())
+        };
+
+        if super::filter_invalid_trace_handles(trace_handle).is_none() {
+            Err(unsafe { GetLastError() })
+        } else {
+            Ok(trace_handle)
+        }
+    }
+
+    fn enable_trace(
+        &self,
+        control_handle: ControlHandle,
+        provider_id: &GUID,
+        control_code: u32,
+        level: u8,
+        match_any_keyword: u64,
+        match_all_keyword: u64,
+        parameters: Option<&EnableTraceParameters>,
+    ) -> WIN32_ERROR {
+        unsafe {
+            Etw::EnableTraceEx2(
+                control_handle,
+                provider_id as *const GUID,
+                control_code,
+                level,
+                match_any_keyword,
+                match_all_keyword,
+                0,
+                parameters.map(|p| p.as_ptr()),
+            )
+        }
+    }
+
+    fn process_trace(&self, trace_handle: TraceHandle) -> WIN32_ERROR {
+        unsafe {
+            // We want to start processing events as soon as January 1601.
+            // * for ETL file traces, this is fine, this means "process everything from the file"
+            // * for real-time traces, this means we might process a few events already waiting in the buffers when the processing is starting. This is fine, I suppose.
+            let mut start = FILETIME::default();
+            Etw::ProcessTrace(&[trace_handle], Some(&mut start as *mut FILETIME), None)
+        }
+    }
+
+    fn control_trace(
+        &self,
+        control_handle: ControlHandle,
+        session_name: Option<&U16CStr>,
+        properties: &mut EventTraceProperties,
+        control_code: Etw::EVENT_TRACE_CONTROL,
+    ) -> WIN32_ERROR {
+        let (control_handle, session_name) = match session_name {
+            // When a name is given, the handle must be zero
+            Some(name) => (Etw::CONTROLTRACE_HANDLE(0), PCWSTR::from_raw(name.as_ptr())),
+            None => (control_handle, PCWSTR::null()),
+        };
+
+        unsafe {
+            // Safety:
+            //  * depending on the control code, the `Properties` can be mutated. This is fine because properties is declared as `&mut` in this function, which means no other Rust function has a reference to it, and the mutation can only happen in the call to `ControlTraceW`, which returns immediately.
+            //  * the session name (if any) is a valid, null-terminated widestring
+            Etw::ControlTraceW(
+                control_handle,
+                session_name,
+                properties.as_mut_ptr(),
+                control_code,
+            )
+        }
+    }
+
+    fn close_trace(&self, trace_handle: TraceHandle) -> WIN32_ERROR {
+        unsafe {
+            Etw::CloseTrace(trace_handle)
+        }
+    }
+
+    fn query_all_traces(&self, properties: &mut [QueriedTraceProperties], logger_count: &mut u32) -> WIN32_ERROR {
+        let mut pointers: Vec<*mut Etw::EVENT_TRACE_PROPERTIES> = properties
+            .iter_mut()
+            .map(|p| unsafe { p.as_mut_ptr() })
+            .collect();
+
+        unsafe {
+            // Safety: every pointer points to a distinct, allocated `QueriedTraceProperties`, whose offsets and buffer sizes are set
+            Etw::QueryAllTracesW(&mut pointers, logger_count)
+        }
+    }
+
+    fn set_information(&self, control_handle: ControlHandle, information_class: TraceInformation, data: &[u8]) -> WIN32_ERROR {
+        unsafe {
+            // Safety: `data` is only read, and is valid for `data.len()` bytes
+            Etw::TraceSetInformation(
+                control_handle,
+                Etw::TRACE_QUERY_INFO_CLASS(information_class as i32),
+                data.as_ptr() as *const std::ffi::c_void,
+                data.len() as u32,
+            )
+        }
+    }
+
+    fn query_information(&self, control_handle: ControlHandle, information_class: TraceInformation, buffer: &mut [u8], return_length: &mut u32) -> WIN32_ERROR {
+        unsafe {
+            // Safety: `buffer` is valid for writes of `buffer.len()` bytes
+            Etw::TraceQueryInformation(
+                control_handle,
+                Etw::TRACE_QUERY_INFO_CLASS(information_class as i32),
+                buffer.as_mut_ptr() as *mut std::ffi::c_void,
+                buffer.len() as u32,
+                Some(return_length as *mut u32),
+            )
+        }
+    }
+}
+
+/// The default backend on targets that have no ETW
+#[cfg(not(windows))]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct UnsupportedBackend;
+
+#[cfg(not(windows))]
+impl EvntraceBackend for UnsupportedBackend {
+    fn start_trace(&self, _control_handle: &mut ControlHandle, _properties: &mut EventTraceProperties) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+
+    fn open_trace(&self, _log_file: &mut EventTraceLogfile) -> Result<TraceHandle, WIN32_ERROR> {
+        Err(ERROR_NOT_SUPPORTED)
+    }
+
+    fn enable_trace(
+        &self,
+        _control_handle: ControlHandle,
+        _provider_id: &GUID,
+        _control_code: u32,
+        _level: u8,
+        _match_any_keyword: u64,
+        _match_all_keyword: u64,
+        _parameters: Option<&EnableTraceParameters>,
+    ) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+
+    fn process_trace(&self, _trace_handle: TraceHandle) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+
+    fn control_trace(
+        &self,
+        _control_handle: ControlHandle,
+        _session_name: Option<&U16CStr>,
+        _properties: &mut EventTraceProperties,
+        _control_code: Etw::EVENT_TRACE_CONTROL,
+    ) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+
+    fn close_trace(&self, _trace_handle: TraceHandle) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+
+    fn query_all_traces(&self, _properties: &mut [QueriedTraceProperties], _logger_count: &mut u32) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+
+    fn set_information(&self, _control_handle: ControlHandle, _information_class: TraceInformation, _data: &[u8]) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+
+    fn query_information(&self, _control_handle: ControlHandle, _information_class: TraceInformation, _buffer: &mut [u8], _return_length: &mut u32) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+}
@@ -0,0 +1,656 @@
+//! An in-process fake of the ETW API
+//!
+//! This simulates:
+//! * sessions, identified by their names. Starting a session whose name is already used fails with `ERROR_ALREADY_EXISTS`
+//! * consumers (i.e. opened traces), whose handles are valid until they are closed
+//! * events that are queued for a consumer. Like the actual ETW, events that were queued when `CloseTrace` is called are still delivered (and `CloseTrace` returns `ERROR_CTX_CLOSE_PENDING`)
+//! * stopping a session, which makes its consumers stop processing once their queue is empty
+//! * updating the settings of a session, and flushing it (which only counts the flushes, since events are never buffered here)
+//! * setting information on a session (which is only recorded, see [`FakeBackend::session_information`])
+//! * system-wide information, that is queried and set by information class (regardless of the input of the query). See [`FakeBackend::set_system_information`]
+//! * listing the running sessions
+//! * providers that cannot be enabled (see [`FakeBackend::reject_provider`]), or that cannot capture their state (see [`FakeBackend::reject_capture_state`])
+//! * querying the statistics of a session (events sent with [`FakeBackend::emit`] count as written, events dropped with [`FakeBackend::lose_events`] count as lost)
+//! * ETL files, that are made of buffers of events (see [`FakeBackend::add_etl_file`]). Consumers of a file stop processing once they have delivered all of its events
+//! * buffer callbacks. For files, they are called after every buffer. For sessions, they are called whenever a consumer has delivered all of its queued events.
+//!   Like the actual ETW, a buffer callback returning `FALSE` makes `ProcessTrace` return `ERROR_CANCELLED`
+//!
+//! Events are sent to a session with [`FakeBackend::emit`].
+//!
+//! Tests usually start their traces with [`fake_user_trace`] or [`fake_kernel_trace`], and start more traces on the same backend with [`FakeBackend::user_trace`].
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use widestring::{U16CStr, U16CString};
+use windows::core::GUID;
+use windows::Win32::Foundation::{
+    ERROR_ACCESS_DENIED, ERROR_ALREADY_EXISTS, ERROR_CANCELLED, ERROR_CTX_CLOSE_PENDING, ERROR_FILE_NOT_FOUND, ERROR_INVALID_HANDLE,
+    ERROR_INVALID_PARAMETER, ERROR_MORE_DATA, ERROR_SUCCESS, ERROR_BAD_LENGTH, ERROR_NOT_SUPPORTED, ERROR_WMI_INSTANCE_NOT_FOUND, WIN32_ERROR,
+};
+use windows::Win32::System::Diagnostics::Etw;
+
+use super::EvntraceBackend;
+use crate::native::etw_types::event_record::OwnedEventRecord;
+use crate::native::etw_types::{EnableTraceParameters, EventTraceLogfile, EventTraceProperties, QueriedTraceProperties, SubscriptionSource, TraceInformation};
+use crate::native::evntrace::{ControlHandle, TraceHandle};
+use crate::trace::{KernelTrace, TraceBuilder, UserTrace};
+
+/// Handles given by every `FakeBackend`.
+///
+/// They are unique across instances, because the `evntrace` layer keeps track of opened traces by their handles.
+static NEXT_FAKE_HANDLE: AtomicU64 = AtomicU64::new(0x0FA6_0000_0000_0000);
+
+fn next_handle() -> u64 {
+    NEXT_FAKE_HANDLE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The values the header of every opened trace is populated with
+pub(crate) const FAKE_BOOT_TIME: i64 = 133_170_048_000_000_000; // 2023-01-01T00:00:00Z
+pub(crate) const FAKE_PERF_FREQ: i64 = 10_000_000;
+pub(crate) const FAKE_CPU_SPEED_MHZ: u32 = 3_000;
+pub(crate) const FAKE_BUFFER_SIZE: u32 = 64 * 1024;
+
+/// A user trace named `name`, that will be started on a new fake backend
+pub(crate) fn fake_user_trace(name: &str) -> (TraceBuilder<UserTrace>, Arc<FakeBackend>) {
+    let backend = Arc::new(FakeBackend::new());
+    (backend.user_trace(name), backend)
+}
+
+/// A kernel trace named `name`, that will be started on a new fake backend
+pub(crate) fn fake_kernel_trace(name: &str) -> (TraceBuilder<KernelTrace>, Arc<FakeBackend>) {
+    let backend = Arc::new(FakeBackend::new());
+    (backend.kernel_trace(name), backend)
+}
+
+/// An [`EvntraceBackend`] that simulates ETW sessions in the current process
+#[derive(Debug, Default)]
+pub(crate) struct FakeBackend {
+    state: Mutex<FakeState>,
+    /// Notified whenever a consumer may have something new to do
+    wake_up: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    /// Running sessions, by control handle
+    sessions: HashMap<u64, FakeSession>,
+    /// Consumers, by trace handle. Closed consumers are kept until they have delivered their queued events
+    consumers: HashMap<u64, FakeConsumer>,
+    /// System-wide information, by information class
+    system_information: HashMap<i32, Vec<u8>>,
+    /// ETL files, by path. They are made of buffers of events
+    etl_files: HashMap<OsString, Vec<Vec<OwnedEventRecord>>>,
+    /// Providers that fail to be enabled
+    rejected_providers: Vec<GUID>,
+    /// Providers that fail to capture their state
+    rejected_capture_states: Vec<GUID>,
+}
+
+#[derive(Debug)]
+struct FakeSession {
+    name: String,
+    providers: Vec<GUID>,
+    events_emitted: u32,
+    events_lost: u32,
+    settings: FakeSessionSettings,
+    log_file_mode: u32,
+    /// The `Wnode.ClientContext`, i.e. the clock of the session
+    clock_type: u32,
+    flush_count: usize,
+    /// Providers that have been asked to capture their state, in order
+    capture_state_requests: Vec<GUID>,
+    /// The data last given to `TraceSetInformation`, by information class
+    information: HashMap<i32, Vec<u8>>,
+}
+
+/// The settings of a session that can be changed with `EVENT_TRACE_CONTROL_UPDATE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FakeSessionSettings {
+    pub flush_timer: u32,
+    pub max_buffers: u32,
+    pub enable_flags: u32,
+    pub log_file: Option<OsString>,
+}
+
+impl FakeSessionSettings {
+    fn from_properties(properties: &EventTraceProperties) -> Self {
+        let raw = properties.as_raw();
+        Self {
+            flush_timer: raw.FlushTimer,
+            max_buffers: raw.MaximumBuffers,
+            enable_flags: raw.EnableFlags.0,
+            log_file: properties.etl_dump_file(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FakeConsumer {
+    session_name: String,
+    callback: unsafe extern "system" fn(*mut Etw::EVENT_RECORD),
+    buffer_callback: Option<unsafe extern "system" fn(*mut Etw::EVENT_TRACE_LOGFILEW) -> u32>,
+    /// The `Context` of the log file (stored as an integer, so that this is `Send`)
+    context: usize,
+    process_trace_mode: u32,
+    queue: VecDeque<OwnedEventRecord>,
+    /// For consumers of a file, the number of events of its buffers that are not entirely delivered yet
+    file_buffer_sizes: VecDeque<usize>,
+    /// The total number of buffers of the file (0 for sessions)
+    buffers_written: u32,
+    buffers_read: u32,
+    events_in_buffer: usize,
+    bytes_in_buffer: u32,
+    processing: bool,
+    closed: bool,
+    session_stopped: bool,
+}
+
+impl FakeConsumer {
+    fn new(session_name: String, log_file: &EventTraceLogfile, callback: unsafe extern "system" fn(*mut Etw::EVENT_RECORD)) -> Self {
+        Self {
+            session_name,
+            callback,
+            buffer_callback: log_file.buffer_callback(),
+            context: log_file.context_ptr() as usize,
+            process_trace_mode: log_file.process_trace_mode(),
+            queue: VecDeque::new(),
+            file_buffer_sizes: VecDeque::new(),
+            buffers_written: 0,
+            buffers_read: 0,
+            events_in_buffer: 0,
+            bytes_in_buffer: 0,
+            processing: false,
+            closed: false,
+            session_stopped: false,
+        }
+    }
+
+    /// Whether the events that were delivered since the last buffer callback make a complete buffer
+    fn buffer_is_complete(&self) -> bool {
+        match self.file_buffer_sizes.front() {
+            Some(size) => self.events_in_buffer >= *size,
+            None => self.events_in_buffer > 0 && self.queue.is_empty(),
+        }
+    }
+}
+
+impl FakeBackend {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// This backend, to be given to a trace builder
+    pub(crate) fn shared(self: &Arc<Self>) -> Arc<dyn EvntraceBackend> {
+        Arc::clone(self) as Arc<dyn EvntraceBackend>
+    }
+
+    /// A user trace named `name`, that will be started on this backend
+    pub(crate) fn user_trace(self: &Arc<Self>, name: &str) -> TraceBuilder<UserTrace> {
+        UserTrace::new().named(name.to_string()).backend(self.shared())
+    }
+
+    /// A kernel trace named `name`, that will be started on this backend
+    pub(crate) fn kernel_trace(self: &Arc<Self>, name: &str) -> TraceBuilder<KernelTrace> {
+        KernelTrace::new().named(name.to_string()).backend(self.shared())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Make every future attempt to enable this provider fail with `ERROR_ACCESS_DENIED`
+    pub(crate) fn reject_provider(&self, provider_id: GUID) {
+        self.lock().rejected_providers.push(provider_id);
+    }
+
+    /// Make every future capture state request for this provider fail with `ERROR_ACCESS_DENIED`
+    pub(crate) fn reject_capture_state(&self, provider_id: GUID) {
+        self.lock().rejected_capture_states.push(provider_id);
+    }
+
+    /// Queue an event for every consumer of a running session.
+    ///
+    /// Events are not filtered by provider: routing them to the right callbacks is the job of the trace.<br/>
+    /// Returns the number of consumers the event has been queued for.
+    pub(crate) fn emit(&self, session_name: &str, record: &OwnedEventRecord) -> usize {
+        let mut state = self.lock();
+        if let Some(session) = state.sessions.values_mut().find(|s| s.name == session_name) {
+            session.events_emitted += 1;
+        }
+        let mut count = 0;
+        for consumer in state.consumers.values_mut() {
+            if consumer.session_name == session_name && !consumer.closed && !consumer.session_stopped {
+                consumer.queue.push_back(record.clone());
+                count += 1;
+            }
+        }
+        self.wake_up.notify_all();
+        count
+    }
+
+    /// Simulate ETW dropping `count` events of a running session (e.g. because its buffers were full)
+    pub(crate) fn lose_events(&self, session_name: &str, count: u32) {
+        if let Some(session) = self.lock().sessions.values_mut().find(|s| s.name == session_name) {
+            session.events_lost += count;
+        }
+    }
+
+    /// The current settings of a running session
+    pub(crate) fn session_settings(&self, session_name: &str) -> Option<FakeSessionSettings> {
+        self.lock().sessions.values().find(|s| s.name == session_name).map(|s| s.settings.clone())
+    }
+
+    /// The `LogFileMode` a running session has been started with
+    pub(crate) fn log_file_mode(&self, session_name: &str) -> Option<u32> {
+        self.lock().sessions.values().find(|s| s.name == session_name).map(|s| s.log_file_mode)
+    }
+
+    /// How many times a running session has been flushed
+    pub(crate) fn flush_count(&self, session_name: &str) -> usize {
+        self.lock().sessions.values().find(|s| s.name == session_name).map(|s| s.flush_count).unwrap_or_default()
+    }
+
+    /// The providers of a running session that have been asked to capture their state, in order
+    pub(crate) fn capture_state_requests(&self, session_name: &str) -> Vec<GUID> {
+        self.lock()
+            .sessions
+            .values()
+            .find(|s| s.name == session_name)
+            .map(|s| s.capture_state_requests.clone())
+            .unwrap_or_default()
+    }
+
+    /// The data last set with `TraceSetInformation` for this information class on a running session
+    pub(crate) fn session_information(&self, session_name: &str, information_class: TraceInformation) -> Option<Vec<u8>> {
+        self.lock()
+            .sessions
+            .values()
+            .find(|s| s.name == session_name)
+            .and_then(|s| s.information.get(&(information_class as i32)).cloned())
+    }
+
+    /// Set the system-wide information that `TraceQueryInformation` returns for this information class
+    pub(crate) fn set_system_information(&self, information_class: TraceInformation, data: Vec<u8>) {
+        self.lock().system_information.insert(information_class as i32, data);
+    }
+
+    /// The names of the running sessions
+    pub(crate) fn session_names(&self) -> Vec<String> {
+        self.lock().sessions.values().map(|s| s.name.clone()).collect()
+    }
+
+    /// The providers that are currently enabled on a running session
+    pub(crate) fn enabled_providers(&self, session_name: &str) -> Vec<GUID> {
+        self.lock()
+            .sessions
+            .values()
+            .find(|s| s.name == session_name)
+            .map(|s| s.providers.clone())
+            .unwrap_or_default()
+    }
+
+    /// The number of consumers that are still known (i.e. not closed, or closed but still delivering their queued events)
+    pub(crate) fn consumer_count(&self) -> usize {
+        self.lock().consumers.len()
+    }
+
+    /// Create an ETL file that can be opened by a `FileTrace`
+    pub(crate) fn add_etl_file(&self, path: &str, buffers: Vec<Vec<OwnedEventRecord>>) {
+        self.lock().etl_files.insert(OsString::from(path), buffers);
+    }
+
+    /// The `ProcessTraceMode` of the consumers of a session
+    pub(crate) fn process_trace_modes(&self, session_name: &str) -> Vec<u32> {
+        self.lock()
+            .consumers
+            .values()
+            .filter(|c| c.session_name == session_name)
+            .map(|c| c.process_trace_mode)
+            .collect()
+    }
+}
+
+impl FakeBackend {
+    fn open_etl_file(&self, path: OsString, log_file: &mut EventTraceLogfile) -> Result<TraceHandle, WIN32_ERROR> {
+        let callback = log_file.event_record_callback().ok_or(ERROR_INVALID_PARAMETER)?;
+
+        let mut state = self.lock();
+        let buffers = state.etl_files.get(&path).ok_or(ERROR_FILE_NOT_FOUND)?;
+
+        let mut header = Etw::TRACE_LOGFILE_HEADER {
+            BootTime: FAKE_BOOT_TIME,
+            PerfFreq: FAKE_PERF_FREQ,
+            BuffersWritten: buffers.len() as u32,
+            ..Default::default()
+        };
+        header.Anonymous2.Anonymous.CpuSpeedInMHz = FAKE_CPU_SPEED_MHZ;
+        log_file.set_logfile_header(header);
+
+        let mut consumer = FakeConsumer::new(path.to_string_lossy().into_owned(), log_file, callback);
+        consumer.queue = buffers.iter().flatten().cloned().collect();
+        consumer.file_buffer_sizes = buffers.iter().map(|buffer| buffer.len()).collect();
+        consumer.buffers_written = buffers.len() as u32;
+        // Files have an end
+        consumer.session_stopped = true;
+
+        let handle = next_handle();
+        state.consumers.insert(handle, consumer);
+        Ok(Etw::PROCESSTRACE_HANDLE(handle))
+    }
+}
+
+impl EvntraceBackend for FakeBackend {
+    fn start_trace(&self, control_handle: &mut ControlHandle, properties: &mut EventTraceProperties) -> WIN32_ERROR {
+        let name = properties.name().to_string_lossy().into_owned();
+        let mut state = self.lock();
+        if state.sessions.values().any(|s| s.name == name) {
+            return ERROR_ALREADY_EXISTS;
+        }
+
+        let handle = next_handle();
+        state.sessions.insert(handle, FakeSession {
+            name,
+            providers: Vec::new(),
+            events_emitted: 0,
+            events_lost: 0,
+            settings: FakeSessionSettings::from_properties(properties),
+            log_file_mode: properties.as_raw().LogFileMode,
+            clock_type: properties.as_raw().Wnode.ClientContext,
+            flush_count: 0,
+            capture_state_requests: Vec::new(),
+            information: HashMap::new(),
+        });
+        *control_handle = Etw::CONTROLTRACE_HANDLE(handle);
+        ERROR_SUCCESS
+    }
+
+    fn open_trace(&self, log_file: &mut EventTraceLogfile) -> Result<TraceHandle, WIN32_ERROR> {
+        let session_name = match log_file.subscription_source() {
+            SubscriptionSource::RealTimeSession(name) => name.to_string_lossy(),
+            SubscriptionSource::FromFile(path) => return self.open_etl_file(path.to_os_string(), log_file),
+        };
+        let callback = log_file.event_record_callback().ok_or(ERROR_INVALID_PARAMETER)?;
+
+        let mut state = self.lock();
+        let clock_type = match state.sessions.values().find(|s| s.name == session_name) {
+            None => return Err(ERROR_WMI_INSTANCE_NOT_FOUND),
+            Some(session) => session.clock_type,
+        };
+
+        // > On success, OpenTrace will update the structure with information from the opened file or session.
+        let mut header = Etw::TRACE_LOGFILE_HEADER {
+            BootTime: FAKE_BOOT_TIME,
+            PerfFreq: FAKE_PERF_FREQ,
+            ReservedFlags: clock_type,
+            ..Default::default()
+        };
+        header.Anonymous2.Anonymous.CpuSpeedInMHz = FAKE_CPU_SPEED_MHZ;
+        log_file.set_logfile_header(header);
+
+        let handle = next_handle();
+        state.consumers.insert(handle, FakeConsumer::new(session_name, log_file, callback));
+        Ok(Etw::PROCESSTRACE_HANDLE(handle))
+    }
+
+    fn enable_trace(
+        &self,
+        control_handle: ControlHandle,
+        provider_id: &GUID,
+        control_code: u32,
+        _level: u8,
+        _match_any_keyword: u64,
+        _match_all_keyword: u64,
+        _parameters: Option<&EnableTraceParameters>,
+    ) -> WIN32_ERROR {
+        let mut state = self.lock();
+        let rejected = state.rejected_providers.contains(provider_id);
+        let rejected_capture_state = state.rejected_capture_states.contains(provider_id);
+        let session = match state.sessions.get_mut(&control_handle.0) {
+            None => return ERROR_INVALID_HANDLE,
+            Some(session) => session,
+        };
+
+        if control_code == Etw::EVENT_CONTROL_CODE_ENABLE_PROVIDER.0 {
+            if rejected {
+                return ERROR_ACCESS_DENIED;
+            }
+            if !session.providers.contains(provider_id) {
+                session.providers.push(*provider_id);
+            }
+        } else if control_code == Etw::EVENT_CONTROL_CODE_DISABLE_PROVIDER.0 {
+            session.providers.retain(|p| p != provider_id);
+        } else if control_code == Etw::EVENT_CONTROL_CODE_CAPTURE_STATE.0 {
+            if rejected_capture_state {
+                return ERROR_ACCESS_DENIED;
+            }
+            session.capture_state_requests.push(*provider_id);
+        }
+        ERROR_SUCCESS
+    }
+
+    fn process_trace(&self, trace_handle: TraceHandle) -> WIN32_ERROR {
+        let mut state = self.lock();
+        match state.consumers.get_mut(&trace_handle.0) {
+            None => return ERROR_INVALID_HANDLE,
+            Some(consumer) if consumer.closed => return ERROR_INVALID_HANDLE,
+            Some(consumer) if consumer.processing => return ERROR_INVALID_PARAMETER,
+            Some(consumer) => consumer.processing = true,
+        }
+
+        loop {
+            // Consumers that are being processed are only removed from the map by this function
+            let consumer = state.consumers.get_mut(&trace_handle.0).unwrap();
+
+            if consumer.buffer_is_complete() {
+                consumer.file_buffer_sizes.pop_front();
+                consumer.buffers_read += 1;
+                let mut log_file = Etw::EVENT_TRACE_LOGFILEW {
+                    BuffersRead: consumer.buffers_read,
+                    BufferSize: FAKE_BUFFER_SIZE,
+                    Filled: consumer.bytes_in_buffer,
+                    Context: consumer.context as *mut std::ffi::c_void,
+                    ..Default::default()
+                };
+                log_file.LogfileHeader.BuffersWritten = consumer.buffers_written;
+                consumer.events_in_buffer = 0;
+                consumer.bytes_in_buffer = 0;
+
+                let buffer_callback = consumer.buffer_callback;
+                let session_name = consumer.session_name.clone();
+                log_file.EventsLost = state.sessions.values().find(|s| s.name == session_name).map(|s| s.events_lost).unwrap_or_default();
+                if let Some(buffer_callback) = buffer_callback {
+                    drop(state);
+                    let keep_processing = unsafe {
+                        // Safety: `log_file` is alive until the end of the callback
+                        buffer_callback(&mut log_file)
+                    };
+                    state = self.lock();
+                    if keep_processing == 0 {
+                        let consumer = state.consumers.get_mut(&trace_handle.0).unwrap();
+                        consumer.processing = false;
+                        if consumer.closed {
+                            state.consumers.remove(&trace_handle.0);
+                        }
+                        return ERROR_CANCELLED;
+                    }
+                }
+                continue;
+            }
+
+            if let Some(record) = consumer.queue.pop_front() {
+                consumer.events_in_buffer += 1;
+                consumer.bytes_in_buffer += (std::mem::size_of::<Etw::EVENT_HEADER>() + record.user_data().len()) as u32;
+                let callback = consumer.callback;
+                let mut native = *record.as_raw();
+                native.UserContext = consumer.context as *mut std::ffi::c_void;
+                drop(state);
+                unsafe {
+                    // Safety: `native` points into the buffers of `record`, that is alive until the end of the callback
+                    callback(&mut native);
+                }
+                state = self.lock();
+                continue;
+            }
+
+            if consumer.closed {
+                state.consumers.remove(&trace_handle.0);
+                return ERROR_SUCCESS;
+            }
+            if consumer.session_stopped {
+                consumer.processing = false;
+                return ERROR_SUCCESS;
+            }
+
+            state = self.wake_up.wait(state).unwrap();
+        }
+    }
+
+    fn control_trace(
+        &self,
+        control_handle: ControlHandle,
+        session_name: Option<&U16CStr>,
+        properties: &mut EventTraceProperties,
+        control_code: Etw::EVENT_TRACE_CONTROL,
+    ) -> WIN32_ERROR {
+        let mut state = self.lock();
+        let key = match session_name {
+            Some(name) => {
+                let name = name.to_string_lossy();
+                state.sessions.iter().find(|(_, s)| s.name == name).map(|(k, _)| *k)
+            },
+            None => state.sessions.contains_key(&control_handle.0).then_some(control_handle.0),
+        };
+        let key = match key {
+            None => return ERROR_WMI_INSTANCE_NOT_FOUND,
+            Some(key) => key,
+        };
+
+        if control_code == Etw::EVENT_TRACE_CONTROL_QUERY {
+            let session = &state.sessions[&key];
+            let raw = unsafe {
+                // Safety: we have a `&mut` to the properties, nothing else can access them
+                &mut *properties.as_mut_ptr()
+            };
+            raw.NumberOfBuffers = 4;
+            raw.FreeBuffers = 4;
+            raw.BuffersWritten = session.events_emitted;
+            raw.EventsLost = session.events_lost;
+            raw.LogBuffersLost = 0;
+            raw.RealTimeBuffersLost = 0;
+            raw.LoggerThreadId = windows::Win32::Foundation::HANDLE(1);
+        } else if control_code == Etw::EVENT_TRACE_CONTROL_UPDATE {
+            let update = FakeSessionSettings::from_properties(properties);
+            let settings = &mut state.sessions.get_mut(&key).unwrap().settings;
+            settings.flush_timer = update.flush_timer;
+            settings.max_buffers = update.max_buffers;
+            settings.enable_flags = update.enable_flags;
+            // A zero LogFileNameOffset means "keep the current file"
+            if update.log_file.is_some() {
+                settings.log_file = update.log_file;
+            }
+        } else if control_code == Etw::EVENT_TRACE_CONTROL_FLUSH {
+            state.sessions.get_mut(&key).unwrap().flush_count += 1;
+        } else if control_code == Etw::EVENT_TRACE_CONTROL_STOP {
+            if let Some(session) = state.sessions.remove(&key) {
+                for consumer in state.consumers.values_mut() {
+                    if consumer.session_name == session.name {
+                        consumer.session_stopped = true;
+                    }
+                }
+                self.wake_up.notify_all();
+            }
+        }
+        ERROR_SUCCESS
+    }
+
+    fn query_all_traces(&self, properties: &mut [QueriedTraceProperties], logger_count: &mut u32) -> WIN32_ERROR {
+        let state = self.lock();
+        *logger_count = state.sessions.len() as u32;
+        if state.sessions.len() > properties.len() {
+            return ERROR_MORE_DATA;
+        }
+
+        for (session, queried) in state.sessions.values().zip(properties.iter_mut()) {
+            let name = U16CString::from_str_truncate(&session.name);
+            let log_file = session.settings.log_file.as_ref().map(U16CString::from_os_str_truncate);
+            queried.set_names(&name, log_file.as_deref());
+
+            let raw = unsafe {
+                // Safety: we have a `&mut` to the properties, nothing else can access them
+                &mut *queried.as_mut_ptr()
+            };
+            raw.LogFileMode = session.log_file_mode;
+            raw.EnableFlags = Etw::EVENT_TRACE_FLAG(session.settings.enable_flags);
+            raw.FlushTimer = session.settings.flush_timer;
+            raw.MaximumBuffers = session.settings.max_buffers;
+            raw.BuffersWritten = session.events_emitted;
+            raw.EventsLost = session.events_lost;
+        }
+        ERROR_SUCCESS
+    }
+
+    fn set_information(&self, control_handle: ControlHandle, information_class: TraceInformation, data: &[u8]) -> WIN32_ERROR {
+        let mut state = self.lock();
+        if control_handle.0 == 0 {
+            state.system_information.insert(information_class as i32, data.to_vec());
+            return ERROR_SUCCESS;
+        }
+
+        match state.sessions.get_mut(&control_handle.0) {
+            None => ERROR_INVALID_HANDLE,
+            Some(session) => {
+                session.information.insert(information_class as i32, data.to_vec());
+                ERROR_SUCCESS
+            }
+        }
+    }
+
+    fn query_information(&self, control_handle: ControlHandle, information_class: TraceInformation, buffer: &mut [u8], return_length: &mut u32) -> WIN32_ERROR {
+        let state = self.lock();
+        let data = if control_handle.0 == 0 {
+            state.system_information.get(&(information_class as i32))
+        } else {
+            match state.sessions.get(&control_handle.0) {
+                None => return ERROR_INVALID_HANDLE,
+                Some(session) => session.information.get(&(information_class as i32)),
+            }
+        };
+
+        match data {
+            None => ERROR_NOT_SUPPORTED,
+            Some(data) => {
+                *return_length = data.len() as u32;
+                if data.len() > buffer.len() {
+                    return ERROR_BAD_LENGTH;
+                }
+                buffer[..data.len()].copy_from_slice(data);
+                ERROR_SUCCESS
+            }
+        }
+    }
+
+    fn close_trace(&self, trace_handle: TraceHandle) -> WIN32_ERROR {
+        let mut state = self.lock();
+        let consumer = match state.consumers.get_mut(&trace_handle.0) {
+            Some(consumer) if !consumer.closed => consumer,
+            _ => return ERROR_INVALID_HANDLE,
+        };
+
+        consumer.closed = true;
+        if consumer.processing {
+            // Queued events will still be delivered, even though the trace is closed
+            let pending = !consumer.queue.is_empty();
+            self.wake_up.notify_all();
+            if pending {
+                ERROR_CTX_CLOSE_PENDING
+            } else {
+                ERROR_SUCCESS
+            }
+        } else {
+            state.consumers.remove(&trace_handle.0);
+            ERROR_SUCCESS
+        }
+    }
+}
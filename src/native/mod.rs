@@ -1,8 +1,12 @@
 //! Abstraction layer for Native functions and types
 //!
 //! This module interacts with the Windows native functions and should abstract all `unsafe` calls
+//!
+//! On other targets, the native types are still available (so that events can be decoded), but the functions that need Windows fail at runtime,
+//! and the few that have no purpose there are not built at all.
 pub(crate) mod etw_types;
 pub(crate) mod evntrace;
+#[cfg(windows)]
 pub(crate) mod pla;
 pub(crate) mod sddl;
 pub(crate) mod tdh;
@@ -10,6 +14,7 @@ pub(crate) mod tdh_types;
 pub(crate) mod version_helper;
 
 // These are used in our custom error types, and must be part of the public API
+#[cfg(windows)]
 pub use pla::PlaError;
 pub use sddl::SddlNativeError;
 pub use tdh::TdhNativeError;
@@ -21,8 +26,28 @@ pub use etw_types::extended_data::ExtendedDataItem;
 pub use etw_types::extended_data::EventHeaderExtendedDataItem;
 pub use evntrace::TraceHandle;
 pub use evntrace::ControlHandle;
+pub use tdh_types::{TdhInType, TdhOutType};
 pub use windows::Win32::System::Diagnostics::Etw::{
     EVENT_EXTENDED_ITEM_INSTANCE,
     EVENT_EXTENDED_ITEM_STACK_TRACE32,
     EVENT_EXTENDED_ITEM_STACK_TRACE64,
 };
+
+/// Build an `io::Error` from a Win32 error code
+///
+/// On Windows, this is `std::io::Error::from_raw_os_error`.<br/>
+/// Other OSes have their own error codes, so the Win32 code is only kept in the error message there (except `ERROR_NOT_SUPPORTED`, that has its own `ErrorKind`).
+pub(crate) fn win32_io_error(code: u32) -> std::io::Error {
+    #[cfg(windows)]
+    {
+        std::io::Error::from_raw_os_error(code as i32)
+    }
+    #[cfg(not(windows))]
+    {
+        if code == windows::Win32::Foundation::ERROR_NOT_SUPPORTED.0 {
+            std::io::Error::new(std::io::ErrorKind::Unsupported, "this is only supported on Windows")
+        } else {
+            std::io::Error::other(format!("Win32 error {}", code))
+        }
+    }
+}
@@ -56,6 +56,31 @@ impl From<i32> for PlaError {
     }
 }
 
+impl std::fmt::Display for HResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HResult::HrOk => write!(f, "S_OK"),
+            HResult::HrAbort => write!(f, "E_ABORT"),
+            HResult::HrAccessDenied => write!(f, "E_ACCESSDENIED"),
+            HResult::HrFail => write!(f, "E_FAIL"),
+            HResult::HrInvalidArg => write!(f, "E_INVALIDARG"),
+            HResult::HrOutOfMemory => write!(f, "E_OUTOFMEMORY"),
+            HResult::NotImplemented(hr) => write!(f, "HRESULT {:#010x}", hr),
+        }
+    }
+}
+
+impl std::fmt::Display for PlaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlaError::NotFound => write!(f, "no provider with this name is registered on this system"),
+            PlaError::ComHResultError(hr) => write!(f, "COM error while looking up the provider: {}", hr),
+        }
+    }
+}
+
+impl std::error::Error for PlaError {}
+
 pub(crate) type ProvidersComResult<T> = Result<T, PlaError>;
 
 const VT_UI4: u16 = 0x13;
@@ -1,8 +1,12 @@
 use core::ffi::c_void;
 use std::str::Utf8Error;
+#[cfg(windows)]
 use windows::core::PSTR;
+#[cfg(windows)]
 use windows::Win32::Foundation::{HLOCAL, PSID};
+#[cfg(windows)]
 use windows::Win32::Security::Authorization::ConvertSidToStringSidA;
+#[cfg(windows)]
 use windows::Win32::System::Memory::LocalFree;
 
 /// SDDL native error
@@ -20,8 +24,27 @@ impl From<Utf8Error> for SddlNativeError {
     }
 }
 
+impl std::fmt::Display for SddlNativeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SddlNativeError::SidParseError(_) => write!(f, "the SID string is not valid UTF-8"),
+            SddlNativeError::IoError(err) => write!(f, "unable to convert the SID to a string: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SddlNativeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SddlNativeError::SidParseError(err) => Some(err),
+            SddlNativeError::IoError(err) => Some(err),
+        }
+    }
+}
+
 pub(crate) type SddlResult<T> = Result<T, SddlNativeError>;
 
+#[cfg(windows)]
 pub fn convert_sid_to_string(sid: *const c_void) -> SddlResult<String> {
     let mut tmp = PSTR::null();
     unsafe {
@@ -40,6 +63,37 @@ pub fn convert_sid_to_string(sid: *const c_void) -> SddlResult<String> {
     }
 }
 
+/// Same as the Windows version, but formatting the SID ourselves, following <https://learn.microsoft.com/en-us/windows/win32/secauthz/sid-components>
+#[cfg(not(windows))]
+pub fn convert_sid_to_string(sid: *const c_void) -> SddlResult<String> {
+    let sid = sid as *const u8;
+    let (revision, sub_authority_count) = unsafe {
+        // Safety: like for the Windows API, callers must give a pointer to a valid SID, which is at least 8 bytes long
+        (*sid, *sid.add(1) as usize)
+    };
+    let authority = unsafe {
+        // Safety: see above
+        std::slice::from_raw_parts(sid.add(2), 6)
+    }
+    .iter()
+    .fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+
+    let mut sid_string = if authority < (1 << 32) {
+        format!("S-{}-{}", revision, authority)
+    } else {
+        format!("S-{}-0x{:012X}", revision, authority)
+    };
+    for i in 0..sub_authority_count {
+        let sub_authority = unsafe {
+            // Safety: a valid SID has `sub_authority_count` sub-authorities (which may not be aligned), right after its 8-byte header
+            std::ptr::read_unaligned(sid.add(8 + 4 * i) as *const u32)
+        };
+        sid_string.push_str(&format!("-{}", u32::from_le(sub_authority)));
+    }
+
+    Ok(sid_string)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -6,13 +6,18 @@
 //! This module shouldn't be accessed directly. Modules from the the crate level provide a safe API to interact
 //! with the crate
 use std::alloc::Layout;
+use std::convert::TryInto;
 
 use super::etw_types::*;
+#[cfg(windows)]
 use crate::traits::*;
 use crate::native::tdh_types::Property;
 use crate::native::etw_types::event_record::EventRecord;
 use windows::Win32::System::Diagnostics::Etw::{self, TRACE_EVENT_INFO, EVENT_PROPERTY_INFO};
+#[cfg(windows)]
 use windows::Win32::Foundation::ERROR_INSUFFICIENT_BUFFER;
+#[cfg(not(windows))]
+use windows::Win32::Foundation::ERROR_NOT_SUPPORTED;
 use windows::core::GUID;
 use widestring::U16CStr;
 
@@ -21,10 +26,31 @@ use widestring::U16CStr;
 pub enum TdhNativeError {
     /// Represents an allocation error
     AllocationError,
+    /// A serialized `TRACE_EVENT_INFO` is malformed (e.g. it contains offsets that are out of bounds)
+    InvalidEventInformation,
     /// Represents an standard IO Error
     IoError(std::io::Error),
 }
 
+impl std::fmt::Display for TdhNativeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TdhNativeError::AllocationError => write!(f, "unable to allocate memory for the event information"),
+            TdhNativeError::InvalidEventInformation => write!(f, "malformed event information"),
+            TdhNativeError::IoError(err) => write!(f, "TDH error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TdhNativeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TdhNativeError::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 pub type TdhNativeResult<T> = Result<T, TdhNativeError>;
 
 
@@ -67,6 +93,15 @@ macro_rules! extract_utf16_string {
 
 impl TraceEventInfo {
     /// Create a instance of `Self` suitable for the given event
+    ///
+    /// This queries the system for the schema of this event, and always fails on targets other than Windows.
+    #[cfg(not(windows))]
+    pub fn build_from_event(_event: &EventRecord) -> TdhNativeResult<Self> {
+        Err(TdhNativeError::IoError(crate::native::win32_io_error(ERROR_NOT_SUPPORTED.0)))
+    }
+
+    /// Create a instance of `Self` suitable for the given event
+    #[cfg(windows)]
     pub fn build_from_event(event: &EventRecord) -> TdhNativeResult<Self> {
         let mut buffer_size = 0;
         let status = unsafe {
@@ -117,6 +152,37 @@ impl TraceEventInfo {
 
     }
 
+    /// Create an instance of `Self` from a copy of a `TRACE_EVENT_INFO` buffer (e.g. one previously obtained with [`Self::as_bytes`])
+    ///
+    /// The buffer is checked, so that every offset it contains lies within its bounds.
+    pub fn from_bytes(bytes: &[u8]) -> TdhNativeResult<Self> {
+        validate_trace_event_info(bytes)?;
+
+        let layout = Layout::from_size_align(bytes.len(), std::mem::align_of::<Etw::TRACE_EVENT_INFO>())
+            .map_err(|_| TdhNativeError::AllocationError)?;
+        let data = unsafe {
+            // Safety: size is not zero (it has been checked by validate_trace_event_info)
+            std::alloc::alloc(layout)
+        };
+        if data.is_null() {
+            return Err(TdhNativeError::AllocationError);
+        }
+        unsafe {
+            // Safety: `data` has just been allocated with the same size as `bytes`
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), data, bytes.len());
+        }
+
+        Ok(Self { data, mut_data_for_dealloc: data, layout })
+    }
+
+    /// The raw `TRACE_EVENT_INFO` buffer (including the variable-length data that follows the struct)
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            // Safety: `data` has been allocated with this layout, and is not mutated until it is deallocated
+            std::slice::from_raw_parts(self.data, self.layout.size())
+        }
+    }
+
     fn as_raw(&self) -> &TRACE_EVENT_INFO {
         let p = self.data.cast::<TRACE_EVENT_INFO>();
         unsafe {
@@ -137,6 +203,10 @@ impl TraceEventInfo {
         self.as_raw().EventDescriptor.Version
     }
 
+    pub fn event_descriptor(&self) -> Etw::EVENT_DESCRIPTOR {
+        self.as_raw().EventDescriptor
+    }
+
     pub fn decoding_source(&self) -> DecodingSource {
         let ds = self.as_raw().DecodingSource;
         DecodingSource::from(ds)
@@ -154,6 +224,11 @@ impl TraceEventInfo {
         extract_utf16_string!(self, OpcodeNameOffset);
     }
 
+    /// The number of properties, including properties that are members of structures
+    pub fn property_count(&self) -> usize {
+        self.as_raw().PropertyCount as usize
+    }
+
     pub fn properties(&self) -> PropertyIterator {
         PropertyIterator::new(self)
     }
@@ -170,6 +245,61 @@ impl Drop for TraceEventInfo {
     }
 }
 
+/// Check that a buffer is a well-formed `TRACE_EVENT_INFO`, i.e. that it can be safely used by [`TraceEventInfo`]
+fn validate_trace_event_info(bytes: &[u8]) -> TdhNativeResult<()> {
+    let properties_offset = offset_of!(TRACE_EVENT_INFO, EventPropertyInfoArray);
+    if bytes.len() < std::mem::size_of::<TRACE_EVENT_INFO>() {
+        return Err(TdhNativeError::InvalidEventInformation);
+    }
+
+    let read_u32 = |offset: usize| -> u32 {
+        u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    };
+
+    // Names are optional (their offset is 0 in this case), but they must be null-terminated within the buffer
+    let is_valid_string_offset = |offset: u32| -> bool {
+        let offset = offset as usize;
+        if offset == 0 {
+            return true;
+        }
+        match bytes.get(offset..) {
+            Some(remaining) if offset.is_multiple_of(2) => remaining
+                .chunks_exact(2)
+                .any(|c| c == [0, 0]),
+            _ => false,
+        }
+    };
+
+    for name_offset in [
+        offset_of!(TRACE_EVENT_INFO, ProviderNameOffset),
+        offset_of!(TRACE_EVENT_INFO, TaskNameOffset),
+        offset_of!(TRACE_EVENT_INFO, OpcodeNameOffset),
+    ] {
+        if !is_valid_string_offset(read_u32(name_offset)) {
+            return Err(TdhNativeError::InvalidEventInformation);
+        }
+    }
+
+    let property_count = read_u32(offset_of!(TRACE_EVENT_INFO, PropertyCount)) as usize;
+    let property_size = std::mem::size_of::<EVENT_PROPERTY_INFO>();
+    let properties_end = property_count
+        .checked_mul(property_size)
+        .and_then(|size| size.checked_add(properties_offset));
+    match properties_end {
+        Some(end) if end <= bytes.len() => (),
+        _ => return Err(TdhNativeError::InvalidEventInformation),
+    }
+
+    for i in 0..property_count {
+        let name_offset = read_u32(properties_offset + i * property_size + offset_of!(EVENT_PROPERTY_INFO, NameOffset));
+        if name_offset == 0 || !is_valid_string_offset(name_offset) {
+            return Err(TdhNativeError::InvalidEventInformation);
+        }
+    }
+
+    Ok(())
+}
+
 pub struct PropertyIterator<'info> {
     next_index: u32,
     count: u32,
@@ -236,6 +366,13 @@ impl<'info> Iterator for PropertyIterator<'info> {
     }
 }
 
+/// The size of a property, as computed by TDH. This always fails on targets other than Windows.
+#[cfg(not(windows))]
+pub fn property_size(_event: &EventRecord, _name: &str) -> TdhNativeResult<u32> {
+    Err(TdhNativeError::IoError(crate::native::win32_io_error(ERROR_NOT_SUPPORTED.0)))
+}
+
+#[cfg(windows)]
 pub fn property_size(event: &EventRecord, name: &str) -> TdhNativeResult<u32> {
     let mut property_size = 0;
 
@@ -262,3 +399,108 @@ pub fn property_size(event: &EventRecord, name: &str) -> TdhNativeResult<u32> {
 
     Ok(property_size)
 }
+
+/// A predicate of a payload filter, i.e. the arguments of a `PAYLOAD_FILTER_PREDICATE`
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct PayloadPredicateArgs<'a> {
+    pub field_name: &'a str,
+    pub compare_op: u16,
+    pub value: String,
+}
+
+/// A payload filter, i.e. the arguments of `TdhCreatePayloadFilter`
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct PayloadFilterArgs<'a> {
+    pub provider_guid: &'a GUID,
+    pub event_descriptor: &'a Etw::EVENT_DESCRIPTOR,
+    pub match_any: bool,
+    pub predicates: Vec<PayloadPredicateArgs<'a>>,
+}
+
+/// Compile payload filters into a single `EVENT_FILTER_DESCRIPTOR` of type `EVENT_FILTER_TYPE_PAYLOAD`. This always fails on targets other than Windows.
+#[cfg(not(windows))]
+pub fn create_payload_filter_descriptor(_filters: &[PayloadFilterArgs]) -> TdhNativeResult<Etw::EVENT_FILTER_DESCRIPTOR> {
+    Err(TdhNativeError::IoError(crate::native::win32_io_error(ERROR_NOT_SUPPORTED.0)))
+}
+
+/// Compile payload filters into a single `EVENT_FILTER_DESCRIPTOR` of type `EVENT_FILTER_TYPE_PAYLOAD`
+///
+/// An event must match every filter that applies to it.
+/// The returned descriptor must be released with [`cleanup_payload_filter_descriptor`]
+#[cfg(windows)]
+pub fn create_payload_filter_descriptor(filters: &[PayloadFilterArgs]) -> TdhNativeResult<Etw::EVENT_FILTER_DESCRIPTOR> {
+    let mut payload_filters: Vec<*mut std::ffi::c_void> = Vec::with_capacity(filters.len());
+    let result = create_payload_filters(filters, &mut payload_filters).and_then(|_| {
+        let mut descriptor = Etw::EVENT_FILTER_DESCRIPTOR::default();
+        let match_all_flags = vec![windows::Win32::Foundation::BOOLEAN(1); payload_filters.len()];
+        let filter_ptrs: Vec<*const std::ffi::c_void> = payload_filters.iter().map(|filter| *filter as *const _).collect();
+        let status = unsafe {
+            // Safety: every payload filter has just been created by TDH, and both arrays have one item per filter
+            Etw::TdhAggregatePayloadFilters(filter_ptrs.len() as u32, filter_ptrs.as_ptr(), Some(match_all_flags.as_ptr()), &mut descriptor)
+        };
+        if status != 0 {
+            return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(status as i32)));
+        }
+        Ok(descriptor)
+    });
+
+    for payload_filter in payload_filters.iter_mut() {
+        unsafe {
+            // Safety: the aggregated descriptor does not reference the payload filters, that are not needed anymore
+            Etw::TdhDeletePayloadFilter(payload_filter);
+        }
+    }
+
+    result
+}
+
+/// Call `TdhCreatePayloadFilter` for every filter, and push the created filters to `payload_filters` (even if a later one fails)
+#[cfg(windows)]
+fn create_payload_filters(filters: &[PayloadFilterArgs], payload_filters: &mut Vec<*mut std::ffi::c_void>) -> TdhNativeResult<()> {
+    for filter in filters {
+        // These buffers must outlive the call to TdhCreatePayloadFilter, that copies them
+        let mut wide_strings: Vec<(Vec<u16>, Vec<u16>)> = filter.predicates
+            .iter()
+            .map(|predicate| (predicate.field_name.into_utf16(), predicate.value.as_str().into_utf16()))
+            .collect();
+        let native_predicates: Vec<Etw::PAYLOAD_FILTER_PREDICATE> = filter.predicates
+            .iter()
+            .zip(wide_strings.iter_mut())
+            .map(|(predicate, (field_name, value))| Etw::PAYLOAD_FILTER_PREDICATE {
+                FieldName: windows::core::PWSTR(field_name.as_mut_ptr()),
+                CompareOp: predicate.compare_op,
+                Value: windows::core::PWSTR(value.as_mut_ptr()),
+            })
+            .collect();
+
+        let mut payload_filter = std::ptr::null_mut();
+        let status = unsafe {
+            // Safety: every pointer is valid for the duration of the call
+            Etw::TdhCreatePayloadFilter(
+                filter.provider_guid,
+                filter.event_descriptor,
+                windows::Win32::Foundation::BOOLEAN(filter.match_any as u8),
+                &native_predicates,
+                &mut payload_filter,
+            )
+        };
+        if status != 0 {
+            return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(status as i32)));
+        }
+        payload_filters.push(payload_filter);
+    }
+    Ok(())
+}
+
+/// Release an `EVENT_FILTER_DESCRIPTOR` returned by [`create_payload_filter_descriptor`]
+#[cfg(not(windows))]
+pub fn cleanup_payload_filter_descriptor(_descriptor: &mut Etw::EVENT_FILTER_DESCRIPTOR) {}
+
+/// Release an `EVENT_FILTER_DESCRIPTOR` returned by [`create_payload_filter_descriptor`]
+#[cfg(windows)]
+pub fn cleanup_payload_filter_descriptor(descriptor: &mut Etw::EVENT_FILTER_DESCRIPTOR) {
+    unsafe {
+        // Safety: this descriptor has been built by TdhAggregatePayloadFilters, and is not used anymore
+        Etw::TdhCleanupPayloadEventFilterDescriptor(descriptor);
+    }
+}
@@ -20,6 +20,16 @@ pub enum PropertyError{
     UnimplementedType
 }
 
+impl std::fmt::Display for PropertyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PropertyError::UnimplementedType => write!(f, "properties of complex types are not supported"),
+        }
+    }
+}
+
+impl std::error::Error for PropertyError {}
+
 
 /// Attributes of a property
 #[derive(Debug, Clone, Default)]
@@ -5,24 +5,53 @@
 //!
 //! At the moment the only option available is to check if the actual System Version is greater than
 //! Win8, is the only check we need for the crate to work as expected
+//!
+//! On other targets, we behave as a recent Windows would.
+#[cfg(windows)]
 use windows::Win32::Foundation::GetLastError;
+#[cfg(windows)]
 use windows::Win32::System::SystemInformation::{OSVERSIONINFOEXA, VER_MAJORVERSION, VER_MINORVERSION, VER_SERVICEPACKMAJOR};
+#[cfg(windows)]
 use windows::Win32::System::SystemInformation::{VerifyVersionInfoA, VerSetConditionMask};
+#[cfg(windows)]
 use windows::Win32::Foundation::ERROR_OLD_WIN_VERSION;
 
 /// Version Helper native error
+#[cfg(windows)]
 #[derive(Debug)]
 pub enum VersionHelperError {
     /// Represents an standard IO Error
     IoError(std::io::Error),
 }
 
+#[cfg(windows)]
+impl std::fmt::Display for VersionHelperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionHelperError::IoError(err) => write!(f, "unable to check the OS version: {}", err),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl std::error::Error for VersionHelperError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VersionHelperError::IoError(err) => Some(err),
+        }
+    }
+}
+
+#[cfg(windows)]
 pub(crate) type VersionHelperResult<T> = Result<T, VersionHelperError>;
 
+#[cfg(windows)]
 type OsVersionInfo = OSVERSIONINFOEXA;
 // Safe cast, we now the value fits in a u8 (VER_GREATER_EQUAL == 3)
+#[cfg(windows)]
 const VER_GREATER_OR_EQUAL: u8 = windows::Win32::System::SystemServices::VER_GREATER_EQUAL as u8;
 
+#[cfg(windows)]
 fn verify_system_version(major: u8, minor: u8, sp_major: u16) -> VersionHelperResult<bool> {
     let mut os_version = OsVersionInfo{
         dwOSVersionInfoSize: std::mem::size_of::<OsVersionInfo>() as u32,
@@ -74,6 +103,7 @@ fn verify_system_version(major: u8, minor: u8, sp_major: u16) -> VersionHelperRe
 ///
 /// # Remarks
 ///
+#[cfg(windows)]
 pub fn is_win8_or_greater() -> bool {
     // Lazy way, let's hardcode this...
     match verify_system_version(6, 2, 0) {
@@ -85,7 +115,12 @@ pub fn is_win8_or_greater() -> bool {
     }
 }
 
-#[cfg(test)]
+#[cfg(not(windows))]
+pub fn is_win8_or_greater() -> bool {
+    true
+}
+
+#[cfg(all(test, windows))]
 mod test {
     use super::*;
 
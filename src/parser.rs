@@ -66,6 +66,35 @@ impl From<std::array::TryFromSliceError> for ParserError {
     }
 }
 
+impl std::fmt::Display for ParserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParserError::NotFound => write!(f, "no property has this name"),
+            ParserError::InvalidType => write!(f, "the property cannot be parsed into the requested type"),
+            ParserError::UnsupportedProperties => write!(f, "the event contains properties that are not supported"),
+            ParserError::ParseError => write!(f, "unable to parse the property"),
+            ParserError::LengthMismatch => write!(f, "the size of the property does not match the requested type"),
+            ParserError::PropertyError(msg) => write!(f, "invalid property: {}", msg),
+            ParserError::Utf8Error(_) => write!(f, "the property is not valid UTF-8"),
+            ParserError::SliceError(_) => write!(f, "the property has an unexpected size"),
+            ParserError::SddlNativeError(err) => err.fmt(f),
+            ParserError::TdhNativeError(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ParserError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParserError::Utf8Error(err) => Some(err),
+            ParserError::SliceError(err) => Some(err),
+            ParserError::SddlNativeError(err) => std::error::Error::source(err),
+            ParserError::TdhNativeError(err) => std::error::Error::source(err),
+            _ => None,
+        }
+    }
+}
+
 type ParserResult<T> = Result<T, ParserError>;
 
 
@@ -2,32 +2,63 @@
 //!
 //! Provides an abstraction over an [ETW Provider](https://docs.microsoft.com/en-us/windows/win32/etw/about-event-tracing#providers)
 use crate::native::etw_types::event_record::EventRecord;
+#[cfg(windows)]
 use crate::native::pla;
 use crate::schema_locator::SchemaLocator;
+use crate::trace::event_context::EventContext;
+use crate::trace::panic_policy::PanicHandler;
 
-use std::sync::{Arc, RwLock};
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex, RwLock};
 use windows::core::GUID;
 
 pub(crate) mod event_filter;
-pub use event_filter::EventFilter;
+pub use event_filter::{EventFilter, EventFilterError};
+pub mod payload_filter;
+pub use payload_filter::PayloadFilter;
+mod event_router;
+use event_router::{EventRouter, ProviderCallback, Route};
 
 pub mod kernel_providers;
+use kernel_providers::KernelGroupMask;
+pub mod system_providers;
+use system_providers::SystemKeywords;
 mod trace_flags;
 pub use trace_flags::TraceFlags;
 
 /// Provider module errors
+#[cfg(windows)]
 #[derive(Debug)]
 pub enum ProviderError {
     /// Wrapper over an internal [PlaError](crate::native::PlaError)
     ComProvider(crate::native::PlaError),
 }
 
+#[cfg(windows)]
 impl From<crate::native::PlaError> for ProviderError {
     fn from(err: crate::native::PlaError) -> Self {
         ProviderError::ComProvider(err)
     }
 }
 
+#[cfg(windows)]
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::ComProvider(err) => write!(f, "unable to look up the provider: {}", err),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl std::error::Error for ProviderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProviderError::ComProvider(err) => Some(err),
+        }
+    }
+}
+
 /// Describes an ETW Provider to use, along with its options
 pub struct Provider {
     /// Provider GUID
@@ -44,10 +75,16 @@ pub struct Provider {
     trace_flags: TraceFlags,
     /// Provider kernel flags, only apply to KernelProvider
     kernel_flags: u32,
+    /// Provider kernel extended flags, only apply to KernelProvider
+    kernel_group_mask: KernelGroupMask,
     /// Provider filters
     filters: Vec<EventFilter>,
+    /// Whether to request a rundown (see [`ProviderBuilder::capture_state_on_enable`])
+    capture_state_on_enable: bool,
+    /// For system providers, the GUIDs of the kernel event classes they emit (see [`Provider::system`])
+    system_event_guids: Option<&'static [GUID]>,
     /// Callbacks that will receive events from this Provider
-    callbacks: Arc<RwLock<Vec<crate::EtwCallback>>>,
+    callbacks: Arc<RwLock<EventRouter>>,
 }
 
 /// A Builder for a `Provider`
@@ -60,8 +97,11 @@ pub struct ProviderBuilder {
     level: u8,
     trace_flags: TraceFlags,
     kernel_flags: u32,
+    kernel_group_mask: KernelGroupMask,
     filters: Vec<EventFilter>,
-    callbacks: Arc<RwLock<Vec<crate::EtwCallback>>>,
+    capture_state_on_enable: bool,
+    system_event_guids: Option<&'static [GUID]>,
+    callbacks: Arc<RwLock<EventRouter>>,
 }
 
 impl std::fmt::Debug for ProviderBuilder {
@@ -73,7 +113,10 @@ impl std::fmt::Debug for ProviderBuilder {
             .field("level", &self.level)
             .field("trace_flags", &self.trace_flags)
             .field("kernel_flags", &self.kernel_flags)
+            .field("kernel_group_mask", &self.kernel_group_mask)
             .field("filters", &self.filters)
+            .field("capture_state_on_enable", &self.capture_state_on_enable)
+            .field("system_event_guids", &self.system_event_guids)
             .field("n_callbacks", &self.callbacks.read().unwrap().len())
             .finish()
     }
@@ -93,8 +136,11 @@ impl Provider {
             level: 5,
             trace_flags: TraceFlags::empty(),
             kernel_flags: 0,
+            kernel_group_mask: KernelGroupMask::empty(),
             filters: Vec::new(),
-            callbacks: Arc::new(RwLock::new(Vec::new())),
+            capture_state_on_enable: false,
+            system_event_guids: None,
+            callbacks: Arc::new(RwLock::new(EventRouter::default())),
         }
     }
 
@@ -107,19 +153,47 @@ impl Provider {
         builder
     }
 
+    /// Create a Kernel Provider that is enabled by a [`KernelGroupMask`]
+    ///
+    /// You can pass either a KernelGroupMaskProvider you have created yourself, or one of the standard providers from [`crate::provider::kernel_providers`] (e.g. [`POOL_PROVIDER`](kernel_providers::POOL_PROVIDER)).
+    pub fn kernel_by_group_mask(kernel_provider: &kernel_providers::KernelGroupMaskProvider) -> ProviderBuilder {
+        let mut builder = Self::by_guid(kernel_provider.guid);
+        builder.kernel_group_mask = kernel_provider.group_mask;
+        builder
+    }
+
+    /// Create a System Provider (Windows 10 20H2+)
+    ///
+    /// The provider and its "Any" keywords are given by the type of keywords, from [`crate::provider::system_providers`].<br/>
+    /// Unlike kernel providers, system providers are meant to be enabled in a [`UserTrace`](crate::trace::UserTrace).
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::Provider;
+    /// # use ferrisetw::provider::system_providers::SystemSchedulerKeywords;
+    /// let scheduler_provider = Provider::system(SystemSchedulerKeywords::CONTEXT_SWITCH).build();
+    /// ```
+    pub fn system<K: SystemKeywords>(keywords: K) -> ProviderBuilder {
+        let mut builder = Self::by_guid(K::PROVIDER_GUID).any(keywords.keywords());
+        builder.system_event_guids = Some(K::EVENT_GUIDS);
+        builder
+    }
+
     /// Create a Provider defined by its name.
     ///
     /// This function will look for the Provider GUID by means of the [ITraceDataProviderCollection](https://docs.microsoft.com/en-us/windows/win32/api/pla/nn-pla-itracedataprovidercollection)
     /// interface.
     ///
     /// # Remark
-    /// This function is considerably slow, prefer using the `by_guid` function when possible
+    /// This function is considerably slow, prefer using the `by_guid` function when possible.<br/>
+    /// This is only available on Windows.
     ///
     /// # Example
     /// ```
     /// # use ferrisetw::provider::Provider;
     /// let my_provider = Provider::by_name("Microsoft-Windows-WinINet").unwrap().build();
     /// ```
+    #[cfg(windows)]
     pub fn by_name(name: &str) -> Result<ProviderBuilder, crate::native::PlaError> {
         let guid = unsafe { pla::get_provider_guid(name) }?;
         Ok(Self::by_guid(guid))
@@ -146,14 +220,49 @@ impl Provider {
     pub fn kernel_flags(&self) -> u32 {
         self.kernel_flags
     }
+    pub fn kernel_group_mask(&self) -> KernelGroupMask {
+        self.kernel_group_mask
+    }
     pub fn filters(&self) -> &[EventFilter] {
         &self.filters
     }
+    pub fn capture_state_on_enable(&self) -> bool {
+        self.capture_state_on_enable
+    }
+    /// Whether this is a system provider (see [`Provider::system`])
+    pub fn is_system_provider(&self) -> bool {
+        self.system_event_guids.is_some()
+    }
 
-    pub(crate) fn on_event(&self, record: &EventRecord, locator: &SchemaLocator) {
-        if let Ok(mut callbacks) = self.callbacks.write() {
-            callbacks.iter_mut().for_each(|cb| cb(record, locator))
-        };
+    /// Whether events from this provider ID should be dispatched to this provider
+    pub(crate) fn receives(&self, provider_id: GUID) -> bool {
+        self.guid == provider_id
+            || self.system_event_guids.map(|guids| guids.contains(&provider_id)).unwrap_or(false)
+    }
+
+    /// A copy of this provider with other settings, that shares the same callbacks
+    pub(crate) fn reconfigured(&self, level: u8, any: u64, all: u64, filters: Vec<EventFilter>) -> Provider {
+        Provider {
+            guid: self.guid,
+            any,
+            all,
+            level,
+            trace_flags: self.trace_flags,
+            kernel_flags: self.kernel_flags,
+            kernel_group_mask: self.kernel_group_mask,
+            filters,
+            capture_state_on_enable: self.capture_state_on_enable,
+            system_event_guids: self.system_event_guids,
+            callbacks: Arc::clone(&self.callbacks),
+        }
+    }
+
+    /// Invoke the callbacks of this provider, until one of them asks to stop processing the trace
+    pub(crate) fn on_event(&self, record: &EventRecord, locator: &SchemaLocator, context: &EventContext, panics: &PanicHandler) -> ControlFlow<()> {
+        match self.callbacks.read() {
+            Ok(callbacks) => callbacks.on_event(record, locator, context, panics),
+            Err(_) => ControlFlow::Continue(()),
+        }
     }
 }
 
@@ -166,7 +275,10 @@ impl std::fmt::Debug for Provider {
          .field("level", &self.level)
          .field("trace_flags", &self.trace_flags)
          .field("kernel_flags", &self.kernel_flags)
+         .field("kernel_group_mask", &self.kernel_group_mask)
          .field("filters", &self.filters)
+         .field("capture_state_on_enable", &self.capture_state_on_enable)
+         .field("system_event_guids", &self.system_event_guids)
          .field("callbacks", &self.callbacks.read().unwrap().len())
          .finish()
     }
@@ -230,6 +342,22 @@ impl ProviderBuilder {
         self
     }
 
+    /// Request the provider to log its current state (running processes, loaded modules, etc.) as soon as it is enabled
+    ///
+    /// Many providers (e.g. Kernel-Process, Kernel-File or the CLR) only emit such "rundown" events when they receive `EVENT_CONTROL_CODE_CAPTURE_STATE`.
+    /// How callbacks can tell them apart from live events depends on the provider (see [`EventRecord::has_rundown_opcode`]).<br/>
+    /// This is only effective for [`UserTrace`](crate::UserTrace)s. See also [`UserTrace::capture_state`](crate::UserTrace::capture_state).
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::Provider;
+    /// let my_provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716").capture_state_on_enable(true).build();
+    /// ```
+    pub fn capture_state_on_enable(mut self, capture_state_on_enable: bool) -> Self {
+        self.capture_state_on_enable = capture_state_on_enable;
+        self
+    }
+
     /// Add a callback function that will be called when the Provider generates an Event
     ///
     /// # Notes
@@ -245,6 +373,8 @@ impl ProviderBuilder {
     /// let provider = Provider::by_guid("1EDEEE53-0AFE-4609-B846-D8C0B2075B1F").add_callback(|record: &EventRecord, schema_locator: &SchemaLocator| {
     ///     // Handle Event
     /// }).build();
+    /// # // ETW sessions can only be started on Windows
+    /// # #[cfg(windows)]
     /// UserTrace::new().enable(provider).start().unwrap();
     /// ```
     ///
@@ -253,8 +383,136 @@ impl ProviderBuilder {
     where
         T: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
     {
+        let mut callback = callback;
+        self.add_callback_with_context(move |record: &EventRecord, schema_locator: &SchemaLocator, _context: &EventContext| {
+            callback(record, schema_locator);
+            ControlFlow::Continue(())
+        })
+    }
+
+    /// Add a callback that is also given an [`EventContext`], and that can stop processing the trace
+    ///
+    /// See [`event_context`](crate::trace::event_context) for more info.
+    ///
+    /// # Example
+    /// ```
+    /// # use std::ops::ControlFlow;
+    /// # use ferrisetw::provider::Provider;
+    /// let provider = Provider::by_guid("1EDEEE53-0AFE-4609-B846-D8C0B2075B1F").add_callback_with_context(|record, _schema_locator, context| {
+    ///     if context.events_handled() >= 1000 {
+    ///         return ControlFlow::Break(());
+    ///     }
+    ///     ControlFlow::Continue(())
+    /// }).build();
+    /// ```
+    pub fn add_callback_with_context<T>(self, callback: T) -> Self
+    where
+        T: FnMut(&EventRecord, &SchemaLocator, &EventContext) -> ControlFlow<()> + Send + Sync + 'static,
+    {
+        self.add_routed_callback(Route::All, ProviderCallback::Exclusive(Mutex::new(Box::new(callback))))
+    }
+
+    /// Add a callback that can be invoked by several threads at once
+    ///
+    /// Other callbacks are never invoked concurrently with themselves, which serializes the events they receive.
+    /// This makes a difference when events are dispatched to several threads (see [`TraceBuilder::dispatcher`](crate::trace::TraceBuilder::dispatcher)).<br/>
+    /// Like [`Self::add_callback_with_context`], this callback can stop processing the trace.
+    pub fn add_shared_callback<T>(self, callback: T) -> Self
+    where
+        T: Fn(&EventRecord, &SchemaLocator, &EventContext) -> ControlFlow<()> + Send + Sync + 'static,
+    {
+        self.add_routed_callback(Route::All, ProviderCallback::Shared(Box::new(callback)))
+    }
+
+    /// Add a callback that only receives the events with this ID
+    ///
+    /// Unlike an [`EventFilter::ByEventIds`], this does not prevent ETW from delivering other events to the trace.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::Provider;
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::schema_locator::SchemaLocator;
+    /// let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716") // Microsoft-Windows-Kernel-Process
+    ///     .on_event_id(1, |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///         // Handle ProcessStart events
+    ///     })
+    ///     .on_event_id(2, |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///         // Handle ProcessStop events
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn on_event_id<T>(self, event_id: u16, callback: T) -> Self
+    where
+        T: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        self.add_routed_callback(Route::EventId(event_id), exclusive_callback(callback))
+    }
+
+    /// Add a callback that only receives the events with this opcode
+    ///
+    /// This is especially useful for kernel providers, that tell their events apart by their opcodes.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::{Provider, kernel_providers};
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::schema_locator::SchemaLocator;
+    /// let provider = Provider::kernel(&kernel_providers::PROCESS_PROVIDER)
+    ///     .on_opcode(1, |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///         // Handle Process/Start events
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn on_opcode<T>(self, opcode: u8, callback: T) -> Self
+    where
+        T: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        self.add_routed_callback(Route::Opcode(opcode), exclusive_callback(callback))
+    }
+
+    /// Add a callback that only receives the events with this task
+    pub fn on_task<T>(self, task: u16, callback: T) -> Self
+    where
+        T: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        self.add_routed_callback(Route::Task(task), exclusive_callback(callback))
+    }
+
+    /// Add a callback that only receives the events whose level is lower than or equal to `level` (i.e. at least as severe)
+    pub fn on_level_at_most<T>(self, level: u8, callback: T) -> Self
+    where
+        T: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        self.add_routed_callback(Route::LevelAtMost(level), exclusive_callback(callback))
+    }
+
+    /// Add a callback that only receives the events for which `predicate` returns `true`
+    ///
+    /// Prefer [`Self::on_event_id`], [`Self::on_opcode`] or [`Self::on_task`] when possible, as they do not need to evaluate a predicate for every event.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::Provider;
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::schema_locator::SchemaLocator;
+    /// let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+    ///     .add_callback_if(|record| record.process_id() == 1234, |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///         // Handle events from PID 1234
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn add_callback_if<P, T>(self, predicate: P, callback: T) -> Self
+    where
+        P: Fn(&EventRecord) -> bool + Send + Sync + 'static,
+        T: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        self.add_routed_callback(Route::Predicate(Box::new(predicate)), exclusive_callback(callback))
+    }
+
+    fn add_routed_callback(self, route: Route, callback: ProviderCallback) -> Self {
         if let Ok(mut callbacks) = self.callbacks.write() {
-            callbacks.push(Box::new(callback));
+            callbacks.push(route, callback);
         }
         self
     }
@@ -264,6 +522,8 @@ impl ProviderBuilder {
     /// Adding multiple filters will bind them with an `AND` relationship.<br/>
     /// If you want an `OR` relationship, include them in the same `EventFilter`.
     ///
+    /// Empty filters are ignored. Other invalid filters (e.g. with too many items) make enabling the provider fail with [`EvntraceNativeError::InvalidFilter`](crate::native::EvntraceNativeError::InvalidFilter).
+    ///
     /// # Example
     /// ```
     /// # use ferrisetw::provider::{EventFilter, Provider};
@@ -301,8 +561,21 @@ impl ProviderBuilder {
             level: self.level,
             trace_flags: self.trace_flags,
             kernel_flags: self.kernel_flags,
+            kernel_group_mask: self.kernel_group_mask,
             filters: self.filters,
+            capture_state_on_enable: self.capture_state_on_enable,
+            system_event_guids: self.system_event_guids,
             callbacks: self.callbacks,
         }
     }
 }
+
+fn exclusive_callback<T>(mut callback: T) -> ProviderCallback
+where
+    T: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+{
+    ProviderCallback::Exclusive(Mutex::new(Box::new(move |record: &EventRecord, schema_locator: &SchemaLocator, _context: &EventContext| {
+        callback(record, schema_locator);
+        ControlFlow::Continue(())
+    })))
+}
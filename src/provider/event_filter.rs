@@ -1,14 +1,17 @@
 use std::alloc::Layout;
-use std::error::Error;
 
 use windows::Win32::Foundation::BOOLEAN;
 use windows::Win32::System::Diagnostics::Etw::{EVENT_FILTER_DESCRIPTOR, EVENT_FILTER_TYPE_PID, EVENT_FILTER_TYPE_EVENT_ID, EVENT_FILTER_EVENT_ID};
 use windows::Win32::System::Diagnostics::Etw::{MAX_EVENT_FILTER_EVENT_ID_COUNT, MAX_EVENT_FILTER_PID_COUNT};
+use windows::Win32::System::Diagnostics::Etw::{EVENT_FILTER_TYPE_EXECUTABLE_NAME, EVENT_FILTER_TYPE_PACKAGE_ID, EVENT_FILTER_TYPE_PACKAGE_APP_ID};
+
+use crate::native::tdh;
+use crate::provider::payload_filter::PayloadFilter;
 
 /// Specifies how this provider will filter its events
 ///
 /// Some filters are not effective prior to Windows 8.1 ([source](https://learn.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_filter_descriptor#remarks))
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum EventFilter {
     /// Filter by PID.
     /// This is only effective on kernel mode logger session.
@@ -18,19 +21,93 @@ pub enum EventFilter {
     ByPids(Vec<u16>),
     /// Filter by ETW Event ID.
     ByEventIds(Vec<u16>),
+    /// Filter by executable file name (e.g. `notepad.exe`).
+    /// Only the events of processes that run one of these executables are delivered.
+    ByExecutableNames(Vec<String>),
+    /// Filter by package ID, for events of packaged (UWP) apps
+    ByPackageIds(Vec<String>),
+    /// Filter by package-relative app ID (PRAID), for events of packaged (UWP) apps
+    ByPackageAppIds(Vec<String>),
+    /// Filter by the values of the properties of an event (see [`PayloadFilter`]).
+    /// The payload filters of a provider are aggregated into a single descriptor, so that an event must match every filter that applies to it.
+    ByPayload(PayloadFilter),
     // TODO: see https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_filter_descriptor
     //       and https://docs.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-enabletraceex2#remarks
-    //       other filter types are possible
-    //       I'm not always sure what they mean though
+    //       The remaining filter types are not supported yet: EVENT_FILTER_TYPE_SCHEMATIZED, EVENT_FILTER_TYPE_EVENT_NAME,
+    //       and the stack walk filters (EVENT_FILTER_TYPE_STACKWALK, EVENT_FILTER_TYPE_STACKWALK_NAME, EVENT_FILTER_TYPE_STACKWALK_LEVEL_KW)
+}
+
+/// Why an [`EventFilter`] cannot be turned into an `EVENT_FILTER_DESCRIPTOR`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventFilterError {
+    /// The filter has no item
+    Empty,
+    /// The filter has more items than ETW supports (this is the maximum)
+    TooManyItems(usize),
+    /// This item of a string filter is empty, or contains a `;` or a NUL character
+    InvalidItem(String),
+    /// The filter data is larger than ETW supports
+    TooLarge,
+    /// Unable to allocate memory for the filter data
+    AllocationError,
+    /// TDH could not compile a payload filter (e.g. this is not Windows, or the event is not described by a manifest)
+    PayloadCompilationError(String),
+}
+
+impl std::fmt::Display for EventFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventFilterError::Empty => write!(f, "filter must not be empty"),
+            EventFilterError::TooManyItems(max) => write!(f, "too many items are filtered (at most {} are supported)", max),
+            EventFilterError::InvalidItem(item) => write!(f, "invalid filter item {:?} (items must not be empty, nor contain ';' or NUL characters)", item),
+            EventFilterError::TooLarge => write!(f, "exceeded filter size limits"),
+            EventFilterError::AllocationError => write!(f, "unable to allocate memory for the filter"),
+            EventFilterError::PayloadCompilationError(err) => write!(f, "unable to compile the payload filter: {}", err),
+        }
+    }
 }
 
+impl std::error::Error for EventFilterError {}
+
 impl EventFilter {
     /// Builds an EventFilterDescriptor (which can in turn generate an EVENT_FILTER_DESCRIPTOR)
-    pub fn to_event_filter_descriptor(&self) -> Result<EventFilterDescriptor, Box<dyn Error>> {
+    pub fn to_event_filter_descriptor(&self) -> Result<EventFilterDescriptor, EventFilterError> {
         match self {
             EventFilter::ByPids(pids) => EventFilterDescriptor::try_new_by_process_ids(pids),
             EventFilter::ByEventIds(ids) => EventFilterDescriptor::try_new_by_event_ids(ids),
+            EventFilter::ByExecutableNames(names) => EventFilterDescriptor::try_new_by_executable_names(names),
+            EventFilter::ByPackageIds(ids) => EventFilterDescriptor::try_new_by_package_ids(ids),
+            EventFilter::ByPackageAppIds(ids) => EventFilterDescriptor::try_new_by_package_app_ids(ids),
+            EventFilter::ByPayload(filter) => EventFilterDescriptor::try_new_by_payload(filter),
+        }
+    }
+
+    /// Builds the EventFilterDescriptors of the filters of a provider
+    ///
+    /// Every [`EventFilter::ByPayload`] is aggregated into a single descriptor (placed where the first one was), since ETW supports only one per provider.
+    pub fn to_event_filter_descriptors(filters: &[EventFilter]) -> Vec<Result<EventFilterDescriptor, EventFilterError>> {
+        let payload_filters: Vec<&PayloadFilter> = filters
+            .iter()
+            .filter_map(|filter| match filter {
+                EventFilter::ByPayload(payload_filter) => Some(payload_filter),
+                _ => None,
+            })
+            .collect();
+
+        let mut descriptors = Vec::with_capacity(filters.len());
+        let mut payload_descriptor_done = false;
+        for filter in filters {
+            match filter {
+                EventFilter::ByPayload(_) => {
+                    if !payload_descriptor_done {
+                        payload_descriptor_done = true;
+                        descriptors.push(EventFilterDescriptor::try_new_by_payloads(&payload_filters));
+                    }
+                },
+                _ => descriptors.push(filter.to_event_filter_descriptor()),
+            }
         }
+        descriptors
     }
 }
 
@@ -42,38 +119,41 @@ pub struct EventFilterDescriptor {
     data: *mut u8,
     layout: Layout,
     ty: u32,
+    /// Set when the descriptor has been allocated by TDH (see [`Self::try_new_by_payload`]), rather than by us
+    tdh_descriptor: Option<EVENT_FILTER_DESCRIPTOR>,
 }
 
 impl EventFilterDescriptor {
     /// Allocates a new instance, where the included data is `data_size` bytes, and is suitably aligned for type `T`
-    fn try_new<T>(data_size: usize) -> Result<Self, Box<dyn Error>> {
+    fn try_new<T>(data_size: usize) -> Result<Self, EventFilterError> {
         let data_size = match data_size {
-            0 => return Err("Filter must not be empty".into()),
+            0 => return Err(EventFilterError::Empty),
             1..=1024 => data_size as u32,
             _ => {
                 // See https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_filter_descriptor
-                return Err("Exceeded filter size limits".into())
+                return Err(EventFilterError::TooLarge)
             },
         };
 
-        let layout = Layout::from_size_align(data_size as usize, std::mem::align_of::<T>())?;
+        let layout = Layout::from_size_align(data_size as usize, std::mem::align_of::<T>())
+            .map_err(|_| EventFilterError::AllocationError)?;
         let data = unsafe {
             // Safety: layout size is non-zero
             std::alloc::alloc(layout)
         };
         if data.is_null() {
-            return Err("Invalid allocation".into());
+            return Err(EventFilterError::AllocationError);
         }
-        Ok(Self { data, layout, ty: 0 })
+        Ok(Self { data, layout, ty: 0, tdh_descriptor: None })
     }
 
     /// Build a new instance that will filter by event ID.
     ///
     /// Returns an `Err` in case the allocation failed, or if either zero or too many filter items were given
-    pub fn try_new_by_event_ids(eids: &[u16]) -> Result<Self, Box<dyn Error>> {
+    pub fn try_new_by_event_ids(eids: &[u16]) -> Result<Self, EventFilterError> {
         if eids.len() > MAX_EVENT_FILTER_EVENT_ID_COUNT as usize {
             // See https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_filter_descriptor
-            return Err("Too many event IDs are filtered".into());
+            return Err(EventFilterError::TooManyItems(MAX_EVENT_FILTER_EVENT_ID_COUNT as usize));
         }
 
         let data_size = std::mem::size_of::<EVENT_FILTER_EVENT_ID>() + (
@@ -118,10 +198,10 @@ impl EventFilterDescriptor {
     /// Build a new instance that will filter by PIDs.
     ///
     /// Returns an `Err` in case the allocation failed, or if either zero or too many filter items were given
-    pub fn try_new_by_process_ids(pids: &[u16]) -> Result<Self, Box<dyn Error>> {
+    pub fn try_new_by_process_ids(pids: &[u16]) -> Result<Self, EventFilterError> {
         if pids.len() > MAX_EVENT_FILTER_PID_COUNT as usize {
             // See https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_filter_descriptor
-            return Err("Too many PIDs are filtered".into());
+            return Err(EventFilterError::TooManyItems(MAX_EVENT_FILTER_PID_COUNT as usize));
         }
 
         let data_size = pids.len() * std::mem::size_of::<u16>(); // PIDs are WORD, i.e. 16bits
@@ -151,6 +231,93 @@ impl EventFilterDescriptor {
         Ok(s)
     }
 
+    /// Build a new instance that will filter by executable file names.
+    ///
+    /// Returns an `Err` in case the allocation failed, if no name was given, if a name is invalid, or if the names are too long (see [`Self::try_new_by_strings`])
+    pub fn try_new_by_executable_names(names: &[String]) -> Result<Self, EventFilterError> {
+        Self::try_new_by_strings(EVENT_FILTER_TYPE_EXECUTABLE_NAME, names)
+    }
+
+    /// Build a new instance that will filter by package IDs.
+    ///
+    /// Returns an `Err` in case the allocation failed, if no ID was given, if an ID is invalid, or if the IDs are too long (see [`Self::try_new_by_strings`])
+    pub fn try_new_by_package_ids(ids: &[String]) -> Result<Self, EventFilterError> {
+        Self::try_new_by_strings(EVENT_FILTER_TYPE_PACKAGE_ID, ids)
+    }
+
+    /// Build a new instance that will filter by package-relative app IDs.
+    ///
+    /// Returns an `Err` in case the allocation failed, if no ID was given, if an ID is invalid, or if the IDs are too long (see [`Self::try_new_by_strings`])
+    pub fn try_new_by_package_app_ids(ids: &[String]) -> Result<Self, EventFilterError> {
+        Self::try_new_by_strings(EVENT_FILTER_TYPE_PACKAGE_APP_ID, ids)
+    }
+
+    /// Build a new instance for string filters.
+    ///
+    /// Their data is a NUL-terminated UTF-16 string, where items are separated by semicolons.
+    /// It must fit in `MAX_EVENT_FILTER_DATA_SIZE` (1024) bytes, NUL terminator included.
+    fn try_new_by_strings(ty: u32, items: &[String]) -> Result<Self, EventFilterError> {
+        if let Some(invalid) = items.iter().find(|item| item.is_empty() || item.contains(';') || item.contains('\0')) {
+            return Err(EventFilterError::InvalidItem(invalid.clone()));
+        }
+        if items.is_empty() {
+            return Err(EventFilterError::Empty);
+        }
+
+        let wide: Vec<u16> = items
+            .join(";")
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+
+        let mut s = Self::try_new::<u16>(std::mem::size_of_val(wide.as_slice()))?;
+        s.ty = ty;
+        unsafe {
+            // Safety: `data` has been allocated with room for exactly `wide.len()` u16s, and is suitably aligned
+            std::ptr::copy_nonoverlapping(wide.as_ptr(), s.data.cast::<u16>(), wide.len());
+        }
+
+        Ok(s)
+    }
+
+    /// Build a new instance that will filter by the values of the properties of an event.
+    ///
+    /// Returns an `Err` if the filter has no predicate, or if TDH cannot compile it (this is always the case on targets other than Windows)
+    pub fn try_new_by_payload(filter: &PayloadFilter) -> Result<Self, EventFilterError> {
+        Self::try_new_by_payloads(&[filter])
+    }
+
+    /// Build a new instance that will filter by the values of the properties of events, with several payload filters.
+    ///
+    /// ETW accepts a single payload descriptor per provider, so the filters are aggregated into this one. An event must match every filter that applies to it.<br/>
+    /// Filters that have no predicate are ignored.
+    /// Returns an `Err` if no filter has any predicate, or if TDH cannot compile them (this is always the case on targets other than Windows)
+    pub fn try_new_by_payloads(filters: &[&PayloadFilter]) -> Result<Self, EventFilterError> {
+        let native_filters: Vec<tdh::PayloadFilterArgs> = filters
+            .iter()
+            .filter(|filter| !filter.predicates().is_empty())
+            .map(|filter| tdh::PayloadFilterArgs {
+                provider_guid: filter.provider_guid(),
+                event_descriptor: filter.event_descriptor(),
+                match_any: filter.is_match_any(),
+                predicates: filter.native_predicates(),
+            })
+            .collect();
+        if native_filters.is_empty() {
+            return Err(EventFilterError::Empty);
+        }
+
+        let descriptor = tdh::create_payload_filter_descriptor(&native_filters)
+            .map_err(|err| EventFilterError::PayloadCompilationError(err.to_string()))?;
+
+        Ok(Self {
+            data: std::ptr::null_mut(),
+            layout: Layout::new::<u8>(),
+            ty: descriptor.Type,
+            tdh_descriptor: Some(descriptor),
+        })
+    }
+
     /// Returns the EVENT_FILTER_DESCRIPTOR from this [`EventFilterDescriptor`]
     ///
     /// # Safety
@@ -158,6 +325,10 @@ impl EventFilterDescriptor {
     /// This will often be fed to an unsafe Windows function (e.g. [EnableTraceEx2](https://docs.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-enabletraceex2)).
     /// Note that this contains pointers to the current `EventFilterDescriptor`, that must remain valid until the called function is done.
     pub fn as_event_filter_descriptor(&self) -> EVENT_FILTER_DESCRIPTOR {
+        if let Some(descriptor) = self.tdh_descriptor {
+            return descriptor;
+        }
+
         EVENT_FILTER_DESCRIPTOR {
             Ptr: self.data as u64,
             Size: self.layout.size() as u32,
@@ -168,6 +339,11 @@ impl EventFilterDescriptor {
 
 impl Drop for EventFilterDescriptor {
     fn drop(&mut self) {
+        if let Some(descriptor) = self.tdh_descriptor.as_mut() {
+            tdh::cleanup_payload_filter_descriptor(descriptor);
+            return;
+        }
+
         unsafe{
             // Safety:
             // * ptr is a block of memory currently allocated via alloc::alloc
@@ -176,3 +352,86 @@ impl Drop for EventFilterDescriptor {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data_of(descriptor: &EventFilterDescriptor) -> Vec<u16> {
+        let native = descriptor.as_event_filter_descriptor();
+        let data = unsafe {
+            // Safety: `descriptor` is alive, so is its data
+            std::slice::from_raw_parts(native.Ptr as *const u16, native.Size as usize / 2)
+        };
+        data.to_vec()
+    }
+
+    #[test]
+    fn test_string_filters() {
+        let names = vec![String::from("notepad.exe"), String::from("cmd.exe")];
+        let descriptor = EventFilter::ByExecutableNames(names).to_event_filter_descriptor().unwrap();
+        assert_eq!(descriptor.as_event_filter_descriptor().Type, EVENT_FILTER_TYPE_EXECUTABLE_NAME);
+        let expected: Vec<u16> = "notepad.exe;cmd.exe\0".encode_utf16().collect();
+        assert_eq!(data_of(&descriptor), expected);
+        assert_eq!(descriptor.as_event_filter_descriptor().Size, 40);
+
+        let descriptor = EventFilter::ByPackageIds(vec![String::from("Microsoft.WindowsCalculator_8wekyb3d8bbwe")]).to_event_filter_descriptor().unwrap();
+        assert_eq!(descriptor.as_event_filter_descriptor().Type, EVENT_FILTER_TYPE_PACKAGE_ID);
+        let descriptor = EventFilter::ByPackageAppIds(vec![String::from("App")]).to_event_filter_descriptor().unwrap();
+        assert_eq!(descriptor.as_event_filter_descriptor().Type, EVENT_FILTER_TYPE_PACKAGE_APP_ID);
+        assert_eq!(data_of(&descriptor), vec![b'A' as u16, b'p' as u16, b'p' as u16, 0]);
+    }
+
+    #[test]
+    fn test_string_filter_limits() {
+        assert_eq!(EventFilterDescriptor::try_new_by_executable_names(&[]).unwrap_err(), EventFilterError::Empty);
+        assert_eq!(EventFilterDescriptor::try_new_by_executable_names(&[String::from("a.exe;b.exe")]).unwrap_err(), EventFilterError::InvalidItem(String::from("a.exe;b.exe")));
+        assert_eq!(EventFilterDescriptor::try_new_by_package_ids(&[String::new()]).unwrap_err(), EventFilterError::InvalidItem(String::new()));
+        let err = EventFilterDescriptor::try_new_by_package_app_ids(&[String::from("app\0id")]).unwrap_err();
+        assert_eq!(err, EventFilterError::InvalidItem(String::from("app\0id")));
+        assert!(err.to_string().contains("NUL"));
+
+        // 511 characters and the NUL terminator fit in 1024 bytes
+        let longest = vec!["a".repeat(255), "b".repeat(255)];
+        assert!(EventFilterDescriptor::try_new_by_executable_names(&longest).is_ok());
+        let too_long = vec!["a".repeat(256), "b".repeat(255)];
+        assert_eq!(EventFilterDescriptor::try_new_by_executable_names(&too_long).unwrap_err(), EventFilterError::TooLarge);
+    }
+
+    #[test]
+    fn test_payload_filters() {
+        use crate::native::tdh_types::{TdhInType, TdhOutType};
+        use crate::provider::payload_filter::PayloadOperator;
+        use crate::record_builder::SchemaBuilder;
+        use windows::core::GUID;
+
+        let schema = SchemaBuilder::new(GUID::from("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716"), 12)
+            .add_property("Status", TdhInType::InTypeInt32, TdhOutType::OutTypeInt32)
+            .build();
+        let empty = PayloadFilter::new(&schema);
+        let failed = PayloadFilter::new(&schema).add_predicate("Status", PayloadOperator::NotEqual(0)).unwrap();
+
+        assert_eq!(EventFilter::ByPayload(empty.clone()).to_event_filter_descriptor().unwrap_err(), EventFilterError::Empty);
+        assert_eq!(EventFilterDescriptor::try_new_by_payloads(&[&empty, &empty]).unwrap_err(), EventFilterError::Empty);
+        #[cfg(not(windows))]
+        assert!(matches!(
+            EventFilter::ByPayload(failed.clone()).to_event_filter_descriptor(),
+            Err(EventFilterError::PayloadCompilationError(_))
+        ));
+
+        // Payload filters are aggregated into a single descriptor, in place of the first one
+        let filters = vec![
+            EventFilter::ByEventIds(vec![12]),
+            EventFilter::ByPayload(failed.clone()),
+            EventFilter::ByPids(vec![4]),
+            EventFilter::ByPayload(failed),
+            EventFilter::ByPayload(empty),
+        ];
+        let descriptors = EventFilter::to_event_filter_descriptors(&filters);
+        assert_eq!(descriptors.len(), 3);
+        assert_eq!(descriptors[0].as_ref().unwrap().as_event_filter_descriptor().Type, EVENT_FILTER_TYPE_EVENT_ID);
+        assert_eq!(descriptors[2].as_ref().unwrap().as_event_filter_descriptor().Type, EVENT_FILTER_TYPE_PID);
+        #[cfg(not(windows))]
+        assert!(matches!(descriptors[1], Err(EventFilterError::PayloadCompilationError(_))));
+    }
+}
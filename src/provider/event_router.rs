@@ -0,0 +1,218 @@
+//! Route events to the callbacks of a provider
+//!
+//! Callbacks can be restricted to some events (see e.g. [`ProviderBuilder::on_event_id`](crate::provider::ProviderBuilder::on_event_id)).
+//! Callbacks restricted to an event ID, opcode or task are indexed in hash tables, and callbacks restricted to a level in a table indexed by level,
+//! so that the callbacks that receive an event are found without scanning every callback (except the ones with a predicate).
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::native::etw_types::event_record::EventRecord;
+use crate::schema_locator::SchemaLocator;
+use crate::trace::event_context::EventContext;
+use crate::trace::panic_policy::{CaughtPanic, PanicHandler};
+
+pub(crate) type EventPredicate = Box<dyn Fn(&EventRecord) -> bool + Send + Sync + 'static>;
+
+/// A callback of a provider
+pub(crate) enum ProviderCallback {
+    /// Invoked by one thread at a time
+    Exclusive(Mutex<crate::EtwContextCallback>),
+    /// May be invoked by several threads at once (see [`ProviderBuilder::add_shared_callback`](crate::provider::ProviderBuilder::add_shared_callback))
+    Shared(crate::EtwSharedCallback),
+}
+
+impl ProviderCallback {
+    fn invoke(&self, record: &EventRecord, locator: &SchemaLocator, context: &EventContext, panics: &PanicHandler) -> Result<ControlFlow<()>, CaughtPanic> {
+        match self {
+            ProviderCallback::Exclusive(cb) => match cb.lock() {
+                Ok(mut cb) => panics.call(|| cb(record, locator, context)),
+                Err(_) => Ok(ControlFlow::Continue(())),
+            },
+            ProviderCallback::Shared(cb) => panics.call(|| cb(record, locator, context)),
+        }
+    }
+}
+
+struct RoutedCallback {
+    callback: ProviderCallback,
+    /// Set when the callback panicked (see [`PanicPolicy::DisableCallback`](crate::trace::panic_policy::PanicPolicy::DisableCallback))
+    disabled: AtomicBool,
+}
+
+/// Which events a callback receives
+pub(crate) enum Route {
+    All,
+    EventId(u16),
+    Opcode(u8),
+    Task(u16),
+    LevelAtMost(u8),
+    Predicate(EventPredicate),
+}
+
+/// The callbacks of a provider, indexed by the events they receive
+#[derive(Default)]
+pub(crate) struct EventRouter {
+    /// Every callback, in the order they have been added
+    callbacks: Vec<RoutedCallback>,
+    /// Indices (in `callbacks`) of the callbacks that receive every event
+    all: Vec<usize>,
+    by_event_id: HashMap<u16, Vec<usize>>,
+    by_opcode: HashMap<u8, Vec<usize>>,
+    by_task: HashMap<u16, Vec<usize>>,
+    /// Indices of the callbacks restricted to a maximum level, by event level: `by_level[l]` lists the callbacks that receive events of level `l`
+    by_level: Vec<Vec<usize>>,
+    by_predicate: Vec<(EventPredicate, usize)>,
+}
+
+impl EventRouter {
+    pub fn push(&mut self, route: Route, callback: ProviderCallback) {
+        let index = self.callbacks.len();
+        self.callbacks.push(RoutedCallback {
+            callback,
+            disabled: AtomicBool::new(false),
+        });
+        match route {
+            Route::All => self.all.push(index),
+            Route::EventId(id) => self.by_event_id.entry(id).or_default().push(index),
+            Route::Opcode(opcode) => self.by_opcode.entry(opcode).or_default().push(index),
+            Route::Task(task) => self.by_task.entry(task).or_default().push(index),
+            Route::LevelAtMost(level) => {
+                let max_level = level as usize;
+                if self.by_level.len() <= max_level {
+                    self.by_level.resize_with(max_level + 1, Vec::new);
+                }
+                self.by_level[..=max_level].iter_mut().for_each(|indices| indices.push(index));
+            },
+            Route::Predicate(predicate) => self.by_predicate.push((predicate, index)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.callbacks.len()
+    }
+
+    /// Indices of the callbacks that receive this event, in the order they have been added
+    fn routes<'a>(&'a self, record: &'a EventRecord) -> Routes<'a> {
+        let matching = |indices: Option<&'a Vec<usize>>| indices.map(Vec::as_slice).unwrap_or_default();
+        Routes {
+            lists: [
+                &self.all,
+                matching(self.by_event_id.get(&record.event_id())),
+                matching(self.by_opcode.get(&record.opcode())),
+                matching(self.by_task.get(&record.task())),
+                matching(self.by_level.get(record.level() as usize)),
+            ],
+            predicates: &self.by_predicate,
+            next_predicate: None,
+            record,
+        }
+    }
+
+    /// Invoke the callbacks that receive this event, until one of them asks to stop processing the trace
+    pub fn on_event(&self, record: &EventRecord, locator: &SchemaLocator, context: &EventContext, panics: &PanicHandler) -> ControlFlow<()> {
+        for index in self.routes(record) {
+            let routed = &self.callbacks[index];
+            if routed.disabled.load(Ordering::Relaxed) {
+                continue;
+            }
+            match routed.callback.invoke(record, locator, context, panics) {
+                Ok(flow) => flow?,
+                Err(CaughtPanic::Continue) => (),
+                Err(CaughtPanic::DisableCallback) => routed.disabled.store(true, Ordering::Relaxed),
+                Err(CaughtPanic::StopTrace) => return ControlFlow::Break(()),
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Iterates over the callbacks that receive an event, in the order they have been added
+///
+/// Every list of indices of the router is sorted, and every callback is in a single list: they are merged on the fly, without allocating.
+struct Routes<'a> {
+    lists: [&'a [usize]; 5],
+    /// The predicates that have not been evaluated yet
+    predicates: &'a [(EventPredicate, usize)],
+    /// The index of the next callback whose predicate matches, once it has been found
+    next_predicate: Option<usize>,
+    record: &'a EventRecord,
+}
+
+impl Iterator for Routes<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.next_predicate.is_none() {
+            let ((predicate, index), rest) = match self.predicates.split_first() {
+                None => break,
+                Some(first) => first,
+            };
+            self.predicates = rest;
+            if predicate(self.record) {
+                self.next_predicate = Some(*index);
+            }
+        }
+
+        let next_in_lists = self.lists
+            .iter()
+            .enumerate()
+            .filter_map(|(list, indices)| indices.first().map(|index| (*index, list)))
+            .min();
+        match (next_in_lists, self.next_predicate) {
+            (Some((index, _)), Some(predicate_index)) if predicate_index < index => self.next_predicate.take(),
+            (Some((index, list)), _) => {
+                self.lists[list] = &self.lists[list][1..];
+                Some(index)
+            },
+            (None, _) => self.next_predicate.take(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::Provider;
+    use crate::record_builder::EventRecordBuilder;
+    use std::sync::{Arc, Mutex};
+    use windows::core::GUID;
+
+    #[test]
+    fn test_routes() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let push = |name: &'static str| {
+            let received = Arc::clone(&received);
+            move |_record: &EventRecord, _locator: &SchemaLocator| received.lock().unwrap().push(name)
+        };
+
+        let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+            .add_callback_if(|record| record.event_id() == 42, push("predicate id 42"))
+            .on_opcode(1, push("opcode 1"))
+            .add_callback(push("all"))
+            .on_event_id(42, push("id 42"))
+            .on_task(7, push("task 7"))
+            .on_level_at_most(3, push("level <= 3"))
+            .add_callback_if(|record| record.process_id() == 1234, push("pid 1234"))
+            .build();
+
+        let locator = SchemaLocator::new();
+        let context = EventContext::new(0, None);
+        let panics = PanicHandler::default();
+        let on_event = |builder: EventRecordBuilder| {
+            received.lock().unwrap().clear();
+            let _ = provider.on_event(&builder.build(), &locator, &context, &panics);
+            received.lock().unwrap().clone()
+        };
+        let record = || EventRecordBuilder::new(GUID::from("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")).level(4);
+
+        assert_eq!(on_event(record()), vec!["all"]);
+        assert_eq!(on_event(record().event_id(42).opcode(1)), vec!["predicate id 42", "opcode 1", "all", "id 42"]);
+        assert_eq!(on_event(record().task(7).level(2)), vec!["all", "task 7", "level <= 3"]);
+        assert_eq!(on_event(record().process_id(1234)), vec!["all", "pid 1234"]);
+        assert_eq!(on_event(record().event_id(42).opcode(1).task(7).level(3).process_id(1234)), vec!["predicate id 42", "opcode 1", "all", "id 42", "task 7", "level <= 3", "pid 1234"]);
+        assert_eq!(on_event(record().level(0)), vec!["all", "level <= 3"]);
+        assert_eq!(on_event(record().level(255)), vec!["all"]);
+    }
+}
@@ -11,7 +11,7 @@ use super::GUID;
 /// List of Kernel Providers GUIDs
 ///
 /// Credits: [KrabsETW::kernel_guids](https://github.com/microsoft/krabsetw/blob/master/krabs/krabs/kernel_guids.hpp)
-mod kernel_guids {
+pub(crate) mod kernel_guids {
     use super::GUID;
     pub const ALPC_GUID: GUID = GUID::from_values(
         0x45d8cccd, 0x539f, 0x4b72, [0xa8, 0xb7, 0x5c, 0x68, 0x31, 0x42, 0x60, 0x9a]);
@@ -59,6 +59,8 @@ mod kernel_guids {
         0x9e814aad, 0x3204, 0x11d2, [0x9a, 0x82, 0x00, 0x60, 0x08, 0xa8, 0x69, 0x39]);
     pub const EVENT_TRACE_CONFIG_GUID: GUID = GUID::from_values(
         0x01853a65, 0x418f, 0x4f36, [0xae, 0xfc, 0xdc, 0x0f, 0x1d, 0x2f, 0xd2, 0x35]);
+    pub const HEAP_GUID: GUID = GUID::from_values(
+        0x222962ab, 0x6180, 0x4b88, [0xa8, 0x25, 0x34, 0x6b, 0x75, 0xf2, 0xa2, 0x4a]);
 }
 
 /// List of Kernel Providers flags
@@ -92,6 +94,85 @@ mod kernel_flags {
     pub const EVENT_TRACE_FLAG_FILE_IO_INIT: u32 = 0x04000000;
 }
 
+/// List of PERFINFO group mask flags
+///
+/// The 3 most significant bits are the index of the mask, the other bits are the flag within this mask.<br/>
+/// Credits: [PerfView::KernelTraceEventParser](https://github.com/microsoft/perfview/blob/main/src/TraceEvent/Parsers/KernelTraceEventParser.cs) and `ntwmi.h`
+mod group_mask_flags {
+    pub const PERF_POOL: u32 = 0x20000040;
+    pub const PERF_DISPATCHER: u32 = 0x20000200;
+    pub const PERF_SPINLOCK: u32 = 0x20010000;
+    pub const PERF_SESSION: u32 = 0x20400000;
+    pub const PERF_HEAP: u32 = 0x40000020;
+    pub const PERF_TIMER: u32 = 0x40020000;
+    pub const PERF_IPI: u32 = 0x40400000;
+    pub const PERF_OB_HANDLE: u32 = 0x80000040;
+    pub const PERF_OB_OBJECT: u32 = 0x80000080;
+}
+
+/// The extended flags of the kernel logger
+///
+/// The classic `EnableFlags` only fit in 32 bits, and are actually the first of the 8 masks of a
+/// `PERFINFO_GROUPMASK`. The other masks enable kernel events (e.g. pool, heap or timer events) that
+/// are not reachable otherwise.<br/>
+/// They are applied with `TraceSetInformation(TraceSystemTraceEnableFlagsInfo)` when a [`KernelTrace`](crate::trace::KernelTrace) starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KernelGroupMask {
+    masks: [u32; 8],
+}
+
+impl KernelGroupMask {
+    /// An empty group mask
+    pub const fn empty() -> KernelGroupMask {
+        KernelGroupMask { masks: [0; 8] }
+    }
+
+    /// A group mask that only contains a single `PERF_*` flag (e.g. `0x20000040` for `PERF_POOL`)
+    ///
+    /// The 3 most significant bits of `flag` are the index of the mask, the other bits are the flag within this mask.
+    pub const fn from_flag(flag: u32) -> KernelGroupMask {
+        let mut masks = [0; 8];
+        masks[(flag >> 29) as usize] = flag & 0x1fff_ffff;
+        KernelGroupMask { masks }
+    }
+
+    /// A group mask that contains the classic `EnableFlags` (see [`KernelProvider::flags`])
+    pub const fn from_enable_flags(flags: u32) -> KernelGroupMask {
+        let mut masks = [0; 8];
+        masks[0] = flags;
+        KernelGroupMask { masks }
+    }
+
+    /// The union of two group masks
+    pub const fn union(self, other: KernelGroupMask) -> KernelGroupMask {
+        let mut masks = self.masks;
+        let mut i = 0;
+        while i < masks.len() {
+            masks[i] |= other.masks[i];
+            i += 1;
+        }
+        KernelGroupMask { masks }
+    }
+
+    /// The 8 masks
+    pub fn masks(&self) -> [u32; 8] {
+        self.masks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.masks.iter().all(|mask| *mask == 0)
+    }
+
+    /// Serialize as a `PERFINFO_GROUPMASK`
+    pub(crate) fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (chunk, mask) in bytes.chunks_exact_mut(4).zip(self.masks.iter()) {
+            chunk.copy_from_slice(&mask.to_le_bytes());
+        }
+        bytes
+    }
+}
+
 /// Contains kernel provider identifiers.
 ///
 /// You'll need to use it with [`crate::provider::Provider::kernel`]
@@ -113,6 +194,82 @@ impl KernelProvider {
     }
 }
 
+/// Contains the identifiers of a kernel provider that cannot be enabled by the classic flags alone, but by a [`KernelGroupMask`]
+///
+/// You'll need to use it with [`crate::provider::Provider::kernel_by_group_mask`]
+#[derive(Debug)]
+pub struct KernelGroupMaskProvider {
+    /// Kernel Provider GUID
+    pub guid: GUID,
+    /// Kernel Provider extended flags
+    pub group_mask: KernelGroupMask,
+}
+
+impl KernelGroupMaskProvider {
+    /// Use the `new` function to create a Kernel Provider which can be then tied into a Provider
+    pub const fn new(guid: GUID, group_mask: KernelGroupMask) -> KernelGroupMaskProvider {
+        KernelGroupMaskProvider {
+            guid,
+            group_mask,
+        }
+    }
+}
+
+/// Identifies a class of kernel events, by the GUID of their kernel provider and their opcode
+///
+/// This is used to enable stack walks on kernel events (see [`TraceBuilder::enable_stack_walk`](crate::trace::TraceBuilder::enable_stack_walk)).
+/// This maps to a [CLASSIC_EVENT_ID](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-classic_event_id).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassicEventId {
+    /// Kernel Provider GUID
+    pub guid: GUID,
+    /// Opcode of the event (e.g. 1 for `Process/Start`)
+    pub opcode: u8,
+}
+
+impl ClassicEventId {
+    pub const fn new(guid: GUID, opcode: u8) -> ClassicEventId {
+        ClassicEventId {
+            guid,
+            opcode,
+        }
+    }
+
+    /// Serialize as a `CLASSIC_EVENT_ID` (i.e. the GUID, the opcode, and 7 reserved bytes)
+    pub(crate) fn to_bytes(self) -> [u8; 24] {
+        let mut bytes = [0u8; 24];
+        bytes[0..4].copy_from_slice(&self.guid.data1.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.guid.data2.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.guid.data3.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.guid.data4);
+        bytes[16] = self.opcode;
+        bytes
+    }
+}
+
+/// `Process/Start` events
+pub static PROCESS_START_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::PROCESS_GUID, 1);
+/// `Process/End` events
+pub static PROCESS_END_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::PROCESS_GUID, 2);
+/// `Thread/Start` events
+pub static THREAD_START_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::THREAD_GUID, 1);
+/// `Thread/End` events
+pub static THREAD_END_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::THREAD_GUID, 2);
+/// `Thread/CSwitch` (context switch) events
+pub static CSWITCH_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::THREAD_GUID, 36);
+/// `Image/Load` events
+pub static IMAGE_LOAD_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::IMAGE_LOAD_GUID, 10);
+/// `PerfInfo/SampledProfile` events
+pub static SAMPLED_PROFILE_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::PERF_INFO_GUID, 46);
+/// `PerfInfo/SysClEnter` (system call) events
+pub static SYSCALL_ENTER_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::PERF_INFO_GUID, 51);
+/// `FileIo/Create` events
+pub static FILE_IO_CREATE_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::FILE_IO_GUID, 64);
+/// `PageFault/VirtualAlloc` events
+pub static VIRTUAL_ALLOC_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::PAGE_FAULT_GUID, 98);
+/// `Registry/Create` events
+pub static REGISTRY_CREATE_EVENT: ClassicEventId = ClassicEventId::new(kernel_guids::REGISTRY_GUID, 10);
+
 /// Represents the VirtualAlloc Kernel Provider
 pub static VIRTUAL_ALLOC_PROVIDER: KernelProvider = KernelProvider::new(
     kernel_guids::PAGE_FAULT_GUID,
@@ -121,6 +278,10 @@ pub static VIRTUAL_ALLOC_PROVIDER: KernelProvider = KernelProvider::new(
 /// Represents the VA Map Kernel Provider
 pub static VAMAP_PROVIDER: KernelProvider =
     KernelProvider::new(kernel_guids::FILE_IO_GUID, kernel_flags::EVENT_TRACE_FLAG_VAMAP);
+/// Represents the StackWalk Kernel Provider
+///
+/// It has no flag of its own: it delivers the call stacks of the events that have stack walks enabled (see [`ClassicEventId`])
+pub static STACK_WALK_PROVIDER: KernelProvider = KernelProvider::new(kernel_guids::STACK_WALK_GUID, 0);
 /// Represents the Thread Kernel Provider
 pub static THREAD_PROVIDER: KernelProvider =
     KernelProvider::new(kernel_guids::THREAD_GUID, kernel_flags::EVENT_TRACE_FLAG_THREAD);
@@ -226,6 +387,51 @@ pub static CONTEXT_SWITCH_PROVIDER: KernelProvider =
 /// Represents the ALPC Kernel Provider
 pub static ALPC_PROVIDER: KernelProvider =
     KernelProvider::new(kernel_guids::ALPC_GUID, kernel_flags::EVENT_TRACE_FLAG_ALPC);
+/// Represents the Ready Thread Kernel Provider
+pub static READY_THREAD_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::THREAD_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_DISPATCHER)
+);
+/// Represents the Pool Kernel Provider
+pub static POOL_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::POOL_TRACE_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_POOL)
+);
+/// Represents the Heap Kernel Provider
+pub static HEAP_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::HEAP_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_HEAP)
+);
+/// Represents the Session Kernel Provider
+pub static SESSION_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::PERF_INFO_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_SESSION)
+);
+/// Represents the Spinlock Kernel Provider
+pub static SPINLOCK_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::PERF_INFO_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_SPINLOCK)
+);
+/// Represents the Timer Kernel Provider
+pub static TIMER_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::PERF_INFO_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_TIMER)
+);
+/// Represents the IPI (Inter-Processor Interrupt) Kernel Provider
+pub static IPI_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::PERF_INFO_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_IPI)
+);
+/// Represents the Object Handle Kernel Provider
+pub static OBJECT_HANDLE_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::OB_TRACE_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_OB_HANDLE)
+);
+/// Represents the Object Kernel Provider
+pub static OBJECT_PROVIDER: KernelGroupMaskProvider = KernelGroupMaskProvider::new(
+    kernel_guids::OB_TRACE_GUID,
+    KernelGroupMask::from_flag(group_mask_flags::PERF_OB_OBJECT)
+);
 
 
 
@@ -235,6 +441,8 @@ mod test {
     use super::kernel_guids::*;
     use super::*;
 
+    use crate::native::etw_types::TraceInformation;
+    use crate::native::evntrace::backend::fake::fake_kernel_trace;
     use crate::provider::Provider;
 
     #[test]
@@ -256,6 +464,87 @@ mod test {
         assert_eq!(GUID::from(IMAGE_LOAD_GUID), kernel_provider.guid());
     }
 
+    #[test]
+    fn test_kernel_group_mask() {
+        let group_mask = HEAP_PROVIDER.group_mask.union(POOL_PROVIDER.group_mask);
+        assert_eq!(group_mask.masks(), [0, 0x40, 0x20, 0, 0, 0, 0, 0]);
+        assert!(KernelGroupMask::empty().is_empty());
+        assert!(!group_mask.is_empty());
+
+        let group_mask = group_mask.union(KernelGroupMask::from_enable_flags(EVENT_TRACE_FLAG_PROCESS));
+        assert_eq!(&group_mask.to_bytes()[0..12], &[1, 0, 0, 0, 0x40, 0, 0, 0, 0x20, 0, 0, 0]);
+
+        let provider = Provider::kernel_by_group_mask(&TIMER_PROVIDER).build();
+        assert_eq!(provider.kernel_flags(), 0);
+        assert_eq!(provider.kernel_group_mask(), KernelGroupMask::from_flag(0x40020000));
+    }
+
+    #[test]
+    fn test_stack_walk() {
+        let (builder, backend) = fake_kernel_trace("fake-stack-walk");
+        let (trace, _handle) = builder
+            .enable(Provider::kernel(&PROCESS_PROVIDER).build())
+            .enable(Provider::kernel(&STACK_WALK_PROVIDER).build())
+            .enable_stack_walk(&PROCESS_START_EVENT)
+            .enable_stack_walk(&CSWITCH_EVENT)
+            .start()
+            .unwrap();
+
+        let info = backend.session_information("fake-stack-walk", TraceInformation::TraceStackTracingInfo).unwrap();
+        assert_eq!(info.len(), 2 * 24);
+        // Process/Start: {3d6fa8d0-fe05-11d0-9dda-00c04fd7ba7c}, opcode 1
+        assert_eq!(&info[..24], &[
+            0xd0, 0xa8, 0x6f, 0x3d, 0x05, 0xfe, 0xd0, 0x11, 0x9d, 0xda, 0x00, 0xc0, 0x4f, 0xd7, 0xba, 0x7c,
+            1, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        assert_eq!(info[24 + 16], 36);
+
+        trace.stop().unwrap();
+    }
+
+    #[test]
+    fn test_pmc_counters() {
+        let (builder, backend) = fake_kernel_trace("fake-pmc");
+        let (trace, _handle) = builder
+            .enable(Provider::kernel(&CONTEXT_SWITCH_PROVIDER).build())
+            .set_pmc_sources(vec![0x02, 0x0b])
+            .enable_pmc_counters(&CSWITCH_EVENT)
+            .start()
+            .unwrap();
+
+        assert_eq!(backend.session_information("fake-pmc", TraceInformation::TracePmcCounterListInfo).unwrap(), vec![2, 0, 0, 0, 11, 0, 0, 0]);
+        assert_eq!(
+            backend.session_information("fake-pmc", TraceInformation::TracePmcEventListInfo).unwrap(),
+            CSWITCH_EVENT.to_bytes().to_vec()
+        );
+        assert!(backend.session_information("fake-pmc", TraceInformation::TraceStackTracingInfo).is_none());
+
+        trace.stop().unwrap();
+    }
+
+    #[test]
+    fn test_group_mask_session() {
+        let (builder, backend) = fake_kernel_trace("fake-group-mask");
+        let (trace, _handle) = builder
+            .enable(Provider::kernel(&PROCESS_PROVIDER).build())
+            .enable(Provider::kernel_by_group_mask(&POOL_PROVIDER).build())
+            .start()
+            .unwrap();
+
+        let info = backend.session_information("fake-group-mask", TraceInformation::TraceSystemTraceEnableFlagsInfo).unwrap();
+        assert_eq!(info.len(), 32);
+        assert_eq!(&info[0..8], &[1, 0, 0, 0, 0x40, 0, 0, 0]);
+        trace.stop().unwrap();
+
+        // Classic flags do not need it
+        let (trace, _handle) = backend.kernel_trace("fake-classic-flags")
+            .enable(Provider::kernel(&PROCESS_PROVIDER).build())
+            .start()
+            .unwrap();
+        assert!(backend.session_information("fake-classic-flags", TraceInformation::TraceSystemTraceEnableFlagsInfo).is_none());
+        trace.stop().unwrap();
+    }
+
     #[test]
     fn test_kernel_provider_guids_correct() {
         assert_eq!(ALPC_GUID, GUID::from("45d8cccd-539f-4b72-a8b7-5c683142609a"));
@@ -281,5 +570,6 @@ mod test {
         assert_eq!(MMCSS_TRACE_GUID, GUID::from("f8f10121-b617-4a56-868b-9df1b27fe32c"));
         assert_eq!(SYSTEM_TRACE_GUID, GUID::from("9e814aad-3204-11d2-9a82-006008a86939"));
         assert_eq!(EVENT_TRACE_CONFIG_GUID, GUID::from("01853a65-418f-4f36-aefc-dc0f1d2fd235"));
+        assert_eq!(HEAP_GUID, GUID::from("222962ab-6180-4b88-a825-346b75f2a24a"));
     }
 }
@@ -0,0 +1,500 @@
+//! Filter events on the values of their properties
+//!
+//! A [`PayloadFilter`] is a set of predicates on the properties of a given event (e.g. "`Status` is not 0").
+//! It is built from the [`Schema`] of this event, so that predicates are checked against the actual properties and their types.
+//!
+//! Once wrapped into an [`EventFilter::ByPayload`](crate::provider::EventFilter::ByPayload), it is compiled by TDH and evaluated by ETW, so that discarded events are never delivered to this process.
+//! Enabling the provider fails in case TDH cannot compile it (e.g. because the event is not described by a manifest).<br/>
+//! The same predicates can also be evaluated in this process (see [`PayloadFilter::matches`]), e.g. to filter events read from an ETL file.
+//!
+//! ```
+//! use ferrisetw::GUID;
+//! use ferrisetw::native::{TdhInType, TdhOutType};
+//! use ferrisetw::provider::{EventFilter, Provider};
+//! use ferrisetw::provider::payload_filter::{PayloadFilter, PayloadOperator};
+//! use ferrisetw::record_builder::SchemaBuilder;
+//!
+//! # fn main() -> Result<(), ferrisetw::provider::payload_filter::PayloadFilterError> {
+//! // Schemas are usually retrieved from a previous event, with `SchemaLocator::event_schema`
+//! let provider = GUID::from("1c95126e-7eea-49a9-a3fe-a378b03ddb4d");
+//! let schema = SchemaBuilder::new(provider, 3006)
+//!     .add_property("QueryName", TdhInType::InTypeUnicodeString, TdhOutType::OutTypeString)
+//!     .add_property("QueryType", TdhInType::InTypeUInt32, TdhOutType::OutTypeUInt32)
+//!     .build();
+//!
+//! let filter = PayloadFilter::new(&schema)
+//!     .add_predicate("QueryName", PayloadOperator::Contains(String::from("corp")))?
+//!     .add_predicate("QueryType", PayloadOperator::Equal(1))?;
+//!
+//! let provider = Provider::by_guid(provider)
+//!     .add_filter(EventFilter::ByPayload(filter))
+//!     .build();
+//! # Ok(())
+//! # }
+//! ```
+use std::convert::TryInto;
+
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::{self, EVENT_DESCRIPTOR, MAX_PAYLOAD_PREDICATES, PAYLOAD_OPERATOR};
+
+use crate::native::etw_types::event_record::EventRecord;
+use crate::native::tdh::PayloadPredicateArgs;
+use crate::native::tdh_types::TdhInType;
+use crate::parser::Parser;
+use crate::schema::Schema;
+
+/// Why a predicate cannot be added to a [`PayloadFilter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadFilterError {
+    /// The event has no such property
+    UnknownProperty(String),
+    /// The operator cannot be applied to the type of this property (e.g. `Contains` on an integer)
+    IncompatibleOperator(String),
+    /// The value does not fit in the type of this property
+    ValueOutOfRange(String),
+    /// The value is invalid for this operator (e.g. a modulo by zero, or a string containing a NUL character)
+    InvalidValue(String),
+    /// The filter already has as many predicates as ETW supports (this is the maximum)
+    TooManyPredicates(usize),
+}
+
+impl std::fmt::Display for PayloadFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadFilterError::UnknownProperty(name) => write!(f, "the event has no property {}", name),
+            PayloadFilterError::IncompatibleOperator(name) => write!(f, "the operator cannot be applied to the type of property {}", name),
+            PayloadFilterError::ValueOutOfRange(name) => write!(f, "the value does not fit in the type of property {}", name),
+            PayloadFilterError::InvalidValue(name) => write!(f, "invalid value for the predicate on property {}", name),
+            PayloadFilterError::TooManyPredicates(max) => write!(f, "too many predicates (at most {} are supported)", max),
+        }
+    }
+}
+
+impl std::error::Error for PayloadFilterError {}
+
+/// How a property is compared to a value
+///
+/// Numeric operators apply to integer, boolean, pointer and hexadecimal properties.
+/// String operators apply to Unicode and ANSI string properties, and ignore case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadOperator {
+    Equal(i128),
+    NotEqual(i128),
+    LessOrEqual(i128),
+    Greater(i128),
+    Less(i128),
+    GreaterOrEqual(i128),
+    /// Between two values, both included
+    Between(i128, i128),
+    /// Not between two values
+    NotBetween(i128, i128),
+    /// The property is a multiple of this (non-zero) value
+    Modulo(i128),
+    StringEqual(String),
+    StringNotEqual(String),
+    Contains(String),
+    DoesNotContain(String),
+}
+
+impl PayloadOperator {
+    fn is_numeric(&self) -> bool {
+        matches!(self,
+            PayloadOperator::Equal(_) | PayloadOperator::NotEqual(_) |
+            PayloadOperator::LessOrEqual(_) | PayloadOperator::Greater(_) |
+            PayloadOperator::Less(_) | PayloadOperator::GreaterOrEqual(_) |
+            PayloadOperator::Between(_, _) | PayloadOperator::NotBetween(_, _) |
+            PayloadOperator::Modulo(_)
+        )
+    }
+
+    /// The numeric values of this operator
+    fn numbers(&self) -> Vec<i128> {
+        match self {
+            PayloadOperator::Equal(n) | PayloadOperator::NotEqual(n) |
+            PayloadOperator::LessOrEqual(n) | PayloadOperator::Greater(n) |
+            PayloadOperator::Less(n) | PayloadOperator::GreaterOrEqual(n) |
+            PayloadOperator::Modulo(n) => vec![*n],
+            PayloadOperator::Between(low, high) | PayloadOperator::NotBetween(low, high) => vec![*low, *high],
+            _ => Vec::new(),
+        }
+    }
+
+    fn native_operator(&self) -> PAYLOAD_OPERATOR {
+        match self {
+            PayloadOperator::Equal(_) | PayloadOperator::StringEqual(_) => Etw::PAYLOADFIELD_EQ,
+            PayloadOperator::NotEqual(_) | PayloadOperator::StringNotEqual(_) => Etw::PAYLOADFIELD_NE,
+            PayloadOperator::LessOrEqual(_) => Etw::PAYLOADFIELD_LE,
+            PayloadOperator::Greater(_) => Etw::PAYLOADFIELD_GT,
+            PayloadOperator::Less(_) => Etw::PAYLOADFIELD_LT,
+            PayloadOperator::GreaterOrEqual(_) => Etw::PAYLOADFIELD_GE,
+            PayloadOperator::Between(_, _) => Etw::PAYLOADFIELD_BETWEEN,
+            PayloadOperator::NotBetween(_, _) => Etw::PAYLOADFIELD_NOTBETWEEN,
+            PayloadOperator::Modulo(_) => Etw::PAYLOADFIELD_MODULO,
+            PayloadOperator::Contains(_) => Etw::PAYLOADFIELD_CONTAINS,
+            PayloadOperator::DoesNotContain(_) => Etw::PAYLOADFIELD_DOESNTCONTAIN,
+        }
+    }
+
+    /// The value, as TDH expects it (ranges are two comma-separated values)
+    fn native_value(&self) -> String {
+        match self {
+            PayloadOperator::Between(low, high) | PayloadOperator::NotBetween(low, high) => format!("{},{}", low, high),
+            PayloadOperator::StringEqual(s) | PayloadOperator::StringNotEqual(s) |
+            PayloadOperator::Contains(s) | PayloadOperator::DoesNotContain(s) => s.clone(),
+            numeric => numeric.numbers()[0].to_string(),
+        }
+    }
+
+    fn eval_number(&self, value: i128) -> bool {
+        match self {
+            PayloadOperator::Equal(n) => value == *n,
+            PayloadOperator::NotEqual(n) => value != *n,
+            PayloadOperator::LessOrEqual(n) => value <= *n,
+            PayloadOperator::Greater(n) => value > *n,
+            PayloadOperator::Less(n) => value < *n,
+            PayloadOperator::GreaterOrEqual(n) => value >= *n,
+            PayloadOperator::Between(low, high) => *low <= value && value <= *high,
+            PayloadOperator::NotBetween(low, high) => value < *low || *high < value,
+            PayloadOperator::Modulo(n) => value % *n == 0,
+            _ => false,
+        }
+    }
+
+    fn eval_string(&self, value: &str) -> bool {
+        let value = value.to_lowercase();
+        match self {
+            PayloadOperator::StringEqual(s) => value == s.to_lowercase(),
+            PayloadOperator::StringNotEqual(s) => value != s.to_lowercase(),
+            PayloadOperator::Contains(s) => value.contains(&s.to_lowercase()),
+            PayloadOperator::DoesNotContain(s) => !value.contains(&s.to_lowercase()),
+            _ => false,
+        }
+    }
+}
+
+/// A predicate of a [`PayloadFilter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadPredicate {
+    property: String,
+    in_type: TdhInType,
+    operator: PayloadOperator,
+}
+
+impl PayloadPredicate {
+    pub fn property(&self) -> &str {
+        &self.property
+    }
+
+    pub fn operator(&self) -> &PayloadOperator {
+        &self.operator
+    }
+
+    fn matches(&self, parser: &Parser) -> bool {
+        if self.operator.is_numeric() {
+            let bytes: Vec<u8> = match parser.try_parse(&self.property) {
+                Ok(bytes) => bytes,
+                Err(_) => return false,
+            };
+            match read_integer(&bytes, is_signed(self.in_type)) {
+                Some(value) => self.operator.eval_number(value),
+                None => false,
+            }
+        } else {
+            match parser.try_parse::<String>(&self.property) {
+                Ok(value) => self.operator.eval_string(&value),
+                Err(_) => false,
+            }
+        }
+    }
+}
+
+/// Predicates on the properties of an event, that are combined with AND (the default) or OR
+///
+/// See the [module-level documentation](self)
+#[derive(Debug, Clone)]
+pub struct PayloadFilter {
+    provider_guid: GUID,
+    event_descriptor: EVENT_DESCRIPTOR,
+    /// Name and type of every property of the event
+    properties: Vec<(String, TdhInType)>,
+    match_any: bool,
+    predicates: Vec<PayloadPredicate>,
+}
+
+impl PayloadFilter {
+    /// Create a filter with no predicate, for the event described by this schema
+    pub fn new(schema: &Schema) -> Self {
+        let te_info = schema.te_info();
+        Self {
+            provider_guid: te_info.provider_guid(),
+            event_descriptor: te_info.event_descriptor(),
+            properties: schema
+                .properties()
+                .iter()
+                .map(|property| (property.name.clone(), property.in_type()))
+                .collect(),
+            match_any: false,
+            predicates: Vec::new(),
+        }
+    }
+
+    /// Whether an event must match any predicate (OR), rather than every predicate (AND, the default)
+    pub fn match_any(mut self, match_any: bool) -> Self {
+        self.match_any = match_any;
+        self
+    }
+
+    /// Add a predicate on a property
+    ///
+    /// Returns an `Err` if the event has no such property, if the operator does not apply to its type, if the value is invalid, or if there are too many predicates already
+    pub fn add_predicate(mut self, property: &str, operator: PayloadOperator) -> Result<Self, PayloadFilterError> {
+        if self.predicates.len() >= MAX_PAYLOAD_PREDICATES as usize {
+            return Err(PayloadFilterError::TooManyPredicates(MAX_PAYLOAD_PREDICATES as usize));
+        }
+
+        let in_type = match self.properties.iter().find(|(name, _)| name == property) {
+            None => return Err(PayloadFilterError::UnknownProperty(property.to_string())),
+            Some((_, in_type)) => *in_type,
+        };
+
+        if operator.is_numeric() {
+            let (min, max) = integer_range(in_type)
+                .ok_or_else(|| PayloadFilterError::IncompatibleOperator(property.to_string()))?;
+            if operator.numbers().iter().any(|n| *n < min || *n > max) {
+                return Err(PayloadFilterError::ValueOutOfRange(property.to_string()));
+            }
+            match operator {
+                PayloadOperator::Modulo(0) => return Err(PayloadFilterError::InvalidValue(property.to_string())),
+                PayloadOperator::Between(low, high) | PayloadOperator::NotBetween(low, high) if low > high => {
+                    return Err(PayloadFilterError::InvalidValue(property.to_string()))
+                },
+                _ => (),
+            }
+        } else {
+            if !matches!(in_type, TdhInType::InTypeUnicodeString | TdhInType::InTypeAnsiString) {
+                return Err(PayloadFilterError::IncompatibleOperator(property.to_string()));
+            }
+            if operator.native_value().contains('\0') {
+                return Err(PayloadFilterError::InvalidValue(property.to_string()));
+            }
+        }
+
+        self.predicates.push(PayloadPredicate {
+            property: property.to_string(),
+            in_type,
+            operator,
+        });
+        Ok(self)
+    }
+
+    pub fn predicates(&self) -> &[PayloadPredicate] {
+        &self.predicates
+    }
+
+    /// The ID of the event this filter applies to
+    pub fn event_id(&self) -> u16 {
+        self.event_descriptor.Id
+    }
+
+    /// Evaluate this filter in this process, the same way ETW does
+    ///
+    /// Events other than the one this filter has been built for are not filtered, and always match.
+    /// A filter with no predicate is not accepted by ETW (and is ignored when the provider is enabled), so it matches every event as well.
+    /// `schema` is the schema of `record`, as given by [`SchemaLocator::event_schema`](crate::schema_locator::SchemaLocator::event_schema).
+    pub fn matches(&self, record: &EventRecord, schema: &Schema) -> bool {
+        if self.predicates.is_empty()
+            || record.provider_id() != self.provider_guid
+            || record.event_id() != self.event_descriptor.Id
+            || record.version() != self.event_descriptor.Version
+        {
+            return true;
+        }
+
+        let parser = Parser::create(record, schema);
+        if self.match_any {
+            self.predicates.iter().any(|predicate| predicate.matches(&parser))
+        } else {
+            self.predicates.iter().all(|predicate| predicate.matches(&parser))
+        }
+    }
+
+    pub(crate) fn provider_guid(&self) -> &GUID {
+        &self.provider_guid
+    }
+
+    pub(crate) fn event_descriptor(&self) -> &EVENT_DESCRIPTOR {
+        &self.event_descriptor
+    }
+
+    pub(crate) fn is_match_any(&self) -> bool {
+        self.match_any
+    }
+
+    /// The predicates, as `PAYLOAD_FILTER_PREDICATE` arguments
+    pub(crate) fn native_predicates(&self) -> Vec<PayloadPredicateArgs<'_>> {
+        self.predicates
+            .iter()
+            .map(|predicate| PayloadPredicateArgs {
+                field_name: &predicate.property,
+                compare_op: predicate.operator.native_operator().0 as u16,
+                value: predicate.operator.native_value(),
+            })
+            .collect()
+    }
+}
+
+fn is_signed(in_type: TdhInType) -> bool {
+    matches!(in_type,
+        TdhInType::InTypeInt8 | TdhInType::InTypeInt16 | TdhInType::InTypeInt32 | TdhInType::InTypeInt64 | TdhInType::InTypeBoolean
+    )
+}
+
+/// The values a property of this type can take, or `None` if this is not an integer type
+fn integer_range(in_type: TdhInType) -> Option<(i128, i128)> {
+    let range = match in_type {
+        TdhInType::InTypeInt8 => (i8::MIN as i128, i8::MAX as i128),
+        TdhInType::InTypeUInt8 => (0, u8::MAX as i128),
+        TdhInType::InTypeInt16 => (i16::MIN as i128, i16::MAX as i128),
+        TdhInType::InTypeUInt16 => (0, u16::MAX as i128),
+        // BOOLs are 4-byte integers
+        TdhInType::InTypeInt32 | TdhInType::InTypeBoolean => (i32::MIN as i128, i32::MAX as i128),
+        TdhInType::InTypeUInt32 | TdhInType::InTypeHexInt32 => (0, u32::MAX as i128),
+        TdhInType::InTypeInt64 => (i64::MIN as i128, i64::MAX as i128),
+        TdhInType::InTypeUInt64 | TdhInType::InTypeHexInt64 | TdhInType::InTypePointer => (0, u64::MAX as i128),
+        _ => return None,
+    };
+    Some(range)
+}
+
+/// Read a native-endian integer of 1, 2, 4 or 8 bytes
+fn read_integer(bytes: &[u8], signed: bool) -> Option<i128> {
+    let value = match (bytes.len(), signed) {
+        (1, true) => i8::from_ne_bytes(bytes.try_into().ok()?) as i128,
+        (1, false) => u8::from_ne_bytes(bytes.try_into().ok()?) as i128,
+        (2, true) => i16::from_ne_bytes(bytes.try_into().ok()?) as i128,
+        (2, false) => u16::from_ne_bytes(bytes.try_into().ok()?) as i128,
+        (4, true) => i32::from_ne_bytes(bytes.try_into().ok()?) as i128,
+        (4, false) => u32::from_ne_bytes(bytes.try_into().ok()?) as i128,
+        (8, true) => i64::from_ne_bytes(bytes.try_into().ok()?) as i128,
+        (8, false) => u64::from_ne_bytes(bytes.try_into().ok()?) as i128,
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::native::tdh_types::TdhOutType;
+    use crate::record_builder::{EventRecordBuilder, PropertyValue, SchemaBuilder};
+
+    const PROVIDER: &str = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716";
+
+    fn schema() -> Schema {
+        SchemaBuilder::new(GUID::from(PROVIDER), 12)
+            .add_property("FileName", TdhInType::InTypeUnicodeString, TdhOutType::OutTypeString)
+            .add_property("Status", TdhInType::InTypeInt32, TdhOutType::OutTypeInt32)
+            .add_property("Size", TdhInType::InTypeUInt64, TdhOutType::OutTypeUInt64)
+            .add_property("Ratio", TdhInType::InTypeFloat, TdhOutType::OutTypeFloat)
+            .build()
+    }
+
+    #[test]
+    fn test_validation() {
+        let schema = schema();
+        let filter = || PayloadFilter::new(&schema);
+
+        assert_eq!(filter().add_predicate("Missing", PayloadOperator::Equal(0)).unwrap_err(), PayloadFilterError::UnknownProperty(String::from("Missing")));
+        assert_eq!(filter().add_predicate("Status", PayloadOperator::Contains(String::from("a"))).unwrap_err(), PayloadFilterError::IncompatibleOperator(String::from("Status")));
+        assert_eq!(filter().add_predicate("FileName", PayloadOperator::Greater(0)).unwrap_err(), PayloadFilterError::IncompatibleOperator(String::from("FileName")));
+        assert_eq!(filter().add_predicate("Ratio", PayloadOperator::Equal(1)).unwrap_err(), PayloadFilterError::IncompatibleOperator(String::from("Ratio")));
+        assert_eq!(filter().add_predicate("Status", PayloadOperator::Equal(1 << 31)).unwrap_err(), PayloadFilterError::ValueOutOfRange(String::from("Status")));
+        assert_eq!(filter().add_predicate("Size", PayloadOperator::Less(-1)).unwrap_err(), PayloadFilterError::ValueOutOfRange(String::from("Size")));
+        assert_eq!(filter().add_predicate("Size", PayloadOperator::Modulo(0)).unwrap_err(), PayloadFilterError::InvalidValue(String::from("Size")));
+        assert_eq!(filter().add_predicate("Size", PayloadOperator::Between(10, 1)).unwrap_err(), PayloadFilterError::InvalidValue(String::from("Size")));
+
+        let mut full = filter();
+        for _ in 0..MAX_PAYLOAD_PREDICATES {
+            full = full.add_predicate("Status", PayloadOperator::NotEqual(0)).unwrap();
+        }
+        assert_eq!(full.add_predicate("Status", PayloadOperator::NotEqual(0)).unwrap_err(), PayloadFilterError::TooManyPredicates(8));
+
+        let filter = filter()
+            .add_predicate("Status", PayloadOperator::Between(-5, 5)).unwrap()
+            .add_predicate("FileName", PayloadOperator::DoesNotContain(String::from(".tmp"))).unwrap();
+        assert_eq!(filter.event_id(), 12);
+        let native = filter.native_predicates();
+        assert_eq!((native[0].field_name, native[0].compare_op, native[0].value.as_str()), ("Status", 6, "-5,5"));
+        assert_eq!((native[1].field_name, native[1].compare_op, native[1].value.as_str()), ("FileName", 21, ".tmp"));
+    }
+
+    #[test]
+    fn test_matches() {
+        let schema = schema();
+        let record = |file_name: &str, status: i32, size: u64| {
+            EventRecordBuilder::new(GUID::from(PROVIDER))
+                .event_id(12)
+                .properties(&schema, &[
+                    ("FileName", PropertyValue::String(file_name.to_string())),
+                    ("Status", PropertyValue::I32(status)),
+                    ("Size", PropertyValue::U64(size)),
+                    ("Ratio", PropertyValue::F32(0.5)),
+                ])
+                .unwrap()
+                .build()
+        };
+
+        let failed_corp_files = PayloadFilter::new(&schema)
+            .add_predicate("FileName", PayloadOperator::Contains(String::from("CORP"))).unwrap()
+            .add_predicate("Status", PayloadOperator::NotEqual(0)).unwrap();
+        assert!(failed_corp_files.matches(&record(r"\\corp\share", -1, 0), &schema));
+        assert!(!failed_corp_files.matches(&record(r"\\corp\share", 0, 0), &schema));
+        assert!(!failed_corp_files.matches(&record(r"C:\file", -1, 0), &schema));
+
+        let large_or_failed = PayloadFilter::new(&schema)
+            .match_any(true)
+            .add_predicate("Size", PayloadOperator::Greater(u64::MAX as i128 - 1)).unwrap()
+            .add_predicate("Status", PayloadOperator::Less(0)).unwrap();
+        assert!(large_or_failed.matches(&record("a", 0, u64::MAX), &schema));
+        assert!(large_or_failed.matches(&record("a", -7, 0), &schema));
+        assert!(!large_or_failed.matches(&record("a", 7, 0), &schema));
+
+        let even_sizes = PayloadFilter::new(&schema)
+            .add_predicate("Size", PayloadOperator::Modulo(2)).unwrap()
+            .add_predicate("Size", PayloadOperator::NotBetween(10, 20)).unwrap();
+        assert!(even_sizes.matches(&record("a", 0, 4), &schema));
+        assert!(!even_sizes.matches(&record("a", 0, 5), &schema));
+        assert!(!even_sizes.matches(&record("a", 0, 14), &schema));
+
+        // Other events are not filtered
+        let other_event = EventRecordBuilder::new(GUID::from(PROVIDER)).event_id(13).build();
+        assert!(failed_corp_files.matches(&other_event, &schema));
+
+        // Empty filters are ignored, the same way they are when enabling a provider
+        let empty = PayloadFilter::new(&schema).match_any(true);
+        assert!(empty.matches(&record("a", 0, 0), &schema));
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_compilation_error() {
+        use crate::native::evntrace::backend::fake::fake_user_trace;
+        use crate::native::EvntraceNativeError;
+        use crate::provider::event_filter::{EventFilter, EventFilterError};
+        use crate::provider::Provider;
+        use crate::trace::TraceError;
+
+        // TDH is not available here: the filter cannot be compiled, and the provider must not be enabled without it
+        let schema = schema();
+        let filter = PayloadFilter::new(&schema).add_predicate("Status", PayloadOperator::NotEqual(0)).unwrap();
+        let (builder, backend) = fake_user_trace("fake-payload-filter");
+        let result = builder
+            .enable(Provider::by_guid(PROVIDER).add_filter(EventFilter::ByPayload(filter)).build())
+            .start();
+        assert!(matches!(
+            result,
+            Err(TraceError::EtwNativeError(EvntraceNativeError::InvalidFilter(EventFilterError::PayloadCompilationError(_))))
+        ));
+        assert!(backend.session_names().is_empty());
+    }
+}
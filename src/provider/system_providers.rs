@@ -0,0 +1,325 @@
+//! System Providers module
+//!
+//! Starting with Windows 10 20H2, kernel events can also be enabled through "system providers", using keywords rather than
+//! the kernel flags of the [`KernelTrace`](crate::trace::KernelTrace).<br/>
+//! Unlike kernel providers, they can be enabled in a [`UserTrace`](crate::trace::UserTrace), alongside regular user-mode providers.
+//! Such sessions are automatically started with `EVENT_TRACE_SYSTEM_LOGGER_MODE`, which is required to enable system providers.
+//!
+//! ```
+//! use ferrisetw::provider::Provider;
+//! use ferrisetw::provider::system_providers::{SystemProcessKeywords, SystemMemoryKeywords};
+//!
+//! let process_provider = Provider::system(SystemProcessKeywords::GENERAL | SystemProcessKeywords::THREAD).build();
+//! let memory_provider = Provider::system(SystemMemoryKeywords::HARD_FAULTS).build();
+//! ```
+//!
+//! Events from system providers are the classic kernel events, and they are reported with the GUID of their kernel event class
+//! (e.g. `Process` or `Thread`) rather than the GUID of the system provider. Callbacks of system providers are called for these events as well.
+//!
+//! More info: [System providers](https://learn.microsoft.com/en-us/windows/win32/etw/system-providers)
+
+use bitflags::bitflags;
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+use super::kernel_providers::kernel_guids;
+
+/// Keywords of a system provider
+///
+/// This is implemented by the keyword types of this module, and used by [`Provider::system`](crate::provider::Provider::system)
+pub trait SystemKeywords: Copy {
+    /// The GUID of the system provider
+    const PROVIDER_GUID: GUID;
+    /// The GUIDs of the kernel event classes this provider emits events for
+    const EVENT_GUIDS: &'static [GUID];
+
+    /// The raw keywords, that are used as the "Any" keywords of the provider
+    fn keywords(self) -> u64;
+}
+
+macro_rules! system_provider_keywords {
+    (
+        $(#[$doc:meta])*
+        $name:ident, $provider_guid:expr, [$($event_guid:expr),*] {
+            $(const $kw:ident = $value:expr;)*
+        }
+    ) => {
+        bitflags! {
+            $(#[$doc])*
+            pub struct $name: u64 {
+                $(const $kw = $value;)*
+            }
+        }
+
+        impl SystemKeywords for $name {
+            const PROVIDER_GUID: GUID = $provider_guid;
+            const EVENT_GUIDS: &'static [GUID] = &[$($event_guid),*];
+
+            fn keywords(self) -> u64 {
+                self.bits()
+            }
+        }
+    };
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemProcessProvider`
+    SystemProcessKeywords, Etw::SystemProcessProviderGuid,
+    [kernel_guids::PROCESS_GUID, kernel_guids::THREAD_GUID, kernel_guids::IMAGE_LOAD_GUID, kernel_guids::DEBUG_GUID] {
+        const GENERAL =         Etw::SYSTEM_PROCESS_KW_GENERAL;
+        const INSWAP =          Etw::SYSTEM_PROCESS_KW_INSWAP;
+        const FREEZE =          Etw::SYSTEM_PROCESS_KW_FREEZE;
+        const PERF_COUNTER =    Etw::SYSTEM_PROCESS_KW_PERF_COUNTER;
+        const WAKE_COUNTER =    Etw::SYSTEM_PROCESS_KW_WAKE_COUNTER;
+        const WAKE_DROP =       Etw::SYSTEM_PROCESS_KW_WAKE_DROP;
+        const WAKE_EVENT =      Etw::SYSTEM_PROCESS_KW_WAKE_EVENT;
+        const DEBUG_EVENTS =    Etw::SYSTEM_PROCESS_KW_DEBUG_EVENTS;
+        const DBGPRINT =        Etw::SYSTEM_PROCESS_KW_DBGPRINT;
+        const JOB =             Etw::SYSTEM_PROCESS_KW_JOB;
+        const WORKER_THREAD =   Etw::SYSTEM_PROCESS_KW_WORKER_THREAD;
+        const THREAD =          Etw::SYSTEM_PROCESS_KW_THREAD;
+        const LOADER =          Etw::SYSTEM_PROCESS_KW_LOADER;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemMemoryProvider`
+    SystemMemoryKeywords, Etw::SystemMemoryProviderGuid,
+    [kernel_guids::PAGE_FAULT_GUID, kernel_guids::POOL_TRACE_GUID, kernel_guids::HEAP_GUID] {
+        const GENERAL =         Etw::SYSTEM_MEMORY_KW_GENERAL;
+        const HARD_FAULTS =     Etw::SYSTEM_MEMORY_KW_HARD_FAULTS;
+        const ALL_FAULTS =      Etw::SYSTEM_MEMORY_KW_ALL_FAULTS;
+        const POOL =            Etw::SYSTEM_MEMORY_KW_POOL;
+        const MEMINFO =         Etw::SYSTEM_MEMORY_KW_MEMINFO;
+        const PFSECTION =       Etw::SYSTEM_MEMORY_KW_PFSECTION;
+        const MEMINFO_WS =      Etw::SYSTEM_MEMORY_KW_MEMINFO_WS;
+        const HEAP =            Etw::SYSTEM_MEMORY_KW_HEAP;
+        const WS =              Etw::SYSTEM_MEMORY_KW_WS;
+        const CONTMEM_GEN =     Etw::SYSTEM_MEMORY_KW_CONTMEM_GEN;
+        const VIRTUAL_ALLOC =   Etw::SYSTEM_MEMORY_KW_VIRTUAL_ALLOC;
+        const FOOTPRINT =       Etw::SYSTEM_MEMORY_KW_FOOTPRINT;
+        const SESSION =         Etw::SYSTEM_MEMORY_KW_SESSION;
+        const REFSET =          Etw::SYSTEM_MEMORY_KW_REFSET;
+        const VAMAP =           Etw::SYSTEM_MEMORY_KW_VAMAP;
+        const NONTRADEABLE =    Etw::SYSTEM_MEMORY_KW_NONTRADEABLE;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemIoProvider`
+    SystemIoKeywords, Etw::SystemIoProviderGuid,
+    [kernel_guids::DISK_IO_GUID, kernel_guids::FILE_IO_GUID, kernel_guids::SPLIT_IO_GUID, kernel_guids::TCP_IP_GUID, kernel_guids::UDP_IP_GUID] {
+        const DISK =            Etw::SYSTEM_IO_KW_DISK;
+        const DISK_INIT =       Etw::SYSTEM_IO_KW_DISK_INIT;
+        const FILENAME =        Etw::SYSTEM_IO_KW_FILENAME;
+        const SPLIT =           Etw::SYSTEM_IO_KW_SPLIT;
+        const FILE =            Etw::SYSTEM_IO_KW_FILE;
+        const OPTICAL =         Etw::SYSTEM_IO_KW_OPTICAL;
+        const OPTICAL_INIT =    Etw::SYSTEM_IO_KW_OPTICAL_INIT;
+        const DRIVERS =         Etw::SYSTEM_IO_KW_DRIVERS;
+        const CC =              Etw::SYSTEM_IO_KW_CC;
+        const NETWORK =         Etw::SYSTEM_IO_KW_NETWORK;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemSchedulerProvider`
+    SystemSchedulerKeywords, Etw::SystemSchedulerProviderGuid,
+    [kernel_guids::THREAD_GUID] {
+        const XSCHEDULER =      Etw::SYSTEM_SCHEDULER_KW_XSCHEDULER;
+        const DISPATCHER =      Etw::SYSTEM_SCHEDULER_KW_DISPATCHER;
+        const KERNEL_QUEUE =    Etw::SYSTEM_SCHEDULER_KW_KERNEL_QUEUE;
+        const SHOULD_YIELD =    Etw::SYSTEM_SCHEDULER_KW_SHOULD_YIELD;
+        const ANTI_STARVATION = Etw::SYSTEM_SCHEDULER_KW_ANTI_STARVATION;
+        const LOAD_BALANCER =   Etw::SYSTEM_SCHEDULER_KW_LOAD_BALANCER;
+        const AFFINITY =        Etw::SYSTEM_SCHEDULER_KW_AFFINITY;
+        const PRIORITY =        Etw::SYSTEM_SCHEDULER_KW_PRIORITY;
+        const IDEAL_PROCESSOR = Etw::SYSTEM_SCHEDULER_KW_IDEAL_PROCESSOR;
+        const CONTEXT_SWITCH =  Etw::SYSTEM_SCHEDULER_KW_CONTEXT_SWITCH;
+        const COMPACT_CSWITCH = Etw::SYSTEM_SCHEDULER_KW_COMPACT_CSWITCH;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemRegistryProvider`
+    SystemRegistryKeywords, Etw::SystemRegistryProviderGuid,
+    [kernel_guids::REGISTRY_GUID] {
+        const GENERAL =         Etw::SYSTEM_REGISTRY_KW_GENERAL;
+        const HIVE =            Etw::SYSTEM_REGISTRY_KW_HIVE;
+        const NOTIFICATION =    Etw::SYSTEM_REGISTRY_KW_NOTIFICATION;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemSyscallProvider`
+    SystemSyscallKeywords, Etw::SystemSyscallProviderGuid,
+    [kernel_guids::PERF_INFO_GUID] {
+        const GENERAL =         Etw::SYSTEM_SYSCALL_KW_GENERAL;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemTimerProvider`
+    SystemTimerKeywords, Etw::SystemTimerProviderGuid,
+    [kernel_guids::PERF_INFO_GUID] {
+        const GENERAL =         Etw::SYSTEM_TIMER_KW_GENERAL;
+        const CLOCK_TIMER =     Etw::SYSTEM_TIMER_KW_CLOCK_TIMER;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemProfileProvider`
+    SystemProfileKeywords, Etw::SystemProfileProviderGuid,
+    [kernel_guids::PERF_INFO_GUID] {
+        const GENERAL =         Etw::SYSTEM_PROFILE_KW_GENERAL;
+        const PMC_PROFILE =     Etw::SYSTEM_PROFILE_KW_PMC_PROFILE;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemInterruptProvider`
+    SystemInterruptKeywords, Etw::SystemInterruptProviderGuid,
+    [kernel_guids::PERF_INFO_GUID] {
+        const GENERAL =         Etw::SYSTEM_INTERRUPT_KW_GENERAL;
+        const CLOCK_INTERRUPT = Etw::SYSTEM_INTERRUPT_KW_CLOCK_INTERRUPT;
+        const DPC =             Etw::SYSTEM_INTERRUPT_KW_DPC;
+        const DPC_QUEUE =       Etw::SYSTEM_INTERRUPT_KW_DPC_QUEUE;
+        const WDF_DPC =         Etw::SYSTEM_INTERRUPT_KW_WDF_DPC;
+        const WDF_INTERRUPT =   Etw::SYSTEM_INTERRUPT_KW_WDF_INTERRUPT;
+        const IPI =             Etw::SYSTEM_INTERRUPT_KW_IPI;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemLockProvider`
+    SystemLockKeywords, Etw::SystemLockProviderGuid,
+    [kernel_guids::PERF_INFO_GUID] {
+        const SPINLOCK =            Etw::SYSTEM_LOCK_KW_SPINLOCK;
+        const SPINLOCK_COUNTERS =   Etw::SYSTEM_LOCK_KW_SPINLOCK_COUNTERS;
+        const SYNC_OBJECTS =        Etw::SYSTEM_LOCK_KW_SYNC_OBJECTS;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemObjectProvider`
+    SystemObjectKeywords, Etw::SystemObjectProviderGuid,
+    [kernel_guids::OB_TRACE_GUID] {
+        const GENERAL =         Etw::SYSTEM_OBJECT_KW_GENERAL;
+        const HANDLE =          Etw::SYSTEM_OBJECT_KW_HANDLE;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemAlpcProvider`
+    SystemAlpcKeywords, Etw::SystemAlpcProviderGuid,
+    [kernel_guids::ALPC_GUID] {
+        const GENERAL =         Etw::SYSTEM_ALPC_KW_GENERAL;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemPowerProvider`
+    SystemPowerKeywords, Etw::SystemPowerProviderGuid,
+    [kernel_guids::POWER_GUID] {
+        const GENERAL =             Etw::SYSTEM_POWER_KW_GENERAL;
+        const HIBER_RUNDOWN =       Etw::SYSTEM_POWER_KW_HIBER_RUNDOWN;
+        const PROCESSOR_IDLE =      Etw::SYSTEM_POWER_KW_PROCESSOR_IDLE;
+        const IDLE_SELECTION =      Etw::SYSTEM_POWER_KW_IDLE_SELECTION;
+        const PPM_EXIT_LATENCY =    Etw::SYSTEM_POWER_KW_PPM_EXIT_LATENCY;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemConfigProvider`
+    SystemConfigKeywords, Etw::SystemConfigProviderGuid,
+    [kernel_guids::EVENT_TRACE_CONFIG_GUID] {
+        const SYSTEM =          Etw::SYSTEM_CONFIG_KW_SYSTEM;
+        const GRAPHICS =        Etw::SYSTEM_CONFIG_KW_GRAPHICS;
+        const STORAGE =         Etw::SYSTEM_CONFIG_KW_STORAGE;
+        const NETWORK =         Etw::SYSTEM_CONFIG_KW_NETWORK;
+        const SERVICES =        Etw::SYSTEM_CONFIG_KW_SERVICES;
+        const PNP =             Etw::SYSTEM_CONFIG_KW_PNP;
+        const OPTICAL =         Etw::SYSTEM_CONFIG_KW_OPTICAL;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemCpuProvider`
+    SystemCpuKeywords, Etw::SystemCpuProviderGuid, [] {
+        const CONFIG =          Etw::SYSTEM_CPU_KW_CONFIG;
+        const CACHE_FLUSH =     Etw::SYSTEM_CPU_KW_CACHE_FLUSH;
+        const SPEC_CONTROL =    Etw::SYSTEM_CPU_KW_SPEC_CONTROL;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemHypervisorProvider`
+    SystemHypervisorKeywords, Etw::SystemHypervisorProviderGuid, [] {
+        const PROFILE =         Etw::SYSTEM_HYPERVISOR_KW_PROFILE;
+        const CALLOUTS =        Etw::SYSTEM_HYPERVISOR_KW_CALLOUTS;
+        const VTL_CHANGE =      Etw::SYSTEM_HYPERVISOR_KW_VTL_CHANGE;
+    }
+}
+
+system_provider_keywords! {
+    /// Keywords of the `SystemIoFilterProvider`
+    SystemIoFilterKeywords, Etw::SystemIoFilterProviderGuid, [] {
+        const GENERAL =         Etw::SYSTEM_IOFILTER_KW_GENERAL;
+        const INIT =            Etw::SYSTEM_IOFILTER_KW_INIT;
+        const FASTIO =          Etw::SYSTEM_IOFILTER_KW_FASTIO;
+        const FAILURE =         Etw::SYSTEM_IOFILTER_KW_FAILURE;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::Provider;
+
+    #[test]
+    fn test_system_provider() {
+        let provider = Provider::system(SystemProcessKeywords::GENERAL | SystemProcessKeywords::THREAD).build();
+
+        assert_eq!(provider.guid(), GUID::from("151f55dc-467d-471f-83b5-5f889d46ff66"));
+        assert_eq!(provider.any(), 0x801);
+        assert!(provider.is_system_provider());
+
+        // Events are reported with the GUID of their kernel event class
+        assert!(provider.receives(kernel_guids::THREAD_GUID));
+        assert!(provider.receives(provider.guid()));
+        assert!(!provider.receives(kernel_guids::REGISTRY_GUID));
+
+        let user_provider = Provider::by_guid(kernel_guids::THREAD_GUID).build();
+        assert!(!user_provider.is_system_provider());
+        assert!(!user_provider.receives(kernel_guids::PROCESS_GUID));
+    }
+
+    #[test]
+    fn test_system_provider_session() {
+        use crate::native::etw_types::LoggingMode;
+        use crate::native::evntrace::backend::fake::fake_user_trace;
+        use crate::record_builder::EventRecordBuilder;
+        use crate::trace::{TraceTrait, UserTrace};
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(16);
+        let (builder, backend) = fake_user_trace("fake-system-providers");
+        let (trace, handle) = builder
+            .enable(Provider::system(SystemProcessKeywords::GENERAL)
+                .add_callback(move |record, _locator| sender.send(record.opcode()).unwrap())
+                .build())
+            .start()
+            .unwrap();
+        let processing_thread = std::thread::spawn(move || UserTrace::process_from_handle(handle));
+
+        let log_file_mode = backend.log_file_mode("fake-system-providers").unwrap();
+        assert!(log_file_mode & LoggingMode::EVENT_TRACE_SYSTEM_LOGGER_MODE.bits() != 0);
+        assert!(log_file_mode & LoggingMode::EVENT_TRACE_REAL_TIME_MODE.bits() != 0);
+
+        // Events are emitted with the GUID of the Process kernel event class
+        backend.emit("fake-system-providers", &EventRecordBuilder::new(kernel_guids::PROCESS_GUID).opcode(1).build());
+        assert_eq!(receiver.recv().unwrap(), 1);
+
+        trace.stop().unwrap();
+        processing_thread.join().unwrap().unwrap();
+    }
+}
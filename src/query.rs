@@ -5,6 +5,7 @@ use zerocopy::AsBytes;
 
 use crate::{
     native::{etw_types::TraceInformation, evntrace},
+    native::evntrace::backend::{default_backend, EvntraceBackend},
     trace::TraceError,
 };
 
@@ -15,33 +16,54 @@ pub enum ProfileSource {
     ProfileTime = 0,
 }
 
+impl From<ProfileSource> for u32 {
+    fn from(source: ProfileSource) -> u32 {
+        source as u32
+    }
+}
+
+/// A profile source, i.e. something that can trigger `SampledProfile` events, or that can be used as a PMC counter
+///
+/// See [`SessionlessInfo::profile_sources`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileSourceInfo {
+    /// Identifier of the source, that is used by the other APIs (e.g. [`SessionlessInfo::set_sample_interval`])
+    pub source: u32,
+    /// Minimum sampling interval, in 100-nanosecond units
+    pub min_interval: u32,
+    /// Maximum sampling interval, in 100-nanosecond units
+    pub max_interval: u32,
+    /// Name of the source (e.g. `Timer`, `TotalIssues`, `BranchMispredictions`, etc.)
+    pub description: String,
+}
+
 pub struct SessionlessInfo;
 
 impl SessionlessInfo {
-    pub fn sample_interval(source: ProfileSource) -> TraceResult<u32> {
-        let mut info = TRACE_PROFILE_INTERVAL {
-            Source: source as u32,
-            Interval: 0,
-        };
+    /// The sampling interval of a profile source, in 100-nanosecond units
+    pub fn sample_interval<S: Into<u32>>(source: S) -> TraceResult<u32> {
+        sample_interval_with(default_backend().as_ref(), source.into())
+    }
 
-        evntrace::query_info(
-            TraceInformation::TraceSampledProfileIntervalInfo,
-            // SAFETY: TRACE_PROFILE_INTERVAL is `#[repr(C)]` and uses only POD
-            unsafe {
-                std::slice::from_raw_parts_mut(
-                    &mut info as *mut _ as *mut u8,
-                    std::mem::size_of::<TRACE_PROFILE_INTERVAL>(),
-                )
-            },
-        )?;
+    /// Change the sampling interval of a profile source, in 100-nanosecond units
+    ///
+    /// This is a system-wide setting, that affects every session (and that requires administrator privileges).
+    /// For instance, this sets the rate of the `SampledProfile` events of the [`PROFILE_PROVIDER`](crate::provider::kernel_providers::PROFILE_PROVIDER) when `source` is [`ProfileSource::ProfileTime`].
+    pub fn set_sample_interval<S: Into<u32>>(source: S, interval: u32) -> TraceResult<()> {
+        set_sample_interval_with(default_backend().as_ref(), source.into(), interval)
+    }
 
-        Ok(info.Interval)
+    /// List the profile sources that are supported by this computer
+    pub fn profile_sources() -> TraceResult<Vec<ProfileSourceInfo>> {
+        let buf = evntrace::query_info_to_vec(default_backend().as_ref(), TraceInformation::TraceProfileSourceListInfo)?;
+        Ok(parse_profile_sources(&buf))
     }
 
     pub fn max_pmc() -> TraceResult<u32> {
         let mut max_pmc = 0u32;
 
         evntrace::query_info(
+            default_backend().as_ref(),
             TraceInformation::TraceMaxPmcCounterQuery,
             max_pmc.as_bytes_mut(),
         )?;
@@ -49,3 +71,129 @@ impl SessionlessInfo {
         Ok(max_pmc)
     }
 }
+
+fn sample_interval_with(backend: &dyn EvntraceBackend, source: u32) -> TraceResult<u32> {
+    let mut info = TRACE_PROFILE_INTERVAL {
+        Source: source,
+        Interval: 0,
+    };
+
+    evntrace::query_info(
+        backend,
+        TraceInformation::TraceSampledProfileIntervalInfo,
+        // SAFETY: TRACE_PROFILE_INTERVAL is `#[repr(C)]` and uses only POD
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut info as *mut _ as *mut u8,
+                std::mem::size_of::<TRACE_PROFILE_INTERVAL>(),
+            )
+        },
+    )?;
+
+    Ok(info.Interval)
+}
+
+fn set_sample_interval_with(backend: &dyn EvntraceBackend, source: u32, interval: u32) -> TraceResult<()> {
+    let info = TRACE_PROFILE_INTERVAL {
+        Source: source,
+        Interval: interval,
+    };
+
+    evntrace::set_sessionless_info(
+        backend,
+        TraceInformation::TraceSampledProfileIntervalInfo,
+        // SAFETY: TRACE_PROFILE_INTERVAL is `#[repr(C)]` and uses only POD
+        unsafe {
+            std::slice::from_raw_parts(
+                &info as *const _ as *const u8,
+                std::mem::size_of::<TRACE_PROFILE_INTERVAL>(),
+            )
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Parse a list of [PROFILE_SOURCE_INFO](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-profile_source_info)
+fn parse_profile_sources(buf: &[u8]) -> Vec<ProfileSourceInfo> {
+    // NextEntryOffset, Source, MinInterval, MaxInterval (4 bytes each), Reserved (8 bytes), then a null-terminated Description
+    const DESCRIPTION_OFFSET: usize = 24;
+    let read_u32 = |bytes: &[u8], offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+
+    let mut sources = Vec::new();
+    let mut remaining = buf;
+    while remaining.len() >= DESCRIPTION_OFFSET {
+        let next_entry_offset = read_u32(remaining, 0) as usize;
+        let entry_end = if next_entry_offset == 0 || next_entry_offset > remaining.len() {
+            remaining.len()
+        } else {
+            next_entry_offset
+        };
+
+        let description: Vec<u16> = remaining[DESCRIPTION_OFFSET..entry_end.max(DESCRIPTION_OFFSET)]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+
+        sources.push(ProfileSourceInfo {
+            source: read_u32(remaining, 4),
+            min_interval: read_u32(remaining, 8),
+            max_interval: read_u32(remaining, 12),
+            description: String::from_utf16_lossy(&description),
+        });
+
+        if next_entry_offset == 0 || next_entry_offset > remaining.len() {
+            break;
+        }
+        remaining = &remaining[next_entry_offset..];
+    }
+    sources
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::native::evntrace::backend::fake::FakeBackend;
+
+    fn profile_source_entry(last: bool, source: u32, min: u32, max: u32, description: &str) -> Vec<u8> {
+        let mut wide_description: Vec<u8> = description.encode_utf16().chain(std::iter::once(0)).flat_map(|c| c.to_le_bytes()).collect();
+        // Entries are 8-byte aligned
+        while !(24 + wide_description.len()).is_multiple_of(8) {
+            wide_description.push(0);
+        }
+        let next_entry_offset = if last { 0 } else { 24 + wide_description.len() as u32 };
+
+        let mut entry = Vec::new();
+        for value in [next_entry_offset, source, min, max] {
+            entry.extend_from_slice(&value.to_le_bytes());
+        }
+        entry.extend_from_slice(&[0; 8]);
+        entry.extend_from_slice(&wide_description);
+        entry
+    }
+
+    #[test]
+    fn test_profile_sources() {
+        let mut buf = profile_source_entry(false, 0, 1221, 1000000, "Timer");
+        buf.extend(profile_source_entry(true, 11, 4096, 0xffffffff, "BranchMispredictions"));
+
+        assert_eq!(parse_profile_sources(&buf), vec![
+            ProfileSourceInfo { source: 0, min_interval: 1221, max_interval: 1000000, description: String::from("Timer") },
+            ProfileSourceInfo { source: 11, min_interval: 4096, max_interval: 0xffffffff, description: String::from("BranchMispredictions") },
+        ]);
+        assert!(parse_profile_sources(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_sample_interval() {
+        let backend = FakeBackend::new();
+        set_sample_interval_with(&backend, ProfileSource::ProfileTime.into(), 1221).unwrap();
+        assert_eq!(sample_interval_with(&backend, ProfileSource::ProfileTime.into()).unwrap(), 1221);
+
+        // Larger outputs are fetched in several attempts
+        let large = vec![0u8; 10_000];
+        backend.set_system_information(TraceInformation::TraceProfileSourceListInfo, large.clone());
+        assert_eq!(evntrace::query_info_to_vec(&backend, TraceInformation::TraceProfileSourceListInfo).unwrap(), large);
+    }
+}
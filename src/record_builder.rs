@@ -0,0 +1,681 @@
+//! Synthesize events, e.g. to test callbacks and parsers
+//!
+//! Actual [`EventRecord`](crate::EventRecord)s are only given by ETW, in trace callbacks.
+//! This module makes it possible to build events (and the [`Schema`]s needed to parse them) from scratch, in a deterministic way.
+//!
+//! ```
+//! use ferrisetw::GUID;
+//! use ferrisetw::native::{TdhInType, TdhOutType};
+//! use ferrisetw::parser::Parser;
+//! use ferrisetw::record_builder::{EventRecordBuilder, PropertyValue, SchemaBuilder};
+//!
+//! let provider = GUID::from("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716");
+//! let schema = SchemaBuilder::new(provider, 1)
+//!     .add_property("ProcessID", TdhInType::InTypeUInt32, TdhOutType::OutTypeUInt32)
+//!     .add_property("ImageName", TdhInType::InTypeUnicodeString, TdhOutType::OutTypeString)
+//!     .build();
+//!
+//! let record = EventRecordBuilder::new(provider)
+//!     .event_id(1)
+//!     .process_id(1234)
+//!     .properties(&schema, &[
+//!         ("ProcessID", PropertyValue::U32(1234)),
+//!         ("ImageName", PropertyValue::String("notepad.exe".to_string())),
+//!     ])
+//!     .unwrap()
+//!     .build();
+//!
+//! let parser = Parser::create(&record, &schema);
+//! let image_name: String = parser.try_parse("ImageName").unwrap();
+//! assert_eq!(image_name, "notepad.exe");
+//! ```
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::{
+    self, EVENT_HEADER, ETW_BUFFER_CONTEXT, EVENT_HEADER_EXTENDED_DATA_ITEM, TRACE_EVENT_INFO, EVENT_PROPERTY_INFO,
+};
+
+use crate::native::etw_types::event_record::OwnedEventRecord;
+use crate::native::tdh::TraceEventInfo;
+use crate::native::tdh_types::{TdhInType, TdhOutType};
+use crate::schema::Schema;
+
+/// Record builder module errors
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecordBuilderError {
+    /// No value has been given for this property of the schema
+    MissingValue(String),
+    /// A value has been given for a property that is not part of the schema
+    UnknownProperty(String),
+    /// The value type is not compatible with the type of this property
+    TypeMismatch(String),
+    /// The value size does not match the fixed size of this property
+    LengthMismatch(String),
+    /// The schema contains properties that are not supported by this crate (yet?)
+    UnsupportedProperties,
+}
+
+impl std::fmt::Display for RecordBuilderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordBuilderError::MissingValue(name) => write!(f, "no value has been given for property {}", name),
+            RecordBuilderError::UnknownProperty(name) => write!(f, "property {} is not part of the schema", name),
+            RecordBuilderError::TypeMismatch(name) => write!(f, "the value type does not match the type of property {}", name),
+            RecordBuilderError::LengthMismatch(name) => write!(f, "the value size does not match the size of property {}", name),
+            RecordBuilderError::UnsupportedProperties => write!(f, "the schema contains properties that are not supported"),
+        }
+    }
+}
+
+impl std::error::Error for RecordBuilderError {}
+
+pub type RecordBuilderResult<T> = Result<T, RecordBuilderError>;
+
+/// Build an [`OwnedEventRecord`] from scratch
+///
+/// Unless specified otherwise, every field is zeroed, and the event looks like it has been emitted by a 64-bit process.
+#[derive(Clone)]
+pub struct EventRecordBuilder {
+    header: EVENT_HEADER,
+    buffer_context: ETW_BUFFER_CONTEXT,
+    user_data: Vec<u8>,
+    extended_data: Vec<(EVENT_HEADER_EXTENDED_DATA_ITEM, Vec<u8>)>,
+}
+
+impl std::fmt::Debug for EventRecordBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventRecordBuilder")
+            .field("provider_id", &self.header.ProviderId)
+            .field("event_id", &self.header.EventDescriptor.Id)
+            .field("user_data_len", &self.user_data.len())
+            .field("extended_data_count", &self.extended_data.len())
+            .finish()
+    }
+}
+
+impl EventRecordBuilder {
+    /// Start building an event from the given provider
+    pub fn new(provider_id: GUID) -> Self {
+        let header = EVENT_HEADER {
+            Size: std::mem::size_of::<EVENT_HEADER>() as u16,
+            Flags: Etw::EVENT_HEADER_FLAG_64_BIT_HEADER as u16,
+            ProviderId: provider_id,
+            ..Default::default()
+        };
+
+        Self {
+            header,
+            buffer_context: ETW_BUFFER_CONTEXT::default(),
+            user_data: Vec::new(),
+            extended_data: Vec::new(),
+        }
+    }
+
+    pub fn event_id(mut self, id: u16) -> Self {
+        self.header.EventDescriptor.Id = id;
+        self
+    }
+
+    pub fn version(mut self, version: u8) -> Self {
+        self.header.EventDescriptor.Version = version;
+        self
+    }
+
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.header.EventDescriptor.Opcode = opcode;
+        self
+    }
+
+    pub fn level(mut self, level: u8) -> Self {
+        self.header.EventDescriptor.Level = level;
+        self
+    }
+
+    pub fn task(mut self, task: u16) -> Self {
+        self.header.EventDescriptor.Task = task;
+        self
+    }
+
+    pub fn channel(mut self, channel: u8) -> Self {
+        self.header.EventDescriptor.Channel = channel;
+        self
+    }
+
+    pub fn keywords(mut self, keywords: u64) -> Self {
+        self.header.EventDescriptor.Keyword = keywords;
+        self
+    }
+
+    pub fn process_id(mut self, pid: u32) -> Self {
+        self.header.ProcessId = pid;
+        self
+    }
+
+    pub fn thread_id(mut self, tid: u32) -> Self {
+        self.header.ThreadId = tid;
+        self
+    }
+
+    /// Set the raw timestamp (see [`EventRecord::raw_timestamp`](crate::EventRecord::raw_timestamp))
+    pub fn timestamp(mut self, timestamp: i64) -> Self {
+        self.header.TimeStamp = timestamp;
+        self
+    }
+
+    pub fn activity_id(mut self, activity_id: GUID) -> Self {
+        self.header.ActivityId = activity_id;
+        self
+    }
+
+    /// Set the `EVENT_HEADER_FLAG_*` flags of the event.
+    ///
+    /// This overwrites the flags set by [`Self::is_32_bit`].
+    /// Note that `EVENT_HEADER_FLAG_EXTENDED_INFO` is automatically set when building, in case extended data items have been added.
+    pub fn flags(mut self, flags: u16) -> Self {
+        self.header.Flags = flags;
+        self
+    }
+
+    /// Whether the event looks like it has been emitted by a 32-bit process (`EVENT_HEADER_FLAG_32_BIT_HEADER`) or by a 64-bit process (`EVENT_HEADER_FLAG_64_BIT_HEADER`)
+    ///
+    /// This changes the size of pointers in the user data, so this must be called before [`Self::properties`].
+    pub fn is_32_bit(mut self, is_32_bit: bool) -> Self {
+        let flag_32 = Etw::EVENT_HEADER_FLAG_32_BIT_HEADER as u16;
+        let flag_64 = Etw::EVENT_HEADER_FLAG_64_BIT_HEADER as u16;
+        if is_32_bit {
+            self.header.Flags = (self.header.Flags & !flag_64) | flag_32;
+        } else {
+            self.header.Flags = (self.header.Flags & !flag_32) | flag_64;
+        }
+        self
+    }
+
+    /// Set the user data (i.e. the payload) of the event
+    ///
+    /// See [`Self::properties`] for a higher-level way of setting it.
+    pub fn user_data(mut self, data: Vec<u8>) -> Self {
+        self.user_data = data;
+        self
+    }
+
+    /// Set the user data (i.e. the payload) of the event, serialized from property values
+    ///
+    /// See [`serialize_properties`] for more info.
+    pub fn properties(mut self, schema: &Schema, values: &[(&str, PropertyValue)]) -> RecordBuilderResult<Self> {
+        let is_32_bit = self.header.Flags & (Etw::EVENT_HEADER_FLAG_32_BIT_HEADER as u16) != 0;
+        self.user_data = serialize_properties(schema, values, is_32_bit)?;
+        Ok(self)
+    }
+
+    /// Add an extended data item of any type
+    pub fn extended_data(mut self, ext_type: u16, data: Vec<u8>) -> Self {
+        let item = EVENT_HEADER_EXTENDED_DATA_ITEM {
+            ExtType: ext_type,
+            ..Default::default()
+        };
+        self.extended_data.push((item, data));
+        self
+    }
+
+    /// Add a `EVENT_HEADER_EXT_TYPE_SID` extended data item, from the binary representation of a SID
+    pub fn sid(self, sid: &[u8]) -> Self {
+        self.extended_data(Etw::EVENT_HEADER_EXT_TYPE_SID as u16, sid.to_vec())
+    }
+
+    /// Add a `EVENT_HEADER_EXT_TYPE_STACK_TRACE32` extended data item
+    pub fn stack_trace32(self, match_id: u64, addresses: &[u32]) -> Self {
+        let mut data = match_id.to_le_bytes().to_vec();
+        data.extend(addresses.iter().flat_map(|a| a.to_le_bytes()));
+        self.extended_data(Etw::EVENT_HEADER_EXT_TYPE_STACK_TRACE32 as u16, data)
+    }
+
+    /// Add a `EVENT_HEADER_EXT_TYPE_STACK_TRACE64` extended data item
+    pub fn stack_trace64(self, match_id: u64, addresses: &[u64]) -> Self {
+        let mut data = match_id.to_le_bytes().to_vec();
+        data.extend(addresses.iter().flat_map(|a| a.to_le_bytes()));
+        self.extended_data(Etw::EVENT_HEADER_EXT_TYPE_STACK_TRACE64 as u16, data)
+    }
+
+    /// Add a `EVENT_HEADER_EXT_TYPE_RELATED_ACTIVITYID` extended data item
+    pub fn related_activity_id(self, related_activity_id: GUID) -> Self {
+        self.extended_data(Etw::EVENT_HEADER_EXT_TYPE_RELATED_ACTIVITYID as u16, guid_to_bytes(&related_activity_id).to_vec())
+    }
+
+    /// Add a `EVENT_HEADER_EXT_TYPE_TS_ID` (terminal session ID) extended data item
+    pub fn ts_id(self, session_id: u32) -> Self {
+        self.extended_data(Etw::EVENT_HEADER_EXT_TYPE_TS_ID as u16, session_id.to_le_bytes().to_vec())
+    }
+
+    /// Add a `EVENT_HEADER_EXT_TYPE_PROCESS_START_KEY` extended data item
+    pub fn process_start_key(self, key: u64) -> Self {
+        self.extended_data(Etw::EVENT_HEADER_EXT_TYPE_PROCESS_START_KEY as u16, key.to_le_bytes().to_vec())
+    }
+
+    /// Add a `EVENT_HEADER_EXT_TYPE_PMC_COUNTERS` extended data item
+    pub fn pmc_counters(self, counters: &[u64]) -> Self {
+        self.extended_data(Etw::EVENT_HEADER_EXT_TYPE_PMC_COUNTERS as u16, counters.iter().flat_map(|c| c.to_le_bytes()).collect())
+    }
+
+    /// Add a `EVENT_HEADER_EXT_TYPE_EVENT_KEY` extended data item
+    pub fn event_key(self, key: u64) -> Self {
+        self.extended_data(Etw::EVENT_HEADER_EXT_TYPE_EVENT_KEY as u16, key.to_le_bytes().to_vec())
+    }
+
+    pub fn build(self) -> OwnedEventRecord {
+        let mut header = self.header;
+        if !self.extended_data.is_empty() {
+            header.Flags |= Etw::EVENT_HEADER_FLAG_EXTENDED_INFO as u16;
+        }
+        OwnedEventRecord::from_parts(header, self.buffer_context, self.user_data, self.extended_data)
+    }
+}
+
+/// A typed value for a property, see [`serialize_properties`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    /// Serialized as a 4-byte `BOOL`
+    Bool(bool),
+    /// Serialized as a null-terminated UTF-16 string, a null-terminated ANSI string or a counted string, depending on the property type
+    String(String),
+    Guid(GUID),
+    /// Serialized as 4 or 8 bytes, depending on whether the event is from a 32-bit process
+    Pointer(u64),
+    /// Raw bytes, e.g. for binary, SID or SYSTEMTIME properties
+    Binary(Vec<u8>),
+}
+
+/// Serialize property values into the user data of an event, according to a schema
+///
+/// Every property of the schema must be given a value, in any order. The value types must be compatible with the property types.
+/// `is_32_bit` tells whether pointers should be serialized as 32-bit or 64-bit values.
+pub fn serialize_properties(schema: &Schema, values: &[(&str, PropertyValue)], is_32_bit: bool) -> RecordBuilderResult<Vec<u8>> {
+    let properties = schema.properties();
+    if properties.len() != schema.te_info().property_count() {
+        return Err(RecordBuilderError::UnsupportedProperties);
+    }
+
+    if let Some((unknown, _)) = values.iter().find(|(name, _)| properties.iter().all(|p| p.name != *name)) {
+        return Err(RecordBuilderError::UnknownProperty(unknown.to_string()));
+    }
+
+    let mut buffer = Vec::new();
+    for property in properties {
+        let value = values
+            .iter()
+            .find(|(name, _)| *name == property.name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| RecordBuilderError::MissingValue(property.name.clone()))?;
+
+        let type_mismatch = || RecordBuilderError::TypeMismatch(property.name.clone());
+        let bytes = match (property.in_type(), value) {
+            (TdhInType::InTypeUnicodeString, PropertyValue::String(s)) => {
+                s.encode_utf16().chain(std::iter::once(0)).flat_map(|c| c.to_le_bytes()).collect()
+            },
+            (TdhInType::InTypeAnsiString, PropertyValue::String(s)) => {
+                let mut bytes = s.as_bytes().to_vec();
+                bytes.push(0);
+                bytes
+            },
+            (TdhInType::InTypeCountedString, PropertyValue::String(s)) => {
+                let chars: Vec<u8> = s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+                let mut bytes = (chars.len() as u16).to_le_bytes().to_vec();
+                bytes.extend(chars);
+                bytes
+            },
+            (TdhInType::InTypePointer, PropertyValue::Pointer(p)) => {
+                if is_32_bit {
+                    (*p as u32).to_le_bytes().to_vec()
+                } else {
+                    p.to_le_bytes().to_vec()
+                }
+            },
+            (TdhInType::InTypeBoolean, PropertyValue::Bool(b)) => (*b as u32).to_le_bytes().to_vec(),
+            (TdhInType::InTypeFloat, PropertyValue::F32(f)) => f.to_le_bytes().to_vec(),
+            (TdhInType::InTypeDouble, PropertyValue::F64(f)) => f.to_le_bytes().to_vec(),
+            (TdhInType::InTypeGuid, PropertyValue::Guid(g)) => guid_to_bytes(g).to_vec(),
+            (TdhInType::InTypeBinary, PropertyValue::Binary(b))
+            | (TdhInType::InTypeSid, PropertyValue::Binary(b))
+            | (TdhInType::InTypeSystemTime, PropertyValue::Binary(b)) => b.clone(),
+            (in_type, value) => {
+                let expected_size = integer_size(in_type).ok_or_else(type_mismatch)?;
+                let bytes = integer_bytes(value).ok_or_else(type_mismatch)?;
+                if bytes.len() != expected_size {
+                    return Err(type_mismatch());
+                }
+                bytes
+            },
+        };
+
+        // Properties may have a fixed length (that is not relevant for pointers, whose size depend on the event bitness)
+        if property.in_type() != TdhInType::InTypePointer && property.len() > 0 && property.len() != bytes.len() {
+            return Err(RecordBuilderError::LengthMismatch(property.name.clone()));
+        }
+
+        buffer.extend(bytes);
+    }
+
+    Ok(buffer)
+}
+
+/// The size of the integer in-types
+fn integer_size(in_type: TdhInType) -> Option<usize> {
+    match in_type {
+        TdhInType::InTypeInt8 | TdhInType::InTypeUInt8 => Some(1),
+        TdhInType::InTypeInt16 | TdhInType::InTypeUInt16 => Some(2),
+        TdhInType::InTypeInt32 | TdhInType::InTypeUInt32 | TdhInType::InTypeHexInt32 => Some(4),
+        TdhInType::InTypeInt64 | TdhInType::InTypeUInt64 | TdhInType::InTypeHexInt64 | TdhInType::InTypeFileTime => Some(8),
+        _ => None,
+    }
+}
+
+fn integer_bytes(value: &PropertyValue) -> Option<Vec<u8>> {
+    let bytes = match value {
+        PropertyValue::I8(v) => v.to_le_bytes().to_vec(),
+        PropertyValue::U8(v) => v.to_le_bytes().to_vec(),
+        PropertyValue::I16(v) => v.to_le_bytes().to_vec(),
+        PropertyValue::U16(v) => v.to_le_bytes().to_vec(),
+        PropertyValue::I32(v) => v.to_le_bytes().to_vec(),
+        PropertyValue::U32(v) => v.to_le_bytes().to_vec(),
+        PropertyValue::I64(v) => v.to_le_bytes().to_vec(),
+        PropertyValue::U64(v) => v.to_le_bytes().to_vec(),
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+fn guid_to_bytes(guid: &GUID) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[0..4].copy_from_slice(&guid.data1.to_le_bytes());
+    bytes[4..6].copy_from_slice(&guid.data2.to_le_bytes());
+    bytes[6..8].copy_from_slice(&guid.data3.to_le_bytes());
+    bytes[8..16].copy_from_slice(&guid.data4);
+    bytes
+}
+
+/// Build a [`Schema`] from scratch
+///
+/// This builds the same kind of `TRACE_EVENT_INFO` TDH would return for a manifest-based event.
+/// Properties are always top-level, non-struct properties.
+#[derive(Debug, Clone)]
+pub struct SchemaBuilder {
+    provider_guid: GUID,
+    event_id: u16,
+    version: u8,
+    opcode: u8,
+    level: u8,
+    provider_name: String,
+    task_name: String,
+    opcode_name: String,
+    /// Name, in type, out type, fixed length (or 0)
+    properties: Vec<(String, TdhInType, TdhOutType, u16)>,
+}
+
+impl SchemaBuilder {
+    pub fn new(provider_guid: GUID, event_id: u16) -> Self {
+        Self {
+            provider_guid,
+            event_id,
+            version: 0,
+            opcode: 0,
+            level: 0,
+            provider_name: String::new(),
+            task_name: String::new(),
+            opcode_name: String::new(),
+            properties: Vec::new(),
+        }
+    }
+
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.opcode = opcode;
+        self
+    }
+
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn provider_name(mut self, name: &str) -> Self {
+        self.provider_name = name.to_string();
+        self
+    }
+
+    pub fn task_name(mut self, name: &str) -> Self {
+        self.task_name = name.to_string();
+        self
+    }
+
+    pub fn opcode_name(mut self, name: &str) -> Self {
+        self.opcode_name = name.to_string();
+        self
+    }
+
+    /// Add a property, whose size is implied by its type (e.g. integers, or null-terminated strings)
+    pub fn add_property(self, name: &str, in_type: TdhInType, out_type: TdhOutType) -> Self {
+        // Like TDH does, let's set the length of fixed-size types
+        let length = match in_type {
+            TdhInType::InTypeBoolean | TdhInType::InTypeFloat => 4,
+            TdhInType::InTypeDouble => 8,
+            TdhInType::InTypeGuid | TdhInType::InTypeSystemTime => 16,
+            // Pointers are 8-byte long, or 4-byte long when read from a 32-bit event
+            TdhInType::InTypePointer => 8,
+            in_type => integer_size(in_type).unwrap_or(0) as u16,
+        };
+        self.add_fixed_length_property(name, in_type, out_type, length)
+    }
+
+    /// Add a property that has a fixed length (in bytes). This is typically needed for binary properties.
+    pub fn add_fixed_length_property(mut self, name: &str, in_type: TdhInType, out_type: TdhOutType, length: u16) -> Self {
+        self.properties.push((name.to_string(), in_type, out_type, length));
+        self
+    }
+
+    pub fn build(self) -> Schema {
+        let property_size = std::mem::size_of::<EVENT_PROPERTY_INFO>();
+        let properties_offset = offset_of!(TRACE_EVENT_INFO, EventPropertyInfoArray);
+        let header_size = (properties_offset + self.properties.len() * property_size).max(std::mem::size_of::<TRACE_EVENT_INFO>());
+
+        let mut buffer = vec![0u8; header_size];
+        let push_string = |buffer: &mut Vec<u8>, s: &str| -> u32 {
+            if s.is_empty() {
+                return 0;
+            }
+            let offset = buffer.len() as u32;
+            buffer.extend(s.encode_utf16().chain(std::iter::once(0)).flat_map(|c| c.to_ne_bytes()));
+            offset
+        };
+
+        let provider_name_offset = push_string(&mut buffer, &self.provider_name);
+        let task_name_offset = push_string(&mut buffer, &self.task_name);
+        let opcode_name_offset = push_string(&mut buffer, &self.opcode_name);
+
+        let mut infos = Vec::with_capacity(self.properties.len());
+        for (name, in_type, out_type, length) in &self.properties {
+            let mut info = EVENT_PROPERTY_INFO {
+                NameOffset: push_string(&mut buffer, name),
+                ..Default::default()
+            };
+            info.Anonymous1.nonStructType.InType = *in_type as u16;
+            info.Anonymous1.nonStructType.OutType = *out_type as u16;
+            info.Anonymous2.count = 1;
+            info.Anonymous3.length = *length;
+            infos.push(info);
+        }
+
+        let mut te_info = TRACE_EVENT_INFO {
+            ProviderGuid: self.provider_guid,
+            DecodingSource: Etw::DecodingSourceXMLFile,
+            ProviderNameOffset: provider_name_offset,
+            TaskNameOffset: task_name_offset,
+            OpcodeNameOffset: opcode_name_offset,
+            PropertyCount: infos.len() as u32,
+            TopLevelPropertyCount: infos.len() as u32,
+            ..Default::default()
+        };
+        te_info.EventDescriptor.Id = self.event_id;
+        te_info.EventDescriptor.Version = self.version;
+        te_info.EventDescriptor.Opcode = self.opcode;
+        te_info.EventDescriptor.Level = self.level;
+
+        // Safety (for both writes): the buffer is large enough to contain the TRACE_EVENT_INFO and the whole properties array
+        unsafe {
+            std::ptr::write_unaligned(buffer.as_mut_ptr() as *mut TRACE_EVENT_INFO, te_info);
+            for (i, info) in infos.into_iter().enumerate() {
+                let p = buffer.as_mut_ptr().add(properties_offset + i * property_size);
+                std::ptr::write_unaligned(p as *mut EVENT_PROPERTY_INFO, info);
+            }
+        }
+
+        let te_info = TraceEventInfo::from_bytes(&buffer)
+            .expect("SchemaBuilder always produces a valid TRACE_EVENT_INFO");
+        Schema::new(te_info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::native::ExtendedDataItem;
+    use crate::parser::{Parser, Pointer};
+
+    const PROVIDER: GUID = GUID::from_u128(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716);
+
+    #[test]
+    fn build_record() {
+        let related = GUID::from_u128(0x01234567_89ab_cdef_0123_456789abcdef);
+        let record = EventRecordBuilder::new(PROVIDER)
+            .event_id(12)
+            .version(2)
+            .opcode(3)
+            .level(4)
+            .keywords(0x80)
+            .process_id(100)
+            .thread_id(200)
+            .timestamp(123_456)
+            .is_32_bit(true)
+            .user_data(vec![1, 2, 3])
+            .related_activity_id(related)
+            .ts_id(7)
+            .process_start_key(99)
+            .stack_trace64(5, &[0x1000, 0x2000])
+            .pmc_counters(&[10, 20])
+            .build();
+
+        assert_eq!(record.provider_id(), PROVIDER);
+        assert_eq!(record.event_id(), 12);
+        assert_eq!(record.version(), 2);
+        assert_eq!(record.opcode(), 3);
+        assert_eq!(record.level(), 4);
+        assert_eq!(record.process_id(), 100);
+        assert_eq!(record.thread_id(), 200);
+        assert_eq!(record.raw_timestamp(), 123_456);
+        assert_eq!(record.user_data(), &[1, 2, 3]);
+        assert_ne!(record.event_flags() & crate::native::etw_types::EVENT_HEADER_FLAG_32_BIT_HEADER, 0);
+        assert_ne!(record.event_flags() & Etw::EVENT_HEADER_FLAG_EXTENDED_INFO as u16, 0);
+
+        let items: Vec<_> = record.extended_data().iter().map(|item| item.to_extended_data_item()).collect();
+        assert_eq!(items.len(), 5);
+        assert!(matches!(items[0], ExtendedDataItem::RelatedActivityId(g) if g == related));
+        assert!(matches!(items[1], ExtendedDataItem::TsId(7)));
+        assert!(matches!(items[2], ExtendedDataItem::ProcessStartKey(99)));
+        match &items[3] {
+            ExtendedDataItem::StackTrace64(st) => assert_eq!(st.MatchId, 5),
+            _ => panic!("Unexpected extended data"),
+        }
+        assert!(matches!(&items[4], ExtendedDataItem::PmcCounters(counters) if counters == &[10, 20]));
+    }
+
+    fn test_schema() -> Schema {
+        SchemaBuilder::new(PROVIDER, 1)
+            .provider_name("Test-Provider")
+            .task_name("TestTask")
+            .add_property("Pid", TdhInType::InTypeUInt32, TdhOutType::OutTypeUInt32)
+            .add_property("Name", TdhInType::InTypeUnicodeString, TdhOutType::OutTypeString)
+            .add_property("Address", TdhInType::InTypePointer, TdhOutType::OutTypeHexInt64)
+            .add_property("AnsiName", TdhInType::InTypeAnsiString, TdhOutType::OutTypeString)
+            .add_fixed_length_property("Blob", TdhInType::InTypeBinary, TdhOutType::OutTypeHexBinary, 4)
+            .add_property("Delta", TdhInType::InTypeInt64, TdhOutType::OutTypeInt64)
+            .build()
+    }
+
+    #[test]
+    fn build_schema() {
+        let schema = test_schema();
+        assert_eq!(schema.provider_name(), "Test-Provider");
+        assert_eq!(schema.task_name(), "TestTask");
+        assert_eq!(schema.opcode_name(), "");
+        let names: Vec<_> = schema.properties().iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Pid", "Name", "Address", "AnsiName", "Blob", "Delta"]);
+    }
+
+    #[test]
+    fn serialize_and_parse() {
+        let schema = test_schema();
+        for is_32_bit in [false, true] {
+            let record = EventRecordBuilder::new(PROVIDER)
+                .event_id(1)
+                .is_32_bit(is_32_bit)
+                .properties(&schema, &[
+                    ("Delta", PropertyValue::I64(-5)),
+                    ("Name", PropertyValue::String("Ferris".to_string())),
+                    ("Pid", PropertyValue::U32(1234)),
+                    ("Address", PropertyValue::Pointer(0xdead)),
+                    ("AnsiName", PropertyValue::String("Crab".to_string())),
+                    ("Blob", PropertyValue::Binary(vec![1, 2, 3, 4])),
+                ])
+                .unwrap()
+                .build();
+
+            let parser = Parser::create(&record, &schema);
+            assert_eq!(parser.try_parse::<u32>("Pid").unwrap(), 1234);
+            assert_eq!(parser.try_parse::<String>("Name").unwrap(), "Ferris");
+            assert_eq!(*parser.try_parse::<Pointer>("Address").unwrap(), 0xdead);
+            assert_eq!(parser.try_parse::<String>("AnsiName").unwrap(), "Crab");
+            assert_eq!(parser.try_parse::<Vec<u8>>("Blob").unwrap(), vec![1, 2, 3, 4]);
+            assert_eq!(parser.try_parse::<i64>("Delta").unwrap(), -5);
+        }
+    }
+
+    #[test]
+    fn serialize_errors() {
+        let schema = SchemaBuilder::new(PROVIDER, 1)
+            .add_property("Pid", TdhInType::InTypeUInt32, TdhOutType::OutTypeUInt32)
+            .add_fixed_length_property("Blob", TdhInType::InTypeBinary, TdhOutType::OutTypeHexBinary, 4)
+            .build();
+
+        assert_eq!(
+            serialize_properties(&schema, &[("Pid", PropertyValue::U32(1))], false),
+            Err(RecordBuilderError::MissingValue("Blob".to_string()))
+        );
+        assert_eq!(
+            serialize_properties(&schema, &[("Pid", PropertyValue::U64(1)), ("Blob", PropertyValue::Binary(vec![0; 4]))], false),
+            Err(RecordBuilderError::TypeMismatch("Pid".to_string()))
+        );
+        assert_eq!(
+            serialize_properties(&schema, &[("Pid", PropertyValue::U32(1)), ("Blob", PropertyValue::Binary(vec![0; 3]))], false),
+            Err(RecordBuilderError::LengthMismatch("Blob".to_string()))
+        );
+        assert_eq!(
+            serialize_properties(&schema, &[("Pid", PropertyValue::U32(1)), ("Blob", PropertyValue::Binary(vec![0; 4])), ("Other", PropertyValue::U8(0))], false),
+            Err(RecordBuilderError::UnknownProperty("Other".to_string()))
+        );
+        assert_eq!(
+            serialize_properties(&schema, &[("Pid", PropertyValue::U32(1)), ("Blob", PropertyValue::Binary(vec![0; 4]))], false),
+            Ok(vec![1, 0, 0, 0, 0, 0, 0, 0])
+        );
+    }
+}
@@ -25,6 +25,11 @@ impl Schema {
         }
     }
 
+    /// The wrapped `TRACE_EVENT_INFO`
+    pub(crate) fn te_info(&self) -> &TraceEventInfo {
+        &self.te_info
+    }
+
     /// Use the `decoding_source` function to obtain the [DecodingSource] from the `TRACE_EVENT_INFO`
     ///
     /// This getter returns the DecodingSource from the event, this value identifies the source used
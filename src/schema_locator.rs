@@ -25,6 +25,22 @@ impl From<tdh::TdhNativeError> for SchemaError {
     }
 }
 
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaError::TdhNativeError(err) => write!(f, "unable to get the schema of the event: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SchemaError::TdhNativeError(err) => Some(err),
+        }
+    }
+}
+
 type SchemaResult<T> = Result<T, SchemaError>;
 
 /// A way to group events that share the same [`Schema`]
@@ -33,7 +49,7 @@ type SchemaResult<T> = Result<T, SchemaError>;
 /// > For manifest-based ETW, the combination Provider.DecodeGuid + Event.Id + Event.Version should uniquely identify an event,
 /// > i.e. all events with the same DecodeGuid, Id, and Version should have the same set of fields with no changes in field names, field types, or field ordering.
 #[derive(Debug, Eq, PartialEq, Hash)]
-struct SchemaKey {
+pub(crate) struct SchemaKey {
     provider: GUID,
     /// From the [docs](https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_descriptor): A 16-bit number used to identify manifest-based events
     id: u16,
@@ -53,7 +69,7 @@ struct SchemaKey {
 }
 
 impl SchemaKey {
-    pub fn new(event: &EventRecord) -> Self {
+    pub(crate) fn new(event: &EventRecord) -> Self {
         SchemaKey {
             provider: event.provider_id(),
             id: event.event_id(),
@@ -96,6 +112,14 @@ impl SchemaLocator {
         }
     }
 
+    /// Add a known schema to the cache, so that later calls to [`Self::event_schema`] for similar events will return it
+    ///
+    /// This is useful when the schema has not been retrieved from the system, but e.g. read from a capture file.
+    pub(crate) fn insert_schema(&self, event: &EventRecord, schema: Arc<Schema>) {
+        let key = SchemaKey::new(event);
+        self.schemas.lock().unwrap().insert(key, schema);
+    }
+
     /// Retrieve the Schema of an ETW Event
     ///
     /// # Arguments
@@ -0,0 +1,322 @@
+//! Clocks and timestamps of the events
+//!
+//! By default, ETW converts the timestamps of the events into "system time" (i.e. a `FILETIME`, that counts hundreds of nanoseconds since January 1, 1601), whatever the [`ClockType`] of the session.<br/>
+//! When a trace is processed with raw timestamps (see [`TraceBuilder::raw_timestamps`](crate::trace::TraceBuilder::raw_timestamps)), timestamps are left in the unit of the clock of the session,
+//! which is cheaper to process and may be more precise. They can then be converted using the [`TimestampConverter`] of the trace (see [`TraceTrait::timestamp_converter`](crate::trace::TraceTrait::timestamp_converter)).
+//!
+//! ```no_run
+//! # use ferrisetw::provider::Provider;
+//! # use ferrisetw::trace::{UserTrace, TraceProperties, TraceTrait};
+//! # use ferrisetw::timestamp::ClockType;
+//! let (trace, handle) = UserTrace::new()
+//!     .set_trace_properties(TraceProperties::default().clock(ClockType::Qpc))
+//!     .raw_timestamps(true)
+//!     .enable(Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716").build())
+//!     .start()
+//!     .unwrap();
+//!
+//! let converter = trace.timestamp_converter().unwrap();
+//! // e.g. in a callback
+//! # let raw_timestamp = 0;
+//! let system_time = converter.to_system_time(raw_timestamp);
+//! ```
+
+use std::time::{Duration, SystemTime};
+
+use windows::Win32::System::Diagnostics::Etw;
+
+const SECONDS_BETWEEN_1601_AND_1970: i128 = 11_644_473_600;
+const NANOS_IN_SECOND: i128 = 1_000_000_000;
+const HUNDREDS_OF_NANOS_IN_SECOND: i128 = 10_000_000;
+
+/// The clock used by a session to timestamp its events
+///
+/// This is set as `Wnode.ClientContext` of the [`EVENT_TRACE_PROPERTIES`](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties).<br/>
+/// See [Microsoft's documentation](https://learn.microsoft.com/en-us/windows/win32/etw/wnode-header) for the trade-offs between these clocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ClockType {
+    /// Query performance counter (QPC). This is the default, and has a high resolution
+    #[default]
+    Qpc = 1,
+    /// System time. This has a low resolution (about 10 to 16 milliseconds), but is cheaper to query
+    SystemTime = 2,
+    /// CPU cycle counter. This has the highest resolution, but may not be accurate on systems whose CPU frequency varies
+    CpuCycle = 3,
+}
+
+/// Number of nanoseconds since the Unix epoch, from a `FILETIME`
+pub(crate) fn filetime_to_unix_nanos(filetime: i64) -> i128 {
+    (filetime as i128 - SECONDS_BETWEEN_1601_AND_1970 * HUNDREDS_OF_NANOS_IN_SECOND) * 100
+}
+
+/// The current `Qpc` tick, and the current time as a `FILETIME`
+#[cfg(windows)]
+pub(crate) fn current_qpc() -> Option<(i64, i64)> {
+    let mut tick = 0;
+    let (ok, filetime) = unsafe {
+        // Safety: `tick` is valid for writes
+        let ok = windows::Win32::System::Performance::QueryPerformanceCounter(&mut tick).as_bool();
+        (ok, windows::Win32::System::SystemInformation::GetSystemTimePreciseAsFileTime())
+    };
+    let filetime = ((filetime.dwHighDateTime as u64) << 32 | filetime.dwLowDateTime as u64) as i64;
+    ok.then_some((tick, filetime))
+}
+
+/// The current `Qpc` tick, and the current time as a `FILETIME`. This is not available on targets other than Windows.
+#[cfg(not(windows))]
+pub(crate) fn current_qpc() -> Option<(i64, i64)> {
+    None
+}
+
+/// Converts the timestamps of the events of a trace into absolute times
+///
+/// See [the module-level documentation](crate::timestamp)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampConverter {
+    clock_type: ClockType,
+    raw_timestamps: bool,
+    frequency: u64,
+    /// A time (as a `FILETIME`) at which the clock read `reference_tick`
+    reference_time: i64,
+    reference_tick: i64,
+}
+
+impl TimestampConverter {
+    /// Create a converter
+    ///
+    /// * `raw_timestamps`: whether timestamps are expressed in the unit of `clock_type` (otherwise, they are `FILETIME`s, and the other parameters are not used)
+    /// * `frequency`: the number of ticks per second of `clock_type`, for the `Qpc` and `CpuCycle` clocks
+    /// * `boot_time`: the time the system booted, as a `FILETIME`. The `Qpc` and `CpuCycle` clocks count ticks since then.
+    pub fn new(clock_type: ClockType, raw_timestamps: bool, frequency: u64, boot_time: i64) -> Self {
+        Self::with_reference(clock_type, raw_timestamps, frequency, boot_time, 0)
+    }
+
+    /// Create a converter from a reading of the clock, rather than from the boot time
+    ///
+    /// `reference_tick` is the value of the `Qpc` or `CpuCycle` clock at `reference_time` (a `FILETIME`). See [`Self::new`] for the other parameters.
+    pub fn with_reference(clock_type: ClockType, raw_timestamps: bool, frequency: u64, reference_time: i64, reference_tick: i64) -> Self {
+        Self {
+            clock_type,
+            raw_timestamps,
+            frequency,
+            reference_time,
+            reference_tick,
+        }
+    }
+
+    /// Create a converter from the header of a trace, that is available once the trace has been opened
+    ///
+    /// Raw timestamps are converted from the `BootTime` of the header. Microsoft only documents it for the Global Logger session though, and it may be 0 for other sessions.
+    /// In this case, `current_qpc` (the current `Qpc` tick and `FILETIME`, that can only be read for real-time sessions) is used as a reference instead.<br/>
+    /// Returns `None` in case raw timestamps cannot be converted.
+    pub(crate) fn from_logfile_header(header: &Etw::TRACE_LOGFILE_HEADER, raw_timestamps: bool, current_qpc: Option<(i64, i64)>) -> Option<Self> {
+        let clock_type = match header.ReservedFlags {
+            2 => ClockType::SystemTime,
+            3 => ClockType::CpuCycle,
+            _ => ClockType::Qpc,
+        };
+        let frequency = match clock_type {
+            ClockType::CpuCycle => {
+                // SAFETY: this member of the union is the one that is used by the consumers of a trace
+                let cpu_speed_mhz = unsafe { header.Anonymous2.Anonymous.CpuSpeedInMHz };
+                cpu_speed_mhz as u64 * 1_000_000
+            },
+            _ => header.PerfFreq as u64,
+        };
+
+        let needs_reference = raw_timestamps && clock_type != ClockType::SystemTime;
+        if !needs_reference || header.BootTime != 0 {
+            return Some(Self::new(clock_type, raw_timestamps, frequency, header.BootTime));
+        }
+        match current_qpc {
+            Some((tick, filetime)) if clock_type == ClockType::Qpc => Some(Self::with_reference(clock_type, raw_timestamps, frequency, filetime, tick)),
+            _ => None,
+        }
+    }
+
+    /// The clock the session uses
+    pub fn clock_type(&self) -> ClockType {
+        self.clock_type
+    }
+
+    /// Whether the timestamps of the events are raw (see [`TraceBuilder::raw_timestamps`](crate::trace::TraceBuilder::raw_timestamps))
+    pub fn raw_timestamps(&self) -> bool {
+        self.raw_timestamps
+    }
+
+    /// Convert a timestamp (see [`EventRecord::raw_timestamp`](crate::EventRecord::raw_timestamp)) into a number of nanoseconds since the Unix epoch
+    pub fn to_unix_nanos(&self, timestamp: i64) -> i128 {
+        if !self.raw_timestamps || self.clock_type == ClockType::SystemTime || self.frequency == 0 {
+            return filetime_to_unix_nanos(timestamp);
+        }
+
+        let nanos_since_reference = (timestamp as i128 - self.reference_tick as i128) * NANOS_IN_SECOND / self.frequency as i128;
+        filetime_to_unix_nanos(self.reference_time) + nanos_since_reference
+    }
+
+    /// Convert a timestamp into a `FILETIME` (i.e. hundreds of nanoseconds since January 1, 1601)
+    pub fn to_filetime(&self, timestamp: i64) -> i64 {
+        (self.to_unix_nanos(timestamp) / 100 + SECONDS_BETWEEN_1601_AND_1970 * HUNDREDS_OF_NANOS_IN_SECOND) as i64
+    }
+
+    /// Convert a timestamp into a `SystemTime`
+    pub fn to_system_time(&self, timestamp: i64) -> SystemTime {
+        let unix_nanos = self.to_unix_nanos(timestamp);
+        let duration = Duration::new(
+            (unix_nanos.abs() / NANOS_IN_SECOND) as u64,
+            (unix_nanos.abs() % NANOS_IN_SECOND) as u32,
+        );
+        if unix_nanos >= 0 {
+            SystemTime::UNIX_EPOCH + duration
+        } else {
+            SystemTime::UNIX_EPOCH - duration
+        }
+    }
+
+    /// Convert a timestamp into a `time::OffsetDateTime`
+    #[cfg(feature = "time_rs")]
+    pub fn to_offset_date_time(&self, timestamp: i64) -> time::OffsetDateTime {
+        // Can't panic: OffsetDateTime (with the 'large-dates' feature) can represent any time from year -999_999 to +999_999.
+        time::OffsetDateTime::from_unix_timestamp_nanos(self.to_unix_nanos(timestamp)).unwrap()
+    }
+
+    /// Convert a timestamp into a `chrono::DateTime`
+    ///
+    /// This returns `None` in case the timestamp is out of the range `chrono` is able to represent.
+    #[cfg(feature = "chrono_rs")]
+    pub fn to_chrono(&self, timestamp: i64) -> Option<chrono::DateTime<chrono::Utc>> {
+        use std::convert::TryFrom;
+
+        let unix_nanos = self.to_unix_nanos(timestamp);
+        let secs = i64::try_from(unix_nanos.div_euclid(NANOS_IN_SECOND)).ok()?;
+        let nanos = unix_nanos.rem_euclid(NANOS_IN_SECOND) as u32;
+        chrono::DateTime::from_timestamp(secs, nanos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 2023-01-01T00:00:00Z
+    const FILETIME_2023: i64 = 133_170_048_000_000_000;
+    const UNIX_2023: u64 = 1_672_531_200;
+
+    #[test]
+    fn test_filetime_timestamps() {
+        let converter = TimestampConverter::new(ClockType::Qpc, false, 10_000_000, 0);
+
+        assert_eq!(converter.to_unix_nanos(FILETIME_2023 + 15), UNIX_2023 as i128 * 1_000_000_000 + 1_500);
+        assert_eq!(converter.to_filetime(FILETIME_2023 + 15), FILETIME_2023 + 15);
+        assert_eq!(converter.to_system_time(FILETIME_2023), SystemTime::UNIX_EPOCH + Duration::from_secs(UNIX_2023));
+        assert_eq!(converter.to_system_time(0), SystemTime::UNIX_EPOCH - Duration::from_secs(11_644_473_600));
+    }
+
+    #[test]
+    fn test_raw_timestamps() {
+        // A 3 MHz QPC, on a system that booted at 2023-01-01T00:00:00Z
+        let converter = TimestampConverter::new(ClockType::Qpc, true, 3_000_000, FILETIME_2023);
+        assert_eq!(converter.to_system_time(3_000_001), SystemTime::UNIX_EPOCH + Duration::new(UNIX_2023 + 1, 333));
+
+        // A 2.5 GHz CPU
+        let converter = TimestampConverter::new(ClockType::CpuCycle, true, 2_500_000_000, FILETIME_2023);
+        assert_eq!(converter.to_unix_nanos(5_000_000_005), (UNIX_2023 as i128 + 2) * 1_000_000_000 + 2);
+
+        // System time is already a FILETIME
+        let converter = TimestampConverter::new(ClockType::SystemTime, true, 10_000_000, 0);
+        assert_eq!(converter.to_filetime(FILETIME_2023), FILETIME_2023);
+    }
+
+    #[test]
+    fn test_from_logfile_header() {
+        let mut header = Etw::TRACE_LOGFILE_HEADER {
+            BootTime: FILETIME_2023,
+            PerfFreq: 10_000_000,
+            ReservedFlags: 3,
+            ..Default::default()
+        };
+        header.Anonymous2.Anonymous.CpuSpeedInMHz = 2_000;
+
+        let converter = TimestampConverter::from_logfile_header(&header, true, None).unwrap();
+        assert_eq!(converter, TimestampConverter::new(ClockType::CpuCycle, true, 2_000_000_000, FILETIME_2023));
+
+        header.ReservedFlags = 0;
+        let converter = TimestampConverter::from_logfile_header(&header, false, None).unwrap();
+        assert_eq!(converter.clock_type(), ClockType::Qpc);
+        assert!(!converter.raw_timestamps());
+    }
+
+    #[test]
+    fn test_header_without_boot_time() {
+        let mut header = Etw::TRACE_LOGFILE_HEADER {
+            BootTime: 0,
+            PerfFreq: 10_000_000,
+            ReservedFlags: 1,
+            ..Default::default()
+        };
+
+        // The clock has been read at 2023-01-01T00:00:00Z
+        let current_qpc = Some((50_000_000, FILETIME_2023));
+        let converter = TimestampConverter::from_logfile_header(&header, true, current_qpc).unwrap();
+        assert_eq!(converter.to_system_time(50_000_000), SystemTime::UNIX_EPOCH + Duration::from_secs(UNIX_2023));
+        assert_eq!(converter.to_system_time(40_000_001), SystemTime::UNIX_EPOCH + Duration::new(UNIX_2023 - 1, 100));
+
+        // Raw timestamps cannot be converted without a reference, instead of being converted to dates near 1601
+        assert_eq!(TimestampConverter::from_logfile_header(&header, true, None), None);
+        header.ReservedFlags = 3;
+        assert_eq!(TimestampConverter::from_logfile_header(&header, true, current_qpc), None);
+
+        // Timestamps that are converted by ETW, or system time ones, do not need one
+        assert!(TimestampConverter::from_logfile_header(&header, false, None).is_some());
+        header.ReservedFlags = 2;
+        assert!(TimestampConverter::from_logfile_header(&header, true, None).is_some());
+    }
+
+    #[test]
+    fn test_trace_clock() {
+        use crate::native::evntrace::backend::fake::{fake_user_trace, FAKE_BOOT_TIME};
+        use crate::trace::{TraceProperties, TraceTrait};
+
+        let (builder, backend) = fake_user_trace("fake-clock");
+        let (trace, _handle) = builder
+            .set_trace_properties(TraceProperties::default().clock(ClockType::CpuCycle))
+            .raw_timestamps(true)
+            .start()
+            .unwrap();
+
+        let process_trace_modes = backend.process_trace_modes("fake-clock");
+        assert_eq!(process_trace_modes.len(), 1);
+        assert!(process_trace_modes[0] & Etw::PROCESS_TRACE_MODE_RAW_TIMESTAMP != 0);
+
+        let converter = trace.timestamp_converter().unwrap();
+        assert_eq!(converter.clock_type(), ClockType::CpuCycle);
+        assert!(converter.raw_timestamps());
+        // 3 GHz
+        let boot_time = TimestampConverter::new(ClockType::SystemTime, false, 0, 0).to_system_time(FAKE_BOOT_TIME);
+        assert_eq!(converter.to_system_time(6_000_000_003), boot_time + Duration::new(2, 1));
+        assert!(converter.to_system_time(0) < SystemTime::now());
+        trace.stop().unwrap();
+
+        // Timestamps are converted by default
+        let (trace, _handle) = backend.user_trace("fake-default-clock")
+            .start()
+            .unwrap();
+        assert!(backend.process_trace_modes("fake-default-clock")[0] & Etw::PROCESS_TRACE_MODE_RAW_TIMESTAMP == 0);
+        let converter = trace.timestamp_converter().unwrap();
+        assert_eq!(converter.clock_type(), ClockType::Qpc);
+        assert!(!converter.raw_timestamps());
+        trace.stop().unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "time_rs", feature = "chrono_rs"))]
+    fn test_typed_timestamps() {
+        let converter = TimestampConverter::new(ClockType::Qpc, true, 3_000_000, FILETIME_2023);
+
+        let offset_date_time = converter.to_offset_date_time(3_000_001);
+        assert_eq!(offset_date_time.unix_timestamp_nanos(), (UNIX_2023 as i128 + 1) * 1_000_000_000 + 333);
+        let date_time = converter.to_chrono(3_000_001).unwrap();
+        assert_eq!(date_time.timestamp_nanos_opt(), Some((UNIX_2023 as i64 + 1) * 1_000_000_000 + 333));
+    }
+}
@@ -3,6 +3,7 @@
 //! Provides both a Kernel and User trace that allows to start an ETW session
 use std::ffi::OsString;
 use std::marker::PhantomData;
+use std::ops::ControlFlow;
 use std::sync::Arc;
 use std::time::Duration;
 use std::path::PathBuf;
@@ -15,8 +16,14 @@ use self::private::{PrivateRealTimeTraceTrait, PrivateTraceTrait};
 
 use crate::native::etw_types::{EventTraceProperties, SubscriptionSource};
 use crate::native::version_helper;
-use crate::native::evntrace::{ControlHandle, TraceHandle, start_trace, open_trace, process_trace, enable_provider, control_trace, control_trace_by_name, close_trace};
+use crate::native::evntrace::{ControlHandle, TraceHandle, start_trace, open_trace, opened_callback_data, process_trace, enable_provider, disable_provider, capture_state, set_information, control_trace, control_trace_by_name, close_trace};
+use crate::native::evntrace::backend::{EvntraceBackend, default_backend};
 use crate::provider::Provider;
+use crate::provider::event_filter::EventFilter;
+use crate::provider::kernel_providers::{ClassicEventId, KernelGroupMask};
+use crate::native::etw_types::TraceInformation;
+use crate::timestamp::{ClockType, TimestampConverter};
+use crate::trace::session_events::{BufferStatistics, LostEventCounts, LostEventKind, TraceHeader};
 use crate::utils;
 use crate::EventRecord;
 use crate::SchemaLocator;
@@ -25,9 +32,20 @@ pub use crate::native::etw_types::LoggingMode;
 pub use crate::native::etw_types::DumpFileLoggingMode;
 
 pub(crate) mod callback_data;
+pub mod dispatcher;
+pub mod event_context;
+pub mod panic_policy;
+pub mod replay;
+pub mod session;
+pub mod session_events;
+#[cfg(feature = "async")]
+pub mod stream;
 use callback_data::CallbackData;
 use callback_data::RealTimeCallbackData;
 use callback_data::CallbackDataFromFile;
+use dispatcher::{DispatcherMetrics, DispatcherOptions};
+use event_context::{EventContext, OwnedSession, TraceOwner};
+use panic_policy::PanicPolicy;
 
 const KERNEL_LOGGER_NAME: &str = "NT Kernel Logger";
 const SYSTEM_TRACE_CONTROL_GUID: &str = "9e814aad-3204-11d2-9a82-006008a86939";
@@ -37,8 +55,14 @@ const EVENT_TRACE_SYSTEM_LOGGER_MODE: u32 = 0x02000000;
 #[derive(Debug)]
 pub enum TraceError {
     InvalidTraceName,
+    /// No provider with this GUID is enabled on the trace
+    ProviderNotEnabled(GUID),
     /// Wrapper over an internal [EvntraceNativeError](crate::native::EvntraceNativeError)
     EtwNativeError(crate::native::EvntraceNativeError),
+    /// A callback panicked, and this stopped the trace (see [`PanicPolicy::StopTrace`]).
+    ///
+    /// This contains the panic payload, e.g. to resume unwinding with [`std::panic::resume_unwind`]
+    CallbackPanicked(Box<dyn std::any::Any + Send>),
 }
 
 impl From<crate::native::EvntraceNativeError> for TraceError {
@@ -47,6 +71,26 @@ impl From<crate::native::EvntraceNativeError> for TraceError {
     }
 }
 
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceError::InvalidTraceName => write!(f, "invalid trace name (it must not contain NUL characters)"),
+            TraceError::ProviderNotEnabled(guid) => write!(f, "no provider with GUID {:?} is enabled on this trace", guid),
+            TraceError::EtwNativeError(err) => err.fmt(f),
+            TraceError::CallbackPanicked(payload) => write!(f, "a callback panicked: {}", panic_policy::panic_message(payload.as_ref())),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TraceError::EtwNativeError(err) => std::error::Error::source(err),
+            _ => None,
+        }
+    }
+}
+
 type TraceResult<T> = Result<T, TraceError>;
 
 /// Trace Properties struct
@@ -68,6 +112,8 @@ pub struct TraceProperties {
     pub flush_timer: Duration,
     /// Represents the ETW Session [Logging Mode](https://docs.microsoft.com/en-us/windows/win32/etw/logging-mode-constants)
     pub log_file_mode: LoggingMode,
+    /// Represents the clock the ETW Session uses to timestamp events
+    pub clock_type: ClockType,
 }
 
 impl Default for TraceProperties {
@@ -79,8 +125,58 @@ impl Default for TraceProperties {
             max_buffer: 0,
             flush_timer: Duration::from_secs(1),
             log_file_mode: LoggingMode::EVENT_TRACE_REAL_TIME_MODE | LoggingMode::EVENT_TRACE_NO_PER_PROCESSOR_BUFFERING,
+            clock_type: ClockType::Qpc,
+        }
+    }
+}
+
+impl TraceProperties {
+    /// Set the clock the ETW Session uses to timestamp events
+    pub fn clock(mut self, clock_type: ClockType) -> Self {
+        self.clock_type = clock_type;
+        self
+    }
+}
+
+/// A snapshot of the statistics of a running trace session
+///
+/// See [`UserTrace::statistics`] and [`KernelTrace::statistics`].<br/>
+/// Counters are cumulative since the session was started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TraceStatistics {
+    /// Number of buffers allocated for the session
+    pub number_of_buffers: u32,
+    /// Number of buffers that are allocated but currently unused
+    pub free_buffers: u32,
+    /// Number of events that were not recorded (e.g. because every buffer was full)
+    pub events_lost: u32,
+    /// Number of buffers written
+    pub buffers_written: u32,
+    /// Number of buffers that could not be written to the log file
+    pub log_buffers_lost: u32,
+    /// Number of buffers that could not be delivered to real-time consumers
+    pub real_time_buffers_lost: u32,
+    /// Identifier of the thread that logs the events of this session
+    pub logger_thread_id: u32,
+}
+
+impl TraceStatistics {
+    fn from_raw(raw: &Etw::EVENT_TRACE_PROPERTIES) -> Self {
+        Self {
+            number_of_buffers: raw.NumberOfBuffers,
+            free_buffers: raw.FreeBuffers,
+            events_lost: raw.EventsLost,
+            buffers_written: raw.BuffersWritten,
+            log_buffers_lost: raw.LogBuffersLost,
+            real_time_buffers_lost: raw.RealTimeBuffersLost,
+            logger_thread_id: raw.LoggerThreadId.0 as u32,
         }
     }
+
+    /// Whether ETW dropped anything (events, or buffers for the log file or for the real-time consumers)
+    pub fn has_losses(&self) -> bool {
+        self.events_lost != 0 || self.log_buffers_lost != 0 || self.real_time_buffers_lost != 0
+    }
 }
 
 /// Trait for common methods to user, kernel and file traces
@@ -91,6 +187,15 @@ pub trait TraceTrait: private::PrivateTraceTrait + Sized {
     // This utility function should be implemented for every trace
     fn events_handled(&self) -> usize;
 
+    /// The converter of the timestamps of the events of this trace
+    ///
+    /// This is available once the trace has been opened.
+    /// With [raw timestamps](TraceBuilder::raw_timestamps), this is not available in case they cannot be converted, e.g. for an ETL file whose header does not tell when the system booted.
+    fn timestamp_converter(&self) -> Option<TimestampConverter>;
+
+    /// How many panics of the callbacks have been caught so far (see [`panic_policy`])
+    fn caught_panics(&self) -> usize;
+
     // The following are default implementations, that work on both user and kernel traces
 
     /// This is blocking and starts triggerring the callbacks.
@@ -98,16 +203,14 @@ pub trait TraceTrait: private::PrivateTraceTrait + Sized {
     /// Because this call is blocking, you probably want to call this from a background thread.<br/>
     /// See [`TraceBuilder::start`] for alternative and more convenient ways to start a trace.
     fn process(&mut self) -> TraceResult<()> {
-        process_trace(self.trace_handle())
-            .map_err(|e| e.into())
+        process_opened_trace(self.trace_handle())
     }
 
     /// Process a trace given its handle.
     ///
     /// See [`TraceBuilder::start`] for alternative and more convenient ways to start a trace.
     fn process_from_handle(handle: TraceHandle) -> TraceResult<()> {
-        process_trace(handle)
-            .map_err(|e| e.into())
+        process_opened_trace(handle)
     }
 
     /// Stops the trace
@@ -119,6 +222,18 @@ pub trait TraceTrait: private::PrivateTraceTrait + Sized {
     }
 }
 
+fn process_opened_trace(handle: TraceHandle) -> TraceResult<()> {
+    // Let's get it before processing, in case the trace is closed in the meantime
+    let callback_data = opened_callback_data(handle);
+
+    let result = process_trace(handle);
+
+    if let Some(payload) = callback_data.and_then(|cb| cb.take_panic_payload()) {
+        return Err(TraceError::CallbackPanicked(payload));
+    }
+    Ok(result?)
+}
+
 /// Trait for common methods to real-time traces
 pub trait RealTimeTraceTrait: TraceTrait + private::PrivateRealTimeTraceTrait {
     // This differs between UserTrace and KernelTrace
@@ -136,11 +251,19 @@ impl TraceTrait for UserTrace {
     fn events_handled(&self) -> usize {
         self.callback_data.events_handled()
     }
+
+    fn timestamp_converter(&self) -> Option<TimestampConverter> {
+        self.callback_data.timestamp_converter()
+    }
+
+    fn caught_panics(&self) -> usize {
+        self.callback_data.caught_panics()
+    }
 }
 
 impl RealTimeTraceTrait for UserTrace {
     fn trace_guid() -> GUID {
-        GUID::new().unwrap_or(GUID::zeroed())
+        utils::new_guid()
     }
 
     fn trace_name(&self) -> OsString {
@@ -148,7 +271,6 @@ impl RealTimeTraceTrait for UserTrace {
     }
 }
 
-// TODO: Implement enable_provider function for providers that require call to TraceSetInformation with extended PERFINFO_GROUPMASK
 impl TraceTrait for KernelTrace {
     fn trace_handle(&self) -> TraceHandle {
         self.trace_handle
@@ -157,12 +279,20 @@ impl TraceTrait for KernelTrace {
     fn events_handled(&self) -> usize {
         self.callback_data.events_handled()
     }
+
+    fn timestamp_converter(&self) -> Option<TimestampConverter> {
+        self.callback_data.timestamp_converter()
+    }
+
+    fn caught_panics(&self) -> usize {
+        self.callback_data.caught_panics()
+    }
 }
 
 impl RealTimeTraceTrait for KernelTrace {
     fn trace_guid() -> GUID {
         if version_helper::is_win8_or_greater() {
-            GUID::new().unwrap_or(GUID::zeroed())
+            utils::new_guid()
         } else {
             GUID::from(SYSTEM_TRACE_CONTROL_GUID)
         }
@@ -181,6 +311,14 @@ impl TraceTrait for FileTrace {
     fn events_handled(&self) -> usize {
         self.callback_data.events_handled()
     }
+
+    fn timestamp_converter(&self) -> Option<TimestampConverter> {
+        self.callback_data.timestamp_converter()
+    }
+
+    fn caught_panics(&self) -> usize {
+        self.callback_data.caught_panics()
+    }
 }
 
 
@@ -199,6 +337,7 @@ pub struct UserTrace {
     // * `Arc`ed, so that dropping a Trace while a callback is still running is not an issue
     // * `Boxed`, so that the `UserTrace` can be moved around the stack (e.g. returned by a function) but the pointers to the `CallbackData` given to Windows ETW API stay valid
     callback_data: Box<Arc<CallbackData>>,
+    backend: Arc<dyn EvntraceBackend>,
 }
 
 /// A real-time trace session to collect events from kernel-mode drivers
@@ -214,6 +353,7 @@ pub struct KernelTrace {
     // * `Arc`ed, so that dropping a Trace while a callback is still running is not an issue
     // * `Boxed`, so that the `UserTrace` can be moved around the stack (e.g. returned by a function) but the pointers to the `CallbackData` given to Windows ETW API stay valid
     callback_data: Box<Arc<CallbackData>>,
+    backend: Arc<dyn EvntraceBackend>,
 }
 
 /// A trace session that reads events from an ETL file
@@ -227,6 +367,7 @@ pub struct FileTrace {
     // * `Arc`ed, so that dropping a Trace while a callback is still running is not an issue
     // * `Boxed`, so that the `UserTrace` can be moved around the stack (e.g. returned by a function) but the pointers to the `CallbackData` given to Windows ETW API stay valid
     callback_data: Box<Arc<CallbackData>>,
+    backend: Arc<dyn EvntraceBackend>,
 }
 
 /// Various parameters related to an ETL dump file
@@ -239,6 +380,22 @@ pub struct DumpFileParams {
     pub max_size: Option<u32>,
 }
 
+/// Settings that can be changed while a real-time trace is running
+///
+/// See [`UserTrace::update`] and [`KernelTrace::update`]. Settings that are `None` are left unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct TraceUpdate {
+    /// New flush interval (rounded to the closest second, see [`TraceProperties::flush_timer`])
+    pub flush_timer: Option<Duration>,
+    /// New maximum number of buffers in the buffer pool
+    pub max_buffer: Option<u32>,
+    /// Switch to a new ETL dump file.
+    ///
+    /// The trace must already log to a file (see [`TraceBuilder::set_etl_dump_file`]).
+    /// Note: the file name may be truncated to a few hundred characters if it is too long.
+    pub etl_dump_file: Option<PathBuf>,
+}
+
 /// Provides a way to crate Trace objects.
 ///
 /// These builders are created using [`UserTrace::new`] or [`KernelTrace::new`]
@@ -247,12 +404,25 @@ pub struct TraceBuilder<T: RealTimeTraceTrait> {
     etl_dump_file: Option<DumpFileParams>,
     properties: TraceProperties,
     rt_callback_data: RealTimeCallbackData,
+    /// Kernel events whose call stacks should be logged
+    stack_walk_events: Vec<ClassicEventId>,
+    /// Profile sources of the PMC counters to collect
+    pmc_sources: Vec<u32>,
+    /// Kernel events that should carry PMC counters
+    pmc_events: Vec<ClassicEventId>,
+    /// Whether to process the trace with `PROCESS_TRACE_MODE_RAW_TIMESTAMP`
+    raw_timestamps: bool,
     trace_kind: PhantomData<T>,
+    backend: Arc<dyn EvntraceBackend>,
 }
 
 pub struct FileTraceBuilder {
     etl_file_path: PathBuf,
-    callback: crate::EtwCallback,
+    callback: crate::EtwContextCallback,
+    on_buffer: Option<callback_data::BufferCallback>,
+    panic_policy: PanicPolicy,
+    raw_timestamps: bool,
+    backend: Arc<dyn EvntraceBackend>,
 }
 
 impl UserTrace {
@@ -264,7 +434,12 @@ impl UserTrace {
             etl_dump_file: None,
             rt_callback_data: RealTimeCallbackData::new(),
             properties: TraceProperties::default(),
+            stack_walk_events: Vec::new(),
+            pmc_sources: Vec::new(),
+            pmc_events: Vec::new(),
+            raw_timestamps: false,
             trace_kind: PhantomData,
+            backend: default_backend(),
         }
     }
 
@@ -275,6 +450,124 @@ impl UserTrace {
     pub fn stop(mut self) -> TraceResult<()> {
         self.non_consuming_stop()
     }
+
+    /// Query the current statistics of the session (buffers, lost events, etc.)
+    ///
+    /// Internally, this calls `ControlTraceW(EVENT_TRACE_CONTROL_QUERY)`.<br/>
+    /// This fails for replayed traces, since they have no actual session.
+    pub fn statistics(&self) -> TraceResult<TraceStatistics> {
+        query_statistics(self.backend.as_ref(), &self.properties, self.control_handle)
+    }
+
+    /// Enable a Provider on this running trace
+    ///
+    /// This can be called while the trace is processing events, including from within a callback.<br/>
+    /// Internally, this calls `EnableTraceEx2(EVENT_CONTROL_CODE_ENABLE_PROVIDER)`.
+    /// The callbacks are registered beforehand, so that the first events of the provider are not missed. They are unregistered in case `EnableTraceEx2` fails.<br/>
+    /// In case the provider has [`capture_state_on_enable`](crate::provider::ProviderBuilder::capture_state_on_enable) and capturing its state fails, the provider is disabled again before the error is returned.
+    /// An `Err` therefore always means that the provider is not enabled.
+    pub fn enable_provider(&self, provider: Provider) -> TraceResult<()> {
+        let provider = self.rt_callback_data().add_provider(provider);
+        if let Err(err) = enable_provider(self.backend.as_ref(), self.control_handle, &provider) {
+            self.rt_callback_data().remove_provider(&provider);
+            return Err(err.into());
+        }
+
+        // Rundown events must be requested once the callbacks are registered, otherwise they would be missed
+        if provider.capture_state_on_enable() {
+            if let Err(err) = capture_state(self.backend.as_ref(), self.control_handle, &provider) {
+                self.rt_callback_data().remove_provider(&provider);
+                let other_provider = self.rt_callback_data()
+                    .providers()
+                    .into_iter()
+                    .find(|prov| prov.guid() == provider.guid());
+                let rollback = match other_provider {
+                    // Other providers with this GUID were enabled beforehand: restore their settings
+                    Some(other_provider) => enable_provider(self.backend.as_ref(), self.control_handle, &other_provider),
+                    None => disable_provider(self.backend.as_ref(), self.control_handle, &provider.guid()),
+                };
+                if let Err(rollback_err) = rollback {
+                    log::warn!("Unable to disable provider {:?} after its state could not be captured: {}", provider.guid(), rollback_err);
+                }
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Disable every Provider with this GUID on this running trace
+    ///
+    /// Their callbacks will not be invoked for new events. Callbacks that are already running are not interrupted.<br/>
+    /// Internally, this calls `EnableTraceEx2(EVENT_CONTROL_CODE_DISABLE_PROVIDER)`.
+    pub fn disable_provider<G: Into<GUID>>(&self, guid: G) -> TraceResult<()> {
+        let guid = guid.into();
+        if !self.rt_callback_data().providers().iter().any(|prov| prov.guid() == guid) {
+            return Err(TraceError::ProviderNotEnabled(guid));
+        }
+
+        disable_provider(self.backend.as_ref(), self.control_handle, &guid)?;
+        self.rt_callback_data().remove_providers(guid);
+        Ok(())
+    }
+
+    /// Change the level, keywords and filters of an enabled Provider on this running trace
+    ///
+    /// Its callbacks are kept. See [`ProviderBuilder`](crate::provider::ProviderBuilder) for the meaning of each setting.
+    pub fn update_provider<G: Into<GUID>>(&self, guid: G, level: u8, any: u64, all: u64, filters: Vec<EventFilter>) -> TraceResult<()> {
+        let guid = guid.into();
+        let current = self.rt_callback_data()
+            .providers()
+            .into_iter()
+            .find(|prov| prov.guid() == guid)
+            .ok_or(TraceError::ProviderNotEnabled(guid))?;
+
+        // Enabling an already enabled provider updates its settings
+        enable_provider(self.backend.as_ref(), self.control_handle, &current.reconfigured(level, any, all, filters.clone()))?;
+        self.rt_callback_data().replace_providers(guid, |prov| prov.reconfigured(level, any, all, filters.clone()));
+        Ok(())
+    }
+
+    /// Change some settings of this running trace
+    ///
+    /// Internally, this calls `ControlTraceW(EVENT_TRACE_CONTROL_UPDATE)`.
+    pub fn update(&mut self, update: &TraceUpdate) -> TraceResult<()> {
+        update_trace(self.backend.as_ref(), &mut self.properties, self.control_handle, update, None)
+    }
+
+    /// Flush the buffers of this running trace, so that the events they contain are delivered to consumers (and written to the ETL dump file, if any)
+    ///
+    /// Internally, this calls `ControlTraceW(EVENT_TRACE_CONTROL_FLUSH)`.
+    pub fn flush(&self) -> TraceResult<()> {
+        flush_trace(self.backend.as_ref(), &self.properties, self.control_handle)
+    }
+
+    /// Request an enabled Provider to log its current state (e.g. running processes, loaded modules, etc.)
+    ///
+    /// The provider emits "rundown" events. How they can be told apart from live events depends on the provider (see [`EventRecord::has_rundown_opcode`]).
+    /// `filters` restrict which rundown events are emitted, and are not applied to live events.<br/>
+    /// Internally, this calls `EnableTraceEx2(EVENT_CONTROL_CODE_CAPTURE_STATE)`.
+    pub fn capture_state<G: Into<GUID>>(&self, guid: G, filters: Vec<EventFilter>) -> TraceResult<()> {
+        let guid = guid.into();
+        let current = self.rt_callback_data()
+            .providers()
+            .into_iter()
+            .find(|prov| prov.guid() == guid)
+            .ok_or(TraceError::ProviderNotEnabled(guid))?;
+
+        capture_state(self.backend.as_ref(), self.control_handle, &current.reconfigured(current.level(), current.any(), current.all(), filters))?;
+        Ok(())
+    }
+
+    /// The current metrics of the dispatcher, if this trace has one (see [`TraceBuilder::dispatcher`])
+    pub fn dispatcher_metrics(&self) -> Option<DispatcherMetrics> {
+        self.rt_callback_data().dispatcher_metrics()
+    }
+
+    fn rt_callback_data(&self) -> &RealTimeCallbackData {
+        self.callback_data
+            .as_real_time()
+            .expect("a UserTrace always has real-time callback data")
+    }
 }
 
 impl KernelTrace {
@@ -285,7 +578,12 @@ impl KernelTrace {
             etl_dump_file: None,
             rt_callback_data: RealTimeCallbackData::new(),
             properties: TraceProperties::default(),
+            stack_walk_events: Vec::new(),
+            pmc_sources: Vec::new(),
+            pmc_events: Vec::new(),
+            raw_timestamps: false,
             trace_kind: PhantomData,
+            backend: default_backend(),
         };
         // Not all names are valid. Let's use the setter to check them for us
         builder.named(format!("n4r1b-trace-{}", utils::rand_string()))
@@ -298,6 +596,77 @@ impl KernelTrace {
     pub fn stop(mut self) -> TraceResult<()> {
         self.non_consuming_stop()
     }
+
+    /// Query the current statistics of the session (buffers, lost events, etc.)
+    ///
+    /// See [`UserTrace::statistics`]
+    pub fn statistics(&self) -> TraceResult<TraceStatistics> {
+        query_statistics(self.backend.as_ref(), &self.properties, self.control_handle)
+    }
+
+    /// The current metrics of the dispatcher, if this trace has one
+    ///
+    /// See [`UserTrace::dispatcher_metrics`]
+    pub fn dispatcher_metrics(&self) -> Option<DispatcherMetrics> {
+        self.callback_data.as_real_time().and_then(|rt_cb| rt_cb.dispatcher_metrics())
+    }
+
+    /// Change some settings of this running trace
+    ///
+    /// See [`UserTrace::update`]
+    pub fn update(&mut self, update: &TraceUpdate) -> TraceResult<()> {
+        update_trace(self.backend.as_ref(), &mut self.properties, self.control_handle, update, None)
+    }
+
+    /// Change the kernel flags (i.e. which kind of kernel events are logged) of this running trace
+    ///
+    /// Events are still dispatched by provider GUID, so this is mostly useful to switch on and off the flags of providers that were given to [`TraceBuilder::enable`].<br/>
+    /// Internally, this calls `ControlTraceW(EVENT_TRACE_CONTROL_UPDATE)`.
+    pub fn update_kernel_flags(&mut self, kernel_flags: u32) -> TraceResult<()> {
+        update_trace(self.backend.as_ref(), &mut self.properties, self.control_handle, &TraceUpdate::default(), Some(kernel_flags))
+    }
+
+    /// Flush the buffers of this running trace
+    ///
+    /// See [`UserTrace::flush`]
+    pub fn flush(&self) -> TraceResult<()> {
+        flush_trace(self.backend.as_ref(), &self.properties, self.control_handle)
+    }
+}
+
+fn query_statistics(backend: &dyn EvntraceBackend, properties: &EventTraceProperties, control_handle: ControlHandle) -> TraceResult<TraceStatistics> {
+    // `ControlTraceW` overwrites the properties. Let's work on a copy, so that the trace keeps the properties it has been started with
+    let mut queried = *properties;
+    control_trace(backend, &mut queried, control_handle, Etw::EVENT_TRACE_CONTROL_QUERY)?;
+    Ok(TraceStatistics::from_raw(queried.as_raw()))
+}
+
+fn update_trace(backend: &dyn EvntraceBackend, properties: &mut EventTraceProperties, control_handle: ControlHandle, update: &TraceUpdate, kernel_flags: Option<u32>) -> TraceResult<()> {
+    let wide_etl_dump_file = update.etl_dump_file.as_ref().map(|path| U16CString::from_os_str_truncate(path.as_os_str()));
+    let mut updated = properties.for_update(
+        update.flush_timer,
+        update.max_buffer,
+        wide_etl_dump_file.as_deref(),
+        kernel_flags,
+    );
+    control_trace(backend, &mut updated, control_handle, Etw::EVENT_TRACE_CONTROL_UPDATE)?;
+
+    // ETW has written the actual settings of the session back
+    *properties = updated;
+    Ok(())
+}
+
+/// Stop a session this crate has started, but that failed to be set up
+fn stop_session(backend: &dyn EvntraceBackend, mut properties: EventTraceProperties, control_handle: ControlHandle) {
+    if let Err(err) = control_trace(backend, &mut properties, control_handle, Etw::EVENT_TRACE_CONTROL_STOP) {
+        log::warn!("Unable to stop a session that failed to start: {:?}", err);
+    }
+}
+
+fn flush_trace(backend: &dyn EvntraceBackend, properties: &EventTraceProperties, control_handle: ControlHandle) -> TraceResult<()> {
+    let mut flushed = *properties;
+    control_trace(backend, &mut flushed, control_handle, Etw::EVENT_TRACE_CONTROL_FLUSH)?;
+    Ok(())
 }
 
 mod private {
@@ -315,9 +684,9 @@ mod private {
     pub trait PrivateRealTimeTraceTrait: PrivateTraceTrait {
         const TRACE_KIND: TraceKind;
         #[allow(clippy::redundant_allocation)] // Being Boxed is really important, let's keep the Box<...> in the function signature to make the intent clearer (see https://github.com/n4r1b/ferrisetw/issues/72)
-        fn build(properties: EventTraceProperties, control_handle: ControlHandle, trace_handle: TraceHandle, callback_data: Box<Arc<CallbackData>>) -> Self;
+        fn build(properties: EventTraceProperties, control_handle: ControlHandle, trace_handle: TraceHandle, callback_data: Box<Arc<CallbackData>>, backend: Arc<dyn EvntraceBackend>) -> Self;
         fn augmented_file_mode() -> u32;
-        fn enable_flags(_providers: &[Provider]) -> u32;
+        fn enable_flags(_providers: &[Arc<Provider>]) -> u32;
     }
 
     pub trait PrivateTraceTrait {
@@ -330,27 +699,31 @@ mod private {
 impl private::PrivateRealTimeTraceTrait for UserTrace {
     const TRACE_KIND: private::TraceKind = private::TraceKind::User;
 
-    fn build(properties: EventTraceProperties, control_handle: ControlHandle, trace_handle: TraceHandle, callback_data: Box<Arc<CallbackData>>) -> Self {
+    fn build(properties: EventTraceProperties, control_handle: ControlHandle, trace_handle: TraceHandle, callback_data: Box<Arc<CallbackData>>, backend: Arc<dyn EvntraceBackend>) -> Self {
         UserTrace {
             properties,
             control_handle,
             trace_handle,
             callback_data,
+            backend,
         }
     }
 
     fn augmented_file_mode() -> u32 {
         0
     }
-    fn enable_flags(_providers: &[Provider]) -> u32 {
+    fn enable_flags(_providers: &[Arc<Provider>]) -> u32 {
         0
     }
 }
 
 impl private::PrivateTraceTrait for UserTrace {
     fn non_consuming_stop(&mut self) -> TraceResult<()> {
-        close_trace(self.trace_handle, &self.callback_data)?;
-        control_trace(&mut self.properties, self.control_handle, Etw::EVENT_TRACE_CONTROL_STOP)?;
+        close_trace(self.backend.as_ref(), self.trace_handle, &self.callback_data)?;
+        if let Some(rt_cb) = self.callback_data.as_real_time() {
+            rt_cb.stop_dispatcher();
+        }
+        control_trace(self.backend.as_ref(), &mut self.properties, self.control_handle, Etw::EVENT_TRACE_CONTROL_STOP)?;
         Ok(())
     }
 }
@@ -358,12 +731,13 @@ impl private::PrivateTraceTrait for UserTrace {
 impl private::PrivateRealTimeTraceTrait for KernelTrace {
     const TRACE_KIND: private::TraceKind = private::TraceKind::Kernel;
 
-    fn build(properties: EventTraceProperties, control_handle: ControlHandle, trace_handle: TraceHandle, callback_data: Box<Arc<CallbackData>>) -> Self {
+    fn build(properties: EventTraceProperties, control_handle: ControlHandle, trace_handle: TraceHandle, callback_data: Box<Arc<CallbackData>>, backend: Arc<dyn EvntraceBackend>) -> Self {
         KernelTrace {
             properties,
             control_handle,
             trace_handle,
             callback_data,
+            backend,
         }
     }
 
@@ -375,22 +749,25 @@ impl private::PrivateRealTimeTraceTrait for KernelTrace {
         }
     }
 
-    fn enable_flags(providers: &[Provider]) -> u32 {
+    fn enable_flags(providers: &[Arc<Provider>]) -> u32 {
         providers.iter().fold(0, |acc, x| acc | x.kernel_flags())
     }
 }
 
 impl private::PrivateTraceTrait for KernelTrace {
     fn non_consuming_stop(&mut self) -> TraceResult<()> {
-        close_trace(self.trace_handle, &self.callback_data)?;
-        control_trace(&mut self.properties, self.control_handle, Etw::EVENT_TRACE_CONTROL_STOP)?;
+        close_trace(self.backend.as_ref(), self.trace_handle, &self.callback_data)?;
+        if let Some(rt_cb) = self.callback_data.as_real_time() {
+            rt_cb.stop_dispatcher();
+        }
+        control_trace(self.backend.as_ref(), &mut self.properties, self.control_handle, Etw::EVENT_TRACE_CONTROL_STOP)?;
         Ok(())
     }
 }
 
 impl private::PrivateTraceTrait for FileTrace {
     fn non_consuming_stop(&mut self) -> TraceResult<()> {
-        close_trace(self.trace_handle, &self.callback_data)?;
+        close_trace(self.backend.as_ref(), self.trace_handle, &self.callback_data)?;
         Ok(())
     }
 }
@@ -434,21 +811,96 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
         self
     }
 
+    /// Call `callback` every time ETW reports that it lost events or buffers of this trace
+    ///
+    /// `callback` is given the kind of the loss, and how many losses of each kind were reported since the trace was started.<br/>
+    /// Note that ETW does not tell how many events were lost. [`UserTrace::statistics`] gives more accurate counters.
+    pub fn on_lost_events<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(LostEventKind, &LostEventCounts) + Send + Sync + 'static
+    {
+        self.rt_callback_data.set_on_lost_events(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` with the header of the trace, that ETW sends when the consumer starts processing the trace
+    pub fn on_trace_header<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&TraceHeader) + Send + Sync + 'static
+    {
+        self.rt_callback_data.set_on_trace_header(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` for every event that no enabled provider matches
+    ///
+    /// Such events are dropped otherwise. Lost events and the trace header are not passed to this callback (see [`Self::on_lost_events`] and [`Self::on_trace_header`]).
+    pub fn on_unmatched_event<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static
+    {
+        self.rt_callback_data.set_on_unmatched_event(Box::new(callback));
+        self
+    }
+
+    /// Call `callback` every time all the events of a buffer have been delivered
+    ///
+    /// `callback` is given statistics about the trace. It can return `false` to stop processing the trace, in which case `process` returns `Ok(())`.
+    pub fn on_buffer<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&BufferStatistics) -> bool + Send + Sync + 'static
+    {
+        self.rt_callback_data.set_on_buffer(Box::new(callback));
+        self
+    }
+
+    /// Invoke the callbacks on a pool of worker threads, rather than on the thread that processes the trace
+    ///
+    /// See the [`dispatcher`] module for more info.
+    pub fn dispatcher(mut self, options: DispatcherOptions) -> Self {
+        self.rt_callback_data.set_dispatcher_options(options);
+        self
+    }
+
+    /// Set what happens when a callback panics (by default, the process exits)
+    ///
+    /// See the [`panic_policy`] module for more info.
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.rt_callback_data.set_panic_policy(policy);
+        self
+    }
+
+    /// Leave the timestamps of the events in the unit of the clock of the session (see [`TraceProperties::clock`])
+    ///
+    /// By default, ETW converts them into system time for every event. Raw timestamps can be converted afterwards, using [`TraceTrait::timestamp_converter`].<br/>
+    /// Internally, this processes the trace with `PROCESS_TRACE_MODE_RAW_TIMESTAMP`.
+    pub fn raw_timestamps(mut self, raw_timestamps: bool) -> Self {
+        self.raw_timestamps = raw_timestamps;
+        self
+    }
+
     /// Enable a Provider for this trace
     ///
     /// This will invoke the provider's callback whenever an event is available
     ///
     /// # Note
-    /// Windows API seems to support removing providers, or changing its properties when the session is processing events (see <https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-enabletraceex2#remarks>)    /// Currently, this crate only supports defining Providers and their settings when building the trace, because it is easier to ensure memory-safety this way.
-    /// It probably would be possible to support changing Providers when the trace is processing, but this is left as a TODO (see <https://github.com/n4r1b/ferrisetw/issues/54>)
-    pub fn enable(mut self, provider: Provider) -> Self {
+    /// Providers of a [`UserTrace`] can also be enabled, disabled or reconfigured once the trace is running (see [`UserTrace::enable_provider`]).
+    pub fn enable(self, provider: Provider) -> Self {
         self.rt_callback_data.add_provider(provider);
         self
     }
 
+    /// Use another backend than the Windows API (e.g. a fake one, in tests)
+    #[cfg(test)]
+    pub(crate) fn backend(mut self, backend: Arc<dyn EvntraceBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
     /// Build the `UserTrace` and start the trace session
     ///
     /// Internally, this calls the `StartTraceW`, `EnableTraceEx2` and `OpenTraceW`.
+    /// This fails (and stops the session) in case a provider cannot be enabled, e.g. because one of its filters is invalid.
     ///
     /// To start receiving events, you'll still have to call either:
     /// * Worst option: `process()` on the returned `T`. This will block the current thread until the trace is stopped.<br/>
@@ -461,7 +913,7 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
     ///   This option returns a `T`, so you can explicitly stop the trace, but there is no way to get the status code of the ProcessTrace API.
     pub fn start(self) -> TraceResult<(T, TraceHandle)> {
         // Prepare a wide version of the trace name
-        let trace_wide_name = U16CString::from_str_truncate(self.name);
+        let trace_wide_name = U16CString::from_str_truncate(&self.name);
         let mut trace_wide_vec = trace_wide_name.into_vec();
         trace_wide_vec.truncate(crate::native::etw_types::TRACE_NAME_MAX_CHARS);
         let trace_wide_name = U16CString::from_vec_truncate(trace_wide_vec);
@@ -469,7 +921,7 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
         // Prepare a wide version of the ETL dump file path
         let wide_etl_dump_file = match self.etl_dump_file {
             None => None,
-            Some(DumpFileParams { file_path, file_logging_mode, max_size }) => {
+            Some(DumpFileParams { ref file_path, file_logging_mode, max_size }) => {
                 let wide_path = U16CString::from_os_str_truncate(file_path.as_os_str());
                 let mut wide_path_vec = wide_path.into_vec();
                 wide_path_vec.truncate(crate::native::etw_types::TRACE_NAME_MAX_CHARS);
@@ -477,34 +929,109 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
             }
         };
 
+        let mut properties = self.properties;
+        if T::TRACE_KIND == private::TraceKind::User && self.rt_callback_data.providers().iter().any(|prov| prov.is_system_provider()) {
+            // System providers can only be enabled in a system logger session
+            if properties.log_file_mode.is_empty() {
+                properties.log_file_mode = TraceProperties::default().log_file_mode;
+            }
+            properties.log_file_mode |= LoggingMode::EVENT_TRACE_SYSTEM_LOGGER_MODE;
+        }
+
         let flags = self.rt_callback_data.provider_flags::<T>();
         let (full_properties, control_handle) = start_trace::<T>(
+            self.backend.as_ref(),
             &trace_wide_name,
             wide_etl_dump_file.as_ref().map(|(path, params, max_size)| (path.as_ucstr(), *params, *max_size)),
-            &self.properties,
+            &properties,
             flags)?;
 
-        // TODO: For kernel traces, implement enable_provider function for providers that require call to TraceSetInformation with extended PERFINFO_GROUPMASK
-
-        if T::TRACE_KIND == private::TraceKind::User {
-            for prov in self.rt_callback_data.providers() {
-                enable_provider(control_handle, prov)?;
-            }
+        // From now on, the session must not outlive a failure
+        if let Err(err) = self.configure_session(control_handle, flags) {
+            stop_session(self.backend.as_ref(), full_properties, control_handle);
+            return Err(err);
         }
 
         let callback_data = Box::new(Arc::new(CallbackData::RealTime(self.rt_callback_data)));
-        let trace_handle = open_trace(SubscriptionSource::RealTimeSession(trace_wide_name), &callback_data)?;
+        callback_data.start_dispatcher();
+        let trace_handle = match open_trace(&self.backend, SubscriptionSource::RealTimeSession(trace_wide_name), self.raw_timestamps, &callback_data) {
+            Ok(handle) => handle,
+            Err(err) => {
+                stop_session(self.backend.as_ref(), full_properties, control_handle);
+                return Err(err.into());
+            }
+        };
+        callback_data.set_owner(TraceOwner {
+            name: Some(full_properties.name()),
+            trace_handle,
+            session: Some(OwnedSession {
+                backend: Arc::clone(&self.backend),
+                properties: full_properties,
+                control_handle,
+            }),
+        });
 
         Ok((T::build(
                 full_properties,
                 control_handle,
                 trace_handle,
                 callback_data,
+                self.backend,
             ),
             trace_handle)
         )
     }
 
+    /// Configure a session that has just been started, and enable its providers
+    fn configure_session(&self, control_handle: ControlHandle, flags: Etw::EVENT_TRACE_FLAG) -> TraceResult<()> {
+        if T::TRACE_KIND == private::TraceKind::Kernel {
+            let group_mask = self.rt_callback_data
+                .providers()
+                .iter()
+                .fold(KernelGroupMask::empty(), |acc, prov| acc.union(prov.kernel_group_mask()));
+            if !group_mask.is_empty() {
+                // This replaces the whole set of flags, including the classic EnableFlags that are the first mask
+                let group_mask = group_mask.union(KernelGroupMask::from_enable_flags(flags.0));
+                set_information(self.backend.as_ref(), control_handle, TraceInformation::TraceSystemTraceEnableFlagsInfo, &group_mask.to_bytes())?;
+            }
+        }
+
+        if !self.stack_walk_events.is_empty() {
+            let classic_event_ids: Vec<u8> = self.stack_walk_events
+                .iter()
+                .flat_map(|event| event.to_bytes())
+                .collect();
+            set_information(self.backend.as_ref(), control_handle, TraceInformation::TraceStackTracingInfo, &classic_event_ids)?;
+        }
+
+        // Counters must be configured before the events that carry them
+        if !self.pmc_sources.is_empty() {
+            let sources: Vec<u8> = self.pmc_sources
+                .iter()
+                .flat_map(|source| source.to_le_bytes())
+                .collect();
+            set_information(self.backend.as_ref(), control_handle, TraceInformation::TracePmcCounterListInfo, &sources)?;
+        }
+        if !self.pmc_events.is_empty() {
+            let classic_event_ids: Vec<u8> = self.pmc_events
+                .iter()
+                .flat_map(|event| event.to_bytes())
+                .collect();
+            set_information(self.backend.as_ref(), control_handle, TraceInformation::TracePmcEventListInfo, &classic_event_ids)?;
+        }
+
+        if T::TRACE_KIND == private::TraceKind::User {
+            for prov in self.rt_callback_data.providers() {
+                enable_provider(self.backend.as_ref(), control_handle, &prov)?;
+                if prov.capture_state_on_enable() {
+                    capture_state(self.backend.as_ref(), control_handle, &prov)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Convenience method that calls [`TraceBuilder::start`] then `process`
     ///
     /// # Notes
@@ -517,27 +1044,145 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
 
         Ok(trace)
     }
+
+    /// Build the trace, but feed it with events from `source` rather than from an actual ETW session
+    ///
+    /// No ETW session is created. Replayed events are dispatched to the callbacks of the enabled providers, exactly like live events would.<br/>
+    /// Like [`TraceBuilder::start`], this returns immediately, and events are replayed when `process` (or `process_from_handle`) is called.
+    /// Processing returns when `source` is exhausted or when the trace is stopped.
+    ///
+    /// See the [`replay`] module for more info.
+    pub fn start_replay<S: replay::ReplaySource>(mut self, source: S) -> TraceResult<(T, TraceHandle)> {
+        self.backend = Arc::new(replay::ReplayBackend::new(Box::new(source)));
+        self.start()
+    }
+}
+
+impl TraceBuilder<KernelTrace> {
+    /// Log the call stack of every kernel event of this class
+    ///
+    /// Call stacks are delivered as separate events, from the [`STACK_WALK_PROVIDER`](crate::provider::kernel_providers::STACK_WALK_PROVIDER).
+    /// Enable it to receive them in a callback. Their `EventTimeStamp` and `StackThread` properties match the timestamp and the thread of the event they belong to.<br/>
+    /// Note that the kernel provider of the event itself must be enabled as well.
+    ///
+    /// Internally, this calls `TraceSetInformation(TraceStackTracingInfo)` once the session is started.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::{Provider, kernel_providers};
+    /// # use ferrisetw::trace::KernelTrace;
+    /// let builder = KernelTrace::new()
+    ///     .enable(Provider::kernel(&kernel_providers::PROCESS_PROVIDER).build())
+    ///     .enable(Provider::kernel(&kernel_providers::STACK_WALK_PROVIDER).build())
+    ///     .enable_stack_walk(&kernel_providers::PROCESS_START_EVENT);
+    /// ```
+    pub fn enable_stack_walk(mut self, event: &ClassicEventId) -> Self {
+        self.stack_walk_events.push(*event);
+        self
+    }
+
+    /// Set the hardware counters (PMC) to collect, by profile source
+    ///
+    /// See [`SessionlessInfo::profile_sources`](crate::query::SessionlessInfo::profile_sources) for the available sources,
+    /// and [`SessionlessInfo::max_pmc`](crate::query::SessionlessInfo::max_pmc) for how many can be collected at once.<br/>
+    /// Their values are attached to the events given to [`Self::enable_pmc_counters`], as [`ExtendedDataItem::PmcCounters`](crate::native::ExtendedDataItem::PmcCounters).
+    ///
+    /// Internally, this calls `TraceSetInformation(TracePmcCounterListInfo)` once the session is started.
+    pub fn set_pmc_sources(mut self, sources: Vec<u32>) -> Self {
+        self.pmc_sources = sources;
+        self
+    }
+
+    /// Attach the values of the PMC counters (see [`Self::set_pmc_sources`]) to every kernel event of this class
+    ///
+    /// Internally, this calls `TraceSetInformation(TracePmcEventListInfo)` once the session is started.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::{Provider, kernel_providers};
+    /// # use ferrisetw::trace::KernelTrace;
+    /// let builder = KernelTrace::new()
+    ///     .enable(Provider::kernel(&kernel_providers::CONTEXT_SWITCH_PROVIDER).build())
+    ///     .set_pmc_sources(vec![0x02, 0x0b]) // e.g. TotalIssues and BranchMispredictions
+    ///     .enable_pmc_counters(&kernel_providers::CSWITCH_EVENT);
+    /// ```
+    pub fn enable_pmc_counters(mut self, event: &ClassicEventId) -> Self {
+        self.pmc_events.push(*event);
+        self
+    }
 }
 
 impl FileTrace {
     /// Create a trace that will read events from a file
     pub fn new<T>(path: PathBuf, callback: T) -> FileTraceBuilder
         where T: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        let mut callback = callback;
+        Self::new_with_context(path, move |record: &EventRecord, schema_locator: &SchemaLocator, _context: &EventContext| {
+            callback(record, schema_locator);
+            ControlFlow::Continue(())
+        })
+    }
+
+    /// Create a trace that will read events from a file, with a callback that can stop processing it
+    ///
+    /// See [`event_context`] for more info.
+    pub fn new_with_context<T>(path: PathBuf, callback: T) -> FileTraceBuilder
+        where T: FnMut(&EventRecord, &SchemaLocator, &EventContext) -> ControlFlow<()> + Send + Sync + 'static,
     {
         FileTraceBuilder{
             etl_file_path: path,
             callback: Box::new(callback),
+            on_buffer: None,
+            panic_policy: PanicPolicy::default(),
+            raw_timestamps: false,
+            backend: default_backend(),
         }
     }
 
     fn non_consuming_stop(&mut self) -> TraceResult<()> {
-        close_trace(self.trace_handle, &self.callback_data)?;
+        close_trace(self.backend.as_ref(), self.trace_handle, &self.callback_data)?;
         Ok(())
     }
 }
 
 
 impl FileTraceBuilder{
+    /// Leave the timestamps of the events in the unit of the clock the file was recorded with
+    ///
+    /// See [`TraceBuilder::raw_timestamps`]
+    pub fn raw_timestamps(mut self, raw_timestamps: bool) -> Self {
+        self.raw_timestamps = raw_timestamps;
+        self
+    }
+
+    /// Call `callback` every time all the events of a buffer of the file have been delivered
+    ///
+    /// This can be used to report progress (see [`BufferStatistics::progress`]), or to cancel processing the file by returning `false`.
+    /// In this case, `process` returns `Ok(())`.
+    pub fn on_buffer<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&BufferStatistics) -> bool + Send + Sync + 'static
+    {
+        self.on_buffer = Some(Box::new(callback));
+        self
+    }
+
+    /// Set what happens when the callback panics
+    ///
+    /// See [`TraceBuilder::panic_policy`]
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Use another backend than the Windows API (e.g. a fake one, in tests)
+    #[cfg(test)]
+    pub(crate) fn backend(mut self, backend: Arc<dyn EvntraceBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
     /// Build the `FileTrace` and start the trace session
     ///
     /// See the documentation for [`TraceBuilder::start`] for more information.
@@ -545,13 +1190,23 @@ impl FileTraceBuilder{
         // Prepare a wide version of the source ETL file path
         let wide_etl_file_path = U16CString::from_os_str_truncate(self.etl_file_path.as_os_str());
 
-        let from_file_cb = CallbackDataFromFile::new(self.callback);
+        let mut from_file_cb = CallbackDataFromFile::new(self.callback);
+        if let Some(on_buffer) = self.on_buffer {
+            from_file_cb.set_on_buffer(on_buffer);
+        }
+        from_file_cb.set_panic_policy(self.panic_policy);
         let callback_data = Box::new(Arc::new(CallbackData::FromFile(from_file_cb)));
-        let trace_handle = open_trace(SubscriptionSource::FromFile(wide_etl_file_path), &callback_data)?;
+        let trace_handle = open_trace(&self.backend, SubscriptionSource::FromFile(wide_etl_file_path), self.raw_timestamps, &callback_data)?;
+        callback_data.set_owner(TraceOwner {
+            name: None,
+            trace_handle,
+            session: None,
+        });
 
         Ok((FileTrace{
                 trace_handle,
                 callback_data,
+                backend: self.backend,
             },
             trace_handle)
         )
@@ -609,6 +1264,7 @@ pub fn stop_trace_by_name(trace_name: &str) -> TraceResult<()> {
         flags);
 
     control_trace_by_name(
+        default_backend().as_ref(),
         &mut properties,
         &wide_name,
         Etw::EVENT_TRACE_CONTROL_STOP,
@@ -620,6 +1276,12 @@ pub fn stop_trace_by_name(trace_name: &str) -> TraceResult<()> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::native::EvntraceNativeError;
+    use crate::native::evntrace::backend::fake::{fake_kernel_trace, fake_user_trace, FakeBackend};
+    use crate::provider::event_filter::EventFilterError;
+
+    const PROV: &str = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716";
+    const OTHER_PROV: &str = "7dd42a49-5329-4832-8dfd-43d979153a88";
 
     #[test]
     fn test_enable_multiple_providers() {
@@ -630,4 +1292,575 @@ mod test {
 
         assert_eq!(trace_builder.rt_callback_data.providers().len(), 2);
     }
+
+    fn replayed_event(provider: &str, id: u16) -> crate::OwnedEventRecord {
+        crate::record_builder::EventRecordBuilder::new(GUID::from(provider))
+            .event_id(id)
+            .build()
+    }
+
+    #[test]
+    fn test_replay_routing() {
+        use std::sync::Mutex;
+        const PROV_B: &str = "a0c1853b-5c40-4b15-8766-3cf1c58f985a";
+        const UNKNOWN: &str = "00000000-1111-2222-3333-444444444444";
+
+        let received_a = Arc::new(Mutex::new(Vec::new()));
+        let received_b = Arc::new(Mutex::new(Vec::new()));
+        let received_a_clone = Arc::clone(&received_a);
+        let received_b_clone = Arc::clone(&received_b);
+
+        let prov_a = Provider::by_guid(PROV)
+            .add_callback(move |record, _| received_a_clone.lock().unwrap().push(record.event_id()))
+            .build();
+        let prov_b = Provider::by_guid(PROV_B)
+            .add_callback(move |record, _| received_b_clone.lock().unwrap().push(record.event_id()))
+            .build();
+
+        let events = vec![
+            replayed_event(PROV, 1),
+            replayed_event(PROV_B, 2),
+            replayed_event(UNKNOWN, 3),
+            replayed_event(PROV, 4),
+        ];
+
+        let (mut trace, _handle) = UserTrace::new()
+            .enable(prov_a)
+            .enable(prov_b)
+            .start_replay(replay::from_iter(events))
+            .unwrap();
+        trace.process().unwrap();
+
+        assert_eq!(*received_a.lock().unwrap(), vec![1, 4]);
+        assert_eq!(*received_b.lock().unwrap(), vec![2]);
+        assert_eq!(trace.events_handled(), 4);
+
+        // A replay can only be processed once
+        assert!(trace.process().is_err());
+        trace.stop().unwrap();
+    }
+
+    #[test]
+    fn test_replay_stop() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (processed_sender, processed_receiver) = std::sync::mpsc::sync_channel(16);
+
+        let prov = Provider::by_guid(PROV)
+            .add_callback(move |record, _| processed_sender.send(record.event_id()).unwrap())
+            .build();
+        let (trace, handle) = UserTrace::new()
+            .enable(prov)
+            .start_replay(receiver)
+            .unwrap();
+        let processing_thread = std::thread::spawn(move || UserTrace::process_from_handle(handle));
+
+        sender.send(replayed_event(PROV, 1)).unwrap();
+        assert_eq!(processed_receiver.recv().unwrap(), 1);
+        trace.stop().unwrap();
+        // Stopping makes the processing return, at the latest when the next event is received
+        let _ = sender.send(replayed_event(PROV, 2));
+        processing_thread.join().unwrap().unwrap();
+        assert!(processed_receiver.try_recv().is_err());
+
+        // The handle is no longer valid
+        assert!(UserTrace::process_from_handle(handle).is_err());
+    }
+
+    #[test]
+    fn test_fake_backend() {
+        let (processed_sender, processed_receiver) = std::sync::mpsc::sync_channel(16);
+
+        let prov = Provider::by_guid(PROV)
+            .add_callback(move |record, _| processed_sender.send(record.event_id()).unwrap())
+            .build();
+        let (builder, backend) = fake_user_trace("fake-backend");
+        let (trace, handle) = builder
+            .enable(prov)
+            .start()
+            .unwrap();
+        assert_eq!(backend.enabled_providers("fake-backend"), vec![GUID::from(PROV)]);
+
+        // Session names are unique
+        let conflict = backend.user_trace("fake-backend")
+            .start();
+        assert!(matches!(conflict, Err(TraceError::EtwNativeError(crate::native::EvntraceNativeError::AlreadyExist))));
+
+        let processing_thread = std::thread::spawn(move || UserTrace::process_from_handle(handle));
+        backend.emit("fake-backend", &replayed_event(PROV, 1));
+        assert_eq!(processed_receiver.recv().unwrap(), 1);
+
+        trace.stop().unwrap();
+        processing_thread.join().unwrap().unwrap();
+        assert!(backend.session_names().is_empty());
+        assert_eq!(backend.consumer_count(), 0);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let (builder, backend) = fake_user_trace("fake-statistics");
+        let (trace, _handle) = builder
+            .enable(Provider::by_guid(PROV).build())
+            .start()
+            .unwrap();
+        assert!(!trace.statistics().unwrap().has_losses());
+
+        backend.emit("fake-statistics", &replayed_event(PROV, 1));
+        backend.emit("fake-statistics", &replayed_event(PROV, 2));
+        backend.lose_events("fake-statistics", 3);
+        let stats = trace.statistics().unwrap();
+        assert_eq!(stats.buffers_written, 2);
+        assert_eq!(stats.events_lost, 3);
+        assert!(stats.has_losses());
+
+        // Querying does not alter the properties the trace has been started with
+        assert_eq!(trace.properties.as_raw().EventsLost, 0);
+        trace.stop().unwrap();
+    }
+
+    #[test]
+    fn test_change_providers_while_running() {
+        let (sender_a, receiver_a) = std::sync::mpsc::sync_channel(16);
+        let (sender_b, receiver_b) = std::sync::mpsc::sync_channel(16);
+
+        let (builder, backend) = fake_user_trace("fake-dynamic-providers");
+        let (trace, handle) = builder
+            .enable(Provider::by_guid(PROV).add_callback(move |record, _| sender_a.send(record.event_id()).unwrap()).build())
+            .start()
+            .unwrap();
+        let processing_thread = std::thread::spawn(move || UserTrace::process_from_handle(handle));
+
+        trace.enable_provider(Provider::by_guid(OTHER_PROV).add_callback(move |record, _| sender_b.send(record.event_id()).unwrap()).build()).unwrap();
+        assert_eq!(backend.enabled_providers("fake-dynamic-providers"), vec![GUID::from(PROV), GUID::from(OTHER_PROV)]);
+        backend.emit("fake-dynamic-providers", &replayed_event(OTHER_PROV, 2));
+        assert_eq!(receiver_b.recv().unwrap(), 2);
+
+        // Callbacks are kept when settings change
+        trace.update_provider(PROV, 5, 0xff, 0, vec![EventFilter::ByEventIds(vec![1])]).unwrap();
+        backend.emit("fake-dynamic-providers", &replayed_event(PROV, 1));
+        assert_eq!(receiver_a.recv().unwrap(), 1);
+
+        trace.disable_provider(OTHER_PROV).unwrap();
+        assert_eq!(backend.enabled_providers("fake-dynamic-providers"), vec![GUID::from(PROV)]);
+        // Events are delivered in order: once the event from A is received, the one from B has been dispatched (to nobody)
+        backend.emit("fake-dynamic-providers", &replayed_event(OTHER_PROV, 3));
+        backend.emit("fake-dynamic-providers", &replayed_event(PROV, 4));
+        assert_eq!(receiver_a.recv().unwrap(), 4);
+        assert!(receiver_b.try_recv().is_err());
+
+        assert!(matches!(trace.disable_provider(OTHER_PROV), Err(TraceError::ProviderNotEnabled(_))));
+        assert!(matches!(trace.update_provider(OTHER_PROV, 0, 0, 0, Vec::new()), Err(TraceError::ProviderNotEnabled(_))));
+
+        // Invalid filters are reported, rather than ignored (which would deliver every event)
+        let invalid_filter = || vec![EventFilter::ByExecutableNames(vec![String::from("a.exe;b.exe")])];
+        assert!(matches!(
+            trace.update_provider(PROV, 0, 0, 0, invalid_filter()),
+            Err(TraceError::EtwNativeError(EvntraceNativeError::InvalidFilter(EventFilterError::InvalidItem(_))))
+        ));
+        assert!(matches!(
+            trace.enable_provider(Provider::by_guid(OTHER_PROV).add_filter(invalid_filter().remove(0)).build()),
+            Err(TraceError::EtwNativeError(EvntraceNativeError::InvalidFilter(_)))
+        ));
+        assert_eq!(backend.enabled_providers("fake-dynamic-providers"), vec![GUID::from(PROV)]);
+        // Empty filters are still ignored
+        trace.update_provider(PROV, 5, 0xff, 0, vec![EventFilter::ByEventIds(Vec::new())]).unwrap();
+
+        // A provider that cannot be enabled is not registered either
+        backend.reject_provider(GUID::from(OTHER_PROV));
+        assert!(trace.enable_provider(Provider::by_guid(OTHER_PROV).build()).is_err());
+        assert_eq!(backend.enabled_providers("fake-dynamic-providers"), vec![GUID::from(PROV)]);
+        assert!(matches!(trace.disable_provider(OTHER_PROV), Err(TraceError::ProviderNotEnabled(_))));
+
+        trace.stop().unwrap();
+        processing_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_failed_start_stops_the_session() {
+        let backend = Arc::new(FakeBackend::new());
+        backend.reject_provider(GUID::from(PROV));
+
+        let result = backend.user_trace("fake-failed-start")
+            .enable(Provider::by_guid(PROV).build())
+            .start();
+        assert!(result.is_err());
+        assert!(backend.session_names().is_empty());
+        assert_eq!(backend.consumer_count(), 0);
+
+        let result = backend.user_trace("fake-invalid-filter")
+            .enable(Provider::by_guid(OTHER_PROV).add_filter(EventFilter::ByPids(vec![1; 9])).build())
+            .start();
+        assert!(matches!(result, Err(TraceError::EtwNativeError(EvntraceNativeError::InvalidFilter(EventFilterError::TooManyItems(8))))));
+        assert!(backend.session_names().is_empty());
+    }
+
+    #[test]
+    fn test_dispatcher() {
+        use crate::record_builder::EventRecordBuilder;
+        use dispatcher::DispatchKey;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::{Duration, Instant};
+        const EVENTS_PER_PROCESS: u16 = 50;
+        const QUEUE_CAPACITY: usize = 4;
+        let handled = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let callback_handled = Arc::clone(&handled);
+        // Callbacks wait as long as this is set
+        let paused = Arc::new(AtomicBool::new(false));
+        let callback_paused = Arc::clone(&paused);
+
+        let (builder, backend) = fake_user_trace("fake-dispatcher");
+        let (trace, handle) = builder
+            .enable(Provider::by_guid(PROV)
+                .add_shared_callback(move |record, _, _| {
+                    // Slow callbacks make the queues fill up
+                    std::thread::sleep(Duration::from_micros(200));
+                    while callback_paused.load(Ordering::Acquire) {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                    callback_handled.lock().unwrap().push((record.process_id(), record.event_id()));
+                    std::ops::ControlFlow::Continue(())
+                })
+                .build())
+            .dispatcher(DispatcherOptions::default().workers(2).key(DispatchKey::Process).queue_capacity(QUEUE_CAPACITY))
+            .start()
+            .unwrap();
+        let processing_thread = std::thread::spawn(move || UserTrace::process_from_handle(handle));
+
+        let emit_interleaved = |ids: std::ops::Range<u16>| {
+            for id in ids {
+                for pid in [1, 2] {
+                    backend.emit("fake-dispatcher", &EventRecordBuilder::new(GUID::from(PROV)).process_id(pid).event_id(id).build());
+                }
+            }
+        };
+        let wait_until = |condition: &dyn Fn() -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !condition() {
+                assert!(Instant::now() < deadline, "timed out, metrics are {:?}", trace.dispatcher_metrics());
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let handled_ids = |pid: u32| -> Vec<u16> {
+            handled.lock().unwrap().iter().filter(|(p, _)| *p == pid).map(|(_, id)| *id).collect()
+        };
+
+        emit_interleaved(0..EVENTS_PER_PROCESS);
+        wait_until(&|| trace.dispatcher_metrics().unwrap().events_dispatched == 2 * EVENTS_PER_PROCESS as u64);
+        let metrics = trace.dispatcher_metrics().unwrap();
+        assert_eq!(metrics.queue_depths, vec![0, 0]);
+        // The thread that processes the trace waited for room in the queues
+        assert!(metrics.max_queue_depth >= 1 && metrics.max_queue_depth <= QUEUE_CAPACITY);
+        assert!(metrics.max_latency >= metrics.average_latency);
+
+        // Events of the same process are handled in order
+        assert_eq!(handled_ids(1), (0..EVENTS_PER_PROCESS).collect::<Vec<_>>());
+        assert_eq!(handled_ids(2), (0..EVENTS_PER_PROCESS).collect::<Vec<_>>());
+
+        // Stopping the trace waits for the queued events to be handled
+        paused.store(true, Ordering::Release);
+        emit_interleaved(EVENTS_PER_PROCESS..EVENTS_PER_PROCESS + 2);
+        wait_until(&|| trace.events_handled() == 2 * (EVENTS_PER_PROCESS as usize + 2));
+        wait_until(&|| trace.dispatcher_metrics().unwrap().queue_depths.iter().sum::<usize>() >= 2);
+        let resume = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            paused.store(false, Ordering::Release);
+        });
+        trace.stop().unwrap();
+        assert_eq!(handled_ids(1), (0..EVENTS_PER_PROCESS + 2).collect::<Vec<_>>());
+        assert_eq!(handled_ids(2), (0..EVENTS_PER_PROCESS + 2).collect::<Vec<_>>());
+        resume.join().unwrap();
+        processing_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_update_and_flush() {
+        use crate::native::evntrace::backend::fake::FakeSessionSettings;
+
+        let (builder, backend) = fake_kernel_trace("fake-update");
+        let (mut trace, _handle) = builder
+            .set_trace_properties(TraceProperties { max_buffer: 16, ..Default::default() })
+            .set_etl_dump_file(DumpFileParams { file_path: PathBuf::from("first.etl"), ..Default::default() })
+            .start()
+            .unwrap();
+        let initial = backend.session_settings("fake-update").unwrap();
+        assert_eq!(initial.max_buffers, 16);
+        assert_eq!(initial.log_file, Some(OsString::from("first.etl")));
+
+        trace.update(&TraceUpdate { flush_timer: Some(Duration::from_secs(5)), ..Default::default() }).unwrap();
+        let updated = backend.session_settings("fake-update").unwrap();
+        assert_eq!(updated.flush_timer, 5);
+        // Unchanged settings are kept, including the log file
+        assert_eq!(updated.max_buffers, 16);
+        assert_eq!(updated.log_file, Some(OsString::from("first.etl")));
+
+        trace.update(&TraceUpdate {
+            max_buffer: Some(64),
+            etl_dump_file: Some(PathBuf::from("second.etl")),
+            ..Default::default()
+        }).unwrap();
+        trace.update_kernel_flags(0x10).unwrap();
+        let updated = backend.session_settings("fake-update").unwrap();
+        assert_eq!(updated, FakeSessionSettings {
+            flush_timer: 5,
+            max_buffers: 64,
+            enable_flags: 0x10,
+            log_file: Some(OsString::from("second.etl")),
+        });
+
+        trace.flush().unwrap();
+        assert_eq!(backend.flush_count("fake-update"), 1);
+        trace.stop().unwrap();
+    }
+
+    #[test]
+    fn test_capture_state() {
+        use crate::record_builder::EventRecordBuilder;
+        let (sender, receiver) = std::sync::mpsc::sync_channel(16);
+
+        let (builder, backend) = fake_user_trace("fake-capture-state");
+        let (trace, handle) = builder
+            .enable(Provider::by_guid(PROV)
+                .capture_state_on_enable(true)
+                .add_callback(move |record, _| sender.send(record.has_rundown_opcode()).unwrap())
+                .build())
+            .start()
+            .unwrap();
+        let processing_thread = std::thread::spawn(move || UserTrace::process_from_handle(handle));
+        assert_eq!(backend.capture_state_requests("fake-capture-state"), vec![GUID::from(PROV)]);
+
+        trace.enable_provider(Provider::by_guid(OTHER_PROV).build()).unwrap();
+        trace.enable_provider(Provider::by_guid(OTHER_PROV).capture_state_on_enable(true).build()).unwrap();
+        trace.capture_state(PROV, vec![EventFilter::ByEventIds(vec![1])]).unwrap();
+        assert_eq!(backend.capture_state_requests("fake-capture-state"), vec![GUID::from(PROV), GUID::from(OTHER_PROV), GUID::from(PROV)]);
+        assert!(matches!(trace.capture_state("0a7b1a3e-0000-0000-0000-000000000000", Vec::new()), Err(TraceError::ProviderNotEnabled(_))));
+
+        // A provider whose state cannot be captured is not left enabled
+        const PROV_C: &str = "0a7b1a3e-0000-0000-0000-000000000000";
+        backend.reject_capture_state(GUID::from(PROV_C));
+        assert!(trace.enable_provider(Provider::by_guid(PROV_C).capture_state_on_enable(true).build()).is_err());
+        assert_eq!(backend.enabled_providers("fake-capture-state"), vec![GUID::from(PROV), GUID::from(OTHER_PROV)]);
+        assert!(matches!(trace.disable_provider(PROV_C), Err(TraceError::ProviderNotEnabled(_))));
+        // Other providers with the same GUID stay enabled
+        backend.reject_capture_state(GUID::from(OTHER_PROV));
+        assert!(trace.enable_provider(Provider::by_guid(OTHER_PROV).capture_state_on_enable(true).build()).is_err());
+        assert_eq!(backend.enabled_providers("fake-capture-state"), vec![GUID::from(PROV), GUID::from(OTHER_PROV)]);
+        assert_eq!(trace.rt_callback_data().providers().iter().filter(|prov| prov.guid() == GUID::from(OTHER_PROV)).count(), 2);
+
+        // Classic rundown events are recognized by their opcode
+        backend.emit("fake-capture-state", &EventRecordBuilder::new(GUID::from(PROV)).opcode(3).build());
+        backend.emit("fake-capture-state", &EventRecordBuilder::new(GUID::from(PROV)).opcode(1).build());
+        backend.emit("fake-capture-state", &EventRecordBuilder::new(GUID::from(PROV)).opcode(4).build());
+        assert_eq!(receiver.iter().take(3).collect::<Vec<bool>>(), vec![true, false, true]);
+
+        trace.stop().unwrap();
+        processing_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_session_events() {
+        use crate::provider::kernel_providers::kernel_guids;
+        use crate::record_builder::EventRecordBuilder;
+        use std::sync::Mutex;
+
+        let lost = Arc::new(Mutex::new(Vec::new()));
+        let lost2 = Arc::clone(&lost);
+        let headers = Arc::new(Mutex::new(Vec::new()));
+        let headers2 = Arc::clone(&headers);
+        let unmatched = Arc::new(Mutex::new(Vec::new()));
+        let unmatched2 = Arc::clone(&unmatched);
+        let (builder, _backend) = fake_user_trace("fake-session-events");
+        let (trace, _handle) = builder
+            .enable(Provider::by_guid(PROV).build())
+            .on_lost_events(move |kind, counts| lost2.lock().unwrap().push((kind, *counts)))
+            .on_trace_header(move |header| headers2.lock().unwrap().push(header.logger_name.clone()))
+            .on_unmatched_event(move |record, _locator| unmatched2.lock().unwrap().push(record.provider_id()))
+            .start()
+            .unwrap();
+
+        let header = EventRecordBuilder::new(kernel_guids::EVENT_TRACE_GUID)
+            .user_data(session_events::test::header_payload("fake-session-events"))
+            .build();
+        let lost_event = EventRecordBuilder::new(kernel_guids::LOST_EVENT_GUID).opcode(32).build();
+        let lost_buffer = EventRecordBuilder::new(kernel_guids::LOST_EVENT_GUID).opcode(33).build();
+        let matched = EventRecordBuilder::new(GUID::from(PROV)).build();
+        let not_enabled = GUID::from("1edeee53-0afe-4609-b846-d8c0b2075b1f");
+        let unmatched_event = EventRecordBuilder::new(not_enabled).build();
+        for record in [&header, &lost_event, &lost_buffer, &lost_event, &matched, &unmatched_event] {
+            trace.rt_callback_data().on_event(record);
+        }
+
+        assert_eq!(*headers.lock().unwrap(), vec![String::from("fake-session-events")]);
+        assert_eq!(*lost.lock().unwrap(), vec![
+            (LostEventKind::Events, LostEventCounts { lost_events: 1, lost_buffers: 0, lost_backing_files: 0 }),
+            (LostEventKind::Buffers, LostEventCounts { lost_events: 1, lost_buffers: 1, lost_backing_files: 0 }),
+            (LostEventKind::Events, LostEventCounts { lost_events: 2, lost_buffers: 1, lost_backing_files: 0 }),
+        ]);
+        assert_eq!(*unmatched.lock().unwrap(), vec![not_enabled]);
+        assert_eq!(trace.rt_callback_data().lost_event_counts().lost_events, 2);
+
+        trace.stop().unwrap();
+    }
+
+    #[test]
+    fn test_file_trace_buffers() {
+        use std::sync::Mutex;
+        let backend = Arc::new(FakeBackend::new());
+        let buffers = (0..4)
+            .map(|buffer| vec![replayed_event(PROV, buffer * 2), replayed_event(PROV, buffer * 2 + 1)])
+            .collect();
+        backend.add_etl_file("fake.etl", buffers);
+
+        // Cancel after the second buffer
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received2 = Arc::clone(&received);
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let progress2 = Arc::clone(&progress);
+        let (mut trace, _handle) = FileTrace::new(PathBuf::from("fake.etl"), move |record, _| received2.lock().unwrap().push(record.event_id()))
+            .on_buffer(move |stats| {
+                progress2.lock().unwrap().push(stats.progress().unwrap());
+                stats.buffers_read < 2
+            })
+            .backend(backend.shared())
+            .start()
+            .unwrap();
+        trace.process().unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(*progress.lock().unwrap(), vec![25.0, 50.0]);
+        trace.stop().unwrap();
+
+        // Process the whole file
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let progress2 = Arc::clone(&progress);
+        let (mut trace, _handle) = FileTrace::new(PathBuf::from("fake.etl"), |_, _| {})
+            .on_buffer(move |stats| {
+                progress2.lock().unwrap().push(stats.progress().unwrap());
+                true
+            })
+            .backend(backend.shared())
+            .start()
+            .unwrap();
+        trace.process().unwrap();
+        assert_eq!(*progress.lock().unwrap(), vec![25.0, 50.0, 75.0, 100.0]);
+        assert_eq!(trace.events_handled(), 8);
+        trace.stop().unwrap();
+
+        assert!(FileTrace::new(PathBuf::from("missing.etl"), |_, _| {})
+            .backend(backend.shared())
+            .start()
+            .is_err());
+    }
+
+    #[test]
+    fn test_event_context() {
+        use std::sync::Mutex;
+        let backend = Arc::new(FakeBackend::new());
+
+        // Stop a file trace at the first matching event, in the middle of a buffer
+        backend.add_etl_file("fake-context.etl", vec![
+            vec![replayed_event(PROV, 0), replayed_event(PROV, 1), replayed_event(PROV, 2)],
+            vec![replayed_event(PROV, 3)],
+        ]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = Arc::clone(&seen);
+        let (mut trace, handle) = FileTrace::new_with_context(PathBuf::from("fake-context.etl"), move |record, _, context| {
+                seen2.lock().unwrap().push((record.event_id(), context.events_handled(), context.trace_name(), context.trace_handle(), context.statistics().is_ok()));
+                if record.event_id() == 1 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            })
+            .backend(backend.shared())
+            .start()
+            .unwrap();
+        trace.process().unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![(0, 1, None, Some(handle), false), (1, 2, None, Some(handle), false)]);
+        assert_eq!(trace.events_handled(), 2);
+        trace.stop().unwrap();
+
+        // Stop a real-time trace
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = Arc::clone(&seen);
+        let prov = Provider::by_guid(PROV)
+            .add_callback_with_context(move |record, _, context| {
+                seen2.lock().unwrap().push((record.event_id(), context.trace_name(), context.statistics().map(|stats| stats.buffers_written).ok()));
+                ControlFlow::Break(())
+            })
+            .build();
+        let (trace, handle) = backend.user_trace("fake-event-context")
+            .enable(prov)
+            .start()
+            .unwrap();
+        backend.emit("fake-event-context", &replayed_event(PROV, 1));
+        backend.emit("fake-event-context", &replayed_event(PROV, 2));
+        UserTrace::process_from_handle(handle).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![(1, Some(OsString::from("fake-event-context")), Some(2))]);
+        assert_eq!(trace.events_handled(), 1);
+        trace.stop().unwrap();
+
+        // Stop a replay
+        let prov = Provider::by_guid(PROV)
+            .add_callback_with_context(|record, _, _| if record.event_id() == 2 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) })
+            .build();
+        let (mut trace, _handle) = UserTrace::new()
+            .enable(prov)
+            .start_replay(replay::from_iter((1..5).map(|id| replayed_event(PROV, id))))
+            .unwrap();
+        trace.process().unwrap();
+        assert_eq!(trace.events_handled(), 2);
+    }
+
+    #[test]
+    fn test_panic_policy() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let replay_with_policy = |policy: PanicPolicy| {
+            let panicking_calls = Arc::new(AtomicUsize::new(0));
+            let other_calls = Arc::new(AtomicUsize::new(0));
+            let panicking_calls2 = Arc::clone(&panicking_calls);
+            let other_calls2 = Arc::clone(&other_calls);
+            let prov = Provider::by_guid(PROV)
+                .add_callback(move |_, _| {
+                    panicking_calls2.fetch_add(1, Ordering::Relaxed);
+                    panic!("malformed event");
+                })
+                .add_callback(move |_, _| { other_calls2.fetch_add(1, Ordering::Relaxed); })
+                .build();
+            let (mut trace, _handle) = UserTrace::new()
+                .enable(prov)
+                .panic_policy(policy)
+                .start_replay(replay::from_iter((1..4).map(|id| replayed_event(PROV, id))))
+                .unwrap();
+            let result = trace.process();
+            (result, trace.caught_panics(), panicking_calls.load(Ordering::Relaxed), other_calls.load(Ordering::Relaxed))
+        };
+
+        let (result, caught, panicking, other) = replay_with_policy(PanicPolicy::LogAndContinue);
+        assert!(result.is_ok());
+        assert_eq!((caught, panicking, other), (3, 3, 3));
+
+        let (result, caught, panicking, other) = replay_with_policy(PanicPolicy::DisableCallback);
+        assert!(result.is_ok());
+        assert_eq!((caught, panicking, other), (1, 1, 3));
+
+        let (result, caught, panicking, other) = replay_with_policy(PanicPolicy::StopTrace);
+        assert!(matches!(result, Err(TraceError::CallbackPanicked(payload)) if payload.downcast_ref::<&str>() == Some(&"malformed event")));
+        assert_eq!((caught, panicking, other), (1, 1, 0));
+
+        // The panic payload is also returned for traces processed by ETW
+        let backend = Arc::new(FakeBackend::new());
+        backend.add_etl_file("fake-panic.etl", vec![
+            vec![replayed_event(PROV, 0), replayed_event(PROV, 1)],
+            vec![replayed_event(PROV, 2)],
+        ]);
+        let (mut trace, _handle) = FileTrace::new(PathBuf::from("fake-panic.etl"), |record, _| {
+                if record.event_id() == 1 {
+                    panic!("event {}", record.event_id());
+                }
+            })
+            .panic_policy(PanicPolicy::StopTrace)
+            .backend(backend.shared())
+            .start()
+            .unwrap();
+        let err = trace.process().unwrap_err();
+        assert_eq!(err.to_string(), "a callback panicked: event 1");
+        assert!(matches!(err, TraceError::CallbackPanicked(payload) if payload.downcast_ref::<String>().map(String::as_str) == Some("event 1")));
+        assert_eq!(trace.events_handled(), 2);
+        assert_eq!(trace.caught_panics(), 1);
+    }
 }
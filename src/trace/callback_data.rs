@@ -1,40 +1,85 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::RwLock;
+use std::any::Any;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+use once_cell::sync::OnceCell;
+use windows::core::GUID;
 use windows::Win32::System::Diagnostics::Etw;
 
 use crate::trace::RealTimeTraceTrait;
 use crate::native::etw_types::event_record::EventRecord;
 use crate::provider::Provider;
+use crate::provider::kernel_providers::kernel_guids;
 use crate::schema_locator::SchemaLocator;
-use crate::EtwCallback;
+use crate::timestamp::TimestampConverter;
+use crate::trace::dispatcher::{Dispatcher, DispatcherMetrics, DispatcherOptions};
+use crate::trace::event_context::{EventContext, TraceOwner};
+use crate::trace::panic_policy::{CaughtPanic, PanicHandler, PanicPolicy};
+use crate::trace::session_events::{BufferStatistics, LostEventCounts, LostEventKind, TraceHeader};
+use crate::{EtwCallback, EtwContextCallback};
 
 pub use crate::native::etw_types::LoggingMode;
 
+pub(crate) type LostEventsCallback = Box<dyn FnMut(LostEventKind, &LostEventCounts) + Send + Sync + 'static>;
+pub(crate) type TraceHeaderCallback = Box<dyn FnMut(&TraceHeader) + Send + Sync + 'static>;
+pub(crate) type BufferCallback = Box<dyn FnMut(&BufferStatistics) -> bool + Send + Sync + 'static>;
+
+/// Opcode of the `EventTrace/Header` event
+const EVENT_TRACE_TYPE_INFO: u8 = 0;
+
 /// Data used by callbacks when the trace is running
 // NOTE: this structure is accessed in an unsafe block in a separate thread (see the `trace_callback_thunk` function)
 //       Thus, this struct must not be mutated (outside of interior mutability and/or using Mutex and other synchronization mechanisms) when the associated trace is running.
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)] // This is always allocated once, behind a `Box<Arc<...>>`: the size of its variants does not matter much
 pub enum CallbackData {
     RealTime(RealTimeCallbackData),
     FromFile(CallbackDataFromFile),
 }
 
-#[derive(Debug)]
 pub struct RealTimeCallbackData {
     /// Represents how many events have been handled so far
     events_handled: AtomicUsize,
     schema_locator: SchemaLocator,
     /// List of Providers associated with the Trace. This also owns the callback closures and their state
-    providers: Vec<Provider>,
+    ///
+    /// This list can be changed while the trace is running (see [`crate::UserTrace::enable_provider`]), hence the lock
+    providers: RwLock<Vec<Arc<Provider>>>,
+    /// Available once the trace has been opened
+    timestamp_converter: OnceCell<TimestampConverter>,
+    lost_event_counts: Mutex<LostEventCounts>,
+    on_lost_events: RwLock<Option<LostEventsCallback>>,
+    on_trace_header: RwLock<Option<TraceHeaderCallback>>,
+    /// Receives the events that no provider matches
+    on_unmatched_event: RwLock<Option<EtwCallback>>,
+    on_buffer: RwLock<Option<BufferCallback>>,
+    /// Receives every event that matches a provider, after the callbacks of the provider (see [`crate::trace::TraceBuilder::into_stream`])
+    event_sink: RwLock<Option<EtwCallback>>,
+    dispatcher_options: Option<DispatcherOptions>,
+    /// Available once the trace has been started, if it has `dispatcher_options`
+    dispatcher: OnceCell<Dispatcher>,
+    /// Available once the trace has been opened
+    owner: OnceCell<TraceOwner>,
+    panics: PanicHandler,
+    /// Set when a callback asked to stop processing the trace
+    stop_requested: AtomicBool,
 }
 
 pub struct CallbackDataFromFile {
     /// Represents how many events have been handled so far
     events_handled: AtomicUsize,
     schema_locator: SchemaLocator,
-    /// This trace is reading from an ETL file, and has a single callback
-    callback: RwLock<EtwCallback>,
+    /// This trace is reading from an ETL file, and has a single callback. It is `None` once it has been disabled after a panic
+    callback: RwLock<Option<EtwContextCallback>>,
+    /// Available once the trace has been opened
+    timestamp_converter: OnceCell<TimestampConverter>,
+    on_buffer: RwLock<Option<BufferCallback>>,
+    /// Available once the trace has been opened
+    owner: OnceCell<TraceOwner>,
+    panics: PanicHandler,
+    /// Set when the callback asked to stop processing the trace
+    stop_requested: AtomicBool,
 }
 
 impl CallbackData {
@@ -51,6 +96,115 @@ impl CallbackData {
             CallbackData::FromFile(f_cb) => f_cb.events_handled(),
         }
     }
+
+    pub fn as_real_time(&self) -> Option<&RealTimeCallbackData> {
+        match self {
+            CallbackData::RealTime(rt_cb) => Some(rt_cb),
+            CallbackData::FromFile(_) => None,
+        }
+    }
+
+    pub fn schema_locator(&self) -> &SchemaLocator {
+        match self {
+            CallbackData::RealTime(rt_cb) => &rt_cb.schema_locator,
+            CallbackData::FromFile(f_cb) => &f_cb.schema_locator,
+        }
+    }
+
+    pub fn timestamp_converter(&self) -> Option<TimestampConverter> {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.timestamp_converter.get().copied(),
+            CallbackData::FromFile(f_cb) => f_cb.timestamp_converter.get().copied(),
+        }
+    }
+
+    /// Called once all the events of a buffer have been delivered. Returns whether the trace should keep being processed
+    pub fn on_buffer(&self, log_file: &Etw::EVENT_TRACE_LOGFILEW) -> bool {
+        if self.stop_requested() {
+            return false;
+        }
+
+        let (on_buffer, from_file) = match self {
+            CallbackData::RealTime(rt_cb) => (&rt_cb.on_buffer, false),
+            CallbackData::FromFile(f_cb) => (&f_cb.on_buffer, true),
+        };
+
+        let keep_processing = invoke_callback(on_buffer, self.panics(), self.stop_requested_flag(), |cb| {
+            cb(&BufferStatistics::from_native(log_file, from_file))
+        });
+        keep_processing.unwrap_or(true) && !self.stop_requested()
+    }
+
+    /// Whether a callback asked to stop processing the trace
+    pub fn stop_requested(&self) -> bool {
+        self.stop_requested_flag().load(Ordering::Acquire)
+    }
+
+    fn stop_requested_flag(&self) -> &AtomicBool {
+        match self {
+            CallbackData::RealTime(rt_cb) => &rt_cb.stop_requested,
+            CallbackData::FromFile(f_cb) => &f_cb.stop_requested,
+        }
+    }
+
+    fn panics(&self) -> &PanicHandler {
+        match self {
+            CallbackData::RealTime(rt_cb) => &rt_cb.panics,
+            CallbackData::FromFile(f_cb) => &f_cb.panics,
+        }
+    }
+
+    /// How many panics of the callbacks have been caught so far
+    pub fn caught_panics(&self) -> usize {
+        self.panics().caught_panics()
+    }
+
+    /// The payload of the panic that stopped the trace, if any (see [`PanicPolicy::StopTrace`])
+    pub fn take_panic_payload(&self) -> Option<Box<dyn Any + Send>> {
+        self.panics().take_stop_payload()
+    }
+
+    /// Run `f`, and handle its panic (if any) like the panic of a callback, according to the panic policy of the trace
+    ///
+    /// This returns `None` in case `f` panicked.
+    pub(crate) fn catch_panics<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        match self.panics().call(f) {
+            Ok(result) => Some(result),
+            Err(CaughtPanic::StopTrace) => {
+                self.stop_requested_flag().store(true, Ordering::Release);
+                None
+            },
+            // There is no callback to disable
+            Err(CaughtPanic::Continue) | Err(CaughtPanic::DisableCallback) => None,
+        }
+    }
+
+    /// Spawn the workers of the dispatcher, for real-time traces that have one
+    ///
+    /// This must be called before the trace is processed, otherwise the first events would be handled on the processing thread.
+    pub(crate) fn start_dispatcher(self: &Arc<Self>) {
+        if let CallbackData::RealTime(rt_cb) = self.as_ref() {
+            if let Some(options) = rt_cb.dispatcher_options {
+                let _ = rt_cb.dispatcher.set(Dispatcher::start(options, Arc::downgrade(self)));
+            }
+        }
+    }
+
+    /// Tell the callbacks which trace they belong to. This has no effect if it has already been set
+    pub(crate) fn set_owner(&self, owner: TraceOwner) {
+        let _ = match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.owner.set(owner),
+            CallbackData::FromFile(f_cb) => f_cb.owner.set(owner),
+        };
+    }
+
+    /// Set the converter of the timestamps. This has no effect if it has already been set
+    pub fn set_timestamp_converter(&self, converter: TimestampConverter) {
+        let _ = match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.timestamp_converter.set(converter),
+            CallbackData::FromFile(f_cb) => f_cb.timestamp_converter.set(converter),
+        };
+    }
 }
 
 impl RealTimeCallbackData {
@@ -58,16 +212,125 @@ impl RealTimeCallbackData {
         Self {
             events_handled: AtomicUsize::new(0),
             schema_locator: SchemaLocator::new(),
-            providers: Vec::new(),
+            providers: RwLock::new(Vec::new()),
+            timestamp_converter: OnceCell::new(),
+            lost_event_counts: Mutex::new(LostEventCounts::default()),
+            on_lost_events: RwLock::new(None),
+            on_trace_header: RwLock::new(None),
+            on_unmatched_event: RwLock::new(None),
+            on_buffer: RwLock::new(None),
+            event_sink: RwLock::new(None),
+            dispatcher_options: None,
+            dispatcher: OnceCell::new(),
+            owner: OnceCell::new(),
+            panics: PanicHandler::default(),
+            stop_requested: AtomicBool::new(false),
         }
     }
 
-    pub fn add_provider(&mut self, provider: Provider) {
-        self.providers.push(provider)
+    pub fn set_on_lost_events(&mut self, callback: LostEventsCallback) {
+        self.on_lost_events = RwLock::new(Some(callback));
+    }
+
+    pub fn set_on_trace_header(&mut self, callback: TraceHeaderCallback) {
+        self.on_trace_header = RwLock::new(Some(callback));
+    }
+
+    pub fn set_on_unmatched_event(&mut self, callback: EtwCallback) {
+        self.on_unmatched_event = RwLock::new(Some(callback));
+    }
+
+    pub fn set_on_buffer(&mut self, callback: BufferCallback) {
+        self.on_buffer = RwLock::new(Some(callback));
+    }
+
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.panics = PanicHandler::new(policy);
     }
 
-    pub fn providers(&self) -> &[Provider] {
-        &self.providers
+    pub fn set_dispatcher_options(&mut self, options: DispatcherOptions) {
+        self.dispatcher_options = Some(options);
+    }
+
+    /// Let the dispatcher (if any) handle the events that are already queued, and stop its workers
+    pub fn stop_dispatcher(&self) {
+        if let Some(dispatcher) = self.dispatcher.get() {
+            dispatcher.shutdown();
+        }
+    }
+
+    /// The metrics of the dispatcher, if this trace has one
+    pub fn dispatcher_metrics(&self) -> Option<DispatcherMetrics> {
+        self.dispatcher.get().map(Dispatcher::metrics)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn set_event_sink(&mut self, callback: EtwCallback) {
+        self.event_sink = RwLock::new(Some(callback));
+    }
+
+    /// How many times ETW reported losses so far
+    pub fn lost_event_counts(&self) -> LostEventCounts {
+        self.lost_event_counts.lock().map(|counts| *counts).unwrap_or_default()
+    }
+
+    /// Add a provider, and return the shared instance that is now used to dispatch events
+    pub fn add_provider(&self, provider: Provider) -> Arc<Provider> {
+        let provider = Arc::new(provider);
+        if let Ok(mut providers) = self.providers.write() {
+            providers.push(Arc::clone(&provider))
+        }
+        provider
+    }
+
+    /// Remove this very provider (as returned by [`Self::add_provider`]). Returns whether it was found
+    pub fn remove_provider(&self, provider: &Arc<Provider>) -> bool {
+        match self.providers.write() {
+            Err(_) => false,
+            Ok(mut providers) => {
+                let count_before = providers.len();
+                providers.retain(|prov| !Arc::ptr_eq(prov, provider));
+                count_before != providers.len()
+            }
+        }
+    }
+
+    /// Remove every provider with this GUID. Returns how many were removed
+    pub fn remove_providers(&self, guid: GUID) -> usize {
+        match self.providers.write() {
+            Err(_) => 0,
+            Ok(mut providers) => {
+                let count_before = providers.len();
+                providers.retain(|prov| prov.guid() != guid);
+                count_before - providers.len()
+            }
+        }
+    }
+
+    /// Replace every provider with this GUID by the result of `f`. Returns how many were replaced
+    pub fn replace_providers<F>(&self, guid: GUID, f: F) -> usize
+    where
+        F: Fn(&Provider) -> Provider
+    {
+        match self.providers.write() {
+            Err(_) => 0,
+            Ok(mut providers) => {
+                let mut count = 0;
+                for prov in providers.iter_mut().filter(|prov| prov.guid() == guid) {
+                    *prov = Arc::new(f(prov));
+                    count += 1;
+                }
+                count
+            }
+        }
+    }
+
+    /// A snapshot of the current providers
+    pub fn providers(&self) -> Vec<Arc<Provider>> {
+        self.providers
+            .read()
+            .map(|providers| providers.clone())
+            .unwrap_or_default()
     }
 
     /// How many events have been handled since this instance was created
@@ -76,43 +339,164 @@ impl RealTimeCallbackData {
     }
 
     pub fn provider_flags<T: RealTimeTraceTrait>(&self) -> Etw::EVENT_TRACE_FLAG {
-        Etw::EVENT_TRACE_FLAG(T::enable_flags(&self.providers))
+        Etw::EVENT_TRACE_FLAG(T::enable_flags(&self.providers()))
     }
 
     pub fn on_event(&self, record: &EventRecord) {
-        self.events_handled.fetch_add(1, Ordering::Relaxed);
+        // ETW may still deliver the rest of the current buffer
+        if self.stop_requested.load(Ordering::Acquire) {
+            return;
+        }
+        let events_handled = self.events_handled.fetch_add(1, Ordering::Relaxed) + 1;
+
+        match self.dispatcher.get() {
+            Some(dispatcher) => dispatcher.dispatch(record, events_handled),
+            None => self.dispatch(record, events_handled),
+        }
+    }
+
+    /// Invoke the callbacks for this event. This runs on a worker thread in case this trace has a dispatcher
+    pub(crate) fn dispatch(&self, record: &EventRecord, events_handled: usize) {
+        // Events may still be queued for the workers
+        if self.stop_requested.load(Ordering::Acquire) {
+            return;
+        }
 
-        for prov in &self.providers {
-            if prov.guid() == record.provider_id() {
-                prov.on_event(record, &self.schema_locator);
+        // Let's not hold the lock while callbacks run, so that they can safely enable or disable providers themselves
+        let matching_providers: Vec<Arc<Provider>> = match self.providers.read() {
+            Err(_) => return,
+            Ok(providers) => providers
+                .iter()
+                .filter(|prov| prov.receives(record.provider_id()))
+                .cloned()
+                .collect(),
+        };
+
+        if matching_providers.is_empty() && !self.on_session_event(record) {
+            invoke_callback(&self.on_unmatched_event, &self.panics, &self.stop_requested, |cb| cb(record, &self.schema_locator));
+        }
+
+        let context = EventContext::new(events_handled, self.owner.get());
+        for prov in &matching_providers {
+            if prov.on_event(record, &self.schema_locator, &context, &self.panics).is_break() {
+                self.stop_requested.store(true, Ordering::Release);
+                return;
+            }
+        }
+
+        if !matching_providers.is_empty() {
+            invoke_callback(&self.event_sink, &self.panics, &self.stop_requested, |sink| sink(record, &self.schema_locator));
+        }
+    }
+
+    /// Handle the events that ETW emits about the session itself. Returns whether `record` was one of them
+    fn on_session_event(&self, record: &EventRecord) -> bool {
+        if record.provider_id() == kernel_guids::LOST_EVENT_GUID {
+            let kind = match LostEventKind::from_opcode(record.opcode()) {
+                None => return false,
+                Some(kind) => kind,
+            };
+            let counts = match self.lost_event_counts.lock() {
+                Err(_) => return true,
+                Ok(mut counts) => {
+                    counts.add(kind);
+                    *counts
+                }
+            };
+            invoke_callback(&self.on_lost_events, &self.panics, &self.stop_requested, |cb| cb(kind, &counts));
+            return true;
+        }
+
+        if record.provider_id() == kernel_guids::EVENT_TRACE_GUID && record.opcode() == EVENT_TRACE_TYPE_INFO {
+            if let Some(header) = TraceHeader::from_record(record) {
+                invoke_callback(&self.on_trace_header, &self.panics, &self.stop_requested, |cb| cb(&header));
             }
+            return true;
         }
+
+        false
     }
 }
 
 
 impl CallbackDataFromFile {
-    pub fn new(callback: EtwCallback) -> Self {
+    pub fn new(callback: EtwContextCallback) -> Self {
         Self {
             events_handled: AtomicUsize::new(0),
             schema_locator: SchemaLocator::new(),
-            callback: RwLock::new(callback),
+            callback: RwLock::new(Some(callback)),
+            timestamp_converter: OnceCell::new(),
+            on_buffer: RwLock::new(None),
+            owner: OnceCell::new(),
+            panics: PanicHandler::default(),
+            stop_requested: AtomicBool::new(false),
         }
     }
 
+    pub fn set_on_buffer(&mut self, callback: BufferCallback) {
+        self.on_buffer = RwLock::new(Some(callback));
+    }
+
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.panics = PanicHandler::new(policy);
+    }
+
     /// How many events have been handled since this instance was created
     pub fn events_handled(&self) -> usize {
         self.events_handled.load(Ordering::Relaxed)
     }
 
     pub fn on_event(&self, record: &EventRecord) {
-        self.events_handled.fetch_add(1, Ordering::Relaxed);
-        if let Ok(mut cb) = self.callback.write() {
-            cb(record, &self.schema_locator);
+        // ETW may still deliver the rest of the current buffer
+        if self.stop_requested.load(Ordering::Acquire) {
+            return;
+        }
+        let events_handled = self.events_handled.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let context = EventContext::new(events_handled, self.owner.get());
+        let flow = invoke_callback(&self.callback, &self.panics, &self.stop_requested, |cb| cb(record, &self.schema_locator, &context));
+        if let Some(ControlFlow::Break(())) = flow {
+            self.stop_requested.store(true, Ordering::Release);
         }
     }
 }
 
+/// Invoke an optional callback, and handle its panic (if any) according to the panic policy of the trace
+///
+/// Returns `None` in case there is no callback, or if it panicked.
+fn invoke_callback<C, R, F>(callback: &RwLock<Option<C>>, panics: &PanicHandler, stop_requested: &AtomicBool, f: F) -> Option<R>
+where
+    F: FnOnce(&mut C) -> R,
+{
+    let mut guard = callback.write().ok()?;
+    let cb = guard.as_mut()?;
+    // The lock is held while the callback runs, so that a panic does not poison it
+    match panics.call(|| f(cb)) {
+        Ok(result) => Some(result),
+        Err(CaughtPanic::Continue) => None,
+        Err(CaughtPanic::DisableCallback) => {
+            *guard = None;
+            None
+        },
+        Err(CaughtPanic::StopTrace) => {
+            stop_requested.store(true, Ordering::Release);
+            None
+        },
+    }
+}
+
+impl std::fmt::Debug for RealTimeCallbackData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RealTimeCallbackData")
+            .field("events_handled", &self.events_handled)
+            .field("schema_locator", &self.schema_locator)
+            .field("providers", &self.providers)
+            .field("timestamp_converter", &self.timestamp_converter)
+            .field("lost_event_counts", &self.lost_event_counts)
+            .finish()
+    }
+}
+
 impl std::fmt::Debug for CallbackDataFromFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CallbackDataFromFile")
@@ -0,0 +1,338 @@
+//! Dispatch events to a pool of worker threads
+//!
+//! By default, every callback is invoked on the thread that processes the trace. Slow callbacks therefore delay the processing of the next events,
+//! and ETW may end up losing events when its buffers are full.<br/>
+//! With a dispatcher (see [`TraceBuilder::dispatcher`](crate::trace::TraceBuilder::dispatcher)), the thread that processes the trace only copies events
+//! into queues, and callbacks are invoked by worker threads.
+//!
+//! Events that have the same [`DispatchKey`] always go to the same worker, so that they are handled in order.
+//! There is no ordering guarantee between events with different keys.<br/>
+//! Note that a callback added with [`ProviderBuilder::add_callback`](crate::provider::ProviderBuilder::add_callback) is never invoked by two workers at once.
+//! Callbacks that can run concurrently can be added with [`ProviderBuilder::add_shared_callback`](crate::provider::ProviderBuilder::add_shared_callback).
+//!
+//! Stopping the trace waits for the workers to handle the events that are already queued.
+//!
+//! ```
+//! # use std::ops::ControlFlow;
+//! # use ferrisetw::provider::Provider;
+//! # use ferrisetw::trace::UserTrace;
+//! # use ferrisetw::trace::dispatcher::{DispatchKey, DispatcherOptions};
+//! let builder = UserTrace::new()
+//!     .enable(Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+//!         .add_shared_callback(|record, _schema_locator, _context| {
+//!             // Slow decoding
+//!             ControlFlow::Continue(())
+//!         })
+//!         .build())
+//!     .dispatcher(DispatcherOptions::default().workers(4).key(DispatchKey::Process));
+//! ```
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::native::etw_types::event_record::{EventRecord, OwnedEventRecord};
+use crate::trace::callback_data::CallbackData;
+
+/// Which events must be handled in order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DispatchKey {
+    /// Events from the same provider
+    #[default]
+    Provider,
+    /// Events from the same process
+    Process,
+    /// Events from the same thread
+    Thread,
+}
+
+impl DispatchKey {
+    fn hash_of(&self, record: &EventRecord) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self {
+            DispatchKey::Provider => record.provider_id().hash(&mut hasher),
+            DispatchKey::Process => record.process_id().hash(&mut hasher),
+            DispatchKey::Thread => record.thread_id().hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+}
+
+/// Settings of the dispatcher of a trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DispatcherOptions {
+    /// Number of worker threads
+    pub workers: usize,
+    pub key: DispatchKey,
+    /// Maximum number of events queued for each worker. When a queue is full, the thread that processes the trace waits for room in it
+    pub queue_capacity: usize,
+}
+
+impl Default for DispatcherOptions {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            key: DispatchKey::default(),
+            queue_capacity: 4096,
+        }
+    }
+}
+
+impl DispatcherOptions {
+    /// Set the number of worker threads (at least 1)
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Set which events must be handled in order
+    pub fn key(mut self, key: DispatchKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Set the maximum number of events queued for each worker (at least 1)
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity.max(1);
+        self
+    }
+}
+
+/// Metrics of the dispatcher of a trace
+///
+/// See [`UserTrace::dispatcher_metrics`](crate::trace::UserTrace::dispatcher_metrics)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DispatcherMetrics {
+    /// Number of events currently queued, for each worker
+    pub queue_depths: Vec<usize>,
+    /// Highest number of events that have been queued for a single worker
+    pub max_queue_depth: usize,
+    /// Number of events the workers have handled so far
+    pub events_dispatched: u64,
+    /// Average time between the reception of an event and the moment its callbacks returned
+    pub average_latency: Duration,
+    /// Longest time between the reception of an event and the moment its callbacks returned
+    pub max_latency: Duration,
+}
+
+struct QueuedEvent {
+    record: OwnedEventRecord,
+    events_handled: usize,
+    received: Instant,
+}
+
+#[derive(Default)]
+struct QueueState {
+    events: VecDeque<QueuedEvent>,
+    /// Set when no event will be queued anymore. The worker exits once the queue is empty
+    closed: bool,
+}
+
+#[derive(Default)]
+struct WorkerQueue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    max_queue_depth: AtomicUsize,
+    events_dispatched: AtomicU64,
+    total_latency_nanos: AtomicU64,
+    max_latency_nanos: AtomicU64,
+}
+
+/// Hands events over to worker threads
+///
+/// Workers stop once [`Dispatcher::shutdown`] has been called, or once this is dropped.
+pub(crate) struct Dispatcher {
+    options: DispatcherOptions,
+    queues: Vec<Arc<WorkerQueue>>,
+    counters: Arc<Counters>,
+    /// Emptied once the workers have been joined
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Dispatcher {
+    /// Spawn the workers. They invoke the callbacks of `callback_data` (that must be real-time), as long as it is alive
+    pub(crate) fn start(options: DispatcherOptions, callback_data: Weak<CallbackData>) -> Self {
+        // The fields are public, so that the builder methods may have been bypassed
+        let options = DispatcherOptions {
+            workers: options.workers.max(1),
+            queue_capacity: options.queue_capacity.max(1),
+            ..options
+        };
+        let counters = Arc::new(Counters::default());
+        let queues: Vec<Arc<WorkerQueue>> = (0..options.workers)
+            .map(|_| Arc::new(WorkerQueue::default()))
+            .collect();
+
+        let workers = queues
+            .iter()
+            .map(|queue| {
+                let queue = Arc::clone(queue);
+                let counters = Arc::clone(&counters);
+                let callback_data = Weak::clone(&callback_data);
+                std::thread::spawn(move || run_worker(&queue, &counters, &callback_data))
+            })
+            .collect();
+
+        Self {
+            options,
+            queues,
+            counters,
+            workers: Mutex::new(workers),
+        }
+    }
+
+    /// Queue a copy of `record` for the worker its key is assigned to
+    pub(crate) fn dispatch(&self, record: &EventRecord, events_handled: usize) {
+        let index = (self.options.key.hash_of(record) % self.queues.len() as u64) as usize;
+        let queue = &self.queues[index];
+
+        let mut state = queue.state.lock().unwrap();
+        while !state.closed && state.events.len() >= self.options.queue_capacity {
+            state = queue.not_full.wait(state).unwrap();
+        }
+        if state.closed {
+            return;
+        }
+        state.events.push_back(QueuedEvent {
+            record: OwnedEventRecord::from_event_record(record),
+            events_handled,
+            received: Instant::now(),
+        });
+        self.counters.max_queue_depth.fetch_max(state.events.len(), Ordering::Relaxed);
+        queue.not_empty.notify_one();
+    }
+
+    pub(crate) fn metrics(&self) -> DispatcherMetrics {
+        let events_dispatched = self.counters.events_dispatched.load(Ordering::Relaxed);
+        let total_latency_nanos = self.counters.total_latency_nanos.load(Ordering::Relaxed);
+        DispatcherMetrics {
+            queue_depths: self.queues.iter().map(|queue| queue.state.lock().unwrap().events.len()).collect(),
+            max_queue_depth: self.counters.max_queue_depth.load(Ordering::Relaxed),
+            events_dispatched,
+            average_latency: Duration::from_nanos(total_latency_nanos.checked_div(events_dispatched).unwrap_or_default()),
+            max_latency: Duration::from_nanos(self.counters.max_latency_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Stop accepting events, and wait for the workers to handle the events that are already queued
+    ///
+    /// This must be called while the callback data is still alive, otherwise the workers could not invoke the callbacks anymore.
+    pub(crate) fn shutdown(&self) {
+        for queue in &self.queues {
+            let mut state = queue.state.lock().unwrap();
+            state.closed = true;
+            queue.not_empty.notify_all();
+            queue.not_full.notify_all();
+        }
+        self.join_workers();
+    }
+
+    fn join_workers(&self) {
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        let current_thread = std::thread::current().id();
+        for worker in workers {
+            // The trace may be stopped (or dropped) from a callback, i.e. from a worker, that cannot wait for itself
+            if worker.thread().id() != current_thread {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        // The callback data is being dropped: the events that are still queued cannot be handled anymore
+        let mut discarded = 0;
+        for queue in &self.queues {
+            let mut state = queue.state.lock().unwrap();
+            state.closed = true;
+            discarded += state.events.len();
+            state.events.clear();
+            queue.not_empty.notify_all();
+            queue.not_full.notify_all();
+        }
+        if discarded > 0 {
+            log::warn!("The trace has been dropped while {} events were queued for the dispatcher. They have been discarded", discarded);
+        }
+        self.join_workers();
+    }
+}
+
+impl std::fmt::Debug for Dispatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("options", &self.options)
+            .field("counters", &self.counters)
+            .finish()
+    }
+}
+
+fn run_worker(queue: &WorkerQueue, counters: &Counters, callback_data: &Weak<CallbackData>) {
+    loop {
+        let event = {
+            let mut state = queue.state.lock().unwrap();
+            loop {
+                if let Some(event) = state.events.pop_front() {
+                    queue.not_full.notify_one();
+                    break event;
+                }
+                if state.closed {
+                    return;
+                }
+                state = queue.not_empty.wait(state).unwrap();
+            }
+        };
+
+        // The trace may have been dropped in the meantime
+        let callback_data = match callback_data.upgrade() {
+            None => return,
+            Some(callback_data) => callback_data,
+        };
+        if let Some(rt_cb) = callback_data.as_real_time() {
+            rt_cb.dispatch(&event.record, event.events_handled);
+        }
+        drop(callback_data);
+
+        let latency_nanos = event.received.elapsed().as_nanos() as u64;
+        counters.events_dispatched.fetch_add(1, Ordering::Relaxed);
+        counters.total_latency_nanos.fetch_add(latency_nanos, Ordering::Relaxed);
+        counters.max_latency_nanos.fetch_max(latency_nanos, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::record_builder::EventRecordBuilder;
+    use windows::core::GUID;
+
+    #[test]
+    fn test_dispatch_keys() {
+        let record = |pid: u32, tid: u32| EventRecordBuilder::new(GUID::from("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")).process_id(pid).thread_id(tid).build();
+
+        assert_eq!(DispatchKey::Provider.hash_of(&record(1, 1)), DispatchKey::Provider.hash_of(&record(2, 2)));
+        assert_eq!(DispatchKey::Process.hash_of(&record(1, 1)), DispatchKey::Process.hash_of(&record(1, 2)));
+        assert_ne!(DispatchKey::Process.hash_of(&record(1, 1)), DispatchKey::Process.hash_of(&record(2, 1)));
+        assert_eq!(DispatchKey::Thread.hash_of(&record(1, 1)), DispatchKey::Thread.hash_of(&record(2, 1)));
+        assert_eq!(DispatcherOptions::default().workers(0).queue_capacity(0), DispatcherOptions { workers: 1, key: DispatchKey::Provider, queue_capacity: 1 });
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        // Options that bypass the builder methods are clamped as well, so that dispatching does not wait forever for room in an empty queue
+        let dispatcher = Dispatcher::start(DispatcherOptions { workers: 0, key: DispatchKey::Provider, queue_capacity: 0 }, Weak::new());
+        assert_eq!(dispatcher.options, DispatcherOptions { workers: 1, key: DispatchKey::Provider, queue_capacity: 1 });
+        assert_eq!(dispatcher.queues.len(), 1);
+
+        dispatcher.dispatch(&EventRecordBuilder::new(GUID::from("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")).build(), 0);
+        dispatcher.shutdown();
+    }
+}
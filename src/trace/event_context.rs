@@ -0,0 +1,94 @@
+//! Information about the trace an event belongs to, that is given to the callbacks
+//!
+//! Callbacks added with [`ProviderBuilder::add_callback_with_context`](crate::provider::ProviderBuilder::add_callback_with_context)
+//! (or given to [`FileTrace::new_with_context`](crate::trace::FileTrace::new_with_context)) receive an [`EventContext`], and return a [`ControlFlow`](std::ops::ControlFlow).
+//! Returning `ControlFlow::Break(())` stops processing the trace: no other callback is invoked afterwards, and `process` returns `Ok(())` once ETW has noticed it.
+//!
+//! ```
+//! # use std::ops::ControlFlow;
+//! # use std::path::PathBuf;
+//! # use ferrisetw::trace::FileTrace;
+//! // Stop at the first event with ID 42
+//! let builder = FileTrace::new_with_context(PathBuf::from("trace.etl"), |record, _schema_locator, _context| {
+//!     if record.event_id() == 42 {
+//!         ControlFlow::Break(())
+//!     } else {
+//!         ControlFlow::Continue(())
+//!     }
+//! });
+//! ```
+use std::ffi::OsString;
+use std::sync::Arc;
+
+use crate::native::etw_types::EventTraceProperties;
+use crate::native::evntrace::backend::EvntraceBackend;
+use crate::native::evntrace::{ControlHandle, TraceHandle};
+use crate::native::EvntraceNativeError;
+use crate::trace::{TraceError, TraceResult, TraceStatistics};
+
+/// What the callbacks can know about the trace they belong to
+pub(crate) struct TraceOwner {
+    pub name: Option<OsString>,
+    pub trace_handle: TraceHandle,
+    /// For traces that control their session
+    pub session: Option<OwnedSession>,
+}
+
+pub(crate) struct OwnedSession {
+    pub backend: Arc<dyn EvntraceBackend>,
+    pub properties: EventTraceProperties,
+    pub control_handle: ControlHandle,
+}
+
+/// Gives callbacks access to the trace that is being processed
+///
+/// See [the module-level documentation](crate::trace::event_context)
+pub struct EventContext<'a> {
+    events_handled: usize,
+    owner: Option<&'a TraceOwner>,
+}
+
+impl<'a> EventContext<'a> {
+    pub(crate) fn new(events_handled: usize, owner: Option<&'a TraceOwner>) -> Self {
+        Self {
+            events_handled,
+            owner,
+        }
+    }
+
+    /// How many events have been handled so far, including this one
+    pub fn events_handled(&self) -> usize {
+        self.events_handled
+    }
+
+    /// The name of the session. This is `None` for traces that read from a file
+    pub fn trace_name(&self) -> Option<OsString> {
+        self.owner.and_then(|owner| owner.name.clone())
+    }
+
+    /// The handle of the trace, i.e. the one [`TraceBuilder::start`](crate::trace::TraceBuilder::start) returned
+    pub fn trace_handle(&self) -> Option<TraceHandle> {
+        self.owner.map(|owner| owner.trace_handle)
+    }
+
+    /// Query the current statistics of the session
+    ///
+    /// This is only available for the sessions this crate has started (i.e. [`UserTrace`](crate::trace::UserTrace)s and [`KernelTrace`](crate::trace::KernelTrace)s).
+    /// See [`UserTrace::statistics`](crate::trace::UserTrace::statistics).
+    pub fn statistics(&self) -> TraceResult<TraceStatistics> {
+        match self.owner.and_then(|owner| owner.session.as_ref()) {
+            None => Err(TraceError::EtwNativeError(EvntraceNativeError::InvalidHandle)),
+            Some(session) => super::query_statistics(session.backend.as_ref(), &session.properties, session.control_handle),
+        }
+    }
+}
+
+impl std::fmt::Debug for EventContext<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventContext")
+            .field("events_handled", &self.events_handled)
+            .field("trace_name", &self.trace_name())
+            .field("trace_handle", &self.trace_handle())
+            .finish()
+    }
+}
@@ -0,0 +1,118 @@
+//! What happens when a callback panics
+//!
+//! Panics must not unwind into ETW, so they are caught around every callback. By default, the whole process is then aborted (see [`PanicPolicy::Abort`]).<br/>
+//! Another policy can be set with [`TraceBuilder::panic_policy`](crate::trace::TraceBuilder::panic_policy)
+//! (or [`FileTraceBuilder::panic_policy`](crate::trace::FileTraceBuilder::panic_policy)).
+//! Whatever the policy, the number of panics that have been caught so far is given by [`TraceTrait::caught_panics`](crate::trace::TraceTrait::caught_panics).
+//!
+//! ```
+//! # use ferrisetw::provider::Provider;
+//! # use ferrisetw::trace::UserTrace;
+//! # use ferrisetw::trace::panic_policy::PanicPolicy;
+//! let builder = UserTrace::new()
+//!     .enable(Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+//!         .add_callback(|record, _schema_locator| {
+//!             // A decoder that may panic on malformed events
+//!         })
+//!         .build())
+//!     .panic_policy(PanicPolicy::DisableCallback);
+//! ```
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// What to do when a callback panics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Log the panic, and abort the process (see [`std::process::abort`])
+    #[default]
+    Abort,
+    /// Log the panic, and keep invoking this callback for the next events
+    LogAndContinue,
+    /// Log the panic, and stop invoking this callback. Other callbacks keep receiving events
+    DisableCallback,
+    /// Stop processing the trace. `process` then returns [`TraceError::CallbackPanicked`](crate::trace::TraceError::CallbackPanicked), with the panic payload
+    StopTrace,
+}
+
+/// What the caller of a callback must do, now that it has panicked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CaughtPanic {
+    Continue,
+    DisableCallback,
+    StopTrace,
+}
+
+/// Invokes callbacks, and handles their panics according to a [`PanicPolicy`]
+#[derive(Debug, Default)]
+pub(crate) struct PanicHandler {
+    policy: PanicPolicy,
+    caught_panics: AtomicUsize,
+    /// The payload of the panic that stopped the trace (see [`PanicPolicy::StopTrace`])
+    stop_payload: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl PanicHandler {
+    pub fn new(policy: PanicPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Invoke `callback`, catching its panic (if any)
+    ///
+    /// Callbacks behind a lock should be invoked while the lock is held, so that a panic does not poison it.
+    pub fn call<R, F: FnOnce() -> R>(&self, callback: F) -> Result<R, CaughtPanic> {
+        let payload = match std::panic::catch_unwind(AssertUnwindSafe(callback)) {
+            Ok(result) => return Ok(result),
+            Err(payload) => payload,
+        };
+
+        self.caught_panics.fetch_add(1, Ordering::Relaxed);
+        let message = panic_message(payload.as_ref());
+        match self.policy {
+            PanicPolicy::Abort => {
+                log::error!("A callback panicked: {message}");
+                std::process::abort();
+            },
+            PanicPolicy::LogAndContinue => {
+                log::error!("A callback panicked: {message}");
+                Err(CaughtPanic::Continue)
+            },
+            PanicPolicy::DisableCallback => {
+                log::error!("A callback panicked, and will not be invoked anymore: {message}");
+                Err(CaughtPanic::DisableCallback)
+            },
+            PanicPolicy::StopTrace => {
+                log::error!("A callback panicked, the trace is stopping: {message}");
+                if let Ok(mut stop_payload) = self.stop_payload.lock() {
+                    stop_payload.get_or_insert(payload);
+                }
+                Err(CaughtPanic::StopTrace)
+            },
+        }
+    }
+
+    /// How many panics have been caught so far
+    pub fn caught_panics(&self) -> usize {
+        self.caught_panics.load(Ordering::Relaxed)
+    }
+
+    /// The payload of the panic that stopped the trace, if any. This returns `None` once it has been taken
+    pub fn take_stop_payload(&self) -> Option<Box<dyn Any + Send>> {
+        self.stop_payload.lock().ok().and_then(|mut payload| payload.take())
+    }
+}
+
+/// The message of a panic, in case its payload is a string
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("<non-string panic payload>")
+    }
+}
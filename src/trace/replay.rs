@@ -0,0 +1,346 @@
+//! Feed recorded or synthetic events to a trace, instead of events from a live ETW session
+//!
+//! A replayed trace is built with the usual [`TraceBuilder`](crate::trace::TraceBuilder) API, and started with [`TraceBuilder::start_replay`](crate::trace::TraceBuilder::start_replay).<br/>
+//! Replayed events go through the same routing as live events: they are dispatched to the callbacks of every enabled [`Provider`](crate::provider::Provider) whose GUID matches.
+//! This makes it possible to test the logic of your callbacks without an actual ETW session (and without administrator privileges).
+//!
+//! ```
+//! use ferrisetw::provider::Provider;
+//! use ferrisetw::trace::{UserTrace, TraceTrait};
+//! use ferrisetw::trace::replay;
+//! # use ferrisetw::OwnedEventRecord;
+//! # fn recorded_events() -> Vec<OwnedEventRecord> { Vec::new() }
+//!
+//! let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+//!     .add_callback(|record, _schema_locator| println!("Event {}", record.event_id()))
+//!     .build();
+//!
+//! let events: Vec<OwnedEventRecord> = recorded_events();
+//! let (mut trace, _handle) = UserTrace::new()
+//!     .enable(provider)
+//!     .start_replay(replay::from_iter(events))
+//!     .unwrap();
+//!
+//! // This returns once every event has been replayed
+//! trace.process().unwrap();
+//! ```
+//!
+//! Events can also be replayed from a [capture](crate::capture), since [`CaptureReader`] implements [`ReplaySource`].
+//!
+//! # Notes
+//! Like for live traces, panics in callbacks are handled according to the [panic policy](crate::trace::panic_policy) of the trace.
+//! In tests, [`PanicPolicy::StopTrace`](crate::trace::panic_policy::PanicPolicy::StopTrace) makes `process()` return the panic payload, that can be resumed with [`std::panic::resume_unwind`].
+use std::ffi::c_void;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+use widestring::U16CStr;
+use windows::core::GUID;
+use windows::Win32::Foundation::{
+    WIN32_ERROR, ERROR_ALREADY_EXISTS, ERROR_CANCELLED, ERROR_INVALID_HANDLE, ERROR_INVALID_PARAMETER, ERROR_NOT_SUPPORTED, ERROR_SUCCESS,
+    ERROR_WMI_INSTANCE_NOT_FOUND,
+};
+use windows::Win32::System::Diagnostics::Etw;
+
+use crate::capture::CaptureReader;
+use crate::native::etw_types::event_record::OwnedEventRecord;
+use crate::native::etw_types::{EnableTraceParameters, EventTraceLogfile, EventTraceProperties, QueriedTraceProperties, TraceInformation};
+use crate::native::evntrace::{self, backend::EvntraceBackend, ControlHandle, TraceHandle};
+use crate::schema::Schema;
+
+/// An event to be replayed
+#[derive(Clone)]
+pub struct ReplayEvent {
+    record: OwnedEventRecord,
+    schema: Option<Arc<Schema>>,
+}
+
+impl ReplayEvent {
+    pub fn new(record: OwnedEventRecord) -> Self {
+        Self { record, schema: None }
+    }
+
+    /// Attach a schema to this event.
+    ///
+    /// It will be made available to the trace callbacks (through their `SchemaLocator`) for this event and every similar event that follows.
+    pub fn with_schema(mut self, schema: Arc<Schema>) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+}
+
+impl std::fmt::Debug for ReplayEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplayEvent")
+            .field("record", &self.record)
+            .field("has_schema", &self.schema.is_some())
+            .finish()
+    }
+}
+
+impl From<OwnedEventRecord> for ReplayEvent {
+    fn from(record: OwnedEventRecord) -> Self {
+        Self::new(record)
+    }
+}
+
+/// A source of events for a replayed trace
+pub trait ReplaySource: Send + 'static {
+    /// Returns the next event to replay, or `None` to end the replay.
+    ///
+    /// This can block, e.g. to wait for more events.
+    fn next_event(&mut self) -> Option<ReplayEvent>;
+}
+
+/// A [`ReplaySource`] that replays the items of an iterator
+///
+/// See [`from_iter`]
+#[derive(Debug)]
+pub struct IterSource<I> {
+    events: I,
+}
+
+/// Build a [`ReplaySource`] that replays a sequence of events
+///
+/// Items can be [`OwnedEventRecord`]s or [`ReplayEvent`]s.
+pub fn from_iter<I>(events: I) -> IterSource<I::IntoIter>
+where
+    I: IntoIterator,
+{
+    IterSource {
+        events: events.into_iter(),
+    }
+}
+
+impl<I, E> ReplaySource for IterSource<I>
+where
+    I: Iterator<Item = E> + Send + 'static,
+    E: Into<ReplayEvent>,
+{
+    fn next_event(&mut self) -> Option<ReplayEvent> {
+        self.events.next().map(|e| e.into())
+    }
+}
+
+/// Events are replayed as they are received, until every sender is dropped
+impl<E> ReplaySource for Receiver<E>
+where
+    E: Into<ReplayEvent> + Send + 'static,
+{
+    fn next_event(&mut self) -> Option<ReplayEvent> {
+        self.recv().ok().map(|e| e.into())
+    }
+}
+
+/// Schemas embedded into the capture are made available to the trace callbacks.
+///
+/// In case the capture is invalid, an error is logged and the replay ends.
+impl<R> ReplaySource for CaptureReader<R>
+where
+    R: Read + Send + 'static,
+{
+    fn next_event(&mut self) -> Option<ReplayEvent> {
+        match self.next_event_with_schema() {
+            Ok(None) => None,
+            Ok(Some((record, schema))) => Some(ReplayEvent { record, schema }),
+            Err(err) => {
+                log::error!("Unable to read event from capture: {:?}", err);
+                None
+            }
+        }
+    }
+}
+
+/// The handles we give to replayed traces (and to their sessions).
+///
+/// They are taken far away from the values ETW returns (that happen to be small integers), and they cannot be mistaken for `INVALID_PROCESSTRACE_HANDLE`.<br/>
+/// They are unique across backends, because the `evntrace` layer finds opened traces by their handle.
+static NEXT_REPLAY_HANDLE: AtomicU64 = AtomicU64::new(0x0FE7_0000_0000_0000);
+
+fn next_replay_handle() -> u64 {
+    NEXT_REPLAY_HANDLE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// An [`EvntraceBackend`] whose single session delivers the events of a [`ReplaySource`]
+///
+/// Replayed traces go through the usual `evntrace` layer (and thus through the same callbacks as live events), only no actual ETW session is involved.
+pub(crate) struct ReplayBackend {
+    state: Mutex<ReplayState>,
+    /// Set once the session is stopped, or the trace is closed
+    closed: AtomicBool,
+}
+
+struct ReplayState {
+    /// `None` once the replay has started processing
+    source: Option<Box<dyn ReplaySource>>,
+    control_handle: Option<u64>,
+    consumer: Option<ReplayConsumer>,
+}
+
+struct ReplayConsumer {
+    trace_handle: u64,
+    callback: unsafe extern "system" fn(*mut Etw::EVENT_RECORD),
+    context: *const c_void,
+}
+
+// Safety: the context is only ever handed back to the callback, which the `evntrace` layer makes thread-safe
+unsafe impl Send for ReplayConsumer {}
+
+impl ReplayBackend {
+    pub(crate) fn new(source: Box<dyn ReplaySource>) -> Self {
+        Self {
+            state: Mutex::new(ReplayState {
+                source: Some(source),
+                control_handle: None,
+                consumer: None,
+            }),
+            closed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl std::fmt::Debug for ReplayBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("ReplayBackend")
+            .field("control_handle", &state.control_handle)
+            .field("trace_handle", &state.consumer.as_ref().map(|consumer| consumer.trace_handle))
+            .field("processed", &state.source.is_none())
+            .field("closed", &self.closed.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl EvntraceBackend for ReplayBackend {
+    fn start_trace(&self, control_handle: &mut ControlHandle, _properties: &mut EventTraceProperties) -> WIN32_ERROR {
+        let mut state = self.state.lock().unwrap();
+        if state.control_handle.is_some() {
+            return ERROR_ALREADY_EXISTS;
+        }
+        let handle = next_replay_handle();
+        state.control_handle = Some(handle);
+        *control_handle = Etw::CONTROLTRACE_HANDLE(handle);
+        ERROR_SUCCESS
+    }
+
+    fn open_trace(&self, log_file: &mut EventTraceLogfile) -> Result<TraceHandle, WIN32_ERROR> {
+        let mut state = self.state.lock().unwrap();
+        if state.control_handle.is_none() {
+            return Err(ERROR_WMI_INSTANCE_NOT_FOUND);
+        }
+        if state.consumer.is_some() {
+            return Err(ERROR_ALREADY_EXISTS);
+        }
+        let callback = log_file.event_record_callback().ok_or(ERROR_INVALID_PARAMETER)?;
+        let trace_handle = next_replay_handle();
+        state.consumer = Some(ReplayConsumer {
+            trace_handle,
+            callback,
+            context: log_file.context_ptr(),
+        });
+        Ok(Etw::PROCESSTRACE_HANDLE(trace_handle))
+    }
+
+    fn enable_trace(
+        &self,
+        _control_handle: ControlHandle,
+        _provider_id: &GUID,
+        _control_code: u32,
+        _level: u8,
+        _match_any_keyword: u64,
+        _match_all_keyword: u64,
+        _parameters: Option<&EnableTraceParameters>,
+    ) -> WIN32_ERROR {
+        // Providers are only used to route replayed events
+        ERROR_SUCCESS
+    }
+
+    fn process_trace(&self, trace_handle: TraceHandle) -> WIN32_ERROR {
+        let (mut source, callback, context) = {
+            let mut state = self.state.lock().unwrap();
+            let (callback, context) = match &state.consumer {
+                Some(consumer) if consumer.trace_handle == trace_handle.0 => (consumer.callback, consumer.context),
+                _ => return ERROR_INVALID_HANDLE,
+            };
+            match state.source.take() {
+                // This has already been (or is being) processed
+                None => return ERROR_INVALID_PARAMETER,
+                Some(source) => (source, callback, context),
+            }
+        };
+        let callback_data = evntrace::opened_callback_data(trace_handle);
+
+        while !self.closed.load(Ordering::Acquire) {
+            let event = match source.next_event() {
+                None => break,
+                Some(event) => event,
+            };
+            // The trace may have been closed while we were waiting for this event
+            if self.closed.load(Ordering::Acquire) {
+                break;
+            }
+
+            if let (Some(schema), Some(callback_data)) = (event.schema, &callback_data) {
+                callback_data.schema_locator().insert_schema(&event.record, schema);
+            }
+            let mut native = *event.record.as_raw();
+            native.UserContext = context as *mut c_void;
+            unsafe {
+                // Safety: this is the callback (and the context) the `evntrace` layer opened this trace with
+                callback(&mut native);
+            }
+
+            if callback_data.as_ref().map(|cb| cb.stop_requested()).unwrap_or(false) {
+                return ERROR_CANCELLED;
+            }
+        }
+
+        ERROR_SUCCESS
+    }
+
+    fn control_trace(
+        &self,
+        control_handle: ControlHandle,
+        _session_name: Option<&U16CStr>,
+        _properties: &mut EventTraceProperties,
+        control_code: Etw::EVENT_TRACE_CONTROL,
+    ) -> WIN32_ERROR {
+        if self.state.lock().unwrap().control_handle != Some(control_handle.0) {
+            return ERROR_WMI_INSTANCE_NOT_FOUND;
+        }
+        if control_code == Etw::EVENT_TRACE_CONTROL_STOP {
+            self.closed.store(true, Ordering::Release);
+            ERROR_SUCCESS
+        } else {
+            // There is no actual session to query, update or flush
+            ERROR_NOT_SUPPORTED
+        }
+    }
+
+    fn close_trace(&self, trace_handle: TraceHandle) -> WIN32_ERROR {
+        let mut state = self.state.lock().unwrap();
+        match &state.consumer {
+            Some(consumer) if consumer.trace_handle == trace_handle.0 => {
+                state.consumer = None;
+                self.closed.store(true, Ordering::Release);
+                ERROR_SUCCESS
+            },
+            _ => ERROR_INVALID_HANDLE,
+        }
+    }
+
+    fn query_all_traces(&self, _properties: &mut [QueriedTraceProperties], _logger_count: &mut u32) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+
+    fn set_information(&self, _control_handle: ControlHandle, _information_class: TraceInformation, _data: &[u8]) -> WIN32_ERROR {
+        // Replayed events are already recorded: there is nothing to configure
+        ERROR_SUCCESS
+    }
+
+    fn query_information(&self, _control_handle: ControlHandle, _information_class: TraceInformation, _buffer: &mut [u8], _return_length: &mut u32) -> WIN32_ERROR {
+        ERROR_NOT_SUPPORTED
+    }
+}
@@ -0,0 +1,260 @@
+//! Sessions this program did not start
+//!
+//! [`list_sessions`] enumerates every running ETW session, and [`RealTimeSession::attach`] consumes the events of an existing real-time session
+//! (e.g. `EventLog-Application`, `Circular Kernel Context Logger`, or a session started by another process).
+//!
+//! ```no_run
+//! use ferrisetw::provider::Provider;
+//! use ferrisetw::trace::session::{list_sessions, RealTimeSession};
+//!
+//! for session in list_sessions().unwrap() {
+//!     println!("{:?} ({} events lost)", session.name, session.statistics.events_lost);
+//! }
+//!
+//! let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+//!     .add_callback(|record, _schema_locator| println!("Event {}", record.event_id()))
+//!     .build();
+//! let session = RealTimeSession::attach("EventLog-Application")
+//!     .enable(provider)
+//!     .start_and_process()
+//!     .unwrap();
+//!
+//! // This only stops consuming events. The session itself keeps running.
+//! drop(session);
+//! ```
+use std::ffi::OsString;
+use std::sync::Arc;
+
+use widestring::U16CString;
+
+use super::callback_data::{CallbackData, RealTimeCallbackData};
+use super::event_context::TraceOwner;
+use super::panic_policy::PanicPolicy;
+use super::{TraceResult, TraceStatistics, TraceTrait};
+use crate::native::etw_types::{LoggingMode, SubscriptionSource};
+use crate::native::evntrace::backend::{default_backend, EvntraceBackend};
+use crate::native::evntrace::{close_trace, open_trace, query_all_traces, TraceHandle};
+use crate::provider::Provider;
+use crate::timestamp::TimestampConverter;
+
+/// Information about a running ETW session
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Name of the session
+    pub name: OsString,
+    /// Path of the file the session logs to, if any
+    pub log_file: Option<OsString>,
+    /// [Logging Mode](https://docs.microsoft.com/en-us/windows/win32/etw/logging-mode-constants) of the session
+    pub log_file_mode: LoggingMode,
+    /// Kernel flags enabled on this session (only meaningful for kernel sessions)
+    pub enable_flags: u32,
+    /// Buffers, lost events, etc.
+    pub statistics: TraceStatistics,
+}
+
+/// List every running ETW session on the system
+///
+/// Internally, this calls `QueryAllTracesW`. This usually requires administrator privileges, otherwise only some sessions are returned.
+pub fn list_sessions() -> TraceResult<Vec<SessionInfo>> {
+    list_sessions_with(default_backend().as_ref())
+}
+
+fn list_sessions_with(backend: &dyn EvntraceBackend) -> TraceResult<Vec<SessionInfo>> {
+    let sessions = query_all_traces(backend)?
+        .iter()
+        .map(|queried| {
+            let raw = queried.as_raw();
+            SessionInfo {
+                name: queried.name(),
+                log_file: queried.log_file(),
+                log_file_mode: LoggingMode::from_bits_truncate(raw.LogFileMode),
+                enable_flags: raw.EnableFlags.0,
+                statistics: TraceStatistics::from_raw(raw),
+            }
+        })
+        .collect();
+    Ok(sessions)
+}
+
+/// A consumer of a real-time session that already exists
+///
+/// Unlike [`UserTrace`](crate::UserTrace) and [`KernelTrace`](crate::KernelTrace), this neither creates nor stops the session:
+/// stopping or dropping a `RealTimeSession` only stops consuming its events.
+#[derive(Debug)]
+#[allow(clippy::redundant_allocation)] // see https://github.com/n4r1b/ferrisetw/issues/72
+pub struct RealTimeSession {
+    trace_handle: TraceHandle,
+    // CallbackData is
+    // * `Arc`ed, so that dropping a Trace while a callback is still running is not an issue
+    // * `Boxed`, so that the `RealTimeSession` can be moved around the stack (e.g. returned by a function) but the pointers to the `CallbackData` given to Windows ETW API stay valid
+    callback_data: Box<Arc<CallbackData>>,
+    backend: Arc<dyn EvntraceBackend>,
+}
+
+/// Provides a way to attach to an existing session
+///
+/// This is created using [`RealTimeSession::attach`]
+pub struct RealTimeSessionBuilder {
+    name: String,
+    rt_callback_data: RealTimeCallbackData,
+    backend: Arc<dyn EvntraceBackend>,
+}
+
+impl RealTimeSession {
+    /// Attach to the running real-time session with this name
+    pub fn attach(name: &str) -> RealTimeSessionBuilder {
+        RealTimeSessionBuilder {
+            name: name.to_string(),
+            rt_callback_data: RealTimeCallbackData::new(),
+            backend: default_backend(),
+        }
+    }
+}
+
+impl RealTimeSessionBuilder {
+    /// Invoke the callbacks of this Provider whenever the session delivers one of its events
+    ///
+    /// Note that this does not enable the provider on the session: the session only delivers events from the providers it was configured with.
+    pub fn enable(self, provider: Provider) -> Self {
+        self.rt_callback_data.add_provider(provider);
+        self
+    }
+
+    /// Set what happens when a callback panics
+    ///
+    /// See [`TraceBuilder::panic_policy`](super::TraceBuilder::panic_policy)
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.rt_callback_data.set_panic_policy(policy);
+        self
+    }
+
+    /// Use another backend than the Windows API (e.g. a fake one, in tests)
+    #[cfg(test)]
+    pub(crate) fn backend(mut self, backend: Arc<dyn EvntraceBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Open the session as a consumer
+    ///
+    /// Internally, this only calls `OpenTraceW`. See the documentation for [`TraceBuilder::start`](super::TraceBuilder::start) for how to process events.
+    pub fn start(self) -> TraceResult<(RealTimeSession, TraceHandle)> {
+        let wide_name = U16CString::from_str_truncate(self.name);
+        let callback_data = Box::new(Arc::new(CallbackData::RealTime(self.rt_callback_data)));
+        let trace_handle = open_trace(&self.backend, SubscriptionSource::RealTimeSession(wide_name.clone()), false, &callback_data)?;
+        callback_data.set_owner(TraceOwner {
+            name: Some(wide_name.to_os_string()),
+            trace_handle,
+            session: None,
+        });
+
+        Ok((RealTimeSession {
+                trace_handle,
+                callback_data,
+                backend: self.backend,
+            },
+            trace_handle)
+        )
+    }
+
+    /// Convenience method that calls [`RealTimeSessionBuilder::start`] then `process`
+    ///
+    /// # Notes
+    /// `process` is called on a spawned thread, and thus this method does not give any way to retrieve the error of `process` (if any)
+    pub fn start_and_process(self) -> TraceResult<RealTimeSession> {
+        let (trace, trace_handle) = self.start()?;
+
+        std::thread::spawn(move || RealTimeSession::process_from_handle(trace_handle));
+
+        Ok(trace)
+    }
+}
+
+impl TraceTrait for RealTimeSession {
+    fn trace_handle(&self) -> TraceHandle {
+        self.trace_handle
+    }
+
+    fn events_handled(&self) -> usize {
+        self.callback_data.events_handled()
+    }
+
+    fn timestamp_converter(&self) -> Option<TimestampConverter> {
+        self.callback_data.timestamp_converter()
+    }
+
+    fn caught_panics(&self) -> usize {
+        self.callback_data.caught_panics()
+    }
+}
+
+impl super::private::PrivateTraceTrait for RealTimeSession {
+    fn non_consuming_stop(&mut self) -> TraceResult<()> {
+        // The session belongs to someone else: let's only close our consumer
+        close_trace(self.backend.as_ref(), self.trace_handle, &self.callback_data)?;
+        Ok(())
+    }
+}
+
+impl Drop for RealTimeSession {
+    fn drop(&mut self) {
+        let _ignored_error_in_drop = super::private::PrivateTraceTrait::non_consuming_stop(self);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::native::evntrace::backend::fake::fake_user_trace;
+    use crate::record_builder::EventRecordBuilder;
+    use windows::core::GUID;
+
+    const PROV: &str = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716";
+
+    #[test]
+    fn test_list_sessions() {
+        let (builder, backend) = fake_user_trace("fake-listed");
+        let (_trace, _handle) = builder
+            .start()
+            .unwrap();
+        backend.lose_events("fake-listed", 2);
+
+        let sessions = list_sessions_with(backend.as_ref()).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].name, OsString::from("fake-listed"));
+        assert_eq!(sessions[0].log_file, None);
+        assert!(sessions[0].log_file_mode.contains(LoggingMode::EVENT_TRACE_REAL_TIME_MODE));
+        assert_eq!(sessions[0].statistics.events_lost, 2);
+    }
+
+    #[test]
+    fn test_attach_does_not_stop() {
+        let (builder, backend) = fake_user_trace("fake-attached");
+        let (owner, _handle) = builder
+            .start()
+            .unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(16);
+        let (attached, handle) = RealTimeSession::attach("fake-attached")
+            .enable(Provider::by_guid(PROV).add_callback(move |record, _| sender.send(record.event_id()).unwrap()).build())
+            .backend(backend.shared())
+            .start()
+            .unwrap();
+        let processing_thread = std::thread::spawn(move || RealTimeSession::process_from_handle(handle));
+
+        backend.emit("fake-attached", &EventRecordBuilder::new(GUID::from(PROV)).event_id(7).build());
+        assert_eq!(receiver.recv().unwrap(), 7);
+
+        attached.stop().unwrap();
+        processing_thread.join().unwrap().unwrap();
+        assert_eq!(backend.session_names(), vec![String::from("fake-attached")]);
+
+        // Attaching to a missing session fails
+        assert!(RealTimeSession::attach("fake-missing")
+            .backend(backend.shared())
+            .start()
+            .is_err());
+
+        owner.stop().unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+//! Events that ETW emits about a real-time session itself, rather than on behalf of a provider
+//!
+//! These are not delivered to the callbacks of the providers, see [`TraceBuilder::on_lost_events`](crate::trace::TraceBuilder::on_lost_events)
+//! and [`TraceBuilder::on_trace_header`](crate::trace::TraceBuilder::on_trace_header) instead.<br/>
+//! This also contains the statistics ETW gives whenever it has delivered a buffer of events (see [`TraceBuilder::on_buffer`](crate::trace::TraceBuilder::on_buffer)).
+
+use windows::Win32::System::Diagnostics::Etw;
+
+use crate::native::etw_types::event_record::EventRecord;
+
+/// Opcodes of the `RT_LostEvent` class
+const RT_LOST_EVENT: u8 = 32;
+const RT_LOST_BUFFER: u8 = 33;
+const RT_LOST_FILE: u8 = 34;
+
+/// What ETW reports to have lost
+///
+/// See [RT_LostEvent](https://learn.microsoft.com/en-us/windows/win32/etw/rt-lostevent)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LostEventKind {
+    /// Some events were lost, e.g. because the buffers were full
+    Events,
+    /// Some buffers were lost, e.g. because the consumer was too slow to process them
+    Buffers,
+    /// The backing file of the session could not be written
+    BackingFile,
+}
+
+impl LostEventKind {
+    pub(crate) fn from_opcode(opcode: u8) -> Option<Self> {
+        match opcode {
+            RT_LOST_EVENT => Some(LostEventKind::Events),
+            RT_LOST_BUFFER => Some(LostEventKind::Buffers),
+            RT_LOST_FILE => Some(LostEventKind::BackingFile),
+            _ => None,
+        }
+    }
+}
+
+/// How many times ETW reported losses since the trace was started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LostEventCounts {
+    /// Number of `RT_LostEvent` notifications
+    pub lost_events: u64,
+    /// Number of `RT_LostBuffer` notifications
+    pub lost_buffers: u64,
+    /// Number of `RT_LostFile` notifications
+    pub lost_backing_files: u64,
+}
+
+impl LostEventCounts {
+    pub(crate) fn add(&mut self, kind: LostEventKind) {
+        match kind {
+            LostEventKind::Events => self.lost_events += 1,
+            LostEventKind::Buffers => self.lost_buffers += 1,
+            LostEventKind::BackingFile => self.lost_backing_files += 1,
+        }
+    }
+}
+
+/// The header of a trace, as reported by the `EventTrace/Header` event
+///
+/// This is the payload of the event, which is a [TRACE_LOGFILE_HEADER](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-trace_logfile_header)
+/// followed by the names of the session and of its log file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceHeader {
+    /// Size of the buffers of the session, in bytes
+    pub buffer_size: u32,
+    /// Version of the operating system
+    pub version: u32,
+    /// Build number of the operating system
+    pub provider_version: u32,
+    pub number_of_processors: u32,
+    /// Time at which the session stopped (or 0 if it is still running), as a `FILETIME`
+    pub end_time: i64,
+    /// Resolution of the hardware timer, in 100-nanosecond units
+    pub timer_resolution: u32,
+    /// Maximum size of the log file, in megabytes
+    pub maximum_file_size: u32,
+    pub log_file_mode: u32,
+    pub buffers_written: u32,
+    /// Size of a pointer on the system that logged the events, in bytes
+    pub pointer_size: u32,
+    pub events_lost: u32,
+    pub cpu_speed_mhz: u32,
+    /// Time the system booted, as a `FILETIME`
+    pub boot_time: i64,
+    /// Frequency of the query performance counter, in ticks per second
+    pub perf_freq: i64,
+    /// Time at which the session started, as a `FILETIME`
+    pub start_time: i64,
+    /// The clock of the session (the `Wnode.ClientContext` it was started with, see [`ClockType`](crate::timestamp::ClockType))
+    pub clock_type: u32,
+    pub buffers_lost: u32,
+    pub logger_name: String,
+    pub log_file_name: String,
+}
+
+impl TraceHeader {
+    /// Parse the payload of an `EventTrace/Header` event
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        let read_u32 = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let read_i64 = |offset: usize| data.get(offset..offset + 8).map(|b| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(b);
+            i64::from_le_bytes(bytes)
+        });
+
+        let pointer_size = read_u32(44)?;
+        // The two pointers to the names (that are meaningless to consumers) are followed by a TIME_ZONE_INFORMATION, then by 8-byte-aligned members
+        let extra = match pointer_size {
+            4 => 0,
+            8 => 8,
+            _ => return None,
+        };
+
+        let header_size = 272 + extra;
+        let mut names = data
+            .get(header_size..)?
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]));
+        let mut read_name = || {
+            let name: Vec<u16> = names.by_ref().take_while(|c| *c != 0).collect();
+            String::from_utf16_lossy(&name)
+        };
+        let logger_name = read_name();
+        let log_file_name = read_name();
+
+        Some(Self {
+            buffer_size: read_u32(0)?,
+            version: read_u32(4)?,
+            provider_version: read_u32(8)?,
+            number_of_processors: read_u32(12)?,
+            end_time: read_i64(16)?,
+            timer_resolution: read_u32(24)?,
+            maximum_file_size: read_u32(28)?,
+            log_file_mode: read_u32(32)?,
+            buffers_written: read_u32(36)?,
+            pointer_size,
+            events_lost: read_u32(48)?,
+            cpu_speed_mhz: read_u32(52)?,
+            boot_time: read_i64(240 + extra)?,
+            perf_freq: read_i64(248 + extra)?,
+            start_time: read_i64(256 + extra)?,
+            clock_type: read_u32(264 + extra)?,
+            buffers_lost: read_u32(268 + extra)?,
+            logger_name,
+            log_file_name,
+        })
+    }
+
+    /// Parse an `EventTrace/Header` event
+    pub(crate) fn from_record(record: &EventRecord) -> Option<Self> {
+        Self::parse(record.user_buffer())
+    }
+}
+
+/// Statistics about a trace, that are given once all the events of a buffer have been delivered
+///
+/// See [EVENT_TRACE_LOGFILEW](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_logfilew)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferStatistics {
+    /// Number of buffers processed so far
+    pub buffers_read: u32,
+    /// Size of each buffer, in bytes
+    pub buffer_size: u32,
+    /// Number of bytes of the last buffer that contained events
+    pub filled: u32,
+    /// Number of events that were lost by the session
+    pub events_lost: u32,
+    /// Time of the last processed event, as a `FILETIME` (or a raw timestamp, see [`TraceBuilder::raw_timestamps`](crate::trace::TraceBuilder::raw_timestamps))
+    pub current_time: i64,
+    /// Total number of buffers of the file, when reading from an ETL file
+    pub total_buffers: Option<u32>,
+}
+
+impl BufferStatistics {
+    pub(crate) fn from_native(log_file: &Etw::EVENT_TRACE_LOGFILEW, from_file: bool) -> Self {
+        Self {
+            buffers_read: log_file.BuffersRead,
+            buffer_size: log_file.BufferSize,
+            filled: log_file.Filled,
+            events_lost: log_file.EventsLost,
+            current_time: log_file.CurrentTime,
+            total_buffers: from_file.then_some(log_file.LogfileHeader.BuffersWritten),
+        }
+    }
+
+    /// How much of the file has been processed, in percent
+    ///
+    /// This is `None` for real-time traces, that have no end.
+    pub fn progress(&self) -> Option<f32> {
+        match self.total_buffers {
+            None => None,
+            Some(0) => Some(100.0),
+            Some(total) => Some((self.buffers_read as f32 * 100.0 / total as f32).min(100.0)),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// The payload of an `EventTrace/Header` event, as logged by a 64-bit system
+    pub(crate) fn header_payload(logger_name: &str) -> Vec<u8> {
+        let mut data = vec![0u8; 280];
+        data[0..4].copy_from_slice(&65536u32.to_le_bytes()); // BufferSize
+        data[12..16].copy_from_slice(&8u32.to_le_bytes()); // NumberOfProcessors
+        data[44..48].copy_from_slice(&8u32.to_le_bytes()); // PointerSize
+        data[52..56].copy_from_slice(&2_904u32.to_le_bytes()); // CPUSpeed
+        data[248..256].copy_from_slice(&133_170_048_000_000_000i64.to_le_bytes()); // BootTime
+        data[256..264].copy_from_slice(&10_000_000i64.to_le_bytes()); // PerfFreq
+        data[272..276].copy_from_slice(&1u32.to_le_bytes()); // ReservedFlags
+        data.extend(logger_name.encode_utf16().chain(std::iter::once(0)).flat_map(|c| c.to_le_bytes()));
+        data.extend([0, 0]); // empty log file name
+        data
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let header = TraceHeader::parse(&header_payload("my-trace")).unwrap();
+
+        assert_eq!(header.buffer_size, 65536);
+        assert_eq!(header.number_of_processors, 8);
+        assert_eq!(header.cpu_speed_mhz, 2_904);
+        assert_eq!(header.boot_time, 133_170_048_000_000_000);
+        assert_eq!(header.perf_freq, 10_000_000);
+        assert_eq!(header.clock_type, 1);
+        assert_eq!(header.logger_name, "my-trace");
+        assert_eq!(header.log_file_name, "");
+
+        assert!(TraceHeader::parse(&[0; 100]).is_none());
+    }
+
+    #[test]
+    fn test_lost_event_counts() {
+        let mut counts = LostEventCounts::default();
+        for opcode in [32, 33, 33, 1] {
+            if let Some(kind) = LostEventKind::from_opcode(opcode) {
+                counts.add(kind);
+            }
+        }
+        assert_eq!(counts, LostEventCounts { lost_events: 1, lost_buffers: 2, lost_backing_files: 0 });
+    }
+
+    #[test]
+    fn test_buffer_statistics() {
+        let mut log_file = Etw::EVENT_TRACE_LOGFILEW {
+            BuffersRead: 3,
+            BufferSize: 65536,
+            Filled: 1024,
+            ..Default::default()
+        };
+        log_file.LogfileHeader.BuffersWritten = 12;
+
+        let stats = BufferStatistics::from_native(&log_file, true);
+        assert_eq!(stats.total_buffers, Some(12));
+        assert_eq!(stats.progress(), Some(25.0));
+
+        log_file.BuffersRead = 13;
+        assert_eq!(BufferStatistics::from_native(&log_file, true).progress(), Some(100.0));
+        assert_eq!(BufferStatistics::from_native(&log_file, false).progress(), None);
+    }
+}
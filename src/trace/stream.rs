@@ -0,0 +1,362 @@
+//! Consume traces as an asynchronous [`Stream`] of events
+//!
+//! This requires the `async` feature.
+//!
+//! Events are copied into a bounded queue by the thread that processes the trace, and are taken out of it by polling the [`TraceStream`].
+//! When the queue is full, the [`OverflowPolicy`] decides what happens to new events.<br/>
+//! The stream ends when the trace is done processing. What processing returned is then available from [`TraceStream::take_result`].<br/>
+//! Dropping the stream stops the trace.
+//!
+//! ```no_run
+//! # use futures::StreamExt;
+//! # use ferrisetw::provider::Provider;
+//! # use ferrisetw::trace::UserTrace;
+//! # use ferrisetw::trace::stream::{OverflowPolicy, StreamOptions};
+//! # async fn run() {
+//! let mut stream = UserTrace::new()
+//!     .enable(Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716").build())
+//!     .into_stream(StreamOptions::default().overflow_policy(OverflowPolicy::DropOldest))
+//!     .unwrap();
+//!
+//! while let Some(record) = stream.next().await {
+//!     println!("{}", record.event_id());
+//! }
+//! # }
+//! ```
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+
+use crate::native::etw_types::event_record::OwnedEventRecord;
+use crate::native::evntrace::backend::{EvntraceBackend, default_backend};
+use crate::native::evntrace::TraceHandle;
+use crate::trace::private::PrivateRealTimeTraceTrait;
+use crate::trace::{FileTrace, RealTimeTraceTrait, TraceBuilder, TraceResult, TraceTrait};
+use crate::EventRecord;
+
+/// What to do with new events when the queue of a [`TraceStream`] is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Block the thread that processes the trace until there is room in the queue.
+    /// ETW may then lose events on its side, if its own buffers get full as well
+    #[default]
+    Block,
+    /// Drop the event that was just received
+    DropNewest,
+    /// Drop the oldest event of the queue to make room for the new one
+    DropOldest,
+}
+
+/// Settings of a [`TraceStream`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamOptions {
+    /// Maximum number of events that are queued until the stream is polled
+    pub capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            overflow_policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl StreamOptions {
+    /// Set the maximum number of queued events (at least 1)
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Set what happens to new events when the queue is full
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    events: VecDeque<OwnedEventRecord>,
+    waker: Option<Waker>,
+    /// Set when the trace is done processing: no event will be pushed anymore
+    closed: bool,
+    /// What processing the trace returned, until it is taken by [`TraceStream::take_result`]
+    result: Option<TraceResult<()>>,
+    /// Set when the stream has been dropped: nobody will pop events anymore
+    abandoned: bool,
+}
+
+/// The queue between the thread that processes the trace and the stream
+#[derive(Debug)]
+struct EventQueue {
+    options: StreamOptions,
+    state: Mutex<QueueState>,
+    /// Notified when an event is popped, or when the stream is dropped
+    not_full: Condvar,
+    dropped_events: AtomicU64,
+}
+
+impl EventQueue {
+    fn new(options: StreamOptions) -> Self {
+        Self {
+            // The fields are public, so that the builder methods may have been bypassed
+            options: StreamOptions { capacity: options.capacity.max(1), ..options },
+            state: Mutex::new(QueueState::default()),
+            not_full: Condvar::new(),
+            dropped_events: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, record: &EventRecord) {
+        let mut state = self.state.lock().unwrap();
+        while !state.abandoned && state.events.len() >= self.options.capacity {
+            match self.options.overflow_policy {
+                OverflowPolicy::Block => state = self.not_full.wait(state).unwrap(),
+                OverflowPolicy::DropNewest => {
+                    self.dropped_events.fetch_add(1, Ordering::Relaxed);
+                    return;
+                },
+                OverflowPolicy::DropOldest => {
+                    state.events.pop_front();
+                    self.dropped_events.fetch_add(1, Ordering::Relaxed);
+                },
+            }
+        }
+        if state.abandoned {
+            return;
+        }
+
+        state.events.push_back(OwnedEventRecord::from_event_record(record));
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&self, result: TraceResult<()>) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn abandon(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.abandoned = true;
+        state.events.clear();
+        self.not_full.notify_all();
+    }
+
+    fn poll_next(&self, cx: &mut Context<'_>) -> Poll<Option<OwnedEventRecord>> {
+        let mut state = self.state.lock().unwrap();
+        match state.events.pop_front() {
+            Some(record) => {
+                self.not_full.notify_one();
+                Poll::Ready(Some(record))
+            },
+            None if state.closed => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+/// A trace whose events are consumed as a [`Stream`]
+///
+/// See [the module-level documentation](crate::trace::stream).<br/>
+/// The stream ends when the trace stops (e.g. when a file has been entirely read). Dropping the stream stops the trace.
+#[derive(Debug)]
+pub struct TraceStream<T: TraceTrait> {
+    trace: Option<T>,
+    queue: Arc<EventQueue>,
+}
+
+impl<T: TraceTrait> TraceStream<T> {
+    /// Start processing the trace on a spawned thread, and stream its events
+    fn spawn(trace: T, trace_handle: TraceHandle, queue: Arc<EventQueue>) -> Self {
+        let processing_queue = Arc::clone(&queue);
+        std::thread::spawn(move || {
+            let result = super::process_opened_trace(trace_handle);
+            processing_queue.close(result);
+        });
+
+        Self {
+            trace: Some(trace),
+            queue,
+        }
+    }
+
+    /// The trace that is being streamed, e.g. to query its statistics
+    pub fn trace(&self) -> &T {
+        // Can't panic: this is only `None` while the stream is dropped
+        self.trace.as_ref().unwrap()
+    }
+
+    /// What processing the trace returned (e.g. an error from `ProcessTrace`)
+    ///
+    /// This is `None` until the stream has ended, and once the result has been taken.
+    pub fn take_result(&mut self) -> Option<TraceResult<()>> {
+        self.queue.state.lock().unwrap().result.take()
+    }
+
+    /// How many events have been dropped so far because the queue was full
+    pub fn dropped_events(&self) -> u64 {
+        self.queue.dropped_events.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: TraceTrait> Stream for TraceStream<T> {
+    type Item = OwnedEventRecord;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.queue.poll_next(cx)
+    }
+}
+
+impl<T: TraceTrait> Drop for TraceStream<T> {
+    fn drop(&mut self) {
+        // Unblock the processing thread first, otherwise stopping the trace could wait for it forever
+        self.queue.abandon();
+        if let Some(trace) = self.trace.take() {
+            let _ignored_error_in_drop = trace.stop();
+        }
+    }
+}
+
+impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
+    /// Start the trace, and consume the events of its providers as a [`Stream`]
+    ///
+    /// The callbacks of the providers are still invoked, before events are queued for the stream.<br/>
+    /// This requires the `async` feature. See [the `stream` module](crate::trace::stream) for more info.
+    pub fn into_stream(mut self, options: StreamOptions) -> TraceResult<TraceStream<T>> {
+        let queue = Arc::new(EventQueue::new(options));
+        let sink_queue = Arc::clone(&queue);
+        self.rt_callback_data.set_event_sink(Box::new(move |record, _schema_locator| sink_queue.push(record)));
+
+        let (trace, trace_handle) = self.start()?;
+        Ok(TraceStream::spawn(trace, trace_handle, queue))
+    }
+}
+
+impl FileTrace {
+    /// Read the events of an ETL file as a [`Stream`]
+    ///
+    /// This requires the `async` feature. See [the `stream` module](crate::trace::stream) for more info.
+    pub fn stream(path: PathBuf, options: StreamOptions) -> TraceResult<TraceStream<FileTrace>> {
+        Self::stream_with_backend(path, options, default_backend())
+    }
+
+    fn stream_with_backend(path: PathBuf, options: StreamOptions, backend: Arc<dyn EvntraceBackend>) -> TraceResult<TraceStream<FileTrace>> {
+        let queue = Arc::new(EventQueue::new(options));
+        let sink_queue = Arc::clone(&queue);
+        let mut builder = FileTrace::new(path, move |record, _schema_locator| sink_queue.push(record));
+        builder.backend = backend;
+
+        let (trace, trace_handle) = builder.start()?;
+        Ok(TraceStream::spawn(trace, trace_handle, queue))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    use crate::native::evntrace::backend::fake::{fake_user_trace, FakeBackend};
+    use crate::provider::Provider;
+    use crate::record_builder::EventRecordBuilder;
+
+    const PROV: &str = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716";
+
+    fn event(id: u16) -> OwnedEventRecord {
+        EventRecordBuilder::new(windows::core::GUID::from(PROV)).event_id(id).build()
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let drain = |queue: &EventQueue, cx: &mut Context| {
+            let mut ids = Vec::new();
+            while let Poll::Ready(Some(record)) = queue.poll_next(cx) {
+                ids.push(record.event_id());
+            }
+            ids
+        };
+
+        let queue = EventQueue::new(StreamOptions::default().capacity(2).overflow_policy(OverflowPolicy::DropNewest));
+        (1..=4).for_each(|id| queue.push(&event(id)));
+        assert_eq!(drain(&queue, &mut cx), vec![1, 2]);
+        assert_eq!(queue.dropped_events.load(Ordering::Relaxed), 2);
+
+        let queue = EventQueue::new(StreamOptions::default().capacity(2).overflow_policy(OverflowPolicy::DropOldest));
+        (1..=4).for_each(|id| queue.push(&event(id)));
+        assert_eq!(drain(&queue, &mut cx), vec![3, 4]);
+        assert_eq!(queue.dropped_events.load(Ordering::Relaxed), 2);
+        queue.close(Ok(()));
+        assert!(matches!(queue.poll_next(&mut cx), Poll::Ready(None)));
+
+        // A blocked producer is released when the stream is dropped
+        let queue = Arc::new(EventQueue::new(StreamOptions::default().capacity(1)));
+        queue.push(&event(1));
+        let producer_queue = Arc::clone(&queue);
+        let producer = std::thread::spawn(move || producer_queue.push(&event(2)));
+        queue.abandon();
+        producer.join().unwrap();
+        assert_eq!(queue.dropped_events.load(Ordering::Relaxed), 0);
+
+        // A capacity of 0 (that bypasses the builder methods) behaves as a capacity of 1
+        let queue = EventQueue::new(StreamOptions { capacity: 0, overflow_policy: OverflowPolicy::DropOldest });
+        (1..=3).for_each(|id| queue.push(&event(id)));
+        assert_eq!(drain(&queue, &mut cx), vec![3]);
+        assert_eq!(queue.dropped_events.load(Ordering::Relaxed), 2);
+        let queue = EventQueue::new(StreamOptions { capacity: 0, overflow_policy: OverflowPolicy::Block });
+        queue.push(&event(1));
+        assert_eq!(drain(&queue, &mut cx), vec![1]);
+    }
+
+    #[test]
+    fn test_file_stream() {
+        let backend = Arc::new(FakeBackend::new());
+        backend.add_etl_file("fake-stream.etl", vec![(1..=3).map(event).collect(), (4..=5).map(event).collect()]);
+
+        let mut stream = FileTrace::stream_with_backend(PathBuf::from("fake-stream.etl"), StreamOptions::default().capacity(2), backend).unwrap();
+        let ids: Vec<u16> = block_on((&mut stream).map(|record| record.event_id()).collect());
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+        assert!(matches!(stream.take_result(), Some(Ok(()))));
+        assert!(stream.take_result().is_none());
+    }
+
+    #[test]
+    fn test_real_time_stream() {
+        let (builder, backend) = fake_user_trace("fake-stream");
+        let mut stream = builder
+            .enable(Provider::by_guid(PROV).build())
+            .into_stream(StreamOptions::default())
+            .unwrap();
+
+        backend.emit("fake-stream", &event(1));
+        backend.emit("fake-stream", &EventRecordBuilder::new(windows::core::GUID::from("1edeee53-0afe-4609-b846-d8c0b2075b1f")).build());
+        backend.emit("fake-stream", &event(2));
+        assert_eq!(block_on(stream.next()).map(|record| record.event_id()), Some(1));
+        assert_eq!(block_on(stream.next()).map(|record| record.event_id()), Some(2));
+        assert_eq!(stream.dropped_events(), 0);
+
+        // Dropping the stream stops the trace
+        drop(stream);
+        assert!(backend.session_names().is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use windows::core::GUID;
 
 pub fn rand_string() -> String {
     thread_rng()
@@ -9,6 +10,22 @@ pub fn rand_string() -> String {
         .collect()
 }
 
+/// Generate a new random GUID
+pub fn new_guid() -> GUID {
+    #[cfg(windows)]
+    {
+        GUID::new().unwrap_or(GUID::zeroed())
+    }
+    #[cfg(not(windows))]
+    {
+        // A version 4 (random) UUID
+        let mut bytes: [u8; 16] = thread_rng().gen();
+        bytes[6] = (bytes[6] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        GUID::from_u128(u128::from_be_bytes(bytes))
+    }
+}
+
 pub fn parse_utf16_guid(v: &[u8]) -> String {
     String::from_utf16_lossy(
         v.chunks_exact(2)
@@ -1,4 +1,5 @@
 //! Use the DNS provider to test a few things regarding user traces
+#![cfg(windows)] // These tests start actual ETW sessions
 
 use std::time::Duration;
 use std::process::Command;
@@ -1,3 +1,5 @@
+#![cfg(windows)] // These tests start actual ETW sessions
+
 use std::time::Duration;
 use std::path::PathBuf;
 
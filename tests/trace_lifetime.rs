@@ -1,4 +1,5 @@
 //! Test that traces are started and stopped as expected
+#![cfg(windows)] // These tests start actual ETW sessions
 
 use std::process::Command;
 